@@ -0,0 +1,19 @@
+#include <stdio.h>
+
+void crash(int depth) {
+    printf("Crashing at depth %d\n", depth);
+    *(int*)0 = depth;
+}
+
+void recurse(int depth) {
+    if (depth == 0) {
+        crash(depth);
+        return;
+    }
+    recurse(depth - 1);
+}
+
+int main() {
+    recurse(10);
+    return 0;
+}
@@ -0,0 +1,8 @@
+#include <stdio.h>
+
+// Built with -fdebug-prefix-map (see the Makefile), so that its debugging info refers to source
+// files that don't exist on this machine
+int main() {
+    printf("Where is my source code?\n");
+    return 0;
+}
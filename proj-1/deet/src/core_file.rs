@@ -0,0 +1,341 @@
+use crate::disassembler::read_bytes;
+use crate::dwarf_data::DwarfData;
+use crate::inferior::{print_frames, walk_stack};
+use nix::sys::signal;
+use std::convert::{TryFrom, TryInto};
+use std::fs;
+
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+
+/// Offsets into struct elf_prstatus (see <sys/procfs.h>) on x86_64
+const PRSTATUS_CURSIG_OFFSET: usize = 12;
+const PRSTATUS_REGS_OFFSET: usize = 112;
+/// Number of registers in a user_regs_struct
+const NUM_REGS: usize = 27;
+
+#[derive(Debug)]
+pub enum Error {
+    ErrorOpeningFile,
+    /// The file isn't an x86_64 ELF core file, or is truncated/corrupted
+    CoreFormatError(&'static str),
+}
+
+/// A memory region that was dumped into the core file
+struct Segment {
+    vaddr: usize,
+    offset: usize,
+    size: usize,
+}
+
+/// A core file produced when a process crashed. This provides read-only access to the registers
+/// and memory of the process at the time it crashed, so that it can be inspected the same way as
+/// a stopped inferior.
+pub struct CoreFile {
+    data: Vec<u8>,
+    segments: Vec<Segment>,
+    regs: libc::user_regs_struct,
+    signal: Option<signal::Signal>,
+}
+
+/// Adds offsets and sizes read from the file, which may be anything in a corrupted one
+fn add(a: usize, b: usize) -> Result<usize, Error> {
+    a.checked_add(b)
+        .ok_or(Error::CoreFormatError("offset out of range"))
+}
+
+/// Returns the len bytes of data at offset
+fn slice(data: &[u8], offset: usize, len: usize) -> Result<&[u8], Error> {
+    data.get(offset..add(offset, len)?)
+        .ok_or(Error::CoreFormatError("unexpected end of file"))
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, Error> {
+    Ok(u16::from_le_bytes(
+        slice(data, offset, 2)?.try_into().unwrap(),
+    ))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, Error> {
+    Ok(u32::from_le_bytes(
+        slice(data, offset, 4)?.try_into().unwrap(),
+    ))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, Error> {
+    Ok(u64::from_le_bytes(
+        slice(data, offset, 8)?.try_into().unwrap(),
+    ))
+}
+
+/// Builds a user_regs_struct from the registers saved in an NT_PRSTATUS note (which are laid out
+/// in exactly the same order)
+fn regs_from_prstatus(desc: &[u8]) -> Result<libc::user_regs_struct, Error> {
+    let mut r = [0_u64; NUM_REGS];
+    for (i, reg) in r.iter_mut().enumerate() {
+        *reg = read_u64(desc, PRSTATUS_REGS_OFFSET + i * 8)?;
+    }
+    Ok(libc::user_regs_struct {
+        r15: r[0],
+        r14: r[1],
+        r13: r[2],
+        r12: r[3],
+        rbp: r[4],
+        rbx: r[5],
+        r11: r[6],
+        r10: r[7],
+        r9: r[8],
+        r8: r[9],
+        rax: r[10],
+        rcx: r[11],
+        rdx: r[12],
+        rsi: r[13],
+        rdi: r[14],
+        orig_rax: r[15],
+        rip: r[16],
+        cs: r[17],
+        eflags: r[18],
+        rsp: r[19],
+        ss: r[20],
+        fs_base: r[21],
+        gs_base: r[22],
+        ds: r[23],
+        es: r[24],
+        fs: r[25],
+        gs: r[26],
+    })
+}
+
+impl CoreFile {
+    pub fn from_file(path: &str) -> Result<CoreFile, Error> {
+        let data = fs::read(path).or(Err(Error::ErrorOpeningFile))?;
+        if data.get(0..4) != Some(b"\x7fELF") || data.get(4) != Some(&2) || data.get(5) != Some(&1)
+        {
+            return Err(Error::CoreFormatError(
+                "not a 64-bit little-endian ELF file",
+            ));
+        }
+        if read_u16(&data, 16)? != ET_CORE {
+            return Err(Error::CoreFormatError("not a core file"));
+        }
+        if read_u16(&data, 18)? != EM_X86_64 {
+            return Err(Error::CoreFormatError("not an x86_64 core file"));
+        }
+
+        let phoff = read_u64(&data, 32)? as usize;
+        let phentsize = read_u16(&data, 54)? as usize;
+        let phnum = read_u16(&data, 56)? as usize;
+        let mut segments = Vec::new();
+        let mut prstatus = None;
+        for i in 0..phnum {
+            let phdr = add(phoff, i * phentsize)?;
+            let p_type = read_u32(&data, phdr)?;
+            let offset = read_u64(&data, add(phdr, 8)?)? as usize;
+            let vaddr = read_u64(&data, add(phdr, 16)?)? as usize;
+            let size = read_u64(&data, add(phdr, 32)?)? as usize;
+            if offset
+                .checked_add(size)
+                .map_or(true, |end| end > data.len())
+            {
+                return Err(Error::CoreFormatError("segment extends past end of file"));
+            }
+            match p_type {
+                PT_LOAD => segments.push(Segment {
+                    vaddr,
+                    offset,
+                    size,
+                }),
+                // Only the first NT_PRSTATUS matters: it belongs to the thread that crashed
+                PT_NOTE if prstatus.is_none() => {
+                    let notes = slice(&data, offset, size)?;
+                    if let Some((start, end)) = find_note(notes, NT_PRSTATUS)? {
+                        prstatus = Some((add(offset, start)?, end - start));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let (start, len) = prstatus.ok_or(Error::CoreFormatError("no NT_PRSTATUS note"))?;
+        let desc = slice(&data, start, len)?;
+        let regs = regs_from_prstatus(desc)?;
+        let signal = signal::Signal::try_from(read_u16(desc, PRSTATUS_CURSIG_OFFSET)? as i32).ok();
+        Ok(CoreFile {
+            data,
+            segments,
+            regs,
+            signal,
+        })
+    }
+
+    /// Returns the registers of the crashed thread
+    pub fn registers(&self) -> libc::user_regs_struct {
+        self.regs
+    }
+
+    /// Returns the signal that caused the core dump, if it's one we recognize
+    pub fn signal(&self) -> Option<signal::Signal> {
+        self.signal
+    }
+
+    /// Reads a word of the crashed process's memory. Returns None if that memory wasn't included
+    /// in the core file.
+    pub fn read_word(&self, addr: usize) -> Option<usize> {
+        let end = addr.checked_add(8)?;
+        let segment = self.segments.iter().find(|seg| {
+            seg.vaddr <= addr
+                && seg
+                    .vaddr
+                    .checked_add(seg.size)
+                    .map_or(false, |seg_end| end <= seg_end)
+        })?;
+        let offset = segment.offset.checked_add(addr - segment.vaddr)?;
+        read_u64(&self.data, offset).ok().map(|word| word as usize)
+    }
+
+    /// Reads up to len bytes of the crashed process's memory starting at addr, stopping early if
+    /// the range runs into memory that isn't in the core file
+    pub fn read_memory(&self, addr: usize, len: usize) -> Vec<u8> {
+        read_bytes(addr, len, |word_addr| self.read_word(word_addr).ok_or(()))
+    }
+
+    pub fn print_backtrace(&self, dwarf_data: &DwarfData, limit: Option<isize>) {
+        let frames = walk_stack(
+            dwarf_data,
+            self.regs.rip as usize,
+            self.regs.rbp as usize,
+            |addr| self.read_word(addr).ok_or(()),
+        )
+        .unwrap_or_else(|_| {
+            println!("backtrace ran into memory that isn't in the core file");
+            Vec::new()
+        });
+        print_frames(&frames, limit);
+    }
+}
+
+/// Searches a PT_NOTE segment for a note of the given type, returning the start and end offsets of
+/// its descriptor within the segment
+fn find_note(notes: &[u8], note_type: u32) -> Result<Option<(usize, usize)>, Error> {
+    let align = |n: usize| add(n, 3).map(|n| n & !3);
+    let mut pos = 0;
+    while pos + 12 <= notes.len() {
+        let namesz = read_u32(notes, pos)? as usize;
+        let descsz = read_u32(notes, pos + 4)? as usize;
+        let n_type = read_u32(notes, pos + 8)?;
+        let desc_start = add(pos + 12, align(namesz)?)?;
+        let desc_end = add(desc_start, descsz)?;
+        if desc_end > notes.len() {
+            return Err(Error::CoreFormatError("note extends past end of segment"));
+        }
+        if n_type == note_type {
+            return Ok(Some((desc_start, desc_end)));
+        }
+        pos = add(desc_start, align(descsz)?)?;
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+
+    /// Runs samples/segfault with core dumps enabled, returning the path of the resulting core file,
+    /// or None if this machine sends core dumps somewhere other than a file in the working directory
+    /// (e.g. to systemd-coredump) or doesn't write one at all
+    fn dump_core() -> Option<String> {
+        let core_pattern = fs::read_to_string("/proc/sys/kernel/core_pattern").unwrap_or_default();
+        if core_pattern.starts_with('|') || core_pattern.contains('/') {
+            println!(
+                "Skipping: core dumps go to \"{}\", not the working directory",
+                core_pattern.trim()
+            );
+            return None;
+        }
+        let dir = std::env::temp_dir().join(format!("deet-core-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let program = fs::canonicalize("samples/segfault").expect("Have you run make?");
+        let mut cmd = Command::new(program);
+        cmd.current_dir(&dir);
+        unsafe {
+            cmd.pre_exec(|| {
+                let mut limit = libc::rlimit {
+                    rlim_cur: 0,
+                    rlim_max: 0,
+                };
+                libc::getrlimit(libc::RLIMIT_CORE, &mut limit);
+                limit.rlim_cur = limit.rlim_max;
+                libc::setrlimit(libc::RLIMIT_CORE, &limit);
+                Ok(())
+            });
+        }
+        cmd.status().expect("Could not run samples/segfault");
+        let core = fs::read_dir(&dir)
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .find(|entry| entry.file_name().to_string_lossy().starts_with("core"));
+        match core {
+            Some(core) => Some(core.path().to_str().unwrap().to_string()),
+            None => {
+                println!("Skipping: no core file was written (is the core size limit 0?)");
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn test_core_backtrace() {
+        let dwarf_data =
+            DwarfData::from_file("samples/segfault").expect("Could not load samples/segfault");
+        let path = match dump_core() {
+            Some(path) => path,
+            None => return,
+        };
+        let core = CoreFile::from_file(&path).expect("Could not parse core file");
+        assert_eq!(core.signal(), Some(signal::Signal::SIGSEGV));
+
+        let regs = core.registers();
+        let frames = walk_stack(&dwarf_data, regs.rip as usize, regs.rbp as usize, |addr| {
+            core.read_word(addr).ok_or(())
+        })
+        .expect("Backtrace needed memory that wasn't in the core file");
+        let names: Vec<String> = frames.iter().map(|frame| frame.function.clone()).collect();
+        assert_eq!(names, vec!["func2", "func1", "main"]);
+        assert_eq!(frames[0].line.number, 5);
+    }
+
+    #[test]
+    fn test_not_a_core_file() {
+        match CoreFile::from_file("samples/segfault") {
+            Err(Error::CoreFormatError(_)) => {}
+            _ => panic!("Expected an executable to be rejected as a core file"),
+        }
+    }
+
+    #[test]
+    fn test_corrupted_core_file() {
+        // A core file header whose program headers are said to start just short of the end of
+        // the address space
+        let mut header = vec![0_u8; 64];
+        header[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        header[16..18].copy_from_slice(&ET_CORE.to_le_bytes());
+        header[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+        header[32..40].copy_from_slice(&(u64::MAX - 4).to_le_bytes());
+        header[54..56].copy_from_slice(&56_u16.to_le_bytes());
+        header[56..58].copy_from_slice(&1_u16.to_le_bytes());
+        let path = std::env::temp_dir().join(format!("deet-bad-core-{}", std::process::id()));
+        fs::write(&path, &header).unwrap();
+        let result = CoreFile::from_file(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        match result {
+            Err(Error::CoreFormatError(_)) => {}
+            _ => panic!("Expected a corrupted core file to be rejected"),
+        }
+    }
+}
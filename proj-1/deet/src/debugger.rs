@@ -1,17 +1,25 @@
+use crate::core_file::{CoreFile, Error as CoreError};
 use crate::debugger_command::DebuggerCommand;
+use crate::disassembler::{disassemble, print_instructions, MAX_INSTRUCTION_LEN};
 use crate::dwarf_data::{DwarfData, Error as DwarfError};
-use crate::inferior::{Inferior, Status};
+use crate::inferior::{is_job_control_stop, Inferior, Status};
+use crate::source::SourceMap;
 use nix::sys::signal;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 use std::collections::HashMap;
+use std::process::{Command, ExitStatus};
 
 pub struct Debugger {
     target: String,
     history_path: String,
     readline: Editor<()>,
     inferior: Option<Inferior>,
+    /// Core file being inspected, if any. A live inferior takes precedence over it.
+    core: Option<CoreFile>,
     debug_data: DwarfData,
+    /// Rewrites the source paths in debug_data to where the files are on this machine
+    source_map: SourceMap,
     breakpoints: HashMap<usize, Option<Breakpoint>>, // mem_addr -> written byte, orig_byte
 }
 
@@ -23,7 +31,7 @@ struct Breakpoint {
 
 impl Debugger {
     /// Initializes the debugger.
-    pub fn new(target: &str) -> Debugger {
+    pub fn new(target: &str, core_path: Option<&str>, source_map: SourceMap) -> Debugger {
         // initialize the DwarfData
         let debug_data = match DwarfData::from_file(target) {
             Ok(val) => val,
@@ -44,13 +52,36 @@ impl Debugger {
         // Attempt to load history from ~/.deet_history if it exists
         let _ = readline.load_history(&history_path);
 
-        Debugger {
+        let mut debugger = Debugger {
             target: target.to_string(),
             history_path,
             readline,
             inferior: None,
+            core: None,
             debug_data,
+            source_map,
             breakpoints: HashMap::new(),
+        };
+        if let Some(core_path) = core_path {
+            debugger.load_core(core_path);
+        }
+        debugger
+    }
+
+    /// Opens a core file for read-only inspection, replacing any previously loaded one
+    fn load_core(&mut self, path: &str) {
+        match CoreFile::from_file(path) {
+            Ok(core) => {
+                match core.signal() {
+                    Some(sig) => println!("Core was generated by a process killed by {}", sig),
+                    None => println!("Loaded core file {}", path),
+                }
+                self.core = Some(core);
+            }
+            Err(CoreError::ErrorOpeningFile) => println!("Could not open core file {}", path),
+            Err(CoreError::CoreFormatError(err)) => {
+                println!("Could not read core file {}: {}", path, err)
+            }
         }
     }
 
@@ -83,12 +114,35 @@ impl Debugger {
                         self.go();
                     }
                 },
-                DebuggerCommand::Backtrace => match &self.inferior {
-                    Some(process) => {
-                        process.print_backtrace(&self.debug_data).unwrap();
+                DebuggerCommand::Backtrace(limit) => match (&self.inferior, &self.core) {
+                    (Some(process), _) => {
+                        process.print_backtrace(&self.debug_data, limit).unwrap();
+                    }
+                    (None, Some(core)) => core.print_backtrace(&self.debug_data, limit),
+                    (None, None) => {}
+                },
+                DebuggerCommand::Registers => match (&self.inferior, &self.core) {
+                    (Some(process), _) => match process.registers() {
+                        Ok(regs) => print_registers(&regs),
+                        Err(err) => println!("Could not read registers: {}", err),
+                    },
+                    (None, Some(core)) => print_registers(&core.registers()),
+                    (None, None) => println!("Run the process or load a core file first!"),
+                },
+                DebuggerCommand::Shell(command) => {
+                    if let Err(err) = run_shell_command(&command) {
+                        println!("Could not run shell command: {}", err);
                     }
-                    None => {}
+                }
+                DebuggerCommand::Core(path) => self.load_core(&path),
+                DebuggerCommand::List => self.list_source(),
+                DebuggerCommand::ExamineInstructions(count, addr) => match parse_address(&addr) {
+                    Some(addr) => self.examine_instructions(addr, count),
+                    None => println!("Invalid address {}", addr),
                 },
+                DebuggerCommand::SetSubstitutePath(from, to) => {
+                    self.source_map.add_substitution(&from, &to);
+                }
                 DebuggerCommand::Breakpoint(addr) => {
                     let num_addr = parse_address(&addr).unwrap();
                     println!("Set breakpoint {} at {}", self.breakpoints.len(), num_addr);
@@ -142,8 +196,10 @@ impl Debugger {
 
     fn go(&mut self) {
         let process = self.inferior.as_mut().unwrap();
+        // Signal to deliver to the inferior the next time we continue it
+        let mut pending_signal = None;
         loop {
-            match process.go_on(None) {
+            match process.go_on(pending_signal.take()) {
                 Err(_) => {
                     format!("Inferior (pid:{}) couldn't continue", process.pid()).as_str();
                     break;
@@ -153,10 +209,23 @@ impl Debugger {
                         format!("Inferior (pid:{}) couldn't wait", process.pid()).as_str();
                         break;
                     }
+                    Ok(Status::Stopped(sig, _rip)) if is_job_control_stop(sig) => {
+                        // Let the stop signal through so that the inferior actually stops; we'll
+                        // get control back once it has entered the group-stop
+                        pending_signal = Some(sig);
+                    }
+                    Ok(Status::GroupStopped(sig)) => {
+                        // The inferior wasn't seized, so PTRACE_LISTEN isn't available; a plain
+                        // PTRACE_CONT (i.e. "continue") resumes it from the group-stop
+                        println!("inferior group-stopped by {}", sig);
+                        println!("Type \"continue\" to resume it");
+                        break;
+                    }
                     Ok(Status::Stopped(sig, rip)) => {
                         println!("child stopped (signal: {}, rip: {})", sig, rip);
                         if let Some(line) = self.debug_data.get_line_from_addr(rip) {
                             println!("Stopped at {}:{}", line.file, line.number);
+                            self.source_map.print_line(&line.file, line.number);
                         }
                         if sig == signal::Signal::SIGTRAP {
                             match self.restore_breakpoint(rip - 1) {
@@ -187,6 +256,65 @@ impl Debugger {
         }
     }
 
+    /// Prints the source code around the line the inferior (or core file) stopped at
+    fn list_source(&self) {
+        let rip = match (&self.inferior, &self.core) {
+            (Some(process), _) => match process.registers() {
+                Ok(regs) => regs.rip,
+                Err(err) => {
+                    println!("Could not read registers: {}", err);
+                    return;
+                }
+            },
+            (None, Some(core)) => core.registers().rip,
+            (None, None) => {
+                println!("Run the process or load a core file first!");
+                return;
+            }
+        };
+        match self.debug_data.get_line_from_addr(rip as usize) {
+            Some(line) => self.source_map.print_listing(&line.file, line.number),
+            None => println!("No line information for {:#x}", rip),
+        }
+    }
+
+    /// Disassembles and prints count instructions starting at addr, in the inferior or the core file
+    fn examine_instructions(&self, addr: usize, count: usize) {
+        let len = match count.checked_mul(MAX_INSTRUCTION_LEN) {
+            Some(len) => len,
+            None => {
+                println!("Too many instructions to examine");
+                return;
+            }
+        };
+        let mut bytes = match (&self.inferior, &self.core) {
+            (Some(process), _) => process.read_memory(addr, len),
+            (None, Some(core)) => core.read_memory(addr, len),
+            (None, None) => {
+                println!("Run the process or load a core file first!");
+                return;
+            }
+        };
+        // Show the original instructions rather than our breakpoints' int3s
+        let end = addr.checked_add(bytes.len()).unwrap_or(usize::MAX);
+        for (bp_addr, bp) in &self.breakpoints {
+            if let Some(bp) = bp {
+                if *bp_addr >= addr && *bp_addr < end {
+                    bytes[*bp_addr - addr] = bp.orig_byte;
+                }
+            }
+        }
+        let instructions = disassemble(&bytes, addr, count);
+        print_instructions(&instructions);
+        if instructions.len() < count {
+            let end = instructions
+                .last()
+                .map(|instruction| instruction.addr.saturating_add(instruction.bytes.len()))
+                .unwrap_or(addr);
+            println!("Cannot access memory at address {:#x}", end);
+        }
+    }
+
     fn clear_inferior(&mut self) {
         match &self.inferior {
             None => {}
@@ -244,6 +372,44 @@ impl Debugger {
     }
 }
 
+/// Runs a command through the shell, with the same terminal as deet, and waits for it to finish
+fn run_shell_command(command: &str) -> Result<ExitStatus, std::io::Error> {
+    let status = Command::new("sh").arg("-c").arg(command).status()?;
+    if !status.success() {
+        match status.code() {
+            Some(code) => println!("shell command exited with status {}", code),
+            None => println!("shell command was killed by a signal"),
+        }
+    }
+    Ok(status)
+}
+
+fn print_registers(regs: &libc::user_regs_struct) {
+    let named = [
+        ("rax", regs.rax),
+        ("rbx", regs.rbx),
+        ("rcx", regs.rcx),
+        ("rdx", regs.rdx),
+        ("rsi", regs.rsi),
+        ("rdi", regs.rdi),
+        ("rbp", regs.rbp),
+        ("rsp", regs.rsp),
+        ("r8", regs.r8),
+        ("r9", regs.r9),
+        ("r10", regs.r10),
+        ("r11", regs.r11),
+        ("r12", regs.r12),
+        ("r13", regs.r13),
+        ("r14", regs.r14),
+        ("r15", regs.r15),
+        ("rip", regs.rip),
+        ("eflags", regs.eflags),
+    ];
+    for (name, value) in named.iter() {
+        println!("{:<8}{:#018x}", name, value);
+    }
+}
+
 pub fn parse_address(addr: &str) -> Option<usize> {
     let addr_without_0x = if addr.to_lowercase().starts_with("0x") {
         &addr[2..]
@@ -252,3 +418,28 @@ pub fn parse_address(addr: &str) -> Option<usize> {
     };
     usize::from_str_radix(addr_without_0x, 16).ok()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shell_command() {
+        let status = run_shell_command("echo hello from deet").expect("Could not run sh");
+        assert!(status.success());
+        let status = run_shell_command("exit 3").expect("Could not run sh");
+        assert_eq!(status.code(), Some(3));
+    }
+
+    #[test]
+    fn test_examine_count_limit() {
+        let examine = |count: &str| {
+            let cmd = format!("x/{}i", count);
+            DebuggerCommand::from_tokens(&vec![cmd.as_str(), "0x401000"]).is_some()
+        };
+        assert!(examine(""));
+        assert!(examine("4096"));
+        assert!(!examine("4097"));
+        assert!(!examine("99999999999999999"));
+    }
+}
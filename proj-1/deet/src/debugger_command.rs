@@ -1,9 +1,19 @@
+/// Most instructions x/<count>i will disassemble at once
+pub const MAX_EXAMINE_COUNT: usize = 4096;
+
 pub enum DebuggerCommand {
     Quit,
     Run(Vec<String>),
     Continue,
-    Backtrace,
+    Backtrace(Option<isize>),
     Breakpoint(String),
+    Registers,
+    Shell(String),
+    Core(String),
+    List,
+    /// x/<count>i <addr>: disassemble count instructions starting at addr
+    ExamineInstructions(usize, String),
+    SetSubstitutePath(String, String),
 }
 
 impl DebuggerCommand {
@@ -17,7 +27,19 @@ impl DebuggerCommand {
                 ))
             }
             "c" | "cont" | "continue" => Some(DebuggerCommand::Continue),
-            "bt" | "back" | "backtrace" => Some(DebuggerCommand::Backtrace),
+            "bt" | "back" | "backtrace" => match tokens.get(1) {
+                None => Some(DebuggerCommand::Backtrace(None)),
+                Some(limit) => match limit.parse::<isize>() {
+                    Ok(limit) => Some(DebuggerCommand::Backtrace(Some(limit))),
+                    Err(_) => {
+                        println!(
+                            "usage: {} [number of frames, negative for outermost]",
+                            tokens[0]
+                        );
+                        None
+                    }
+                },
+            },
             "b" | "break" | "breakpoint" => {
                 if tokens.len() < 2 {
                     println!("usage: {} <memory in hex>", tokens[0]);
@@ -26,6 +48,59 @@ impl DebuggerCommand {
                 let args = tokens[1];
                 Some(DebuggerCommand::Breakpoint(args.to_string()))
             }
+            "regs" | "registers" => Some(DebuggerCommand::Registers),
+            "shell" | "!" => {
+                if tokens.len() < 2 {
+                    println!("usage: {} <command>", tokens[0]);
+                    return None;
+                }
+                Some(DebuggerCommand::Shell(tokens[1..].join(" ")))
+            }
+            "core" => {
+                if tokens.len() < 2 {
+                    println!("usage: {} <core file>", tokens[0]);
+                    return None;
+                }
+                Some(DebuggerCommand::Core(tokens[1].to_string()))
+            }
+            "l" | "list" => Some(DebuggerCommand::List),
+            "set" => match tokens.get(1) {
+                Some(&"substitute-path") if tokens.len() == 4 => {
+                    Some(DebuggerCommand::SetSubstitutePath(
+                        tokens[2].to_string(),
+                        tokens[3].to_string(),
+                    ))
+                }
+                _ => {
+                    println!("usage: {} substitute-path <from> <to>", tokens[0]);
+                    None
+                }
+            },
+            cmd if cmd.starts_with("x/") && cmd.ends_with('i') => {
+                let count = &cmd[2..cmd.len() - 1];
+                let count = if count.is_empty() {
+                    Some(1)
+                } else {
+                    count.parse::<usize>().ok()
+                };
+                match (count, tokens.get(1)) {
+                    (Some(count), Some(_)) if count > MAX_EXAMINE_COUNT => {
+                        println!(
+                            "Can examine at most {} instructions at a time",
+                            MAX_EXAMINE_COUNT
+                        );
+                        None
+                    }
+                    (Some(count), Some(addr)) => Some(DebuggerCommand::ExamineInstructions(
+                        count,
+                        addr.to_string(),
+                    )),
+                    _ => {
+                        println!("usage: x/[count]i <memory in hex>");
+                        None
+                    }
+                }
+            }
             // Default case:
             _ => None,
         }
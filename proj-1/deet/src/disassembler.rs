@@ -0,0 +1,106 @@
+use iced_x86::{Decoder, DecoderError, DecoderOptions, Formatter, IntelFormatter};
+use std::mem::size_of;
+
+/// x86 instructions are never longer than this
+pub const MAX_INSTRUCTION_LEN: usize = 15;
+
+/// A single decoded instruction
+pub struct Instruction {
+    pub addr: usize,
+    pub bytes: Vec<u8>,
+    /// The instruction in Intel syntax, e.g. "mov rbp,rsp"
+    pub text: String,
+}
+
+/// Reads len bytes starting at addr, one word at a time using read_word. If part of that range
+/// isn't readable (e.g. it runs off the end of a mapped region), the bytes up to that point are
+/// returned.
+pub fn read_bytes<E>(
+    addr: usize,
+    len: usize,
+    read_word: impl Fn(usize) -> Result<usize, E>,
+) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(len);
+    // Reading aligned words means we never read across more region boundaries than we have to
+    let mut word_addr = addr & !(size_of::<usize>() - 1);
+    while bytes.len() < len {
+        let word = match read_word(word_addr) {
+            Ok(word) => word,
+            Err(_) => break,
+        };
+        for (i, byte) in word.to_le_bytes().iter().enumerate() {
+            if word_addr + i >= addr && bytes.len() < len {
+                bytes.push(*byte);
+            }
+        }
+        word_addr += size_of::<usize>();
+    }
+    bytes
+}
+
+/// Decodes up to count instructions from bytes, which were read from memory starting at addr.
+/// Fewer instructions are returned if bytes ends partway through an instruction.
+pub fn disassemble(bytes: &[u8], addr: usize, count: usize) -> Vec<Instruction> {
+    let mut decoder = Decoder::with_ip(64, bytes, addr as u64, DecoderOptions::NONE);
+    let mut formatter = IntelFormatter::new();
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while instructions.len() < count && decoder.can_decode() {
+        let instruction = decoder.decode();
+        if decoder.last_error() == DecoderError::NoMoreBytes {
+            break;
+        }
+        let mut text = String::new();
+        if instruction.is_invalid() {
+            text.push_str("(bad)");
+        } else {
+            formatter.format(&instruction, &mut text);
+        }
+        instructions.push(Instruction {
+            addr: instruction.ip() as usize,
+            bytes: bytes[offset..offset + instruction.len()].to_vec(),
+            text,
+        });
+        offset += instruction.len();
+    }
+    instructions
+}
+
+/// Prints instructions the way `x/i` does: address, raw bytes, then the decoded instruction
+pub fn print_instructions(instructions: &[Instruction]) {
+    for instruction in instructions {
+        let bytes: Vec<String> = instruction
+            .bytes
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        println!(
+            "{:#x}:\t{:<24}{}",
+            instruction.addr,
+            bytes.join(" "),
+            instruction.text
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_partial_read() {
+        // Pretend only the word at 0x1000 is mapped: push rbp; mov rbp,rsp; then the first two
+        // bytes of a 7-byte lea
+        let word = usize::from_le_bytes([0x55, 0x48, 0x89, 0xe5, 0x48, 0x8d, 0x05, 0xd3]);
+        let read_word = |addr: usize| if addr == 0x1000 { Ok(word) } else { Err(()) };
+
+        let bytes = read_bytes(0x1001, 2 * MAX_INSTRUCTION_LEN, read_word);
+        assert_eq!(bytes, vec![0x48, 0x89, 0xe5, 0x48, 0x8d, 0x05, 0xd3]);
+        let instructions = disassemble(&bytes, 0x1001, 2);
+        assert_eq!(instructions.len(), 1);
+        assert_eq!(instructions[0].addr, 0x1001);
+        assert_eq!(instructions[0].text, "mov rbp,rsp");
+
+        assert!(read_bytes(0x2000, MAX_INSTRUCTION_LEN, read_word).is_empty());
+    }
+}
@@ -1,8 +1,10 @@
-use crate::dwarf_data::DwarfData;
+use crate::disassembler::read_bytes;
+use crate::dwarf_data::{DwarfData, Line};
 use nix::sys::ptrace;
 use nix::sys::signal;
 use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd::Pid;
+use std::cmp::min;
 use std::mem::size_of;
 use std::os::unix::process::CommandExt;
 use std::process::Child;
@@ -25,6 +27,22 @@ pub enum Status {
     /// Indicates the inferior exited due to a signal. Contains the signal that killed the
     /// process.
     Signaled(signal::Signal),
+
+    /// Indicates the inferior entered a job-control group-stop (e.g. after SIGSTOP or SIGTSTP was
+    /// delivered to it). Contains the signal that caused the stop. Registers aren't read in this
+    /// state, since the inferior isn't stopped at any instruction we care about.
+    GroupStopped(signal::Signal),
+}
+
+/// Returns true if the given signal stops the whole thread group when it is delivered.
+pub fn is_job_control_stop(sig: signal::Signal) -> bool {
+    matches!(
+        sig,
+        signal::Signal::SIGSTOP
+            | signal::Signal::SIGTSTP
+            | signal::Signal::SIGTTIN
+            | signal::Signal::SIGTTOU
+    )
 }
 
 /// This function calls ptrace with PTRACE_TRACEME to enable debugging on a process. You should use
@@ -36,6 +54,15 @@ fn child_traceme() -> Result<(), std::io::Error> {
     )))
 }
 
+/// Walking the frame pointer chain of a corrupted stack could otherwise go on forever
+const MAX_BACKTRACE_FRAMES: usize = 1024;
+
+/// A single stack frame in a backtrace
+pub struct Frame {
+    pub function: String,
+    pub line: Line,
+}
+
 pub struct Inferior {
     child: Child,
 }
@@ -67,6 +94,18 @@ impl Inferior {
             WaitStatus::Exited(_pid, exit_code) => Status::Exited(exit_code),
             WaitStatus::Signaled(_pid, signal, _core_dumped) => Status::Signaled(signal),
             WaitStatus::Stopped(_pid, signal) => {
+                // A stopping signal is first reported as a signal-delivery-stop. If we let it be
+                // delivered, the inferior enters a group-stop, which is reported with the same
+                // signal; the only way to tell them apart is that PTRACE_GETSIGINFO fails with
+                // EINVAL for a group-stop.
+                if is_job_control_stop(signal)
+                    && matches!(
+                        ptrace::getsiginfo(self.pid()),
+                        Err(nix::Error::Sys(nix::errno::Errno::EINVAL))
+                    )
+                {
+                    return Ok(Status::GroupStopped(signal));
+                }
                 let regs = ptrace::getregs(self.pid())?;
                 Status::Stopped(signal, regs.rip as usize)
             }
@@ -88,31 +127,36 @@ impl Inferior {
         }
     }
 
-    pub fn print_backtrace(&self, dwarf_data: &DwarfData) -> Result<(), nix::Error> {
+    /// Returns the frames on the inferior's stack, innermost first (see walk_stack).
+    pub fn backtrace(&self, dwarf_data: &DwarfData) -> Result<Vec<Frame>, nix::Error> {
         let regs = ptrace::getregs(self.pid()).expect("getregs returned unexpected error");
-        let mut rip = regs.rip as usize;
-        let mut rbp = regs.rbp as usize;
-        loop {
-            let line = dwarf_data.get_line_from_addr(rip as usize);
-            let func = dwarf_data.get_function_from_addr(rip as usize);
-            match (line, func) {
-                (Some(line), Some(func)) => {
-                    println!("{} ({}:{})", func, line.file, line.number);
-                    if func == "main" {
-                        break;
-                    }
-                    rip = ptrace::read(self.pid(), (rbp + 8) as ptrace::AddressType)? as usize;
-                    rbp = ptrace::read(self.pid(), rbp as ptrace::AddressType)? as usize;
-                }
-                (_, _) => {
-                    println!("couldn't find line or func pertaining to rip: {}", rip);
-                    break;
-                }
-            }
-        }
+        walk_stack(dwarf_data, regs.rip as usize, regs.rbp as usize, |addr| {
+            Ok(ptrace::read(self.pid(), addr as ptrace::AddressType)? as usize)
+        })
+    }
+
+    /// Returns the inferior's registers
+    pub fn registers(&self) -> Result<libc::user_regs_struct, nix::Error> {
+        ptrace::getregs(self.pid())
+    }
+
+    /// Prints the inferior's backtrace. A positive limit prints only that many of the innermost
+    /// frames, and a negative limit prints that many of the outermost frames.
+    pub fn print_backtrace(
+        &self,
+        dwarf_data: &DwarfData,
+        limit: Option<isize>,
+    ) -> Result<(), nix::Error> {
+        print_frames(&self.backtrace(dwarf_data)?, limit);
         Ok(())
     }
 
+    /// Reads up to len bytes of the inferior's memory starting at addr, stopping early if the
+    /// range runs into memory that isn't mapped
+    pub fn read_memory(&self, addr: usize, len: usize) -> Vec<u8> {
+        read_bytes(addr, len, |word_addr| self.read_byte(word_addr))
+    }
+
     pub fn read_byte(&self, addr: usize) -> Result<usize, nix::Error> {
         Ok(ptrace::read(self.pid(), addr as ptrace::AddressType)? as usize)
     }
@@ -134,6 +178,179 @@ impl Inferior {
     }
 }
 
+/// Walks the frame pointer chain starting at the given rip/rbp and returns the frames on the stack,
+/// innermost first, using read_word to read the stack's memory. The walk stops at main, at an
+/// address we have no debugging information for, at a frame pointer too close to the top of the
+/// address space to hold a return address, or after MAX_BACKTRACE_FRAMES frames (in case the stack
+/// is corrupted).
+pub fn walk_stack<E>(
+    dwarf_data: &DwarfData,
+    mut rip: usize,
+    mut rbp: usize,
+    read_word: impl Fn(usize) -> Result<usize, E>,
+) -> Result<Vec<Frame>, E> {
+    let mut frames = Vec::new();
+    while frames.len() < MAX_BACKTRACE_FRAMES {
+        let line = dwarf_data.get_line_from_addr(rip);
+        let func = dwarf_data.get_function_from_addr(rip);
+        match (line, func) {
+            (Some(line), Some(func)) => {
+                let is_main = func == "main";
+                frames.push(Frame {
+                    function: func,
+                    line,
+                });
+                if is_main {
+                    break;
+                }
+                let return_addr = match rbp.checked_add(8) {
+                    Some(addr) => addr,
+                    None => break,
+                };
+                rip = read_word(return_addr)?;
+                rbp = read_word(rbp)?;
+            }
+            (_, _) => {
+                println!("couldn't find line or func pertaining to rip: {}", rip);
+                break;
+            }
+        }
+    }
+    Ok(frames)
+}
+
+/// Returns the frames that `backtrace N` should print: all of them if there's no limit, the
+/// innermost N for a positive limit, or the outermost N for a negative one.
+pub fn select_frames(frames: &[Frame], limit: Option<isize>) -> &[Frame] {
+    match limit {
+        None => frames,
+        Some(n) if n >= 0 => &frames[..min(n as usize, frames.len())],
+        Some(n) => &frames[frames.len().saturating_sub(n.unsigned_abs())..],
+    }
+}
+
+/// Prints the frames selected by the given `backtrace N` limit
+pub fn print_frames(frames: &[Frame], limit: Option<isize>) {
+    for frame in select_frames(frames, limit) {
+        println!(
+            "{} ({}:{})",
+            frame.function, frame.line.file, frame.line.number
+        );
+    }
+}
+
 fn align_addr_to_word(addr: usize) -> usize {
     addr & (-(size_of::<usize>() as isize) as usize)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::disassembler::{disassemble, MAX_INSTRUCTION_LEN};
+
+    fn start_sample(program: &str, args: &[&str]) -> Inferior {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let inferior = Inferior::new(program, &args)
+            .unwrap_or_else(|| panic!("Could not start {}. Have you run make?", program));
+        // Wait for the stop at exec
+        match inferior.wait(None) {
+            Ok(Status::Stopped(signal::Signal::SIGTRAP, _)) => {}
+            _ => panic!("Inferior did not stop with SIGTRAP after exec"),
+        }
+        inferior
+    }
+
+    #[test]
+    fn test_group_stop() {
+        let inferior = start_sample("samples/sleepy_print", &["2"]);
+        inferior.go_on(None).unwrap();
+        signal::kill(inferior.pid(), signal::Signal::SIGSTOP).unwrap();
+
+        // The first stop is the signal being delivered, not the group-stop itself
+        match inferior.wait(None) {
+            Ok(Status::Stopped(signal::Signal::SIGSTOP, _)) => {}
+            _ => panic!("Expected a signal-delivery-stop for SIGSTOP"),
+        }
+        inferior.go_on(Some(signal::Signal::SIGSTOP)).unwrap();
+        match inferior.wait(None) {
+            Ok(Status::GroupStopped(signal::Signal::SIGSTOP)) => {}
+            _ => panic!("Expected the inferior to be group-stopped by SIGSTOP"),
+        }
+
+        // Continuing should resume it from the group-stop, and it should run to completion
+        inferior.go_on(None).unwrap();
+        match inferior.wait(None) {
+            Ok(Status::Exited(0)) => {}
+            _ => panic!("Expected the inferior to exit normally after being resumed"),
+        }
+    }
+
+    #[test]
+    fn test_backtrace_limit() {
+        let dwarf_data =
+            DwarfData::from_file("samples/recursion").expect("Could not load samples/recursion");
+        let inferior = start_sample("samples/recursion", &[]);
+        inferior.go_on(None).unwrap();
+        match inferior.wait(None) {
+            Ok(Status::Stopped(signal::Signal::SIGSEGV, _)) => {}
+            _ => panic!("Expected samples/recursion to segfault"),
+        }
+        let frames = inferior.backtrace(&dwarf_data).unwrap();
+        // crash, 11 calls to recurse, main
+        assert_eq!(frames.len(), 13);
+        let names = |frames: &[Frame]| -> Vec<String> {
+            frames.iter().map(|frame| frame.function.clone()).collect()
+        };
+
+        let innermost = select_frames(&frames, Some(3));
+        assert_eq!(names(innermost), vec!["crash", "recurse", "recurse"]);
+        assert_eq!(innermost[0].line.number, 5);
+
+        let outermost = select_frames(&frames, Some(-2));
+        assert_eq!(names(outermost), vec!["recurse", "main"]);
+
+        assert_eq!(select_frames(&frames, Some(100)).len(), frames.len());
+        assert_eq!(select_frames(&frames, Some(isize::MIN)).len(), frames.len());
+        assert_eq!(select_frames(&frames, Some(isize::MAX)).len(), frames.len());
+        assert_eq!(select_frames(&frames, None).len(), frames.len());
+        let _ = inferior.kill();
+    }
+
+    #[test]
+    fn test_walk_stack_overflow() {
+        let dwarf_data =
+            DwarfData::from_file("samples/recursion").expect("Could not load samples/recursion");
+        let crash_addr = dwarf_data
+            .get_addr_for_function(None, "crash")
+            .expect("Could not find crash");
+        // A frame pointer this close to the top of memory can't have a return address above it
+        let frames = walk_stack(&dwarf_data, crash_addr, usize::MAX - 4, |addr| {
+            Err::<usize, _>(addr)
+        })
+        .expect("Stack walk should have stopped before reading memory");
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].function, "crash");
+    }
+
+    #[test]
+    fn test_read_instructions() {
+        let dwarf_data =
+            DwarfData::from_file("samples/hello").expect("Could not load samples/hello");
+        let main_addr = dwarf_data
+            .get_addr_for_function(None, "main")
+            .expect("Could not find main");
+        let inferior = start_sample("samples/hello", &[]);
+
+        let bytes = inferior.read_memory(main_addr, 2 * MAX_INSTRUCTION_LEN);
+        assert_eq!(bytes.len(), 2 * MAX_INSTRUCTION_LEN);
+        let instructions = disassemble(&bytes, main_addr, 2);
+        let decoded: Vec<(&str, usize)> = instructions
+            .iter()
+            .map(|instruction| (instruction.text.as_str(), instruction.bytes.len()))
+            .collect();
+        assert_eq!(decoded, vec![("push rbp", 1), ("mov rbp,rsp", 3)]);
+        assert_eq!(instructions[1].addr, main_addr + 1);
+
+        inferior.kill().unwrap();
+    }
+}
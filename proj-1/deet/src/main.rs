@@ -1,24 +1,49 @@
+mod core_file;
 mod debugger;
 mod debugger_command;
+mod disassembler;
 mod dwarf_data;
 mod gimli_wrapper;
 mod inferior;
+mod source;
 
 use crate::debugger::Debugger;
+use crate::source::SourceMap;
 use nix::sys::signal::{signal, SigHandler, Signal};
 use std::env;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: {} <target program>", args[0]);
+    let usage = format!(
+        "Usage: {} [--source-map from=to]... <target program> [core file]",
+        args[0]
+    );
+    let mut source_map = SourceMap::new();
+    let mut positional = Vec::new();
+    let mut remaining = args[1..].iter();
+    while let Some(arg) = remaining.next() {
+        if arg == "--source-map" {
+            match remaining.next() {
+                Some(mapping) if source_map.add_substitution_from_arg(mapping) => {}
+                _ => {
+                    println!("{}", usage);
+                    std::process::exit(1);
+                }
+            }
+        } else {
+            positional.push(arg.as_str());
+        }
+    }
+    if positional.len() != 1 && positional.len() != 2 {
+        println!("{}", usage);
         std::process::exit(1);
     }
-    let target = &args[1];
+    let target = positional[0];
+    let core = positional.get(1).copied();
 
     // Disable handling of ctrl+c in this process (so that ctrl+c only gets delivered to child
     // processes)
     unsafe { signal(Signal::SIGINT, SigHandler::SigIgn) }.expect("Error disabling SIGINT handling");
 
-    Debugger::new(target).run();
+    Debugger::new(target, core, source_map).run();
 }
@@ -0,0 +1,152 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Number of lines the list command shows on either side of the current line
+const LIST_CONTEXT_LINES: usize = 5;
+
+/// Rewrites the source paths recorded in DWARF info before we try to open them, like gdb's
+/// `set substitute-path`. This is needed when the target was compiled on another machine (or in
+/// another directory), since the paths the compiler recorded won't exist here.
+#[derive(Default)]
+pub struct SourceMap {
+    /// (from, to) prefix pairs, in the order they were added
+    substitutions: Vec<(PathBuf, PathBuf)>,
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap::default()
+    }
+
+    pub fn add_substitution(&mut self, from: &str, to: &str) {
+        self.substitutions
+            .push((PathBuf::from(from), PathBuf::from(to)));
+    }
+
+    /// Parses a `from=to` command-line argument and adds it as a substitution. Returns false if
+    /// the argument isn't in that form.
+    pub fn add_substitution_from_arg(&mut self, arg: &str) -> bool {
+        match arg.find('=') {
+            Some(idx) if idx > 0 => {
+                self.add_substitution(&arg[..idx], &arg[idx + 1..]);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the local path for a path recorded in the DWARF info. Substitutions are tried in
+    /// the order they were added, and the first one whose prefix matches (on whole path
+    /// components, so /src doesn't match /srcs) is applied. Paths that no substitution matches are
+    /// returned unchanged.
+    pub fn resolve(&self, path: &str) -> PathBuf {
+        for (from, to) in &self.substitutions {
+            if let Ok(rest) = Path::new(path).strip_prefix(from) {
+                return to.join(rest);
+            }
+        }
+        PathBuf::from(path)
+    }
+
+    /// Reads lines first..=last (1-indexed) of a source file, returning them along with their line
+    /// numbers. Lines past the end of the file are left out.
+    pub fn read_lines(
+        &self,
+        path: &str,
+        first: usize,
+        last: usize,
+    ) -> io::Result<Vec<(usize, String)>> {
+        let contents = fs::read_to_string(self.resolve(path))?;
+        Ok(contents
+            .lines()
+            .enumerate()
+            .map(|(idx, line)| (idx + 1, line.to_string()))
+            .filter(|(number, _)| *number >= first && *number <= last)
+            .collect())
+    }
+
+    /// Prints a single line of a source file, the way gdb does when the inferior stops
+    pub fn print_line(&self, path: &str, line_number: usize) {
+        if let Ok(lines) = self.read_lines(path, line_number, line_number) {
+            for (number, line) in lines {
+                println!("{}\t{}", number, line);
+            }
+        }
+    }
+
+    /// Prints the lines surrounding line_number in a source file
+    pub fn print_listing(&self, path: &str, line_number: usize) {
+        let first = line_number.saturating_sub(LIST_CONTEXT_LINES).max(1);
+        match self.read_lines(path, first, line_number + LIST_CONTEXT_LINES) {
+            Ok(lines) => {
+                for (number, line) in lines {
+                    println!("{}\t{}", number, line);
+                }
+            }
+            Err(err) => println!(
+                "Could not read {}: {} (see \"set substitute-path\")",
+                self.resolve(path).display(),
+                err
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dwarf_data::DwarfData;
+
+    /// samples/remapped is built with -fdebug-prefix-map, so its DWARF info points at a directory
+    /// that doesn't exist
+    const REMAPPED_DIR: &str = "/nonexistent/deet";
+
+    #[test]
+    fn test_substitute_path() {
+        let dwarf_data = DwarfData::from_file("samples/remapped").expect("Have you run make?");
+        let addr = dwarf_data
+            .get_addr_for_function(None, "main")
+            .expect("Could not find main");
+        let line = dwarf_data
+            .get_line_from_addr(addr)
+            .expect("Could not find line for main");
+        assert!(line.file.starts_with(REMAPPED_DIR));
+
+        let mut source_map = SourceMap::new();
+        assert!(source_map.read_lines(&line.file, 1, 100).is_err());
+
+        // Substitutions are applied in order, so an earlier matching (but wrong) mapping wins
+        let cwd = std::env::current_dir().unwrap();
+        source_map.add_substitution("/nonexistent/deet/samples", "/still/not/here");
+        source_map.add_substitution(REMAPPED_DIR, cwd.to_str().unwrap());
+        assert!(source_map.read_lines(&line.file, 1, 100).is_err());
+
+        let mut source_map = SourceMap::new();
+        assert!(source_map.add_substitution_from_arg(&format!(
+            "{}={}",
+            REMAPPED_DIR,
+            cwd.display()
+        )));
+        let lines = source_map
+            .read_lines(&line.file, 1, 100)
+            .expect("Could not read remapped source file");
+        assert!(lines.iter().any(|(_, text)| text.contains("int main")));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let mut source_map = SourceMap::new();
+        source_map.add_substitution("/build/src", "/home/me/src");
+        assert_eq!(
+            source_map.resolve("/build/src/main.c"),
+            PathBuf::from("/home/me/src/main.c")
+        );
+        // Prefixes only match whole path components
+        assert_eq!(
+            source_map.resolve("/build/srcs/main.c"),
+            PathBuf::from("/build/srcs/main.c")
+        );
+        assert!(!source_map.add_substitution_from_arg("no-equals-sign"));
+    }
+}
@@ -0,0 +1,230 @@
+use crate::{logging, trace, ClientInfo, ProxyState};
+use parking_lot::Mutex;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{Duration, Instant};
+
+/// A file that gets one line per request, in Apache's combined log format, followed by how long
+/// the request took (in seconds), the upstream that answered it, and the request's ID:
+///
+/// ```text
+/// 203.0.113.7 - - [15/Oct/2026:12:00:00 +0000] "GET / HTTP/1.1" 200 512 "-" "curl/7.68.0" 0.004 10.0.0.1:8080 3f0c...
+/// ```
+///
+/// Unlike log::info lines, these are meant to be read by log analysis tools.
+pub struct AccessLog {
+    path: String,
+    file: Mutex<File>,
+}
+
+impl AccessLog {
+    /// Opens the file at path for appending, creating it if it doesn't exist
+    pub fn open(path: &str) -> Result<AccessLog, String> {
+        Ok(AccessLog {
+            path: path.to_string(),
+            file: Mutex::new(open_file(path)?),
+        })
+    }
+
+    /// Opens the file again by name, so that once log rotation has moved the old file aside, we
+    /// start writing to a new one
+    pub fn reopen(&self) {
+        match open_file(&self.path) {
+            Ok(file) => *self.file.lock() = file,
+            Err(err) => log::error!("{}", err),
+        }
+    }
+
+    fn write(&self, line: &str) {
+        // Written in one go, so that lines from different requests don't get mixed up
+        if let Err(err) = self.file.lock().write_all(line.as_bytes()) {
+            log::warn!("Could not write to access log {}: {}", self.path, err);
+        }
+    }
+}
+
+fn open_file(path: &str) -> Result<File, String> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|err| format!("Could not open access log {}: {}", path, err))
+}
+
+/// Reopens the access log whenever we get SIGUSR1 (which is what logrotate and friends are usually
+/// set up to send once they've moved it)
+pub async fn reopen_on_signal(log: Arc<AccessLog>) {
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(err) => {
+            log::error!("Could not install SIGUSR1 handler: {}", err);
+            return;
+        }
+    };
+    while signals.recv().await.is_some() {
+        log::info!("Received signal, reopening access log {}", log.path);
+        log.reopen();
+    }
+}
+
+/// The record of a request, which is written to the access log (and, with --log-format json,
+/// logged as an event), counted in the metrics, and exported as the request's span (with
+/// --otlp-endpoint) once the request is done with, when this is dropped. If no response was
+/// sent, its status is logged as "-".
+pub struct Entry {
+    state: Arc<ProxyState>,
+    /// When the request's head was read
+    received: chrono::DateTime<chrono::Utc>,
+    started: Instant,
+    client_ip: IpAddr,
+    method: http::Method,
+    path: String,
+    version: http::Version,
+    referer: Option<http::HeaderValue>,
+    user_agent: Option<http::HeaderValue>,
+    request_id: String,
+    status: Option<u16>,
+    bytes: u64,
+    upstream: Option<String>,
+    span: trace::Span,
+}
+
+impl Entry {
+    /// Starts an entry for a request from client (which for_request has given an ID). If there's
+    /// no --access-log, nothing is written to one.
+    pub fn new(
+        state: &Arc<ProxyState>,
+        client: &ClientInfo,
+        request: &http::Request<Vec<u8>>,
+    ) -> Entry {
+        let mut span = trace::Span::new(
+            state.tracer.as_ref(),
+            client.trace,
+            client.trace_parent,
+            request.method().as_str(),
+            trace::SpanKind::Server,
+        );
+        span.set_attribute("http.request.method", request.method().as_str());
+        span.set_attribute("url.path", request.uri().path());
+        span.set_attribute("client.address", client.ip.to_string());
+        if let Some(request_id) = &client.request_id {
+            span.set_attribute("balancebeam.request_id", request_id.as_str());
+        }
+        Entry {
+            state: Arc::clone(state),
+            received: chrono::DateTime::from(std::time::SystemTime::now()),
+            started: Instant::now(),
+            client_ip: client.ip,
+            method: request.method().clone(),
+            path: request.uri().to_string(),
+            version: request.version(),
+            referer: request.headers().get(http::header::REFERER).cloned(),
+            user_agent: request.headers().get(http::header::USER_AGENT).cloned(),
+            request_id: client.request_id.clone().unwrap_or_else(|| "-".to_string()),
+            status: None,
+            bytes: 0,
+            upstream: None,
+            span,
+        }
+    }
+
+    /// Records the response sent to the client. Its body is counted too, which is right for
+    /// responses we made ourselves; for a response from an upstream (whose body is passed on
+    /// rather than held in memory), call set_bytes once the body has been sent.
+    pub fn set_response(&mut self, response: &http::Response<Vec<u8>>) {
+        self.status = Some(response.status().as_u16());
+        self.bytes = response.body().len() as u64;
+    }
+
+    /// Records the size of the response's body
+    pub fn set_bytes(&mut self, bytes: u64) {
+        self.bytes = bytes;
+    }
+
+    /// Records which upstream the request went to
+    pub fn set_upstream(&mut self, upstream: Option<&str>) {
+        self.upstream = upstream.map(str::to_string);
+    }
+
+    fn write_to(&self, log: &AccessLog, latency: Duration) {
+        let header = |value: &Option<http::HeaderValue>| match value {
+            Some(value) => escape(value.as_bytes()),
+            None => "-".to_string(),
+        };
+        let status = self
+            .status
+            .map_or_else(|| "-".to_string(), |status| status.to_string());
+        // Combined log format writes an empty body as "-"
+        let bytes = match self.bytes {
+            0 => "-".to_string(),
+            bytes => bytes.to_string(),
+        };
+        let request_line = format!("{} {} {:?}", self.method, self.path, self.version);
+        log.write(&format!(
+            "{} - - [{}] \"{}\" {} {} \"{}\" \"{}\" {:.3} {} {}\n",
+            self.client_ip,
+            self.received.format("%d/%b/%Y:%H:%M:%S %z"),
+            escape(request_line.as_bytes()),
+            status,
+            bytes,
+            header(&self.referer),
+            header(&self.user_agent),
+            latency.as_secs_f64(),
+            self.upstream.as_deref().unwrap_or("-"),
+            self.request_id
+        ));
+    }
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        let latency = self.started.elapsed();
+        if let Some(log) = &self.state.access_log {
+            self.write_to(log, latency);
+        }
+        self.state.metrics.record_request(self.status, latency);
+        match self.status {
+            Some(status) => {
+                self.span.set_attribute("http.response.status_code", status);
+                if status >= 500 {
+                    self.span.set_error();
+                }
+            }
+            None => self.span.set_error(),
+        }
+        if let Some(upstream) = &self.upstream {
+            self.span
+                .set_attribute("balancebeam.upstream", upstream.as_str());
+        }
+        logging::Event {
+            client_ip: self.client_ip,
+            upstream: self.upstream.as_deref(),
+            method: self.method.as_str(),
+            path: &self.path,
+            status: self.status,
+            latency_ms: latency.as_secs_f64() * 1000.0,
+            request_id: &self.request_id,
+        }
+        .log();
+    }
+}
+
+/// Makes a value safe to put between double quotes in a log line, the way Apache does: quotes and
+/// backslashes are escaped with a backslash, and anything unprintable is written as \xHH
+fn escape(value: &[u8]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for &byte in value {
+        match byte {
+            b'"' | b'\\' => {
+                escaped.push('\\');
+                escaped.push(byte as char);
+            }
+            b' '..=b'~' => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,172 @@
+use crate::cidr::Cidr;
+use crate::path;
+use crate::response;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MethodAction {
+    /// Only these methods may be used under the prefix (anything else gets a 405)
+    Allow,
+    /// These methods may not be used under the prefix (they get a 403)
+    Deny,
+}
+
+/// Which methods may be used on some paths, checked before a request is forwarded. Written on the
+/// command line as `allow METHODS [PATH PREFIX]` or `deny METHODS [PATH PREFIX]`, with the methods
+/// separated by commas, e.g. `allow GET,HEAD /public` or `deny TRACE`. A prefix matches whole path
+/// segments, as for --route, and defaults to /.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MethodRule {
+    pub action: MethodAction,
+    pub methods: Vec<http::Method>,
+    pub path_prefix: String,
+}
+
+impl MethodRule {
+    pub fn new(
+        action: MethodAction,
+        methods: &[&str],
+        path_prefix: Option<&str>,
+    ) -> Result<MethodRule, String> {
+        let methods = methods
+            .iter()
+            .map(|method| {
+                method
+                    .trim()
+                    .to_ascii_uppercase()
+                    .parse()
+                    .map_err(|_| format!("invalid method \"{}\"", method))
+            })
+            .collect::<Result<Vec<http::Method>, String>>()?;
+        if methods.is_empty() {
+            return Err("method rule lists no methods".to_string());
+        }
+        let path_prefix = path_prefix.unwrap_or("/").trim();
+        let path_prefix = match path_prefix.strip_suffix("/*") {
+            Some("") => "/",
+            Some(prefix) => prefix,
+            None => path_prefix,
+        };
+        if !path_prefix.starts_with('/') {
+            return Err(format!(
+                "method rule path prefix \"{}\" should start with /",
+                path_prefix
+            ));
+        }
+        Ok(MethodRule {
+            action,
+            methods,
+            path_prefix: path_prefix.to_string(),
+        })
+    }
+
+    fn covers(&self, path: &str) -> bool {
+        path::covers(&self.path_prefix, path)
+    }
+}
+
+impl FromStr for MethodRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let action = match words.next().map(str::to_ascii_lowercase).as_deref() {
+            Some("allow") => MethodAction::Allow,
+            Some("deny") => MethodAction::Deny,
+            _ => {
+                return Err(format!(
+                    "invalid method rule \"{}\" (expected e.g. \"allow GET,HEAD /public\" or \
+                     \"deny TRACE\")",
+                    s
+                ))
+            }
+        };
+        let methods: Vec<&str> = match words.next() {
+            Some(methods) => methods.split(',').filter(|m| !m.is_empty()).collect(),
+            None => Vec::new(),
+        };
+        let path_prefix = words.next();
+        if let Some(extra) = words.next() {
+            return Err(format!("unexpected \"{}\" in method rule \"{}\"", extra, s));
+        }
+        MethodRule::new(action, &methods, path_prefix)
+    }
+}
+
+/// Checks a request's method against the rules. If it isn't allowed, returns the response to send
+/// instead of forwarding it: a 403 if a deny rule covers its path and method, or a 405 (with an
+/// Allow header) if the allow rule with the longest prefix covering its path doesn't list its
+/// method.
+pub fn check_method(
+    rules: &[MethodRule],
+    request: &http::Request<Vec<u8>>,
+) -> Option<http::Response<Vec<u8>>> {
+    let path = path::normalize(request.uri().path());
+    let method = request.method();
+    let covering = rules.iter().filter(|rule| rule.covers(&path));
+    if covering
+        .clone()
+        .any(|rule| rule.action == MethodAction::Deny && rule.methods.contains(method))
+    {
+        log::info!("Refusing {} {}: the method is denied", method, path);
+        return Some(response::make_http_error(http::StatusCode::FORBIDDEN));
+    }
+    let allowed = covering
+        .filter(|rule| rule.action == MethodAction::Allow)
+        .max_by_key(|rule| rule.path_prefix.len());
+    match allowed {
+        Some(rule) if !rule.methods.contains(method) => {
+            log::info!("Refusing {} {}: the method isn't allowed", method, path);
+            let mut response = response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED);
+            let allow: Vec<&str> = rule.methods.iter().map(http::Method::as_str).collect();
+            if let Ok(allow) = http::HeaderValue::from_str(&allow.join(", ")) {
+                response.headers_mut().insert(http::header::ALLOW, allow);
+            }
+            Some(response)
+        }
+        _ => None,
+    }
+}
+
+/// Clients that may connect at all, checked as soon as a connection is accepted (against its
+/// address, or the one in its PROXY protocol header). Unlike the rate limit lists, these turn a
+/// client away before any of its requests are read.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientAccess {
+    /// If not empty, only clients in these blocks may connect
+    pub allow: Vec<Cidr>,
+    /// Clients in these blocks may not connect, even if they're in allow
+    pub deny: Vec<Cidr>,
+}
+
+impl ClientAccess {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|cidr| cidr.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip)))
+    }
+}
+
+/// What a client that ClientAccess doesn't permit gets
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RefusalAction {
+    /// A 403 Forbidden, if the connection is plain HTTP (anything else is just closed)
+    Forbidden,
+    /// The connection is closed without a word
+    Drop,
+}
+
+impl FromStr for RefusalAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "forbidden" => Ok(RefusalAction::Forbidden),
+            "drop" => Ok(RefusalAction::Drop),
+            other => Err(format!(
+                "unknown denied client action \"{}\" (expected forbidden or drop)",
+                other
+            )),
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use tokio::time::{Duration, Instant};
+
+/// How much weight each request's outcome gets in an upstream's moving error rate
+const ERROR_EWMA_ALPHA: f64 = 0.1;
+/// How quickly an upstream's error rate decays towards zero while it isn't getting any traffic, so
+/// that an upstream that was failing is given more requests again over time
+const ERROR_HALF_LIFE: Duration = Duration::from_secs(30);
+/// Added to response times when comparing upstreams, so that small differences between fast
+/// upstreams (1ms vs. 2ms) don't count for much
+const LATENCY_TOLERANCE_SECS: f64 = 0.01;
+
+/// Exponentially-weighted moving average of the fraction of an upstream's requests that fail (with
+/// an error or a 5xx response), for --adaptive-weights
+#[derive(Debug, Default)]
+pub struct ErrorRate {
+    /// Error rate as of last_updated
+    rate: f64,
+    last_updated: Option<Instant>,
+}
+
+impl ErrorRate {
+    /// Returns the error rate, decayed according to how long ago it was last updated
+    pub fn estimate(&self, now: Instant) -> f64 {
+        match self.last_updated {
+            Some(last_updated) => {
+                let elapsed = now.duration_since(last_updated).as_secs_f64();
+                self.rate * 0.5_f64.powf(elapsed / ERROR_HALF_LIFE.as_secs_f64())
+            }
+            None => 0.0,
+        }
+    }
+
+    pub fn record(&mut self, success: bool) {
+        let now = Instant::now();
+        let sample = if success { 0.0 } else { 1.0 };
+        self.rate = self.estimate(now) * (1.0 - ERROR_EWMA_ALPHA) + sample * ERROR_EWMA_ALPHA;
+        self.last_updated = Some(now);
+    }
+}
+
+/// How well an upstream with this average response time (in seconds) and error rate is doing. The
+/// success rate is squared so that errors count for more than slowness: an upstream failing half
+/// its requests gets a quarter of its share.
+pub fn score(latency: f64, error_rate: f64) -> f64 {
+    (1.0 - error_rate).powi(2) / (latency + LATENCY_TOLERANCE_SECS)
+}
+
+/// The fraction of its configured share of requests that an upstream with this score should get,
+/// given the best score of the upstreams it's competing with. It never drops below min_share, so
+/// that a degraded upstream still gets a trickle of requests to show when it has recovered.
+pub fn share(score: f64, best_score: f64, min_share: f64) -> f64 {
+    if best_score <= 0.0 {
+        return 1.0;
+    }
+    (score / best_score).clamp(min_share, 1.0)
+}
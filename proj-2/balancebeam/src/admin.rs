@@ -0,0 +1,493 @@
+use crate::{
+    metrics, parse_upstream_state, rebuild_hash_ring, request, response, send_response,
+    set_draining, ClientInfo, ProxyState, UpstreamState,
+};
+use serde::Serialize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::io::BufReader;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Instant;
+
+/// Where the status page is served (see handle_status_request)
+const STATUS_PATH: &str = "/__balancebeam/status";
+
+/// Accepts connections on the admin listener. The admin API is served separately from proxied
+/// traffic so that it can be bound to a private interface, and so that it never gets forwarded
+/// upstream.
+pub async fn serve(mut listener: TcpListener, state: Arc<ProxyState>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((socket, _addr)) => socket,
+            Err(err) => {
+                log::error!("Couldn't get admin client: {}", err);
+                continue;
+            }
+        };
+
+        let shared_state = Arc::clone(&state);
+        tokio::spawn(async move {
+            handle_admin_connection(stream, &shared_state).await;
+        });
+    }
+}
+
+async fn handle_admin_connection(client_conn: TcpStream, state: &Arc<ProxyState>) {
+    let client = ClientInfo::new(&client_conn);
+    let mut client_conn = BufReader::new(client_conn);
+    loop {
+        let request =
+            match request::read_from_stream(&mut client_conn, false, &state.header_limits).await {
+                Ok(request) => request,
+                Err(request::Error::IncompleteRequest(0)) => return,
+                Err(error) => {
+                    log::debug!("Error parsing admin request: {}", error);
+                    let response = response::make_http_error(match error {
+                        request::Error::HeadersTooLarge => {
+                            http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+                        }
+                        _ => http::StatusCode::BAD_REQUEST,
+                    });
+                    send_response(state, &mut client_conn, &client, response).await;
+                    return;
+                }
+            };
+        log::info!("Admin request: {}", request::format_request_line(&request));
+        let response = handle_admin_request(&request, state).await;
+        send_response(state, &mut client_conn, &client, response).await;
+    }
+}
+
+/// One entry in the `GET /upstreams` listing
+#[derive(Serialize)]
+struct UpstreamStatus<'a> {
+    address: &'a str,
+    /// The DNS name the address was resolved from, if any
+    resolved_from: Option<&'a str>,
+    /// The discovery source the upstream was found through, if any
+    discovered_by: Option<&'a str>,
+    alive: bool,
+    draining: bool,
+    weight: usize,
+    /// "primary" or "backup"
+    tier: &'static str,
+    /// The pool the upstream is in, if any
+    pool: Option<&'a str>,
+    active_connections: usize,
+    /// "closed", "open", or "half-open"
+    circuit_breaker: &'static str,
+    /// Requests sent to the upstream since it was added, and how many of those failed
+    requests: u64,
+    errors: u64,
+    /// Request count, error rate, and latency percentiles over the last minute
+    recent: metrics::RecentStats,
+}
+
+fn upstream_statuses(upstreams: &[UpstreamState]) -> Vec<UpstreamStatus<'_>> {
+    let now = Instant::now();
+    upstreams
+        .iter()
+        .map(|upstream| UpstreamStatus {
+            address: &upstream.addr,
+            resolved_from: upstream.resolved_from.as_deref(),
+            discovered_by: upstream.discovered_by.as_deref(),
+            alive: !upstream.is_dead,
+            draining: upstream.draining,
+            weight: upstream.weight,
+            tier: upstream.tier.name(),
+            pool: upstream.pool.as_deref(),
+            active_connections: upstream.active_connections.load(Ordering::SeqCst),
+            circuit_breaker: upstream.breaker.lock().state_name(now),
+            requests: upstream.metrics.requests(),
+            errors: upstream.metrics.errors(),
+            recent: upstream.metrics.recent_stats(),
+        })
+        .collect()
+}
+
+/// The status page's contents
+#[derive(Serialize)]
+struct ProxyStatus<'a> {
+    uptime_seconds: u64,
+    /// Requests we've finished with since starting
+    total_requests: u64,
+    in_flight_requests: usize,
+    client_connections: usize,
+    draining: bool,
+    maintenance: bool,
+    /// Clients being tracked for rate limiting
+    /// None if the counters are kept in Redis
+    rate_limit_table_size: Option<usize>,
+    upstreams: Vec<UpstreamStatus<'a>>,
+}
+
+/// Routes an admin API request. Supported endpoints:
+///
+/// * `GET /ready`: 200 if this instance should be sent new traffic, or 503 if it is draining
+/// * `POST /drain`: puts the instance into drain mode. Existing connections keep being served, and
+///   the process keeps running; only the readiness check changes.
+/// * `GET /__balancebeam/status`: an overview of the proxy and its upstreams, as an HTML page, or
+///   as JSON if asked for with `Accept: application/json` or `?format=json`
+/// * `GET /metrics`: request counts, latencies, and connection gauges, for the proxy as a whole and
+///   for each upstream, in Prometheus text format
+/// * `GET /upstreams`: lists the upstreams, with their health, load, circuit breaker state, and
+///   recent error rate and latency percentiles, as JSON
+/// * `POST /upstreams/<upstream>`: adds an upstream. `<upstream>` is written the same way as for
+///   --upstream, e.g. `/upstreams/127.0.0.1:8080,weight=3`.
+/// * `DELETE /upstreams/<address>`: removes an upstream. Clients already connected to it are served
+///   until they hang up.
+/// * `POST /upstreams/<address>/dead` and `POST /upstreams/<address>/alive`: overrides an
+///   upstream's health. Active health checks will still change it back if they disagree.
+/// * `POST /upstreams/<address>/drain` and `POST /upstreams/<address>/undrain`: starts or stops
+///   draining an upstream. A draining upstream gets no new connections, but its existing ones are
+///   left to finish.
+/// * `GET /maintenance`: whether maintenance mode is on, and which path prefixes it covers (all
+///   paths if none), as JSON
+/// * `POST /maintenance/on` and `POST /maintenance/off`: turns maintenance mode on or off (see
+///   maintenance::Maintenance), returning the same JSON. Giving `path=<prefix>` parameters to `on`,
+///   e.g. `/maintenance/on?path=/api&path=/admin`, replaces the prefixes it covers.
+/// * `POST /cache/purge?url=<url>` and `POST /cache/purge?prefix=<url>`: drops the cached
+///   responses for a URL, or for every URL starting with the prefix (see cache::Cache::purge), and
+///   returns the number dropped as JSON
+async fn handle_admin_request(
+    request: &http::Request<Vec<u8>>,
+    state: &Arc<ProxyState>,
+) -> http::Response<Vec<u8>> {
+    let path = request.uri().path();
+    if path == "/upstreams" || path.starts_with("/upstreams/") {
+        return handle_upstreams_request(request.method(), path, state).await;
+    }
+    if path == STATUS_PATH {
+        return handle_status_request(request, state).await;
+    }
+    if path == "/cache/purge" {
+        return handle_purge_request(request, state);
+    }
+    if path == "/maintenance" || path.starts_with("/maintenance/") {
+        return handle_maintenance_request(request, state);
+    }
+    if path == "/metrics" {
+        if request.method() != http::Method::GET {
+            return response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED);
+        }
+        let body = metrics::render(state, &state.upstream_addresses.read().await);
+        return make_response("text/plain; version=0.0.4", body);
+    }
+    let status = match (request.method(), path) {
+        (&http::Method::GET, "/ready") => {
+            if state.draining.load(Ordering::SeqCst) {
+                http::StatusCode::SERVICE_UNAVAILABLE
+            } else {
+                http::StatusCode::OK
+            }
+        }
+        (&http::Method::POST, "/drain") => {
+            if !state.draining.swap(true, Ordering::SeqCst) {
+                log::info!("Entering drain mode; readiness checks will now fail");
+            }
+            http::StatusCode::OK
+        }
+        (_, "/ready") | (_, "/drain") => http::StatusCode::METHOD_NOT_ALLOWED,
+        _ => http::StatusCode::NOT_FOUND,
+    };
+    response::make_http_error(status)
+}
+
+/// Handles the /upstreams endpoints (see handle_admin_request)
+async fn handle_upstreams_request(
+    method: &http::Method,
+    path: &str,
+    state: &Arc<ProxyState>,
+) -> http::Response<Vec<u8>> {
+    if path == "/upstreams" {
+        if method != http::Method::GET {
+            return response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED);
+        }
+        let r_upstream_addresses = state.upstream_addresses.read().await;
+        let statuses = upstream_statuses(&r_upstream_addresses);
+        return make_response(
+            "application/json",
+            serde_json::to_string(&statuses).unwrap(),
+        );
+    }
+
+    let target = &path["/upstreams/".len()..];
+    let (addr, action) = match target.rsplit_once('/') {
+        Some((addr, action @ ("dead" | "alive" | "drain" | "undrain"))) => (addr, Some(action)),
+        Some(_) => return response::make_http_error(http::StatusCode::NOT_FOUND),
+        None => (target, None),
+    };
+
+    let mut w_upstream_addresses = state.upstream_addresses.write().await;
+    let existing = w_upstream_addresses
+        .iter()
+        .position(|upstream| upstream.addr == addr);
+    let status = match (method, action, existing) {
+        (&http::Method::POST, Some(action), Some(idx)) => {
+            let upstream = &mut w_upstream_addresses[idx];
+            match action {
+                "dead" | "alive" => {
+                    log::info!("Marking upstream {} {} via the admin API", addr, action);
+                    upstream.is_dead = action == "dead";
+                }
+                _ => set_draining(upstream, action == "drain"),
+            }
+            http::StatusCode::OK
+        }
+        (&http::Method::POST, None, _) => match parse_upstream_state(addr) {
+            Ok(upstream) => {
+                if w_upstream_addresses
+                    .iter()
+                    .any(|existing| existing.addr == upstream.addr)
+                {
+                    return response::make_http_error(http::StatusCode::CONFLICT);
+                }
+                log::info!("Adding upstream {} via the admin API", upstream.addr);
+                w_upstream_addresses.push(upstream);
+                http::StatusCode::CREATED
+            }
+            Err(err) => {
+                log::debug!("Invalid upstream in admin request: {}", err);
+                return response::make_http_error(http::StatusCode::BAD_REQUEST);
+            }
+        },
+        (&http::Method::DELETE, None, Some(idx)) => {
+            let removed = w_upstream_addresses.remove(idx);
+            log::info!(
+                "Removing upstream {} via the admin API ({} connections left to drain)",
+                removed.addr,
+                removed.active_connections.load(Ordering::SeqCst)
+            );
+            http::StatusCode::OK
+        }
+        (&http::Method::POST, Some(_), None) | (&http::Method::DELETE, None, None) => {
+            http::StatusCode::NOT_FOUND
+        }
+        _ => http::StatusCode::METHOD_NOT_ALLOWED,
+    };
+    rebuild_hash_ring(state, &w_upstream_addresses);
+    response::make_http_error(status)
+}
+
+/// Serves the status page (see handle_admin_request)
+async fn handle_status_request(
+    request: &http::Request<Vec<u8>>,
+    state: &Arc<ProxyState>,
+) -> http::Response<Vec<u8>> {
+    if request.method() != http::Method::GET {
+        return response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED);
+    }
+    let r_upstream_addresses = state.upstream_addresses.read().await;
+    let status = ProxyStatus {
+        uptime_seconds: state.started_at.elapsed().as_secs(),
+        total_requests: state.metrics.total_requests(),
+        in_flight_requests: state.in_flight_requests.load(Ordering::SeqCst),
+        client_connections: state.metrics.client_connections(),
+        draining: state.draining.load(Ordering::SeqCst),
+        maintenance: state.maintenance.is_on(),
+        rate_limit_table_size: state.rate_limit_store.tracked_clients(),
+        upstreams: upstream_statuses(&r_upstream_addresses),
+    };
+    if wants_json(request) {
+        make_response("application/json", serde_json::to_string(&status).unwrap())
+    } else {
+        make_response("text/html; charset=utf-8", render_status_page(&status))
+    }
+}
+
+/// Handles the /cache/purge endpoint (see handle_admin_request)
+fn handle_purge_request(
+    request: &http::Request<Vec<u8>>,
+    state: &ProxyState,
+) -> http::Response<Vec<u8>> {
+    if request.method() != http::Method::POST {
+        return response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED);
+    }
+    let cache = match &state.cache {
+        Some(cache) => cache,
+        None => return response::make_http_error(http::StatusCode::NOT_FOUND),
+    };
+    let param = request.uri().query().and_then(|query| {
+        query
+            .split('&')
+            .filter_map(|param| param.split_once('='))
+            .find(|(name, _)| *name == "url" || *name == "prefix")
+    });
+    let (name, url) = match param {
+        Some((name, url)) if !url.is_empty() => (name, percent_decode(url)),
+        _ => return response::make_http_error(http::StatusCode::BAD_REQUEST),
+    };
+    let purged = cache.purge(&url, name == "prefix");
+    log::info!(
+        "Purged {} cached responses for {} {} via the admin API",
+        purged,
+        name,
+        url
+    );
+    make_response(
+        "application/json",
+        serde_json::json!({ "purged": purged }).to_string(),
+    )
+}
+
+/// Handles the /maintenance endpoints (see handle_admin_request)
+fn handle_maintenance_request(
+    request: &http::Request<Vec<u8>>,
+    state: &ProxyState,
+) -> http::Response<Vec<u8>> {
+    let on = match (request.method(), request.uri().path()) {
+        (&http::Method::GET, "/maintenance") => None,
+        (&http::Method::POST, "/maintenance/on") => Some(true),
+        (&http::Method::POST, "/maintenance/off") => Some(false),
+        (_, "/maintenance") | (_, "/maintenance/on") | (_, "/maintenance/off") => {
+            return response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED);
+        }
+        _ => return response::make_http_error(http::StatusCode::NOT_FOUND),
+    };
+    if let Some(on) = on {
+        let paths: Vec<String> = request
+            .uri()
+            .query()
+            .unwrap_or("")
+            .split('&')
+            .filter_map(|param| param.strip_prefix("path="))
+            .map(percent_decode)
+            .collect();
+        let paths = Some(paths).filter(|paths| on && !paths.is_empty());
+        if let Err(err) = state.maintenance.set(on, paths) {
+            log::debug!("Invalid maintenance request: {}", err);
+            return response::make_http_error(http::StatusCode::BAD_REQUEST);
+        }
+    }
+    make_response(
+        "application/json",
+        serde_json::json!({
+            "on": state.maintenance.is_on(),
+            "paths": state.maintenance.paths(),
+        })
+        .to_string(),
+    )
+}
+
+/// Decodes the %XX escapes (and + for space) in a query parameter
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (bytes[i], escaped) {
+            (_, Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+            (b'+', None) => decoded.push(b' '),
+            (byte, None) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Returns true if the status page was asked for as JSON rather than HTML
+fn wants_json(request: &http::Request<Vec<u8>>) -> bool {
+    let query_asks = request
+        .uri()
+        .query()
+        .is_some_and(|query| query.split('&').any(|param| param == "format=json"));
+    let accept_asks = request
+        .headers()
+        .get(http::header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json") && !accept.contains("text/html"));
+    query_asks || accept_asks
+}
+
+fn render_status_page(status: &ProxyStatus) -> String {
+    let mut rows = String::new();
+    for upstream in &status.upstreams {
+        let health = match (upstream.alive, upstream.draining) {
+            (false, _) => "dead",
+            (true, true) => "draining",
+            (true, false) => "alive",
+        };
+        let milliseconds =
+            |value: Option<f64>| value.map_or_else(|| "-".to_string(), |ms| format!("{:.1}", ms));
+        let error_rate = upstream
+            .recent
+            .error_rate
+            .map_or_else(|| "-".to_string(), |rate| format!("{:.1}%", rate * 100.0));
+        rows.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td>\
+             <td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            escape_html(upstream.address),
+            health,
+            upstream.tier,
+            upstream.weight,
+            upstream.circuit_breaker,
+            upstream.active_connections,
+            upstream.requests,
+            upstream.errors,
+            error_rate,
+            milliseconds(upstream.recent.p50_ms),
+            milliseconds(upstream.recent.p99_ms),
+        ));
+    }
+    format!(
+        "<!DOCTYPE html>
+<html>
+<head><meta charset=\"utf-8\"><title>balancebeam status</title></head>
+<body>
+<h1>balancebeam</h1>
+<table>
+<tr><th>Uptime</th><td>{}s</td></tr>
+<tr><th>Total requests</th><td>{}</td></tr>
+<tr><th>In-flight requests</th><td>{}</td></tr>
+<tr><th>Client connections</th><td>{}</td></tr>
+<tr><th>Draining</th><td>{}</td></tr>
+<tr><th>Maintenance mode</th><td>{}</td></tr>
+<tr><th>Rate-limit table size</th><td>{}</td></tr>
+</table>
+<h2>Upstreams</h2>
+<table>
+<tr><th>Address</th><th>Health</th><th>Tier</th><th>Weight</th><th>Circuit breaker</th>\
+<th>Active</th><th>Requests</th><th>Errors</th><th>Error rate (1m)</th><th>p50 ms (1m)</th>\
+<th>p99 ms (1m)</th></tr>
+{}</table>
+</body>
+</html>
+",
+        status.uptime_seconds,
+        status.total_requests,
+        status.in_flight_requests,
+        status.client_connections,
+        if status.draining { "yes" } else { "no" },
+        if status.maintenance { "on" } else { "off" },
+        status
+            .rate_limit_table_size
+            .map_or("-".to_string(), |size| size.to_string()),
+        rows
+    )
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn make_response(content_type: &str, body: String) -> http::Response<Vec<u8>> {
+    let body = body.into_bytes();
+    http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header("Content-Type", content_type)
+        .header("Content-Length", body.len().to_string())
+        .version(http::Version::HTTP_11)
+        .body(body)
+        .unwrap()
+}
@@ -0,0 +1,594 @@
+use crate::path;
+use crate::response;
+use ring::signature::{self, UnparsedPublicKey};
+use ring::{constant_time, digest, hmac};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How far a token's exp and nbf may be off, to allow for clocks that don't quite agree
+const CLOCK_SKEW: u64 = 60;
+
+/// The header we tell upstreams who a request was authenticated as in. Clients can't set it
+/// themselves on protected paths.
+const USER_HEADER: &str = "x-authenticated-user";
+
+/// How requests for some paths must prove who they're from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scheme {
+    /// HTTP Basic auth, checked against --basic-auth-file
+    Basic,
+    /// A bearer JWT, signed with --jwt-secret or a key in --jwt-jwks
+    Jwt,
+    /// Nothing (for carving public paths out of protected ones)
+    None,
+}
+
+/// Protects the paths under a prefix with an auth scheme. Written on the command line as
+/// `<scheme> <path prefix>`, e.g. `basic /admin`, `jwt /api`, or `none /api/health`. A prefix
+/// matches whole path segments, as for --route, and the rule with the longest matching prefix
+/// applies.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthRule {
+    pub scheme: Scheme,
+    pub path_prefix: String,
+}
+
+impl AuthRule {
+    fn covers(&self, path: &str) -> bool {
+        path::covers(&self.path_prefix, path)
+    }
+}
+
+impl FromStr for AuthRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let scheme = match words.next().map(str::to_ascii_lowercase).as_deref() {
+            Some("basic") => Scheme::Basic,
+            Some("jwt") => Scheme::Jwt,
+            Some("none") => Scheme::None,
+            _ => {
+                return Err(format!(
+                    "invalid auth rule \"{}\" (expected e.g. \"basic /admin\" or \"jwt /api\")",
+                    s
+                ))
+            }
+        };
+        let path_prefix = words.next().unwrap_or("/");
+        if let Some(extra) = words.next() {
+            return Err(format!("unexpected \"{}\" in auth rule \"{}\"", extra, s));
+        }
+        let path_prefix = match path_prefix.strip_suffix("/*") {
+            Some("") => "/",
+            Some(prefix) => prefix,
+            None => path_prefix,
+        };
+        if !path_prefix.starts_with('/') {
+            return Err(format!(
+                "auth rule path prefix \"{}\" should start with /",
+                path_prefix
+            ));
+        }
+        Ok(AuthRule {
+            scheme,
+            path_prefix: path_prefix.to_string(),
+        })
+    }
+}
+
+/// Where auth settings come from (see Auth::new)
+#[derive(Debug, Default)]
+pub struct Options<'a> {
+    pub basic_auth_file: Option<&'a str>,
+    pub realm: &'a str,
+    pub jwt_secret: Option<&'a str>,
+    pub jwt_jwks: Option<&'a str>,
+    pub jwt_issuer: Option<&'a str>,
+    pub jwt_audience: Option<&'a str>,
+}
+
+/// Checks that requests for protected paths are from someone we know
+#[derive(Debug, Default)]
+pub struct Auth {
+    rules: Vec<AuthRule>,
+    /// Password hashes by user, from --basic-auth-file
+    users: HashMap<String, PasswordHash>,
+    realm: String,
+    jwt_secret: Option<Vec<u8>>,
+    jwt_keys: Vec<Jwk>,
+    jwt_issuer: Option<String>,
+    jwt_audience: Option<String>,
+}
+
+impl Auth {
+    /// Loads whatever the rules need: the users for basic auth, and the keys for JWTs
+    pub fn new(rules: Vec<AuthRule>, options: Options) -> Result<Auth, String> {
+        let mut auth = Auth {
+            realm: options.realm.to_string(),
+            jwt_secret: options.jwt_secret.map(|secret| secret.as_bytes().to_vec()),
+            jwt_issuer: options.jwt_issuer.map(str::to_string),
+            jwt_audience: options.jwt_audience.map(str::to_string),
+            ..Auth::default()
+        };
+        if rules.iter().any(|rule| rule.scheme == Scheme::Basic) {
+            let path = options
+                .basic_auth_file
+                .ok_or("basic auth rules need a --basic-auth-file")?;
+            auth.users = load_htpasswd(path)?;
+        }
+        if let Some(path) = options.jwt_jwks {
+            auth.jwt_keys = load_jwks(path)?;
+        }
+        if rules.iter().any(|rule| rule.scheme == Scheme::Jwt)
+            && auth.jwt_secret.is_none()
+            && auth.jwt_keys.is_empty()
+        {
+            return Err("jwt auth rules need a --jwt-secret or --jwt-jwks".to_string());
+        }
+        auth.rules = rules;
+        Ok(auth)
+    }
+
+    /// Checks a request's credentials if its path is protected. If they're missing or wrong,
+    /// returns the 401 to send instead of forwarding it. Otherwise, tells the upstream who the
+    /// request is from (in X-Authenticated-User), if the credentials say.
+    pub fn check(&self, request: &mut http::Request<Vec<u8>>) -> Option<http::Response<Vec<u8>>> {
+        // Whatever the path, only we get to say who a request is from
+        request.headers_mut().remove(USER_HEADER);
+        let path = request.uri().path().to_string();
+        let normalized = path::normalize(&path);
+        let scheme = self
+            .rules
+            .iter()
+            .filter(|rule| rule.covers(&normalized))
+            .max_by_key(|rule| rule.path_prefix.len())
+            .map_or(Scheme::None, |rule| rule.scheme);
+        if scheme == Scheme::None {
+            return None;
+        }
+        let credentials = request
+            .headers()
+            .get(http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().split_once(' '))
+            .map(|(kind, credentials)| (kind.to_ascii_lowercase(), credentials.trim()));
+        let user = match (scheme, credentials) {
+            (Scheme::Basic, Some((kind, credentials))) if kind == "basic" => {
+                self.check_basic(credentials)
+            }
+            (Scheme::Jwt, Some((kind, token))) if kind == "bearer" => self.check_jwt(token),
+            _ => Err("no credentials".to_string()),
+        };
+        match user {
+            Ok(Some(user)) => {
+                if let Ok(user) = http::HeaderValue::from_str(&user) {
+                    request.headers_mut().insert(USER_HEADER, user);
+                }
+                None
+            }
+            Ok(None) => None,
+            Err(reason) => {
+                log::info!("Refusing unauthenticated request for {}: {}", path, reason);
+                let mut response = response::make_http_error(http::StatusCode::UNAUTHORIZED);
+                let challenge = match scheme {
+                    Scheme::Basic => format!("Basic realm=\"{}\"", self.realm),
+                    _ => format!("Bearer realm=\"{}\"", self.realm),
+                };
+                if let Ok(challenge) = http::HeaderValue::from_str(&challenge) {
+                    response
+                        .headers_mut()
+                        .insert(http::header::WWW_AUTHENTICATE, challenge);
+                }
+                Some(response)
+            }
+        }
+    }
+
+    /// Returns the user that basic auth credentials are for, if the password is right
+    fn check_basic(&self, credentials: &str) -> Result<Option<String>, String> {
+        let decoded = base64::decode(credentials).map_err(|_| "malformed credentials")?;
+        let decoded = String::from_utf8(decoded).map_err(|_| "malformed credentials")?;
+        let (user, password) = decoded.split_once(':').ok_or("malformed credentials")?;
+        match self.users.get(user) {
+            Some(hash) if hash.matches(password) => Ok(Some(user.to_string())),
+            Some(_) => Err(format!("wrong password for {}", user)),
+            None => Err(format!("unknown user {}", user)),
+        }
+    }
+
+    /// Returns the subject of a JWT, if its signature and claims check out
+    fn check_jwt(&self, token: &str) -> Result<Option<String>, String> {
+        #[derive(Deserialize)]
+        struct Header {
+            alg: String,
+            kid: Option<String>,
+        }
+        #[derive(Deserialize)]
+        struct Claims {
+            sub: Option<String>,
+            exp: Option<u64>,
+            nbf: Option<u64>,
+            iss: Option<String>,
+            #[serde(default)]
+            aud: Audience,
+        }
+        #[derive(Deserialize, Default)]
+        #[serde(untagged)]
+        enum Audience {
+            #[default]
+            None,
+            One(String),
+            Many(Vec<String>),
+        }
+
+        let mut parts = token.split('.');
+        let (header, claims, signature) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(header), Some(claims), Some(signature)) if parts.next().is_none() => {
+                (header, claims, signature)
+            }
+            _ => return Err("malformed token".to_string()),
+        };
+        let decode = |part: &str| {
+            base64::decode_config(part, base64::URL_SAFE_NO_PAD)
+                .map_err(|_| "malformed token".to_string())
+        };
+        let header: Header =
+            serde_json::from_slice(&decode(header)?).map_err(|_| "malformed token header")?;
+        let signed = &token[..token.len() - signature.len() - 1];
+        self.verify_signature(
+            &header.alg,
+            header.kid.as_deref(),
+            signed,
+            &decode(signature)?,
+        )?;
+
+        let claims: Claims =
+            serde_json::from_slice(&decode(claims)?).map_err(|_| "malformed token claims")?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs());
+        if claims
+            .exp
+            .is_some_and(|exp| exp.saturating_add(CLOCK_SKEW) < now)
+        {
+            return Err("token has expired".to_string());
+        }
+        if claims
+            .nbf
+            .is_some_and(|nbf| nbf > now.saturating_add(CLOCK_SKEW))
+        {
+            return Err("token isn't valid yet".to_string());
+        }
+        if let Some(issuer) = &self.jwt_issuer {
+            if claims.iss.as_ref() != Some(issuer) {
+                return Err("token is from the wrong issuer".to_string());
+            }
+        }
+        if let Some(audience) = &self.jwt_audience {
+            let for_us = match &claims.aud {
+                Audience::None => false,
+                Audience::One(aud) => aud == audience,
+                Audience::Many(auds) => auds.contains(audience),
+            };
+            if !for_us {
+                return Err("token is for another audience".to_string());
+            }
+        }
+        Ok(claims.sub)
+    }
+
+    /// Checks a JWT's signature with the secret (for HS* algorithms) or the JWKS key it names (or
+    /// any that fits, if it doesn't name one)
+    fn verify_signature(
+        &self,
+        alg: &str,
+        kid: Option<&str>,
+        signed: &str,
+        signature: &[u8],
+    ) -> Result<(), String> {
+        let hmac_algorithm = match alg {
+            "HS256" => Some(hmac::HMAC_SHA256),
+            "HS384" => Some(hmac::HMAC_SHA384),
+            "HS512" => Some(hmac::HMAC_SHA512),
+            _ => None,
+        };
+        if let Some(algorithm) = hmac_algorithm {
+            let secret = self.jwt_secret.as_ref().ok_or("no secret for HS* tokens")?;
+            let key = hmac::Key::new(algorithm, secret);
+            return hmac::verify(&key, signed.as_bytes(), signature)
+                .map_err(|_| "bad signature".to_string());
+        }
+        let verified = self
+            .jwt_keys
+            .iter()
+            .filter(|key| kid.is_none() || key.kid.as_deref() == kid)
+            .any(|key| key.verify(alg, signed.as_bytes(), signature));
+        match verified {
+            true => Ok(()),
+            false => Err(format!("bad signature (alg {})", alg)),
+        }
+    }
+}
+
+/// A password hash from an htpasswd file
+#[derive(Debug)]
+enum PasswordHash {
+    /// `{SHA}` followed by the base64 SHA-1 of the password (htpasswd -s)
+    Sha1(Vec<u8>),
+    /// Apache's MD5-based crypt, `$apr1$<salt>$<hash>` (htpasswd -m, the default)
+    Apr1 { salt: String, hash: String },
+}
+
+impl PasswordHash {
+    fn matches(&self, password: &str) -> bool {
+        match self {
+            PasswordHash::Sha1(hash) => {
+                let digest = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, password.as_bytes());
+                constant_time::verify_slices_are_equal(digest.as_ref(), hash).is_ok()
+            }
+            PasswordHash::Apr1 { salt, hash } => {
+                let computed = apr1_hash(password.as_bytes(), salt.as_bytes());
+                constant_time::verify_slices_are_equal(computed.as_bytes(), hash.as_bytes()).is_ok()
+            }
+        }
+    }
+}
+
+impl FromStr for PasswordHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(hash) = s.strip_prefix("{SHA}") {
+            let hash = base64::decode(hash).map_err(|_| "malformed {SHA} hash")?;
+            return Ok(PasswordHash::Sha1(hash));
+        }
+        if let Some(rest) = s.strip_prefix("$apr1$") {
+            let (salt, hash) = rest.split_once('$').ok_or("malformed $apr1$ hash")?;
+            return Ok(PasswordHash::Apr1 {
+                salt: salt.to_string(),
+                hash: hash.to_string(),
+            });
+        }
+        if s.starts_with("$2") {
+            return Err(
+                "bcrypt hashes aren't supported; use htpasswd -m (MD5) or -s (SHA-1)".to_string(),
+            );
+        }
+        Err("unsupported hash (use htpasswd -m or -s)".to_string())
+    }
+}
+
+/// Reads `user:hash` lines from an htpasswd file
+fn load_htpasswd(path: &str) -> Result<HashMap<String, PasswordHash>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| format!("Could not read basic auth file {}: {}", path, err))?;
+    let mut users = HashMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (user, hash) = line
+            .split_once(':')
+            .ok_or_else(|| format!("{} line {}: expected user:hash", path, i + 1))?;
+        let hash = hash
+            .parse()
+            .map_err(|err| format!("{} line {}: {}", path, i + 1, err))?;
+        users.insert(user.to_string(), hash);
+    }
+    Ok(users)
+}
+
+/// A public key from a JWKS file
+#[derive(Debug)]
+struct Jwk {
+    kid: Option<String>,
+    key: JwkKey,
+}
+
+#[derive(Debug)]
+enum JwkKey {
+    Rsa {
+        n: Vec<u8>,
+        e: Vec<u8>,
+    },
+    /// An uncompressed point (0x04, then x and y)
+    EcP256(Vec<u8>),
+    EcP384(Vec<u8>),
+}
+
+impl Jwk {
+    fn verify(&self, alg: &str, message: &[u8], signature: &[u8]) -> bool {
+        match (&self.key, alg) {
+            (JwkKey::Rsa { n, e }, _) => {
+                let algorithm = match alg {
+                    "RS256" => &signature::RSA_PKCS1_2048_8192_SHA256,
+                    "RS384" => &signature::RSA_PKCS1_2048_8192_SHA384,
+                    "RS512" => &signature::RSA_PKCS1_2048_8192_SHA512,
+                    _ => return false,
+                };
+                signature::RsaPublicKeyComponents { n, e }
+                    .verify(algorithm, message, signature)
+                    .is_ok()
+            }
+            (JwkKey::EcP256(point), "ES256") => {
+                UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                    .verify(message, signature)
+                    .is_ok()
+            }
+            (JwkKey::EcP384(point), "ES384") => {
+                UnparsedPublicKey::new(&signature::ECDSA_P384_SHA384_FIXED, point)
+                    .verify(message, signature)
+                    .is_ok()
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Reads the RSA and EC public keys from a JWKS file (`{"keys": [...]}`). Keys of other types are
+/// skipped.
+fn load_jwks(path: &str) -> Result<Vec<Jwk>, String> {
+    #[derive(Deserialize)]
+    struct JwkSet {
+        keys: Vec<RawJwk>,
+    }
+    #[derive(Deserialize)]
+    struct RawJwk {
+        kty: String,
+        kid: Option<String>,
+        crv: Option<String>,
+        n: Option<String>,
+        e: Option<String>,
+        x: Option<String>,
+        y: Option<String>,
+    }
+
+    let contents =
+        std::fs::read(path).map_err(|err| format!("Could not read JWKS file {}: {}", path, err))?;
+    let set: JwkSet = serde_json::from_slice(&contents)
+        .map_err(|err| format!("Could not parse JWKS file {}: {}", path, err))?;
+    let decode = |value: &Option<String>| {
+        let value = value
+            .as_deref()
+            .ok_or_else(|| format!("Key in JWKS file {} is missing a parameter it needs", path))?;
+        base64::decode_config(value, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| format!("Key in JWKS file {} has a malformed parameter", path))
+    };
+    let mut keys = Vec::new();
+    for raw in set.keys {
+        let key = match (raw.kty.as_str(), raw.crv.as_deref()) {
+            ("RSA", _) => JwkKey::Rsa {
+                n: decode(&raw.n)?,
+                e: decode(&raw.e)?,
+            },
+            ("EC", Some(crv @ "P-256")) | ("EC", Some(crv @ "P-384")) => {
+                let point = [vec![0x04], decode(&raw.x)?, decode(&raw.y)?].concat();
+                match crv {
+                    "P-256" => JwkKey::EcP256(point),
+                    _ => JwkKey::EcP384(point),
+                }
+            }
+            (kty, crv) => {
+                log::warn!(
+                    "Skipping unsupported key (kty {}, crv {:?}) in JWKS file {}",
+                    kty,
+                    crv,
+                    path
+                );
+                continue;
+            }
+        };
+        keys.push(Jwk { kid: raw.kid, key });
+    }
+    if keys.is_empty() {
+        return Err(format!("JWKS file {} has no usable keys", path));
+    }
+    Ok(keys)
+}
+
+/// Apache's MD5 crypt (the `$apr1$` variant of FreeBSD's md5crypt), without the prefix and salt:
+/// 22 characters of encoded hash
+fn apr1_hash(password: &[u8], salt: &[u8]) -> String {
+    const ITOA64: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+    let salt = &salt[..salt.len().min(8)];
+
+    let alternate = md5(&[password, salt, password].concat());
+    let mut input = [password, b"$apr1$", salt].concat();
+    let mut remaining = password.len();
+    while remaining > 0 {
+        let n = remaining.min(16);
+        input.extend_from_slice(&alternate[..n]);
+        remaining -= n;
+    }
+    let mut bits = password.len();
+    while bits != 0 {
+        input.push(if bits & 1 == 1 { 0 } else { password[0] });
+        bits >>= 1;
+    }
+    let mut hash = md5(&input);
+    for round in 0..1000 {
+        let mut input = Vec::new();
+        input.extend_from_slice(if round % 2 == 1 { password } else { &hash });
+        if round % 3 != 0 {
+            input.extend_from_slice(salt);
+        }
+        if round % 7 != 0 {
+            input.extend_from_slice(password);
+        }
+        input.extend_from_slice(if round % 2 == 1 { &hash } else { password });
+        hash = md5(&input);
+    }
+
+    let mut encoded = String::new();
+    let mut push = |mut value: u32, chars: usize| {
+        for _ in 0..chars {
+            encoded.push(ITOA64[(value & 0x3f) as usize] as char);
+            value >>= 6;
+        }
+    };
+    for &(a, b, c) in &[(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+        push(
+            (hash[a] as u32) << 16 | (hash[b] as u32) << 8 | hash[c] as u32,
+            4,
+        );
+    }
+    push(hash[11] as u32, 2);
+    encoded
+}
+
+/// MD5 (RFC 1321), which ring doesn't offer. It's only used for apr1 password hashes.
+fn md5(data: &[u8]) -> [u8; 16] {
+    const SHIFTS: [u32; 64] = [
+        7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 5, 9, 14, 20, 5, 9, 14, 20, 5,
+        9, 14, 20, 5, 9, 14, 20, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 6, 10,
+        15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+    ];
+    let constants: Vec<u32> = (0..64)
+        .map(|i| ((i as f64 + 1.0).sin().abs() * 4294967296.0) as u32)
+        .collect();
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for block in message.chunks(64) {
+        let words: Vec<u32> = block
+            .chunks(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a
+                .wrapping_add(f)
+                .wrapping_add(constants[i])
+                .wrapping_add(words[g])
+                .rotate_left(SHIFTS[i]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(rotated);
+        }
+        for (word, value) in state.iter_mut().zip(&[a, b, c, d]) {
+            *word = word.wrapping_add(*value);
+        }
+    }
+    let mut digest = [0; 16];
+    for (i, word) in state.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
@@ -0,0 +1,370 @@
+use crate::throttle::Pacer;
+use std::cmp::min;
+use std::future::poll_fn;
+use std::pin::Pin;
+use tokio::io::{AsyncBufRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Size of the buffer bodies are copied through. However big a body is, this is all of it we hold
+/// in memory at once.
+pub const COPY_BUFFER_SIZE: usize = 16 * 1024;
+/// Longest chunk-size or trailer line we accept in a chunked body
+const MAX_LINE_SIZE: usize = 8000;
+/// Most trailer fields we accept at the end of a chunked body
+const MAX_TRAILERS: usize = 32;
+
+/// How a message's body is delimited on the wire
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+    /// There is no body
+    Empty,
+    /// The body is exactly this many bytes (from Content-Length)
+    Length(u64),
+    /// The body uses the chunked transfer coding
+    Chunked,
+    /// The body is everything the sender sends until it closes the connection. Only responses can
+    /// be framed this way.
+    UntilClose,
+}
+
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum Error {
+    /// The body is chunked, but isn't valid chunked encoding
+    MalformedChunkedBody,
+    /// The sender hung up before the end of the body
+    IncompleteBody,
+    /// The body is bigger than the limit given to read_to_end or copy
+    BodyTooLarge,
+    /// Encountered an I/O error when reading the body
+    ReadError(std::io::Error),
+    /// Encountered an I/O error when passing the body on
+    WriteError(std::io::Error),
+}
+
+/// Returns true if a message with these headers has a chunked body. (Chunked has to be the last
+/// transfer coding applied, if it's used at all.)
+pub fn is_chunked(headers: &http::HeaderMap) -> bool {
+    headers
+        .get_all("transfer-encoding")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .last()
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+/// Where a Reader is in the body
+#[derive(Debug)]
+enum State {
+    /// This many bytes of a Content-Length body are left
+    Remaining(u64),
+    /// The next thing in a chunked body is a chunk-size line
+    ChunkStart,
+    /// This many bytes of the current chunk are left, followed by the CRLF that ends the chunk
+    InChunk(u64),
+    /// Reading until the sender hangs up
+    UntilClose,
+    Done,
+}
+
+/// Reads a message body from a stream a piece at a time, removing any chunked framing. It reads
+/// exactly as far as the end of the body, so the stream is left at the start of the next message.
+pub struct Reader {
+    state: State,
+    /// Fields sent after the last chunk of a chunked body
+    trailers: http::HeaderMap,
+}
+
+impl Reader {
+    pub fn new(framing: Framing) -> Reader {
+        let state = match framing {
+            Framing::Empty => State::Done,
+            Framing::Length(length) => State::Remaining(length),
+            Framing::Chunked => State::ChunkStart,
+            Framing::UntilClose => State::UntilClose,
+        };
+        Reader {
+            state,
+            trailers: http::HeaderMap::new(),
+        }
+    }
+
+    /// The trailer fields that followed the body. These are only known once read has returned 0,
+    /// and there are only ever any for chunked bodies.
+    pub fn trailers(&self) -> &http::HeaderMap {
+        &self.trailers
+    }
+
+    /// Reads the next piece of the body into buf, returning its length. Returns 0 once the whole
+    /// body has been read.
+    pub async fn read<S: AsyncBufRead + Unpin>(
+        &mut self,
+        stream: &mut S,
+        buf: &mut [u8],
+    ) -> Result<usize, Error> {
+        loop {
+            match self.state {
+                State::Done => return Ok(0),
+                State::Remaining(0) => self.state = State::Done,
+                State::Remaining(left) => {
+                    let bytes_read = read_some(stream, buf, left).await?;
+                    self.state = State::Remaining(left - bytes_read as u64);
+                    return Ok(bytes_read);
+                }
+                State::UntilClose => {
+                    let bytes_read = stream.read(buf).await.map_err(Error::ReadError)?;
+                    if bytes_read == 0 {
+                        self.state = State::Done;
+                    }
+                    return Ok(bytes_read);
+                }
+                State::ChunkStart => {
+                    // Each chunk starts with its size in hex (optionally followed by extensions,
+                    // which we ignore) on a line of its own
+                    let mut line = read_line(stream).await?;
+                    line.extend_from_slice(b"\r\n");
+                    let size = match httparse::parse_chunk_size(&line) {
+                        Ok(httparse::Status::Complete((_, size))) => size,
+                        _ => return Err(Error::MalformedChunkedBody),
+                    };
+                    if size == 0 {
+                        // The last chunk is followed by the trailer fields (if any), one per line,
+                        // and then an empty line
+                        loop {
+                            let line = read_line(stream).await?;
+                            if line.is_empty() {
+                                break;
+                            }
+                            if self.trailers.len() == MAX_TRAILERS {
+                                return Err(Error::MalformedChunkedBody);
+                            }
+                            let (name, value) = parse_trailer(&line)?;
+                            self.trailers.append(name, value);
+                        }
+                        self.state = State::Done;
+                    } else {
+                        self.state = State::InChunk(size);
+                    }
+                }
+                State::InChunk(0) => {
+                    if !read_line(stream).await?.is_empty() {
+                        return Err(Error::MalformedChunkedBody);
+                    }
+                    self.state = State::ChunkStart;
+                }
+                State::InChunk(left) => {
+                    let bytes_read = read_some(stream, buf, left).await?;
+                    self.state = State::InChunk(left - bytes_read as u64);
+                    return Ok(bytes_read);
+                }
+            }
+        }
+    }
+}
+
+/// Parses a "name: value" trailer line
+fn parse_trailer(line: &[u8]) -> Result<(http::HeaderName, http::HeaderValue), Error> {
+    let colon = line
+        .iter()
+        .position(|&byte| byte == b':')
+        .ok_or(Error::MalformedChunkedBody)?;
+    let name =
+        http::HeaderName::from_bytes(&line[..colon]).map_err(|_| Error::MalformedChunkedBody)?;
+    let value = http::HeaderValue::from_bytes(line[colon + 1..].trim_ascii())
+        .map_err(|_| Error::MalformedChunkedBody)?;
+    Ok((name, value))
+}
+
+/// Reads up to left bytes (and no more than fit in buf) of a body whose length we know
+async fn read_some<S: AsyncBufRead + Unpin>(
+    stream: &mut S,
+    buf: &mut [u8],
+    left: u64,
+) -> Result<usize, Error> {
+    let len = min(buf.len() as u64, left) as usize;
+    let bytes_read = stream
+        .read(&mut buf[..len])
+        .await
+        .map_err(Error::ReadError)?;
+    if bytes_read == 0 {
+        return Err(Error::IncompleteBody);
+    }
+    Ok(bytes_read)
+}
+
+/// Waits until the stream has some data buffered, and hands it to look without taking it out of
+/// the stream. look gets an empty slice if the stream has ended.
+pub async fn peek<S, T>(stream: &mut S, mut look: impl FnMut(&[u8]) -> T) -> std::io::Result<T>
+where
+    S: AsyncBufRead + Unpin,
+{
+    poll_fn(|cx| Pin::new(&mut *stream).poll_fill_buf(cx).map_ok(&mut look)).await
+}
+
+/// Reads a CRLF-terminated line, and returns it without the CRLF
+async fn read_line<S: AsyncBufRead + Unpin>(stream: &mut S) -> Result<Vec<u8>, Error> {
+    let mut line = Vec::new();
+    loop {
+        let (consumed, found_end) = peek(stream, |available| {
+            match available.iter().position(|&byte| byte == b'\n') {
+                Some(end) => {
+                    line.extend_from_slice(&available[..end]);
+                    (end + 1, true)
+                }
+                None => {
+                    line.extend_from_slice(available);
+                    (available.len(), false)
+                }
+            }
+        })
+        .await
+        .map_err(Error::ReadError)?;
+        if consumed == 0 {
+            return Err(Error::IncompleteBody);
+        }
+        Pin::new(&mut *stream).consume(consumed);
+        if found_end {
+            break;
+        }
+        if line.len() > MAX_LINE_SIZE {
+            return Err(Error::MalformedChunkedBody);
+        }
+    }
+    match line.pop() {
+        Some(b'\r') => Ok(line),
+        _ => Err(Error::MalformedChunkedBody),
+    }
+}
+
+/// Writes a message body to a stream a piece at a time, adding chunked framing if the message is
+/// chunked
+pub struct Writer {
+    chunked: bool,
+}
+
+impl Writer {
+    pub fn new(framing: Framing) -> Writer {
+        Writer {
+            chunked: framing == Framing::Chunked,
+        }
+    }
+
+    pub async fn write<S: AsyncWrite + Unpin>(
+        &mut self,
+        stream: &mut S,
+        data: &[u8],
+    ) -> Result<(), std::io::Error> {
+        if data.is_empty() {
+            // An empty chunk would mark the end of the body
+            return Ok(());
+        }
+        if self.chunked {
+            stream
+                .write_all(format!("{:x}\r\n", data.len()).as_bytes())
+                .await?;
+            stream.write_all(data).await?;
+            stream.write_all(b"\r\n").await
+        } else {
+            stream.write_all(data).await
+        }
+    }
+
+    /// Marks the end of the body, and flushes it out. Only chunked bodies can carry trailers; for
+    /// anything else they're dropped.
+    pub async fn finish<S: AsyncWrite + Unpin>(
+        &mut self,
+        stream: &mut S,
+        trailers: &http::HeaderMap,
+    ) -> Result<(), std::io::Error> {
+        if self.chunked {
+            stream.write_all(b"0\r\n").await?;
+            for (name, value) in trailers {
+                stream.write_all(format!("{}: ", name).as_bytes()).await?;
+                stream.write_all(value.as_bytes()).await?;
+                stream.write_all(b"\r\n").await?;
+            }
+            stream.write_all(b"\r\n").await?;
+        } else if !trailers.is_empty() {
+            log::debug!("Dropping trailers from a body that isn't chunked");
+        }
+        stream.flush().await
+    }
+}
+
+/// Copies a body from one stream to another through a fixed-size buffer. The body is written with
+/// the same framing it was read with, trailers and all. Returns the number of body bytes copied.
+///
+/// If max_size is given, fails with BodyTooLarge as soon as the body turns out to be longer than
+/// that, without writing the bytes past the limit. The body on the writing side is left unfinished
+/// in that case. If pacer is given, each buffer's worth waits its turn before being written.
+pub async fn copy<R, W>(
+    from: &mut R,
+    to: &mut W,
+    framing: Framing,
+    max_size: Option<u64>,
+    pacer: Option<&Pacer>,
+) -> Result<u64, Error>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut reader = Reader::new(framing);
+    let mut writer = Writer::new(framing);
+    let mut buffer = vec![0_u8; COPY_BUFFER_SIZE];
+    let mut copied = 0;
+    loop {
+        let bytes_read = reader.read(from, &mut buffer).await?;
+        if bytes_read == 0 {
+            break;
+        }
+        copied += bytes_read as u64;
+        if max_size.is_some_and(|max_size| copied > max_size) {
+            return Err(Error::BodyTooLarge);
+        }
+        if let Some(pacer) = pacer {
+            pacer.take(bytes_read).await;
+        }
+        writer
+            .write(to, &buffer[..bytes_read])
+            .await
+            .map_err(Error::WriteError)?;
+    }
+    writer
+        .finish(to, reader.trailers())
+        .await
+        .map_err(Error::WriteError)?;
+    Ok(copied)
+}
+
+/// Reads a whole body into memory, for the few places that need all of it at once (e.g. admin API
+/// requests). Fails with BodyTooLarge if the body is longer than max_size.
+pub async fn read_to_end<S: AsyncBufRead + Unpin>(
+    stream: &mut S,
+    framing: Framing,
+    max_size: usize,
+) -> Result<Vec<u8>, Error> {
+    let mut reader = Reader::new(framing);
+    let mut body = Vec::new();
+    let mut buffer = vec![0_u8; COPY_BUFFER_SIZE];
+    loop {
+        let bytes_read = reader.read(stream, &mut buffer).await?;
+        if bytes_read == 0 {
+            return Ok(body);
+        }
+        if body.len() + bytes_read > max_size {
+            return Err(Error::BodyTooLarge);
+        }
+        body.extend_from_slice(&buffer[..bytes_read]);
+    }
+}
+
+/// Writes a body that's entirely in memory, with the given framing
+pub async fn write_all<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    body: &[u8],
+    framing: Framing,
+) -> Result<(), std::io::Error> {
+    let mut writer = Writer::new(framing);
+    writer.write(stream, body).await?;
+    writer.finish(stream, &http::HeaderMap::new()).await
+}
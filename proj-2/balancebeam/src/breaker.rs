@@ -0,0 +1,149 @@
+use std::collections::VecDeque;
+use tokio::time::{Duration, Instant};
+
+/// Number of recent requests an upstream's error rate is measured over
+const ERROR_RATE_WINDOW: usize = 20;
+
+/// When circuit breakers trip, and for how long
+#[derive(Debug, Clone, Copy)]
+pub struct Settings {
+    /// Consecutive failed requests that trip a breaker (0 = no limit)
+    pub max_failures: usize,
+    /// Fraction of the last ERROR_RATE_WINDOW requests that may fail before a breaker trips, if
+    /// there's a limit
+    pub max_error_rate: Option<f64>,
+    /// How long a tripped breaker keeps requests away before letting a probe through
+    pub open_time: Duration,
+}
+
+impl Settings {
+    fn enabled(&self) -> bool {
+        self.max_failures > 0 || self.max_error_rate.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    /// Requests flow as usual
+    Closed,
+    /// Tripped: no requests are sent until the given time
+    Open { until: Instant },
+    /// A single probe request has been let through, and its outcome decides whether the breaker
+    /// closes or opens again. If it never reports back (e.g. the client hung up), another probe is
+    /// let through once the given time passes.
+    HalfOpen { probe_until: Instant },
+}
+
+/// What a call to CircuitBreaker::record changed, so that it can be logged
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transition {
+    Tripped,
+    Reset,
+}
+
+/// Keeps requests away from an upstream that keeps failing, without waiting for an active health
+/// check to notice
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    state: State,
+    consecutive_failures: usize,
+    /// Whether each of the last ERROR_RATE_WINDOW requests succeeded, oldest first
+    recent: VecDeque<bool>,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> CircuitBreaker {
+        CircuitBreaker {
+            state: State::Closed,
+            consecutive_failures: 0,
+            recent: VecDeque::with_capacity(ERROR_RATE_WINDOW),
+        }
+    }
+}
+
+impl CircuitBreaker {
+    /// Returns true if requests shouldn't be sent through this breaker right now
+    pub fn is_open(&self, now: Instant) -> bool {
+        match self.state {
+            State::Closed => false,
+            State::Open { until } => now < until,
+            State::HalfOpen { probe_until } => now < probe_until,
+        }
+    }
+
+    /// Name of the breaker's state, for the admin API
+    pub fn state_name(&self, now: Instant) -> &'static str {
+        match self.state {
+            State::Closed => "closed",
+            State::Open { .. } if self.is_open(now) => "open",
+            State::Open { .. } | State::HalfOpen { .. } => "half-open",
+        }
+    }
+
+    /// Called when a request is sent through the breaker. If it isn't closed, this request becomes
+    /// the probe.
+    pub fn start_request(&mut self, settings: &Settings, now: Instant) {
+        if self.state != State::Closed {
+            self.state = State::HalfOpen {
+                probe_until: now + settings.open_time,
+            };
+        }
+    }
+
+    /// Records whether a request sent through the breaker succeeded
+    pub fn record(
+        &mut self,
+        success: bool,
+        settings: &Settings,
+        now: Instant,
+    ) -> Option<Transition> {
+        if !settings.enabled() {
+            return None;
+        }
+        match self.state {
+            // Requests that were already in flight when the breaker tripped don't count
+            State::Open { .. } => None,
+            State::HalfOpen { .. } if success => {
+                *self = CircuitBreaker::default();
+                Some(Transition::Reset)
+            }
+            State::HalfOpen { .. } => Some(self.trip(settings, now)),
+            State::Closed => {
+                if self.recent.len() == ERROR_RATE_WINDOW {
+                    self.recent.pop_front();
+                }
+                self.recent.push_back(success);
+                self.consecutive_failures = if success {
+                    0
+                } else {
+                    self.consecutive_failures + 1
+                };
+                let too_many_failures =
+                    settings.max_failures > 0 && self.consecutive_failures >= settings.max_failures;
+                let failures = self.recent.iter().filter(|&&success| !success).count();
+                let error_rate_too_high = match settings.max_error_rate {
+                    Some(max_error_rate) => {
+                        self.recent.len() == ERROR_RATE_WINDOW
+                            && failures as f64 / ERROR_RATE_WINDOW as f64 > max_error_rate
+                    }
+                    None => false,
+                };
+                if too_many_failures || error_rate_too_high {
+                    Some(self.trip(settings, now))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn trip(&mut self, settings: &Settings, now: Instant) -> Transition {
+        *self = CircuitBreaker {
+            state: State::Open {
+                until: now + settings.open_time,
+            },
+            ..CircuitBreaker::default()
+        };
+        Transition::Tripped
+    }
+}
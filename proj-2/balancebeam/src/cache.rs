@@ -0,0 +1,559 @@
+use crate::body::Framing;
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
+
+/// Statuses whose responses may be cached (RFC 7231 section 6.1)
+const CACHEABLE_STATUSES: [u16; 10] = [200, 203, 204, 300, 301, 404, 405, 410, 414, 501];
+
+/// Headers of a 304 Not Modified that don't describe the stored response, and so aren't copied
+/// onto it when it's revalidated
+const NOT_MODIFIED_SKIPPED_HEADERS: [&str; 4] = [
+    "connection",
+    "content-length",
+    "transfer-encoding",
+    "keep-alive",
+];
+
+/// Limits on what the cache holds
+#[derive(Debug, Clone, Copy)]
+pub struct Settings {
+    /// How long a response is served from the cache once stored, unless its Cache-Control or
+    /// Expires headers say otherwise
+    pub ttl: Duration,
+    /// Responses with bodies bigger than this aren't stored
+    pub max_entry_size: u64,
+    /// Most bytes of responses to hold at once. Past this, the least recently used are dropped.
+    pub max_size: usize,
+}
+
+/// A response as it was stored
+#[derive(Debug)]
+struct Stored {
+    status: http::StatusCode,
+    version: http::Version,
+    headers: http::HeaderMap,
+    body: Vec<u8>,
+}
+
+impl Stored {
+    fn to_response(&self) -> http::Response<Vec<u8>> {
+        let mut response = http::Response::builder()
+            .status(self.status)
+            .version(self.version)
+            .body(self.body.clone())
+            .unwrap();
+        *response.headers_mut() = self.headers.clone();
+        response
+    }
+}
+
+/// Set on a request by Cache::get when it's sent upstream to check whether a stale response is
+/// still good (see Cache::revalidated)
+#[derive(Debug, Clone)]
+struct Revalidating(Arc<Stored>);
+
+#[derive(Debug)]
+struct Entry {
+    stored: Arc<Stored>,
+    /// The key of the Variants this is one of
+    primary: String,
+    stored_at: Instant,
+    expires: Instant,
+    /// Roughly how much memory the entry takes up, counting towards Settings::max_size
+    size: usize,
+    /// When the entry was last used, as a position in Table::lru
+    last_used: u64,
+}
+
+/// The responses stored for one method and URL. There's one for each combination of values of the
+/// request headers named in their Vary header.
+#[derive(Debug)]
+struct Variants {
+    vary: Vec<http::header::HeaderName>,
+    /// The keys of the entries
+    keys: HashSet<String>,
+    /// The Host and request target of the URL, for purging
+    host: String,
+    target: String,
+}
+
+#[derive(Debug, Default)]
+struct Table {
+    entries: HashMap<String, Entry>,
+    /// By the method and URL (see key)
+    variants: HashMap<String, Variants>,
+    /// The keys of all the entries, least recently used first
+    lru: BTreeMap<u64, String>,
+    next_use: u64,
+    /// Total size of the entries
+    size: usize,
+}
+
+impl Table {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.last_used);
+            self.size -= entry.size;
+            if let Some(variants) = self.variants.get_mut(&entry.primary) {
+                variants.keys.remove(key);
+                if variants.keys.is_empty() {
+                    self.variants.remove(&entry.primary);
+                }
+            }
+        }
+    }
+
+    /// Marks an entry as just used
+    fn touch(&mut self, key: &str) {
+        let next_use = self.next_use;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.lru.remove(&entry.last_used);
+            entry.last_used = next_use;
+            self.lru.insert(next_use, key.to_string());
+            self.next_use += 1;
+        }
+    }
+
+    /// The key of the entry for a request, if any of the URL's responses are stored
+    fn variant_key(&self, primary: &str, request: &http::Request<Vec<u8>>) -> Option<String> {
+        let variants = self.variants.get(primary)?;
+        Some(variant_key(primary, &variants.vary, request))
+    }
+}
+
+/// An in-memory cache of upstream responses to GET and HEAD requests, keyed on the method and URL
+/// (and any request headers the response Varies on), so that repeated requests can be answered
+/// without bothering an upstream. Upstreams' Cache-Control and Expires headers decide how long each
+/// response is kept. Stale responses with an ETag are revalidated with If-None-Match rather than
+/// fetched again.
+#[derive(Debug)]
+pub struct Cache {
+    settings: Settings,
+    table: Mutex<Table>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Cache {
+    pub fn new(settings: Settings) -> Cache {
+        Cache {
+            settings,
+            table: Mutex::new(Table::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the stored response to a request, if there's one that's still fresh (or a 304 Not
+    /// Modified, if the client already has it). Requests that could be answered from the cache
+    /// count as hits or misses.
+    ///
+    /// If the stored response is stale but has an ETag, the request is changed to ask the upstream
+    /// whether it's still good, and the answer should be passed to revalidated.
+    pub fn get(&self, request: &mut http::Request<Vec<u8>>) -> Option<http::Response<Vec<u8>>> {
+        let primary = key(request)?;
+        let request_directives = cache_control(request.headers());
+        if has_directive(&request_directives, "no-cache")
+            || has_directive(&request_directives, "no-store")
+        {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        let now = Instant::now();
+        let mut table = self.table.lock();
+        let key = table.variant_key(&primary, request);
+        let found = key.as_ref().and_then(|key| table.entries.get(key));
+        let (key, stored, stored_at, expires) = match found {
+            Some(entry) => (
+                key.unwrap(),
+                Arc::clone(&entry.stored),
+                entry.stored_at,
+                entry.expires,
+            ),
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        };
+        if expires <= now {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            let conditional = request.headers().contains_key(http::header::IF_NONE_MATCH)
+                || request
+                    .headers()
+                    .contains_key(http::header::IF_MODIFIED_SINCE);
+            match stored.headers.get(http::header::ETAG) {
+                // If the client asked its own question, the upstream's answer is for it
+                Some(_) if conditional => {}
+                Some(etag) => {
+                    request
+                        .headers_mut()
+                        .insert(http::header::IF_NONE_MATCH, etag.clone());
+                    request
+                        .extensions_mut()
+                        .insert(Revalidating(Arc::clone(&stored)));
+                }
+                None => table.remove(&key),
+            }
+            return None;
+        }
+        table.touch(&key);
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        let mut response = match request.headers().get(http::header::IF_NONE_MATCH) {
+            Some(tags) if etag_matches(tags, &stored.headers) => not_modified(&stored),
+            _ => stored.to_response(),
+        };
+        let age = now.duration_since(stored_at).as_secs();
+        response
+            .headers_mut()
+            .insert(http::header::AGE, http::HeaderValue::from(age));
+        Some(response)
+    }
+
+    /// Whether the upstream's response to a request should be stored (once its body has been read
+    /// in full). Only bodies with a known length that fits in an entry are stored, and only if the
+    /// upstream's headers allow it.
+    pub fn accepts(
+        &self,
+        request: &http::Request<Vec<u8>>,
+        response: &http::Response<Vec<u8>>,
+        framing: Framing,
+    ) -> bool {
+        let fits = match framing {
+            Framing::Empty => true,
+            Framing::Length(length) => length <= self.settings.max_entry_size,
+            Framing::Chunked | Framing::UntilClose => false,
+        };
+        fits && key(request).is_some()
+            && CACHEABLE_STATUSES.contains(&response.status().as_u16())
+            && !response.headers().contains_key(http::header::SET_COOKIE)
+            && vary(response.headers()).is_some()
+            && match self.freshness(response.headers()) {
+                // A response that's stale straight away is only worth keeping if it can be
+                // revalidated
+                Some(freshness) => {
+                    freshness > Duration::from_secs(0)
+                        || response.headers().contains_key(http::header::ETAG)
+                }
+                None => false,
+            }
+    }
+
+    /// Stores the response to a request, whose body is given separately. If the cache is full, the
+    /// least recently used responses are dropped to make room.
+    pub fn insert(
+        &self,
+        request: &http::Request<Vec<u8>>,
+        response: &http::Response<Vec<u8>>,
+        body: Vec<u8>,
+    ) {
+        let (primary, vary, freshness) = match (
+            key(request),
+            vary(response.headers()),
+            self.freshness(response.headers()),
+        ) {
+            (Some(primary), Some(vary), Some(freshness)) => (primary, vary, freshness),
+            _ => return,
+        };
+        let key = variant_key(&primary, &vary, request);
+        let headers_size: usize = response
+            .headers()
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        let size = key.len() + headers_size + body.len();
+        if size > self.settings.max_size {
+            return;
+        }
+        let now = Instant::now();
+        let mut table = self.table.lock();
+        table.remove(&key);
+        // The URL's other responses were stored under different Vary headers, so they can't be
+        // found any more
+        let stale_variants: Vec<String> = match table.variants.get(&primary) {
+            Some(variants) if variants.vary != vary => variants.keys.iter().cloned().collect(),
+            _ => Vec::new(),
+        };
+        for stale in stale_variants {
+            table.remove(&stale);
+        }
+        while table.size + size > self.settings.max_size {
+            let oldest = match table.lru.values().next() {
+                Some(oldest) => oldest.clone(),
+                None => break,
+            };
+            table.remove(&oldest);
+        }
+        let last_used = table.next_use;
+        table.next_use += 1;
+        table.lru.insert(last_used, key.clone());
+        table.size += size;
+        let host = request_host(request).to_string();
+        let target = request.uri().to_string();
+        table
+            .variants
+            .entry(primary.clone())
+            .or_insert_with(|| Variants {
+                vary,
+                keys: HashSet::new(),
+                host,
+                target,
+            })
+            .keys
+            .insert(key.clone());
+        table.entries.insert(
+            key,
+            Entry {
+                stored: Arc::new(Stored {
+                    status: response.status(),
+                    version: response.version(),
+                    headers: response.headers().clone(),
+                    body,
+                }),
+                primary,
+                stored_at: now,
+                expires: now + freshness,
+                size,
+                last_used,
+            },
+        );
+    }
+
+    /// Given the upstream's answer to a request that get sent to revalidate a stale response,
+    /// returns the response to send the client if the stored one is still good (a 304 Not
+    /// Modified), storing it again with the upstream's updated headers. Returns None otherwise.
+    pub fn revalidated(
+        &self,
+        request: &http::Request<Vec<u8>>,
+        response: &http::Response<Vec<u8>>,
+    ) -> Option<http::Response<Vec<u8>>> {
+        if response.status() != http::StatusCode::NOT_MODIFIED {
+            return None;
+        }
+        let Revalidating(stored) = request.extensions().get::<Revalidating>()?;
+        let mut updated = stored.to_response();
+        for name in response.headers().keys() {
+            if NOT_MODIFIED_SKIPPED_HEADERS.contains(&name.as_str()) {
+                continue;
+            }
+            updated.headers_mut().remove(name);
+            for value in response.headers().get_all(name) {
+                updated.headers_mut().append(name, value.clone());
+            }
+        }
+        self.insert(request, &updated, stored.body.clone());
+        Some(updated)
+    }
+
+    /// Drops the stored responses for a URL, or for every URL starting with it if prefix is set.
+    /// The URL can be a path (e.g. `/images/logo.png`), which matches it on any Host, or can
+    /// include the host (e.g. `http://example.com/images/logo.png`). Returns the number of
+    /// responses dropped.
+    pub fn purge(&self, url: &str, prefix: bool) -> usize {
+        let url = url
+            .strip_prefix("http://")
+            .or_else(|| url.strip_prefix("https://"))
+            .unwrap_or(url);
+        let matches = |variants: &Variants| {
+            let candidate = if url.starts_with('/') {
+                variants.target.clone()
+            } else {
+                format!("{}{}", variants.host, variants.target)
+            };
+            if prefix {
+                candidate.starts_with(url)
+            } else {
+                candidate == url
+            }
+        };
+        let mut table = self.table.lock();
+        let purged: Vec<String> = table
+            .variants
+            .values()
+            .filter(|variants| matches(variants))
+            .flat_map(|variants| variants.keys.iter().cloned())
+            .collect();
+        for key in &purged {
+            table.remove(key);
+        }
+        purged.len()
+    }
+
+    /// How long a response may be served from the cache, going by its Cache-Control or Expires
+    /// headers (or Settings::ttl if it has neither), or None if it mustn't be stored at all
+    fn freshness(&self, headers: &http::HeaderMap) -> Option<Duration> {
+        let directives = cache_control(headers);
+        if has_directive(&directives, "no-store") || has_directive(&directives, "private") {
+            return None;
+        }
+        if has_directive(&directives, "no-cache") {
+            return Some(Duration::from_secs(0));
+        }
+        // s-maxage is meant for shared caches like us, so it wins over max-age
+        for name in &["s-maxage", "max-age"] {
+            let max_age = directives
+                .iter()
+                .find(|(directive, _)| directive == name)
+                .and_then(|(_, value)| value.as_deref()?.parse::<u64>().ok());
+            if let Some(max_age) = max_age {
+                return Some(Duration::from_secs(max_age));
+            }
+        }
+        if let Some(expires) = headers.get(http::header::EXPIRES) {
+            // An invalid date (often "0") means it has already expired
+            let date = headers
+                .get(http::header::DATE)
+                .and_then(parse_http_date)
+                .unwrap_or_else(|| chrono::DateTime::from(std::time::SystemTime::now()));
+            return Some(
+                parse_http_date(expires)
+                    .and_then(|expires| (expires - date).to_std().ok())
+                    .unwrap_or_default(),
+            );
+        }
+        Some(self.settings.ttl)
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// The number of stored responses, and how many bytes they take up
+    pub fn usage(&self) -> (usize, usize) {
+        let table = self.table.lock();
+        (table.entries.len(), table.size)
+    }
+}
+
+/// What a request's responses are stored under, or None if it can't be answered from the cache:
+/// only GET and HEAD requests are, and not those carrying credentials, whose responses may be meant
+/// for that client alone
+fn key(request: &http::Request<Vec<u8>>) -> Option<String> {
+    if request.method() != http::Method::GET && request.method() != http::Method::HEAD {
+        return None;
+    }
+    if request.headers().contains_key(http::header::AUTHORIZATION) {
+        return None;
+    }
+    Some(format!(
+        "{} {}{}",
+        request.method(),
+        request_host(request),
+        request.uri()
+    ))
+}
+
+fn request_host(request: &http::Request<Vec<u8>>) -> &str {
+    request
+        .headers()
+        .get(http::header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or("")
+}
+
+/// The key of the entry for a request, given the headers its URL's responses Vary on
+fn variant_key(
+    primary: &str,
+    vary: &[http::header::HeaderName],
+    request: &http::Request<Vec<u8>>,
+) -> String {
+    let mut key = primary.to_string();
+    for name in vary {
+        key.push('\n');
+        key.push_str(name.as_str());
+        key.push(':');
+        for value in request.headers().get_all(name) {
+            key.push_str(&String::from_utf8_lossy(value.as_bytes()));
+            key.push(',');
+        }
+    }
+    key
+}
+
+/// The request headers named in a response's Vary headers, or None if it's `Vary: *` (meaning it
+/// can't be reused for any other request)
+fn vary(headers: &http::HeaderMap) -> Option<Vec<http::header::HeaderName>> {
+    let mut names = Vec::new();
+    for value in headers.get_all(http::header::VARY) {
+        for name in value.to_str().unwrap_or("").split(',') {
+            let name = name.trim();
+            if name == "*" {
+                return None;
+            }
+            if let Ok(name) = http::header::HeaderName::from_bytes(name.as_bytes()) {
+                names.push(name);
+            }
+        }
+    }
+    names.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    names.dedup();
+    Some(names)
+}
+
+/// Splits Cache-Control headers into their directives, as lowercase names and their values (if any)
+fn cache_control(headers: &http::HeaderMap) -> Vec<(String, Option<String>)> {
+    headers
+        .get_all(http::header::CACHE_CONTROL)
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or("").split(','))
+        .filter(|directive| !directive.trim().is_empty())
+        .map(|directive| match directive.split_once('=') {
+            Some((name, value)) => (
+                name.trim().to_ascii_lowercase(),
+                Some(value.trim().trim_matches('"').to_string()),
+            ),
+            None => (directive.trim().to_ascii_lowercase(), None),
+        })
+        .collect()
+}
+
+fn has_directive(directives: &[(String, Option<String>)], name: &str) -> bool {
+    directives.iter().any(|(directive, _)| directive == name)
+}
+
+/// Parses an HTTP-date (e.g. `Sun, 06 Nov 1994 08:49:37 GMT`)
+fn parse_http_date(value: &http::HeaderValue) -> Option<chrono::DateTime<chrono::Utc>> {
+    let value = value.to_str().ok()?;
+    chrono::DateTime::parse_from_rfc2822(value)
+        .ok()
+        .map(|date| date.with_timezone(&chrono::Utc))
+}
+
+/// Whether an If-None-Match header names the ETag in a stored response's headers. Weak ETags match
+/// their strong equivalents, as If-None-Match compares them weakly.
+fn etag_matches(if_none_match: &http::HeaderValue, headers: &http::HeaderMap) -> bool {
+    let etag = match headers
+        .get(http::header::ETAG)
+        .and_then(|etag| etag.to_str().ok())
+    {
+        Some(etag) => etag.trim_start_matches("W/"),
+        None => return false,
+    };
+    if_none_match
+        .to_str()
+        .unwrap_or("")
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// A 304 Not Modified telling a client that its copy of a stored response is still good
+fn not_modified(stored: &Stored) -> http::Response<Vec<u8>> {
+    let mut response = http::Response::builder()
+        .status(http::StatusCode::NOT_MODIFIED)
+        .version(stored.version)
+        .body(Vec::new())
+        .unwrap();
+    for (name, value) in &stored.headers {
+        if !NOT_MODIFIED_SKIPPED_HEADERS.contains(&name.as_str()) {
+            response.headers_mut().append(name, value.clone());
+        }
+    }
+    response
+}
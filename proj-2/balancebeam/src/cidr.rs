@@ -0,0 +1,60 @@
+use std::net::IpAddr;
+
+/// A block of IP addresses, e.g. `10.0.0.0/8` or `2001:db8::/32`. A bare address is a block of one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Returns true if ip is in this block. IPv4 addresses written as IPv4-mapped IPv6 addresses
+    /// (which is how a dual-stack listener sees IPv4 clients) count as the IPv4 address.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::str::FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, prefix_len) = match s.split_once('/') {
+            Some((network, prefix_len)) => (network, Some(prefix_len)),
+            None => (s, None),
+        };
+        let network: IpAddr = network
+            .parse()
+            .map_err(|_| format!("invalid IP address in \"{}\"", s))?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => match prefix_len.parse::<u8>() {
+                Ok(prefix_len) if prefix_len <= max_len => prefix_len,
+                _ => return Err(format!("invalid prefix length in \"{}\"", s)),
+            },
+            None => max_len,
+        };
+        Ok(Cidr {
+            network,
+            prefix_len,
+        })
+    }
+}
@@ -0,0 +1,760 @@
+//! Compresses response bodies for clients that accept it (Accept-Encoding), with the gzip and
+//! deflate content codings. Both are DEFLATE (RFC 1951) underneath, with a gzip (RFC 1952) or
+//! zlib (RFC 1950) wrapper. The encoder is deliberately simple: LZ77 matching over a 32KB window,
+//! and the fixed Huffman codes, so that compressing a body costs little more than copying it.
+//! Brotli isn't offered, as we have no encoder for it.
+
+use crate::body::{self, Framing};
+use crate::throttle::Pacer;
+use tokio::io::{AsyncBufRead, AsyncWrite};
+
+/// Content types compressed when --compress-type isn't given
+const DEFAULT_TYPES: [&str; 6] = [
+    "text/*",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "application/wasm",
+    "image/svg+xml",
+];
+
+/// How far back a match may refer
+const WINDOW_SIZE: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// Most earlier positions to try when looking for a match. More finds longer matches, slowly.
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+/// Marks the end of a hash chain
+const NO_POSITION: u32 = u32::MAX;
+
+/// The lengths that each length symbol (257 onwards) starts at, and how many extra bits follow it
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA_BITS: [u32; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+/// The distances that each distance symbol starts at, and how many extra bits follow it
+const DISTANCE_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA_BITS: [u32; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+const END_OF_BLOCK: u32 = 256;
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+/// A content coding we can compress with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Coding {
+    Gzip,
+    Deflate,
+}
+
+impl Coding {
+    /// The coding's name in Accept-Encoding and Content-Encoding
+    pub fn name(self) -> &'static str {
+        match self {
+            Coding::Gzip => "gzip",
+            Coding::Deflate => "deflate",
+        }
+    }
+}
+
+/// Which responses get compressed, and how
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub gzip: bool,
+    pub deflate: bool,
+    /// Bodies smaller than this aren't worth compressing. (Bodies of unknown length always are.)
+    pub min_size: u64,
+    /// Lowercase media types to compress. A type ending in "/*" covers everything under it.
+    pub types: Vec<String>,
+}
+
+impl Settings {
+    pub fn new(gzip: bool, deflate: bool, min_size: u64, types: &[String]) -> Settings {
+        let types = match types.is_empty() {
+            true => DEFAULT_TYPES.iter().map(|t| t.to_string()).collect(),
+            false => types
+                .iter()
+                .map(|t| t.trim().to_ascii_lowercase())
+                .collect(),
+        };
+        Settings {
+            gzip,
+            deflate,
+            min_size,
+            types,
+        }
+    }
+
+    /// Picks the coding to compress a response's body with on its way to the client, or None if
+    /// it should be sent as it is: because the client doesn't accept any coding we offer, or the
+    /// upstream compressed it already, or it isn't of a type that compresses well, or it's too
+    /// small to bother.
+    pub fn choose(
+        &self,
+        request: &http::Request<Vec<u8>>,
+        response: &http::Response<Vec<u8>>,
+        framing: Framing,
+    ) -> Option<Coding> {
+        if !self.gzip && !self.deflate {
+            return None;
+        }
+        // Partial content and HEAD responses describe a body we'd be changing. HTTP/1.0 responses
+        // are passed on as they are, as their clients may not understand chunked bodies.
+        if request.method() == http::Method::HEAD
+            || response.status() != http::StatusCode::OK
+            || response.version() == http::Version::HTTP_10
+        {
+            return None;
+        }
+        match framing {
+            Framing::Empty | Framing::Length(0) => return None,
+            Framing::Length(length) if length < self.min_size => return None,
+            _ => {}
+        }
+        let headers = response.headers();
+        if headers.contains_key(http::header::CONTENT_ENCODING) || no_transform(headers) {
+            return None;
+        }
+        let content_type = headers
+            .get(http::header::CONTENT_TYPE)?
+            .to_str()
+            .ok()?
+            .split(';')
+            .next()?
+            .trim()
+            .to_ascii_lowercase();
+        let compressible = self.types.iter().any(|t| match t.strip_suffix("/*") {
+            Some(prefix) => content_type.split('/').next() == Some(prefix),
+            None => content_type == *t,
+        });
+        if !compressible {
+            return None;
+        }
+
+        // Use whichever coding the client likes best, with gzip winning ties
+        let mut chosen = None;
+        let mut best = 0.0;
+        for (coding, enabled) in [(Coding::Gzip, self.gzip), (Coding::Deflate, self.deflate)] {
+            let quality = accepted_quality(request.headers(), coding.name());
+            if enabled && quality > best {
+                chosen = Some(coding);
+                best = quality;
+            }
+        }
+        chosen
+    }
+
+    /// Compresses a response whose body is all in memory (e.g. one from the cache), if the client
+    /// accepts it and it's worth it
+    pub fn compress_in_memory(
+        &self,
+        request: &http::Request<Vec<u8>>,
+        response: &mut http::Response<Vec<u8>>,
+    ) {
+        let framing = Framing::Length(response.body().len() as u64);
+        if let Some(coding) = self.choose(request, response, framing) {
+            let body = std::mem::take(response.body_mut());
+            *response.body_mut() = encode(response.headers_mut(), coding, &body);
+        }
+    }
+}
+
+/// How much a request's Accept-Encoding headers say the client wants a coding, from 0 (not at all)
+/// to 1
+fn accepted_quality(headers: &http::HeaderMap, name: &str) -> f32 {
+    let mut wildcard = None;
+    for item in headers
+        .get_all(http::header::ACCEPT_ENCODING)
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or("").split(','))
+    {
+        let mut params = item.split(';');
+        let coding = params.next().unwrap_or("").trim();
+        let quality = params
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .map(|q| q.trim().parse().unwrap_or(0.0))
+            .unwrap_or(1.0);
+        if coding.eq_ignore_ascii_case(name) {
+            return quality;
+        } else if coding == "*" {
+            wildcard = Some(quality);
+        }
+    }
+    wildcard.unwrap_or(0.0)
+}
+
+/// Whether the upstream asked for its response to be passed on untouched (Cache-Control:
+/// no-transform)
+fn no_transform(headers: &http::HeaderMap) -> bool {
+    headers
+        .get_all(http::header::CACHE_CONTROL)
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or("").split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"))
+}
+
+/// Compresses a body that's all in memory, updating its response's headers to match
+pub fn encode(headers: &mut http::HeaderMap, coding: Coding, body: &[u8]) -> Vec<u8> {
+    let mut encoder = Encoder::new(coding);
+    let mut encoded = encoder.write(body);
+    encoded.extend(encoder.finish());
+    rewrite_head(headers, coding, Some(encoded.len()));
+    encoded
+}
+
+/// Updates a response's headers for a body that's compressed as it's passed on (see copy). Its
+/// compressed length isn't known until it's over, so it's sent chunked.
+pub fn encode_streamed(headers: &mut http::HeaderMap, coding: Coding) {
+    rewrite_head(headers, coding, None);
+}
+
+fn rewrite_head(headers: &mut http::HeaderMap, coding: Coding, length: Option<usize>) {
+    headers.insert(
+        http::header::CONTENT_ENCODING,
+        http::HeaderValue::from_static(coding.name()),
+    );
+    match length {
+        Some(length) => {
+            headers.remove(http::header::TRANSFER_ENCODING);
+            headers.insert(http::header::CONTENT_LENGTH, length.into());
+        }
+        None => {
+            headers.remove(http::header::CONTENT_LENGTH);
+            headers.insert(
+                http::header::TRANSFER_ENCODING,
+                http::HeaderValue::from_static("chunked"),
+            );
+        }
+    }
+    // Caches further along mustn't hand the compressed body to clients that can't take it
+    let varies = headers
+        .get_all(http::header::VARY)
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or("").split(','))
+        .any(|name| name.trim() == "*" || name.trim().eq_ignore_ascii_case("accept-encoding"));
+    if !varies {
+        headers.append(
+            http::header::VARY,
+            http::HeaderValue::from_static("Accept-Encoding"),
+        );
+    }
+    // A strong ETag promises these exact bytes, which the compressed body isn't
+    let etag = headers
+        .get(http::header::ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .filter(|etag| !etag.starts_with("W/"))
+        .and_then(|etag| http::HeaderValue::from_str(&format!("W/{}", etag)).ok());
+    if let Some(etag) = etag {
+        headers.insert(http::header::ETAG, etag);
+    }
+}
+
+/// Like body::copy, but compresses the body as it goes. The body is always written chunked (see
+/// encode_streamed), and any trailers are passed on. Returns the number of compressed bytes
+/// written, which are what the pacer (if any) paces.
+pub async fn copy<R, W>(
+    from: &mut R,
+    to: &mut W,
+    framing: Framing,
+    coding: Coding,
+    pacer: Option<&Pacer>,
+) -> Result<u64, body::Error>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut reader = body::Reader::new(framing);
+    let mut writer = body::Writer::new(Framing::Chunked);
+    let mut encoder = Encoder::new(coding);
+    let mut buffer = vec![0_u8; body::COPY_BUFFER_SIZE];
+    let mut written = 0;
+    loop {
+        let bytes_read = reader.read(from, &mut buffer).await?;
+        let encoded = match bytes_read {
+            0 => encoder.finish(),
+            _ => encoder.write(&buffer[..bytes_read]),
+        };
+        written += encoded.len() as u64;
+        if let Some(pacer) = pacer {
+            pacer.take(encoded.len()).await;
+        }
+        writer
+            .write(to, &encoded)
+            .await
+            .map_err(body::Error::WriteError)?;
+        if bytes_read == 0 {
+            break;
+        }
+    }
+    writer
+        .finish(to, reader.trailers())
+        .await
+        .map_err(body::Error::WriteError)?;
+    Ok(written)
+}
+
+/// Compresses a body a piece at a time. Each piece written comes out as a DEFLATE block of its
+/// own, so the client can start decompressing before the body is over.
+pub struct Encoder {
+    coding: Coding,
+    deflater: Deflater,
+    /// Running checksum of the uncompressed body: CRC-32 for gzip, Adler-32 for deflate
+    checksum: u32,
+    /// Length of the uncompressed body so far
+    size: u64,
+}
+
+impl Encoder {
+    pub fn new(coding: Coding) -> Encoder {
+        let mut deflater = Deflater::new();
+        let checksum = match coding {
+            Coding::Gzip => {
+                // Magic number, DEFLATE, no flags, no modification time, no extra flags, and an
+                // unknown OS
+                deflater
+                    .bits
+                    .out
+                    .extend_from_slice(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255]);
+                0
+            }
+            Coding::Deflate => {
+                // DEFLATE with a 32KB window, and the check bits that make this a multiple of 31
+                deflater.bits.out.extend_from_slice(&[0x78, 0x01]);
+                1
+            }
+        };
+        Encoder {
+            coding,
+            deflater,
+            checksum,
+            size: 0,
+        }
+    }
+
+    /// Compresses the next piece of the body, returning what's ready to send. (A few bits may be
+    /// held back until the next call.)
+    pub fn write(&mut self, data: &[u8]) -> Vec<u8> {
+        self.checksum = match self.coding {
+            Coding::Gzip => crc32(self.checksum, data),
+            Coding::Deflate => adler32(self.checksum, data),
+        };
+        self.size += data.len() as u64;
+        self.deflater.write(data);
+        std::mem::take(&mut self.deflater.bits.out)
+    }
+
+    /// Ends the body, returning the rest of the compressed data
+    pub fn finish(&mut self) -> Vec<u8> {
+        self.deflater.finish();
+        let mut out = std::mem::take(&mut self.deflater.bits.out);
+        match self.coding {
+            Coding::Gzip => {
+                out.extend_from_slice(&self.checksum.to_le_bytes());
+                out.extend_from_slice(&(self.size as u32).to_le_bytes());
+            }
+            Coding::Deflate => out.extend_from_slice(&self.checksum.to_be_bytes()),
+        }
+        out
+    }
+}
+
+/// Packs values into bytes, least significant bit first, as DEFLATE wants
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, count: u32) {
+        self.bits |= (value as u64) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Writes a Huffman code, which (unlike everything else) goes most significant bit first
+    fn write_code(&mut self, code: u32, length: u32) {
+        self.write(code.reverse_bits() >> (32 - length), length);
+    }
+
+    /// Pads out the last byte
+    fn align(&mut self) {
+        if self.count > 0 {
+            self.write(0, 8 - self.count);
+        }
+    }
+}
+
+/// A DEFLATE compressor, using the fixed Huffman codes
+struct Deflater {
+    bits: BitWriter,
+    /// The last WINDOW_SIZE bytes compressed (which matches can refer back to), followed by what's
+    /// being compressed now
+    window: Vec<u8>,
+    /// For each hash of three bytes, the last position in window they were seen at
+    head: Vec<u32>,
+    /// For each position in window, the previous position with the same hash
+    prev: Vec<u32>,
+}
+
+impl Deflater {
+    fn new() -> Deflater {
+        Deflater {
+            bits: BitWriter {
+                out: Vec::new(),
+                bits: 0,
+                count: 0,
+            },
+            window: Vec::new(),
+            head: vec![NO_POSITION; 1 << HASH_BITS],
+            prev: Vec::new(),
+        }
+    }
+
+    fn hash(&self, position: usize) -> usize {
+        let bytes = &self.window[position..position + MIN_MATCH];
+        let value = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        (value.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, position: usize) {
+        if position + MIN_MATCH <= self.window.len() {
+            let hash = self.hash(position);
+            self.prev[position] = self.head[hash];
+            self.head[hash] = position as u32;
+        }
+    }
+
+    /// The longest earlier match for the bytes at position, as its length and distance
+    fn longest_match(&self, position: usize) -> (usize, usize) {
+        let max_length = MAX_MATCH.min(self.window.len() - position);
+        if max_length < MIN_MATCH {
+            return (0, 0);
+        }
+        let mut best = (0, 0);
+        let mut candidate = self.head[self.hash(position)];
+        for _ in 0..MAX_CHAIN {
+            if candidate == NO_POSITION || position - candidate as usize > WINDOW_SIZE {
+                break;
+            }
+            let start = candidate as usize;
+            let length = self.window[start..start + max_length]
+                .iter()
+                .zip(&self.window[position..position + max_length])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if length > best.0 {
+                best = (length, position - start);
+                if length == max_length {
+                    break;
+                }
+            }
+            candidate = self.prev[start];
+        }
+        best
+    }
+
+    /// Compresses data as a block of its own
+    fn write(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        // The hash chains are rebuilt over the window each time, which is simpler than keeping
+        // them up to date as the window slides
+        let start = self.window.len();
+        self.window.extend_from_slice(data);
+        self.head.iter_mut().for_each(|head| *head = NO_POSITION);
+        self.prev.clear();
+        self.prev.resize(self.window.len(), NO_POSITION);
+        for position in 0..start {
+            self.insert(position);
+        }
+
+        // Not the last block; fixed Huffman codes
+        self.bits.write(0, 1);
+        self.bits.write(1, 2);
+        let mut position = start;
+        while position < self.window.len() {
+            let (length, distance) = self.longest_match(position);
+            if length >= MIN_MATCH {
+                self.write_match(length, distance);
+                for skipped in position..position + length {
+                    self.insert(skipped);
+                }
+                position += length;
+            } else {
+                self.write_symbol(self.window[position] as u32);
+                self.insert(position);
+                position += 1;
+            }
+        }
+        self.write_symbol(END_OF_BLOCK);
+
+        if self.window.len() > WINDOW_SIZE {
+            self.window.drain(..self.window.len() - WINDOW_SIZE);
+        }
+    }
+
+    /// Ends the stream with an empty final block
+    fn finish(&mut self) {
+        self.bits.write(1, 1);
+        self.bits.write(1, 2);
+        self.write_symbol(END_OF_BLOCK);
+        self.bits.align();
+    }
+
+    /// Writes a literal byte, end of block, or length symbol, in the fixed literal/length code
+    fn write_symbol(&mut self, symbol: u32) {
+        match symbol {
+            0..=143 => self.bits.write_code(0x30 + symbol, 8),
+            144..=255 => self.bits.write_code(0x190 + symbol - 144, 9),
+            256..=279 => self.bits.write_code(symbol - 256, 7),
+            _ => self.bits.write_code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn write_match(&mut self, length: usize, distance: usize) {
+        let index = LENGTH_BASES
+            .iter()
+            .rposition(|&base| base as usize <= length)
+            .unwrap();
+        self.write_symbol(257 + index as u32);
+        self.bits.write(
+            (length - LENGTH_BASES[index] as usize) as u32,
+            LENGTH_EXTRA_BITS[index],
+        );
+        let index = DISTANCE_BASES
+            .iter()
+            .rposition(|&base| base as usize <= distance)
+            .unwrap();
+        self.bits.write_code(index as u32, 5);
+        self.bits.write(
+            (distance - DISTANCE_BASES[index] as usize) as u32,
+            DISTANCE_EXTRA_BITS[index],
+        );
+    }
+}
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xedb8_8320,
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc = CRC_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+fn adler32(adler: u32, data: &[u8]) -> u32 {
+    let (mut a, mut b) = (adler & 0xffff, adler >> 16);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads bits least significant first, as BitWriter writes them
+    struct BitReader<'a> {
+        data: &'a [u8],
+        position: usize,
+    }
+
+    impl BitReader<'_> {
+        fn bit(&mut self) -> u32 {
+            let bit = (self.data[self.position / 8] >> (self.position % 8)) & 1;
+            self.position += 1;
+            bit as u32
+        }
+
+        fn read(&mut self, count: u32) -> u32 {
+            (0..count).fold(0, |value, i| value | self.bit() << i)
+        }
+
+        /// Reads a Huffman code of the given length, most significant bit first
+        fn read_code(&mut self, length: u32) -> u32 {
+            (0..length).fold(0, |code, _| code << 1 | self.bit())
+        }
+
+        /// Reads a symbol in the fixed literal/length code
+        fn read_symbol(&mut self) -> u32 {
+            let code = self.read_code(7);
+            if code < 24 {
+                return 256 + code;
+            }
+            let code = code << 1 | self.bit();
+            match code {
+                0x30..=0xbf => code - 0x30,
+                0xc0..=0xc7 => 280 + code - 0xc0,
+                _ => 144 + (code << 1 | self.bit()) - 0x190,
+            }
+        }
+    }
+
+    /// Decompresses a DEFLATE stream made of fixed Huffman blocks (all Deflater writes), returning
+    /// the data and how many bytes the stream took up
+    fn inflate(data: &[u8]) -> (Vec<u8>, usize) {
+        let mut bits = BitReader { data, position: 0 };
+        let mut out = Vec::new();
+        loop {
+            let last = bits.read(1) == 1;
+            assert_eq!(bits.read(2), 1, "not a fixed Huffman block");
+            loop {
+                let symbol = bits.read_symbol();
+                match symbol {
+                    0..=255 => out.push(symbol as u8),
+                    END_OF_BLOCK => break,
+                    _ => {
+                        let index = (symbol - 257) as usize;
+                        let length = LENGTH_BASES[index] as usize
+                            + bits.read(LENGTH_EXTRA_BITS[index]) as usize;
+                        let index = bits.read_code(5) as usize;
+                        let distance = DISTANCE_BASES[index] as usize
+                            + bits.read(DISTANCE_EXTRA_BITS[index]) as usize;
+                        assert!(distance <= WINDOW_SIZE && distance <= out.len());
+                        for _ in 0..length {
+                            out.push(out[out.len() - distance]);
+                        }
+                    }
+                }
+            }
+            if last {
+                return (out, bits.position.div_ceil(8));
+            }
+        }
+    }
+
+    /// Compresses data in the given pieces, checks the wrapper, and returns the decompressed body
+    fn round_trip(coding: Coding, pieces: &[&[u8]]) -> Vec<u8> {
+        let mut encoder = Encoder::new(coding);
+        let mut compressed = Vec::new();
+        for piece in pieces {
+            compressed.extend(encoder.write(piece));
+        }
+        compressed.extend(encoder.finish());
+        let data = pieces.concat();
+        match coding {
+            Coding::Gzip => {
+                assert_eq!(&compressed[..4], &[0x1f, 0x8b, 8, 0]);
+                let (body, length) = inflate(&compressed[10..]);
+                let trailer = &compressed[10 + length..];
+                assert_eq!(trailer[..4], crc32(0, &data).to_le_bytes());
+                assert_eq!(trailer[4..], (data.len() as u32).to_le_bytes());
+                body
+            }
+            Coding::Deflate => {
+                assert_eq!(u16::from_be_bytes([compressed[0], compressed[1]]) % 31, 0);
+                let (body, length) = inflate(&compressed[2..]);
+                let trailer = &compressed[2 + length..];
+                assert_eq!(trailer, adler32(1, &data).to_be_bytes());
+                body
+            }
+        }
+    }
+
+    /// Text that repeats itself at all sorts of distances, some beyond the window
+    fn sample_text(length: usize) -> Vec<u8> {
+        let words = [
+            "balance", "beam", "upstream", "proxy", "request", "response", "\n",
+        ];
+        let mut state: u32 = 12345;
+        let mut text = Vec::with_capacity(length);
+        while text.len() < length {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            text.extend_from_slice(words[(state >> 16) as usize % words.len()].as_bytes());
+            text.extend_from_slice(format!(" {} ", state % 1000).as_bytes());
+        }
+        text.truncate(length);
+        text
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(0, b""), 0);
+        assert_eq!(crc32(0, b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(crc32(0, b"12345"), b"6789"), 0xcbf4_3926);
+        assert_eq!(adler32(1, b""), 1);
+        assert_eq!(adler32(1, b"Wikipedia"), 0x11e6_0398);
+        assert_eq!(adler32(adler32(1, b"Wiki"), b"pedia"), 0x11e6_0398);
+        // Big enough for the sums to wrap around the modulus many times
+        let text = sample_text(100_000);
+        assert_eq!(
+            adler32(adler32(1, &text[..60_000]), &text[60_000..]),
+            adler32(1, &text)
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        for &coding in &[Coding::Gzip, Coding::Deflate] {
+            assert!(round_trip(coding, &[]).is_empty());
+            assert!(round_trip(coding, &[b""]).is_empty());
+            let small = sample_text(10_000);
+            assert_eq!(round_trip(coding, &[&small]), small);
+            let large = sample_text(200_000);
+            assert_eq!(round_trip(coding, &[&large]), large);
+        }
+    }
+
+    #[test]
+    fn streamed_round_trips() {
+        let text = sample_text(150_000);
+        for &coding in &[Coding::Gzip, Coding::Deflate] {
+            // Pieces of all sizes, some much smaller and some bigger than the window
+            let mut pieces = Vec::new();
+            let mut rest = &text[..];
+            for &size in [1, 7, 4096, 0, 40_000, 3, 65_536].iter().cycle() {
+                if rest.is_empty() {
+                    break;
+                }
+                let (piece, after) = rest.split_at(size.min(rest.len()));
+                pieces.push(piece);
+                rest = after;
+            }
+            assert_eq!(round_trip(coding, &pieces), text);
+        }
+    }
+
+    #[test]
+    fn compresses_repetition() {
+        let text = "balancebeam ".repeat(10_000);
+        let mut encoder = Encoder::new(Coding::Gzip);
+        let mut compressed = encoder.write(text.as_bytes());
+        compressed.extend(encoder.finish());
+        assert!(compressed.len() < text.len() / 50);
+    }
+}
@@ -0,0 +1,1065 @@
+use crate::acl::{MethodAction, MethodRule};
+use crate::error_page::ErrorPage;
+use crate::rate_limit::RouteRule;
+use crate::routing::{Pool, Route};
+use crate::{CmdOptions, UpstreamState};
+use clap::{ArgMatches, ValueSource};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// The contents of a `--config` file. Every setting is optional; anything left out keeps its
+/// command-line value (or default). For example:
+///
+/// ```toml
+/// strategy = "least-connections"
+/// slow_start = 30
+/// adaptive_weights = true
+/// dns_refresh_interval = 10
+/// upstream_connect_timeout = 5
+/// upstream_response_timeout = 30
+///
+/// [listener]
+/// bind = ["0.0.0.0:1100", "[::]:1100", "0.0.0.0:1101,proxy_protocol"]
+/// forwarded_header_style = "both"
+/// trusted_proxies = ["10.0.0.0/8", "fd00::/8"]
+/// max_connections = 10000
+/// connection_queue_timeout = 500
+/// request_queue_size = 1000
+/// request_queue_timeout = 5000
+/// client_idle_timeout = 30
+///
+/// [[upstream]]
+/// address = "10.0.0.1:8080"
+/// weight = 3
+/// max_requests = 100
+///
+/// [[upstream]]
+/// address = "10.0.0.2:8080"
+/// drain = true
+/// health_check_interval = 30
+///
+/// [[upstream]]
+/// address = "tls://10.1.0.1:443"
+/// sni = "api.internal"
+///
+/// [[upstream]]
+/// address = "10.2.0.1:8080"
+/// tier = "backup"
+/// proxy_protocol = "v2"
+///
+/// [[upstream]]
+/// address = "10.3.0.1:8080"
+/// pool = "api"
+///
+/// [[pool]]
+/// name = "api"
+/// strategy = "least-connections"
+/// health_check_path = "/healthz"
+/// health_check_interval = 5
+///
+/// [[pool]]
+/// name = "grpc"
+/// protocol = "h2c"
+///
+/// [[route]]
+/// path_prefix = "/api"
+/// pool = "api"
+///
+/// [[route]]
+/// path_prefix = "/helloworld.Greeter"
+/// pool = "grpc"
+///
+/// [[route]]
+/// host = "*.api.example.com"
+/// pool = "api"
+///
+/// [canary]
+/// pool = "canary"
+/// percent = 5
+/// sticky = true
+///
+/// [mirror]
+/// pool = "shadow"
+/// percent = 10
+///
+/// [[method_rule]]
+/// path_prefix = "/public"
+/// allow = ["GET", "HEAD"]
+///
+/// [[method_rule]]
+/// deny = ["TRACE"]
+///
+/// [health_check]
+/// interval = 5
+/// jitter = 20
+/// path = "/healthz"
+/// method = "POST"
+/// headers = { Authorization = "Bearer abc123" }
+/// request_body = "{}"
+/// timeout = 2
+/// expected_status = "200-299"
+/// expected_body = "ok"
+///
+/// [client_access]
+/// allow = ["10.0.0.0/8", "192.168.0.0/16"]
+/// deny = ["10.66.0.0/16"]
+/// action = "drop"
+///
+/// [rate_limit]
+/// max_requests_per_minute = 600
+/// burst = 50
+/// algorithm = "token-bucket"
+/// max_clients = 100000
+/// key_header = "X-Api-Key"
+/// redis = "redis://127.0.0.1:6379/0"
+/// json_errors = true
+/// client_bandwidth = 1048576
+/// allow = ["10.1.2.0/24"]
+/// deny = ["192.0.2.0/24"]
+///
+/// [[rate_limit.route]]
+/// method = "POST"
+/// path_prefix = "/login"
+/// max_requests_per_minute = 10
+///
+/// [logging]
+/// access_log = "/var/log/balancebeam/access.log"
+/// format = "json"
+///
+/// [tracing]
+/// otlp_endpoint = "http://127.0.0.1:4318"
+///
+/// [cache]
+/// size = 104857600
+/// ttl = 30
+/// max_entry_size = 1048576
+///
+/// [compression]
+/// gzip = true
+/// deflate = true
+/// min_size = 1024
+/// types = ["text/*", "application/json"]
+///
+/// [cors]
+/// origins = ["https://app.example.com", "https://*.example.com"]
+/// methods = "GET, POST"
+/// headers = "Content-Type, Authorization"
+/// expose_headers = "X-Request-Id"
+/// credentials = true
+/// max_age = 600
+///
+/// [auth]
+/// rules = ["basic /admin", "jwt /api", "none /api/health"]
+/// basic_auth_file = "/etc/balancebeam/htpasswd"
+/// realm = "example"
+/// jwt_jwks = "/etc/balancebeam/jwks.json"
+/// jwt_issuer = "https://auth.example.com"
+/// jwt_audience = "api"
+///
+/// [maintenance]
+/// enabled = false
+/// paths = ["/api"]
+/// allow = ["10.0.0.0/8"]
+/// page = "/etc/balancebeam/maintenance.html"
+/// retry_after = 600
+///
+/// [error_pages]
+/// 502 = "/etc/balancebeam/502.html"
+/// 5xx = "/etc/balancebeam/5xx.html"
+/// 429 = "/etc/balancebeam/429.json"
+///
+/// [headers]
+/// request = ["remove X-Debug", "set X-Env: prod"]
+/// response = ["remove Server", "set Strict-Transport-Security: max-age=63072000"]
+///
+/// [rewrite]
+/// path = ["strip /api", "replace ^/v1/(.*)$ /v2/$1"]
+///
+/// [upstream_pool]
+/// max_idle = 16
+/// idle_timeout = 10
+///
+/// [circuit_breaker]
+/// failures = 5
+/// error_rate = 50
+/// open_time = 30
+///
+/// [discovery]
+/// sources = ["srv:_http._tcp.api.internal", "consul:127.0.0.1:8500/api"]
+/// interval = 15
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    #[serde(default)]
+    upstream: Vec<UpstreamConfig>,
+    #[serde(default)]
+    pool: Vec<PoolConfig>,
+    #[serde(default)]
+    route: Vec<RouteConfig>,
+    #[serde(default)]
+    method_rule: Vec<MethodRuleConfig>,
+    #[serde(default)]
+    canary: CanaryConfig,
+    #[serde(default)]
+    mirror: MirrorConfig,
+    mode: Option<String>,
+    udp_session_timeout: Option<u64>,
+    strategy: Option<String>,
+    /// "http1" or "h2c"
+    upstream_protocol: Option<String>,
+    sticky_sessions: Option<bool>,
+    shutdown_timeout: Option<u64>,
+    hedge_after: Option<u64>,
+    upstream_connect_timeout: Option<u64>,
+    upstream_response_timeout: Option<u64>,
+    slow_start: Option<u64>,
+    adaptive_weights: Option<bool>,
+    /// A percentage
+    adaptive_min_share: Option<u8>,
+    dns_refresh_interval: Option<u64>,
+    upstream_tls_ca: Option<String>,
+    #[serde(default)]
+    listener: ListenerConfig,
+    #[serde(default)]
+    admin: AdminConfig,
+    #[serde(default)]
+    health_check: HealthCheckConfig,
+    #[serde(default)]
+    client_access: ClientAccessConfig,
+    #[serde(default)]
+    rate_limit: RateLimitConfig,
+    #[serde(default)]
+    logging: LoggingConfig,
+    #[serde(default)]
+    tracing: TracingConfig,
+    #[serde(default)]
+    cache: CacheConfig,
+    #[serde(default)]
+    compression: CompressionConfig,
+    #[serde(default)]
+    cors: CorsConfig,
+    #[serde(default)]
+    auth: AuthConfig,
+    #[serde(default)]
+    maintenance: MaintenanceConfig,
+    /// Statuses (e.g. "502" or "5xx") mapped to files, as for --error-page
+    error_pages: Option<BTreeMap<String, String>>,
+    #[serde(default)]
+    headers: HeadersConfig,
+    #[serde(default)]
+    rewrite: RewriteConfig,
+    #[serde(default)]
+    upstream_pool: UpstreamPoolConfig,
+    #[serde(default)]
+    circuit_breaker: CircuitBreakerConfig,
+    #[serde(default)]
+    discovery: DiscoveryConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpstreamConfig {
+    address: String,
+    weight: Option<usize>,
+    /// "primary" (the default) or "backup"
+    tier: Option<String>,
+    /// The pool the upstream is in, which routes send requests to
+    pool: Option<String>,
+    /// Stop sending new connections to this upstream (see UpstreamState::draining)
+    #[serde(default)]
+    drain: bool,
+    /// For tls:// upstreams (see parse_upstream_state)
+    sni: Option<String>,
+    verify: Option<bool>,
+    /// Seconds between active health checks of this upstream, overriding health_check.interval
+    health_check_interval: Option<u64>,
+    /// "v1" or "v2", to start each connection to this upstream with a PROXY protocol header
+    proxy_protocol: Option<String>,
+    /// Most requests to send this upstream at once
+    max_requests: Option<usize>,
+}
+
+/// Settings for a pool of upstreams (see routing::Pool)
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PoolConfig {
+    name: String,
+    strategy: Option<String>,
+    /// "http1" or "h2c"
+    protocol: Option<String>,
+    health_check_path: Option<String>,
+    health_check_interval: Option<u64>,
+}
+
+/// Sends requests for a host and/or under a path prefix to a pool (see routing::Route)
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RouteConfig {
+    /// e.g. "api.example.com", or "*.example.com" for any subdomain
+    host: Option<String>,
+    path_prefix: Option<String>,
+    pool: String,
+}
+
+/// A setting that can be given either as a single value or as a list of them
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> OneOrMany<T> {
+    fn into_vec(self) -> Vec<T> {
+        match self {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        }
+    }
+}
+
+/// Options for the listeners that accept proxied traffic
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListenerConfig {
+    /// One address, or a list of them
+    bind: Option<OneOrMany<String>>,
+    forwarded_header_style: Option<String>,
+    /// CIDR blocks, as for --trusted-proxies
+    trusted_proxies: Option<OneOrMany<String>>,
+    require_content_length: Option<bool>,
+    max_body_size: Option<u64>,
+    max_header_size: Option<usize>,
+    max_headers: Option<usize>,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    tls_client_ca: Option<String>,
+    http2: Option<bool>,
+    max_connections: Option<usize>,
+    /// In milliseconds
+    connection_queue_timeout: Option<u64>,
+    request_queue_size: Option<usize>,
+    /// In milliseconds
+    request_queue_timeout: Option<u64>,
+    client_idle_timeout: Option<u64>,
+}
+
+/// Options for the admin API listener
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AdminConfig {
+    bind: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HealthCheckConfig {
+    interval: Option<usize>,
+    /// A percentage
+    jitter: Option<u8>,
+    path: Option<String>,
+    max_revivals: Option<usize>,
+    method: Option<String>,
+    /// Header names mapped to values
+    headers: Option<BTreeMap<String, String>>,
+    request_body: Option<String>,
+    timeout: Option<u64>,
+    /// Codes and ranges, as for --health-check-status
+    expected_status: Option<String>,
+    expected_body: Option<String>,
+    passive_failure_threshold: Option<usize>,
+    passive_success_threshold: Option<usize>,
+}
+
+/// Clients that may connect at all, as for --allow-client and --deny-client
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClientAccessConfig {
+    allow: Option<OneOrMany<String>>,
+    deny: Option<OneOrMany<String>>,
+    /// "forbidden" or "drop"
+    action: Option<String>,
+}
+
+/// Maintenance mode, as for --maintenance and friends
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MaintenanceConfig {
+    enabled: Option<bool>,
+    paths: Option<OneOrMany<String>>,
+    allow: Option<OneOrMany<String>>,
+    page: Option<String>,
+    retry_after: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RateLimitConfig {
+    max_requests_per_minute: Option<usize>,
+    burst: Option<usize>,
+    /// "token-bucket" or "sliding-window"
+    algorithm: Option<String>,
+    max_clients: Option<usize>,
+    /// A header to identify clients by, instead of their IP
+    key_header: Option<String>,
+    /// A Redis server to keep counters in, written as for --rate-limit-redis
+    redis: Option<String>,
+    /// Whether 429 responses have a JSON body
+    json_errors: Option<bool>,
+    /// Bytes per second of response bodies to send each client IP
+    client_bandwidth: Option<u64>,
+    /// CIDR blocks of clients that are never rate limited
+    allow: Option<OneOrMany<String>>,
+    /// CIDR blocks of clients that are always refused
+    deny: Option<OneOrMany<String>>,
+    /// Limits on requests to particular routes (see RouteRule)
+    #[serde(default)]
+    route: Vec<RouteRateLimitConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RouteRateLimitConfig {
+    method: Option<String>,
+    path_prefix: Option<String>,
+    max_requests_per_minute: usize,
+    burst: Option<usize>,
+}
+
+/// Where and how to log
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LoggingConfig {
+    access_log: Option<String>,
+    /// "text" or "json"
+    format: Option<String>,
+}
+
+/// Where to export traces to
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TracingConfig {
+    otlp_endpoint: Option<String>,
+}
+
+/// Options for the response cache
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CacheConfig {
+    /// In bytes
+    size: Option<usize>,
+    ttl: Option<u64>,
+    max_entry_size: Option<u64>,
+}
+
+/// Options for compressing responses
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CompressionConfig {
+    gzip: Option<bool>,
+    deflate: Option<bool>,
+    /// In bytes
+    min_size: Option<u64>,
+    types: Option<OneOrMany<String>>,
+}
+
+/// Cross-origin resource sharing, as for --cors-origin
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CorsConfig {
+    origins: Option<OneOrMany<String>>,
+    methods: Option<String>,
+    headers: Option<String>,
+    expose_headers: Option<String>,
+    credentials: Option<bool>,
+    /// In seconds
+    max_age: Option<u64>,
+}
+
+/// Which paths need credentials, as for --auth
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AuthConfig {
+    rules: Option<OneOrMany<String>>,
+    basic_auth_file: Option<String>,
+    realm: Option<String>,
+    jwt_secret: Option<String>,
+    jwt_jwks: Option<String>,
+    jwt_issuer: Option<String>,
+    jwt_audience: Option<String>,
+}
+
+/// Rules for changing the headers of requests and responses, as for --request-header and
+/// --response-header
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HeadersConfig {
+    request: Option<OneOrMany<String>>,
+    response: Option<OneOrMany<String>>,
+}
+
+/// Limits the methods that may be used under a path prefix (see acl::MethodRule). Exactly one of
+/// allow and deny should be given.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MethodRuleConfig {
+    path_prefix: Option<String>,
+    allow: Option<Vec<String>>,
+    deny: Option<Vec<String>>,
+}
+
+/// Sends a share of requests to a canary pool, as for --canary-pool
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CanaryConfig {
+    pool: Option<String>,
+    percent: Option<f64>,
+    sticky: Option<bool>,
+}
+
+/// Copies a sample of requests to a mirror pool, as for --mirror-pool
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MirrorConfig {
+    pool: Option<String>,
+    percent: Option<f64>,
+}
+
+/// Rules for changing the paths of requests, as for --rewrite-path
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RewriteConfig {
+    path: Option<OneOrMany<String>>,
+}
+
+/// Options for reusing upstream connections
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpstreamPoolConfig {
+    max_idle: Option<usize>,
+    idle_timeout: Option<u64>,
+}
+
+/// Options for upstreams' circuit breakers
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CircuitBreakerConfig {
+    failures: Option<usize>,
+    /// A percentage
+    error_rate: Option<u8>,
+    open_time: Option<u64>,
+}
+
+/// Where to find upstreams besides the [[upstream]] list
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DiscoveryConfig {
+    /// One source, or a list of them, written as for --discover
+    sources: Option<OneOrMany<String>>,
+    interval: Option<u64>,
+}
+
+/// Reads and parses a config file
+pub fn load(path: &str) -> Result<ConfigFile, String> {
+    let contents = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+    toml::from_str(&contents).map_err(|err| err.to_string())
+}
+
+/// Returns true if the user passed this argument on the command line, in which case it overrides
+/// whatever the config file says. field is the CmdOptions field name; the derive macro names the
+/// argument after it in kebab-case.
+fn from_command_line(matches: &ArgMatches, field: &str) -> bool {
+    matches.value_source(field.replace('_', "-")) == Some(ValueSource::CommandLine)
+}
+
+impl ConfigFile {
+    /// Copies the settings in this file into options, except for those that were given on the
+    /// command line. matches must be the ArgMatches that options was built from.
+    pub fn apply(self, options: &mut CmdOptions, matches: &ArgMatches) -> Result<(), String> {
+        // Upstreams given on the command line replace the file's list, rather than adding to it
+        if !from_command_line(matches, "upstream") && !self.upstream.is_empty() {
+            options.upstream = Vec::new();
+            for upstream in self.upstream {
+                if upstream.address.is_empty() {
+                    return Err("upstream address is empty".to_string());
+                }
+                let weight = match upstream.weight {
+                    Some(0) => return Err(format!("invalid weight 0 for {}", upstream.address)),
+                    Some(weight) => weight,
+                    None => 1,
+                };
+                let health_check_interval = match upstream.health_check_interval {
+                    Some(0) => {
+                        return Err(format!(
+                            "invalid health_check_interval 0 for {}",
+                            upstream.address
+                        ))
+                    }
+                    interval => interval.map(std::time::Duration::from_secs),
+                };
+                if upstream.max_requests == Some(0) {
+                    return Err(format!("invalid max_requests 0 for {}", upstream.address));
+                }
+                let mut state = UpstreamState::new(upstream.address, weight);
+                state.max_requests = upstream.max_requests;
+                state.draining = upstream.drain;
+                if let Some(tier) = upstream.tier {
+                    state.tier = tier.parse()?;
+                }
+                state.pool = upstream.pool.filter(|pool| !pool.is_empty());
+                state.health_check_interval = health_check_interval;
+                if let Some(version) = upstream.proxy_protocol {
+                    state.proxy_protocol = Some(version.parse()?);
+                }
+                if let Some(sni) = upstream.sni {
+                    state.tls_options()?.server_name = sni;
+                }
+                if let Some(verify) = upstream.verify {
+                    state.tls_options()?.verify = verify;
+                }
+                state.check_tls()?;
+                options.upstream.push(state);
+            }
+        }
+
+        // Likewise for pools and routes
+        if !from_command_line(matches, "pool") && !self.pool.is_empty() {
+            options.pool = Vec::new();
+            for pool in self.pool {
+                if pool.name.is_empty() {
+                    return Err("pool name is empty".to_string());
+                }
+                if pool.health_check_interval == Some(0) {
+                    return Err(format!(
+                        "invalid health_check_interval 0 for pool {}",
+                        pool.name
+                    ));
+                }
+                let mut settings = Pool::new(pool.name);
+                settings.strategy = pool.strategy.map(|s| s.parse()).transpose()?;
+                settings.protocol = pool.protocol.map(|p| p.parse()).transpose()?;
+                settings.health_check_path = pool.health_check_path;
+                settings.health_check_interval = pool
+                    .health_check_interval
+                    .map(std::time::Duration::from_secs);
+                options.pool.push(settings);
+            }
+        }
+        if !from_command_line(matches, "route") && !self.route.is_empty() {
+            options.route = Vec::new();
+            for route in self.route {
+                if route.host.is_none() && route.path_prefix.is_none() {
+                    return Err(format!(
+                        "route to pool {} needs a host, a path_prefix, or both",
+                        route.pool
+                    ));
+                }
+                let path_prefix = route.path_prefix.as_deref().unwrap_or("/");
+                options
+                    .route
+                    .push(Route::new(route.host.as_deref(), path_prefix, &route.pool)?);
+            }
+        }
+
+        if !from_command_line(matches, "method_rule") && !self.method_rule.is_empty() {
+            options.method_rule = Vec::new();
+            for rule in self.method_rule {
+                let (action, methods) = match (rule.allow, rule.deny) {
+                    (Some(methods), None) => (MethodAction::Allow, methods),
+                    (None, Some(methods)) => (MethodAction::Deny, methods),
+                    _ => {
+                        return Err(format!(
+                            "method rule for {} needs either allow or deny",
+                            rule.path_prefix.as_deref().unwrap_or("/")
+                        ))
+                    }
+                };
+                let methods: Vec<&str> = methods.iter().map(String::as_str).collect();
+                options.method_rule.push(MethodRule::new(
+                    action,
+                    &methods,
+                    rule.path_prefix.as_deref(),
+                )?);
+            }
+        }
+
+        // Likewise for route rate limits
+        if !from_command_line(matches, "route_rate_limit") && !self.rate_limit.route.is_empty() {
+            options.route_rate_limit = Vec::new();
+            for route in self.rate_limit.route {
+                if route.max_requests_per_minute == 0 {
+                    return Err(
+                        "route rate limit max_requests_per_minute must be above 0".to_string()
+                    );
+                }
+                if route.method.is_none() && route.path_prefix.is_none() {
+                    return Err(
+                        "route rate limit needs a method, a path_prefix, or both".to_string()
+                    );
+                }
+                let method = route
+                    .method
+                    .map(|method| {
+                        method
+                            .parse()
+                            .map_err(|_| format!("invalid route rate limit method \"{}\"", method))
+                    })
+                    .transpose()?;
+                options.route_rate_limit.push(RouteRule {
+                    method,
+                    path_prefix: route.path_prefix.unwrap_or_else(|| "/".to_string()),
+                    per_minute: route.max_requests_per_minute,
+                    burst: route.burst,
+                });
+            }
+        }
+
+        macro_rules! set {
+            ($id:ident, $value:expr) => {
+                if let Some(value) = $value {
+                    if !from_command_line(matches, stringify!($id)) {
+                        options.$id = value;
+                    }
+                }
+            };
+        }
+        set!(
+            bind,
+            self.listener
+                .bind
+                .map(|binds| {
+                    binds
+                        .into_vec()
+                        .iter()
+                        .map(|bind| bind.parse())
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .transpose()?
+        );
+        set!(
+            forwarded_header_style,
+            self.listener
+                .forwarded_header_style
+                .map(|style| style.parse())
+                .transpose()?
+        );
+        set!(
+            trusted_proxies,
+            self.listener
+                .trusted_proxies
+                .map(|blocks| {
+                    blocks
+                        .into_vec()
+                        .iter()
+                        .map(|block| block.parse())
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .transpose()?
+        );
+        set!(require_content_length, self.listener.require_content_length);
+        set!(max_body_size, self.listener.max_body_size);
+        set!(max_header_size, self.listener.max_header_size);
+        set!(max_headers, self.listener.max_headers);
+        set!(tls_cert, self.listener.tls_cert.map(Some));
+        set!(tls_key, self.listener.tls_key.map(Some));
+        set!(tls_client_ca, self.listener.tls_client_ca.map(Some));
+        set!(http2, self.listener.http2);
+        set!(max_connections, self.listener.max_connections);
+        set!(
+            connection_queue_timeout,
+            self.listener.connection_queue_timeout
+        );
+        set!(request_queue_size, self.listener.request_queue_size);
+        set!(request_queue_timeout, self.listener.request_queue_timeout);
+        set!(client_idle_timeout, self.listener.client_idle_timeout);
+        set!(upstream_tls_ca, self.upstream_tls_ca.map(Some));
+        set!(cache_size, self.cache.size);
+        set!(cache_ttl, self.cache.ttl);
+        set!(cache_max_entry_size, self.cache.max_entry_size);
+        set!(
+            request_header,
+            self.headers
+                .request
+                .map(|rules| rules.into_vec().iter().map(|rule| rule.parse()).collect())
+                .transpose()?
+        );
+        set!(
+            response_header,
+            self.headers
+                .response
+                .map(|rules| rules.into_vec().iter().map(|rule| rule.parse()).collect())
+                .transpose()?
+        );
+        set!(canary_pool, self.canary.pool.map(Some));
+        set!(canary_percent, self.canary.percent);
+        set!(canary_sticky, self.canary.sticky);
+        set!(mirror_pool, self.mirror.pool.map(Some));
+        set!(mirror_percent, self.mirror.percent);
+        set!(
+            rewrite_path,
+            self.rewrite
+                .path
+                .map(|rules| rules.into_vec().iter().map(|rule| rule.parse()).collect())
+                .transpose()?
+        );
+        set!(compress_gzip, self.compression.gzip);
+        set!(compress_deflate, self.compression.deflate);
+        set!(compress_min_size, self.compression.min_size);
+        set!(
+            compress_type,
+            self.compression.types.map(OneOrMany::into_vec)
+        );
+        set!(cors_origin, self.cors.origins.map(OneOrMany::into_vec));
+        set!(cors_methods, self.cors.methods);
+        set!(cors_headers, self.cors.headers.map(Some));
+        set!(cors_expose_headers, self.cors.expose_headers.map(Some));
+        set!(cors_credentials, self.cors.credentials);
+        set!(cors_max_age, self.cors.max_age);
+        set!(
+            auth,
+            self.auth
+                .rules
+                .map(|rules| rules.into_vec().iter().map(|rule| rule.parse()).collect())
+                .transpose()?
+        );
+        set!(basic_auth_file, self.auth.basic_auth_file.map(Some));
+        set!(auth_realm, self.auth.realm);
+        set!(jwt_secret, self.auth.jwt_secret.map(Some));
+        set!(jwt_jwks, self.auth.jwt_jwks.map(Some));
+        set!(jwt_issuer, self.auth.jwt_issuer.map(Some));
+        set!(jwt_audience, self.auth.jwt_audience.map(Some));
+        set!(maintenance, self.maintenance.enabled);
+        set!(
+            maintenance_path,
+            self.maintenance.paths.map(OneOrMany::into_vec)
+        );
+        set!(
+            maintenance_allow,
+            self.maintenance
+                .allow
+                .map(|blocks| {
+                    blocks
+                        .into_vec()
+                        .iter()
+                        .map(|block| block.parse())
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .transpose()?
+        );
+        set!(maintenance_page, self.maintenance.page.map(Some));
+        set!(
+            maintenance_retry_after,
+            self.maintenance.retry_after.map(Some)
+        );
+        set!(
+            error_page,
+            self.error_pages
+                .map(|pages| {
+                    pages
+                        .iter()
+                        .map(|(status, path)| ErrorPage::new(status, path))
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .transpose()?
+        );
+        set!(upstream_max_idle, self.upstream_pool.max_idle);
+        set!(upstream_idle_timeout, self.upstream_pool.idle_timeout);
+        set!(breaker_failures, self.circuit_breaker.failures);
+        set!(breaker_error_rate, self.circuit_breaker.error_rate);
+        set!(breaker_open_time, self.circuit_breaker.open_time);
+        set!(admin_bind, self.admin.bind.map(Some));
+        set!(access_log, self.logging.access_log.map(Some));
+        set!(
+            log_format,
+            self.logging
+                .format
+                .map(|format| format.parse())
+                .transpose()?
+        );
+        set!(
+            otlp_endpoint,
+            self.tracing
+                .otlp_endpoint
+                .map(|endpoint| endpoint.parse().map(Some))
+                .transpose()?
+        );
+        set!(mode, self.mode.map(|mode| mode.parse()).transpose()?);
+        set!(udp_session_timeout, self.udp_session_timeout);
+        set!(
+            strategy,
+            self.strategy.map(|strategy| strategy.parse()).transpose()?
+        );
+        set!(
+            upstream_protocol,
+            self.upstream_protocol
+                .map(|protocol| protocol.parse())
+                .transpose()?
+        );
+        set!(sticky_sessions, self.sticky_sessions);
+        set!(shutdown_timeout, self.shutdown_timeout);
+        set!(hedge_after, self.hedge_after);
+        set!(upstream_connect_timeout, self.upstream_connect_timeout);
+        set!(upstream_response_timeout, self.upstream_response_timeout);
+        set!(slow_start, self.slow_start);
+        set!(adaptive_weights, self.adaptive_weights);
+        set!(adaptive_min_share, self.adaptive_min_share);
+        set!(dns_refresh_interval, self.dns_refresh_interval);
+        set!(
+            discover,
+            self.discovery
+                .sources
+                .map(|sources| {
+                    sources
+                        .into_vec()
+                        .iter()
+                        .map(|source| source.parse())
+                        .collect::<Result<_, _>>()
+                })
+                .transpose()?
+        );
+        set!(discovery_interval, self.discovery.interval);
+        set!(active_health_check_interval, self.health_check.interval);
+        set!(health_check_jitter, self.health_check.jitter);
+        set!(active_health_check_path, self.health_check.path);
+        set!(
+            health_check_method,
+            self.health_check
+                .method
+                .map(|method| method
+                    .parse()
+                    .map_err(|_| format!("invalid method \"{}\"", method)))
+                .transpose()?
+        );
+        set!(
+            health_check_header,
+            self.health_check
+                .headers
+                .map(|headers| {
+                    headers
+                        .iter()
+                        .map(|(name, value)| crate::parse_header(name, value))
+                        .collect::<Result<_, _>>()
+                })
+                .transpose()?
+        );
+        set!(
+            health_check_request_body,
+            self.health_check.request_body.map(Some)
+        );
+        set!(health_check_timeout, self.health_check.timeout);
+        set!(
+            health_check_status,
+            self.health_check
+                .expected_status
+                .map(|status| status.parse())
+                .transpose()?
+        );
+        set!(health_check_body, self.health_check.expected_body.map(Some));
+        set!(
+            max_revivals_per_health_check,
+            self.health_check.max_revivals
+        );
+        set!(
+            passive_failure_threshold,
+            self.health_check.passive_failure_threshold
+        );
+        set!(
+            passive_success_threshold,
+            self.health_check.passive_success_threshold
+        );
+        set!(
+            max_requests_per_minute,
+            self.rate_limit.max_requests_per_minute
+        );
+        set!(rate_limit_burst, self.rate_limit.burst.map(Some));
+        set!(rate_limit_max_clients, self.rate_limit.max_clients);
+        set!(
+            rate_limit_algorithm,
+            self.rate_limit
+                .algorithm
+                .map(|algorithm| algorithm.parse())
+                .transpose()?
+        );
+        set!(
+            rate_limit_header,
+            self.rate_limit
+                .key_header
+                .map(|name| {
+                    name.parse()
+                        .map(Some)
+                        .map_err(|_| format!("invalid rate limit key_header \"{}\"", name))
+                })
+                .transpose()?
+        );
+        set!(rate_limit_json_errors, self.rate_limit.json_errors);
+        set!(client_bandwidth, self.rate_limit.client_bandwidth);
+        set!(
+            rate_limit_redis,
+            self.rate_limit
+                .redis
+                .map(|address| address.parse().map(Some))
+                .transpose()?
+        );
+        set!(
+            allow_client,
+            self.client_access
+                .allow
+                .map(|blocks| {
+                    blocks
+                        .into_vec()
+                        .iter()
+                        .map(|block| block.parse())
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .transpose()?
+        );
+        set!(
+            deny_client,
+            self.client_access
+                .deny
+                .map(|blocks| {
+                    blocks
+                        .into_vec()
+                        .iter()
+                        .map(|block| block.parse())
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .transpose()?
+        );
+        set!(
+            denied_client_action,
+            self.client_access
+                .action
+                .map(|action| action.parse())
+                .transpose()?
+        );
+        set!(
+            rate_limit_allow,
+            self.rate_limit
+                .allow
+                .map(|blocks| {
+                    blocks
+                        .into_vec()
+                        .iter()
+                        .map(|block| block.parse())
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .transpose()?
+        );
+        set!(
+            rate_limit_deny,
+            self.rate_limit
+                .deny
+                .map(|blocks| {
+                    blocks
+                        .into_vec()
+                        .iter()
+                        .map(|block| block.parse())
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .transpose()?
+        );
+        Ok(())
+    }
+}
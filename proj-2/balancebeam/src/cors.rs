@@ -0,0 +1,175 @@
+use http::header;
+
+/// Cross-origin resource sharing, handled here so that the upstreams don't each have to. With any
+/// --cors-origin given, preflight requests are answered without bothering an upstream, and
+/// responses to allowed origins get Access-Control-* headers.
+#[derive(Debug)]
+pub struct Settings {
+    /// Lowercase origins (e.g. https://app.example.com) whose pages may call us. `*` allows any
+    /// origin, and `https://*.example.com` any subdomain of example.com.
+    origins: Vec<String>,
+    /// Value for Access-Control-Allow-Methods
+    methods: http::HeaderValue,
+    /// Value for Access-Control-Allow-Headers, or None to allow whatever a preflight asks for
+    headers: Option<http::HeaderValue>,
+    /// Value for Access-Control-Expose-Headers
+    expose_headers: Option<http::HeaderValue>,
+    credentials: bool,
+    max_age: u64,
+}
+
+impl Settings {
+    pub fn new(
+        origins: &[String],
+        methods: &str,
+        headers: Option<&str>,
+        expose_headers: Option<&str>,
+        credentials: bool,
+        max_age: u64,
+    ) -> Result<Settings, String> {
+        let value = |name, value: &str| {
+            http::HeaderValue::from_str(value.trim())
+                .map_err(|_| format!("invalid {} \"{}\"", name, value))
+        };
+        Ok(Settings {
+            origins: origins
+                .iter()
+                .map(|origin| origin.trim().trim_end_matches('/').to_ascii_lowercase())
+                .collect(),
+            methods: value("--cors-methods", methods)?,
+            headers: headers
+                .map(|headers| value("--cors-headers", headers))
+                .transpose()?,
+            expose_headers: expose_headers
+                .map(|headers| value("--cors-expose-headers", headers))
+                .transpose()?,
+            credentials,
+            max_age,
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.origins.is_empty()
+    }
+
+    /// The request's Origin, if it's one we allow
+    fn allowed_origin<'a>(&self, request: &'a http::Request<Vec<u8>>) -> Option<&'a str> {
+        let origin = request.headers().get(header::ORIGIN)?.to_str().ok()?;
+        let lowercase = origin.to_ascii_lowercase();
+        let allowed = self.origins.iter().any(|allowed| {
+            if allowed == "*" || *allowed == lowercase {
+                return true;
+            }
+            match allowed.split_once("://*.") {
+                Some((scheme, domain)) => lowercase
+                    .strip_prefix(scheme)
+                    .and_then(|rest| rest.strip_prefix("://"))
+                    .and_then(|host| host.strip_suffix(domain))
+                    .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.')),
+                None => false,
+            }
+        });
+        if allowed {
+            Some(origin)
+        } else {
+            None
+        }
+    }
+
+    /// Adds Access-Control-Allow-Origin (and -Credentials) for an allowed origin
+    fn allow_origin(&self, origin: &str, headers: &mut http::HeaderMap) {
+        // A credentialed request can't be answered with a wildcard, so the origin is echoed back
+        let value = match self.origins.iter().any(|allowed| allowed == "*") && !self.credentials {
+            true => http::HeaderValue::from_static("*"),
+            false => match http::HeaderValue::from_str(origin) {
+                Ok(value) => value,
+                Err(_) => return,
+            },
+        };
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, value);
+        if self.credentials {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                http::HeaderValue::from_static("true"),
+            );
+        }
+    }
+
+    /// If the request is a CORS preflight, the response to send instead of forwarding it: a 204
+    /// saying what's allowed, or a 403 if the origin isn't allowed
+    pub fn preflight(&self, request: &http::Request<Vec<u8>>) -> Option<http::Response<Vec<u8>>> {
+        if !self.enabled()
+            || request.method() != http::Method::OPTIONS
+            || !request.headers().contains_key(header::ORIGIN)
+            || !request
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+        {
+            return None;
+        }
+        let origin = match self.allowed_origin(request) {
+            Some(origin) => origin,
+            None => {
+                log::debug!(
+                    "Refusing CORS preflight from origin {:?}",
+                    request.headers().get(header::ORIGIN).unwrap()
+                );
+                return Some(crate::response::make_http_error(
+                    http::StatusCode::FORBIDDEN,
+                ));
+            }
+        };
+        let mut response = http::Response::builder()
+            .status(http::StatusCode::NO_CONTENT)
+            .body(Vec::new())
+            .unwrap();
+        let headers = response.headers_mut();
+        self.allow_origin(origin, headers);
+        headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, self.methods.clone());
+        let allow_headers = self.headers.clone().or_else(|| {
+            request
+                .headers()
+                .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+                .cloned()
+        });
+        if let Some(allow_headers) = allow_headers {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+        }
+        headers.insert(header::ACCESS_CONTROL_MAX_AGE, self.max_age.into());
+        headers.insert(
+            header::VARY,
+            http::HeaderValue::from_static(
+                "Origin, Access-Control-Request-Method, Access-Control-Request-Headers",
+            ),
+        );
+        Some(response)
+    }
+
+    /// Adds Access-Control-* headers to a response if the request came from an allowed origin,
+    /// replacing any the upstream set
+    pub fn apply(&self, request: &http::Request<Vec<u8>>, headers: &mut http::HeaderMap) {
+        if !self.enabled() {
+            return;
+        }
+        // The headers depend on the origin, so caches mustn't hand them to another
+        let varies = headers
+            .get_all(header::VARY)
+            .iter()
+            .flat_map(|value| value.to_str().unwrap_or("").split(','))
+            .any(|name| name.trim() == "*" || name.trim().eq_ignore_ascii_case("origin"));
+        if !varies {
+            headers.append(header::VARY, http::HeaderValue::from_static("Origin"));
+        }
+        let origin = match self.allowed_origin(request) {
+            Some(origin) => origin,
+            None => return,
+        };
+        self.allow_origin(origin, headers);
+        if let Some(expose_headers) = &self.expose_headers {
+            headers.insert(
+                header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                expose_headers.clone(),
+            );
+        }
+    }
+}
@@ -0,0 +1,364 @@
+use crate::{dns, request, response, ProxyState, Tier, UpstreamState};
+use serde::Deserialize;
+use std::fmt;
+use std::net::SocketAddr;
+use tokio::io::BufReader;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::{delay_for, timeout, Duration};
+
+/// How long to wait for a DNS server or Consul agent to answer
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where to find upstreams, besides the ones given with --upstream
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    /// A DNS SRV record, e.g. `srv:_http._tcp.api.internal`, optionally asking a particular DNS
+    /// server (`srv:_http._tcp.api.internal@10.0.0.2:53`) rather than the first one in
+    /// /etc/resolv.conf. Targets with the lowest priority are primary upstreams, and the rest are
+    /// backups; each target's SRV weight becomes its weight.
+    Srv {
+        name: String,
+        server: Option<SocketAddr>,
+    },
+    /// The healthy instances of a service in a Consul catalog, e.g. `consul:127.0.0.1:8500/api`
+    Consul { agent: String, service: String },
+}
+
+impl std::str::FromStr for Source {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(rest) = s.strip_prefix("srv:") {
+            let (name, server) = match rest.split_once('@') {
+                Some((name, server)) => (
+                    name,
+                    Some(
+                        server
+                            .parse()
+                            .map_err(|_| format!("invalid DNS server \"{}\"", server))?,
+                    ),
+                ),
+                None => (rest, None),
+            };
+            if name.is_empty() {
+                return Err("SRV record name is empty".to_string());
+            }
+            return Ok(Source::Srv {
+                name: name.trim_end_matches('.').to_string(),
+                server,
+            });
+        }
+        if let Some(rest) = s.strip_prefix("consul:") {
+            return match rest.split_once('/') {
+                Some((agent, service)) if !agent.is_empty() && !service.is_empty() => {
+                    Ok(Source::Consul {
+                        agent: agent.to_string(),
+                        service: service.to_string(),
+                    })
+                }
+                _ => Err(format!(
+                    "Consul source \"{}\" should look like consul:<agent host:port>/<service>",
+                    s
+                )),
+            };
+        }
+        Err(format!(
+            "unknown discovery source \"{}\" (expected srv:<name> or consul:<agent>/<service>)",
+            s
+        ))
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Srv { name, server: None } => write!(f, "srv:{}", name),
+            Source::Srv {
+                name,
+                server: Some(server),
+            } => write!(f, "srv:{}@{}", name, server),
+            Source::Consul { agent, service } => write!(f, "consul:{}/{}", agent, service),
+        }
+    }
+}
+
+/// Looks up the upstreams a source currently lists, tagged with the source they came from. Targets
+/// given by DNS name are resolved to addresses (see dns::resolve_all).
+pub async fn discover(source: &Source) -> Result<Vec<UpstreamState>, String> {
+    let targets = match source {
+        Source::Srv { name, server } => lookup_srv(name, *server).await?,
+        Source::Consul { agent, service } => lookup_consul(agent, service).await?,
+    };
+    let upstreams = targets
+        .into_iter()
+        .map(|target| UpstreamState {
+            tier: target.tier,
+            discovered_by: Some(source.to_string()),
+            ..UpstreamState::new(target.addr, target.weight.max(1))
+        })
+        .collect();
+    Ok(dns::resolve_all(upstreams).await)
+}
+
+/// Looks up each source once, for startup. Sources that fail are logged and skipped; refresh tries
+/// them again later.
+pub async fn discover_all(sources: &[Source]) -> Vec<UpstreamState> {
+    let mut upstreams = Vec::new();
+    for source in sources {
+        match discover(source).await {
+            Ok(discovered) => {
+                log::info!(
+                    "Discovered {} upstream(s) from {}",
+                    discovered.len(),
+                    source
+                );
+                upstreams.extend(discovered);
+            }
+            Err(err) => log::error!("Could not discover upstreams from {}: {}", source, err),
+        }
+    }
+    upstreams
+}
+
+/// Looks up each source again every interval, adding and removing upstreams to match. Upstreams
+/// that are still listed keep their health and connection stats. If a lookup fails, the upstreams
+/// from that source are left alone until the next one.
+pub async fn refresh(state: &ProxyState, sources: &[Source], interval: Duration) {
+    loop {
+        delay_for(interval).await;
+        for source in sources {
+            let source_name = source.to_string();
+            match discover(source).await {
+                Ok(discovered) => {
+                    dns::replace_group(
+                        state,
+                        |upstream| upstream.discovered_by.as_deref() == Some(&source_name),
+                        &source_name,
+                        discovered,
+                    )
+                    .await
+                }
+                Err(err) => log::warn!("Could not discover upstreams from {}: {}", source, err),
+            }
+        }
+    }
+}
+
+/// An upstream listed by a discovery source
+struct Target {
+    /// host:port
+    addr: String,
+    weight: usize,
+    tier: Tier,
+}
+
+/// Fetches the healthy instances of a service from a Consul agent's health API
+async fn lookup_consul(agent: &str, service: &str) -> Result<Vec<Target>, String> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct Entry {
+        node: Node,
+        service: Service,
+    }
+    #[derive(Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct Node {
+        address: String,
+    }
+    #[derive(Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct Service {
+        #[serde(default)]
+        address: String,
+        port: u16,
+        weights: Option<Weights>,
+    }
+    #[derive(Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct Weights {
+        passing: usize,
+    }
+
+    let request = http::Request::builder()
+        .method(http::Method::GET)
+        .uri(format!("/v1/health/service/{}?passing=true", service))
+        .header("Host", agent)
+        .body(Vec::new())
+        .unwrap();
+    let fetch = async {
+        let mut conn = BufReader::new(TcpStream::connect(agent).await?);
+        request::write_to_stream(&request, &mut conn).await?;
+        response::read_from_stream(&mut conn, request.method())
+            .await
+            .map_err(|err| std::io::Error::other(err.to_string()))
+    };
+    let response = timeout(LOOKUP_TIMEOUT, fetch)
+        .await
+        .map_err(|_| "timed out".to_string())?
+        .map_err(|err| err.to_string())?;
+    if response.status() != http::StatusCode::OK {
+        return Err(format!("Consul answered {}", response.status()));
+    }
+    let entries: Vec<Entry> =
+        serde_json::from_slice(response.body()).map_err(|err| err.to_string())?;
+    Ok(entries
+        .into_iter()
+        .map(|entry| {
+            // Services registered without an address of their own are reached at their node's
+            let host = if entry.service.address.is_empty() {
+                entry.node.address
+            } else {
+                entry.service.address
+            };
+            let host = if host.contains(':') {
+                format!("[{}]", host)
+            } else {
+                host
+            };
+            Target {
+                addr: format!("{}:{}", host, entry.service.port),
+                weight: entry.service.weights.map_or(1, |weights| weights.passing),
+                tier: Tier::Primary,
+            }
+        })
+        .collect())
+}
+
+/// Returns the first nameserver in /etc/resolv.conf
+fn system_dns_server() -> Result<SocketAddr, String> {
+    let resolv_conf = std::fs::read_to_string("/etc/resolv.conf")
+        .map_err(|err| format!("Could not read /etc/resolv.conf: {}", err))?;
+    resolv_conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|server| server.trim().parse().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .next()
+        .ok_or_else(|| "no nameserver in /etc/resolv.conf".to_string())
+}
+
+/// DNS record type for SRV records (RFC 2782)
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+
+/// Looks up a DNS SRV record. (This is a bare-bones DNS client: one question, over UDP, with no
+/// retries.)
+async fn lookup_srv(name: &str, server: Option<SocketAddr>) -> Result<Vec<Target>, String> {
+    let server = match server {
+        Some(server) => server,
+        None => system_dns_server()?,
+    };
+    let id: u16 = rand::random();
+    let mut query = Vec::new();
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("invalid DNS name \"{}\"", name));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_SRV.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    let bind_addr = if server.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let mut socket = UdpSocket::bind(bind_addr)
+        .await
+        .map_err(|err| err.to_string())?;
+    socket
+        .connect(server)
+        .await
+        .map_err(|err| err.to_string())?;
+    socket.send(&query).await.map_err(|err| err.to_string())?;
+    let mut answer = vec![0; 4096];
+    let len = timeout(LOOKUP_TIMEOUT, socket.recv(&mut answer))
+        .await
+        .map_err(|_| format!("{} didn't answer", server))?
+        .map_err(|err| err.to_string())?;
+    answer.truncate(len);
+    parse_srv_answer(&answer, id).ok_or_else(|| format!("invalid answer from {}", server))
+}
+
+/// Parses the SRV records out of a DNS answer. Returns None if the answer is malformed or isn't a
+/// successful answer to our query.
+fn parse_srv_answer(answer: &[u8], id: u16) -> Option<Vec<Target>> {
+    let read_u16 = |pos: usize| -> Option<u16> {
+        Some(u16::from_be_bytes([
+            *answer.get(pos)?,
+            *answer.get(pos + 1)?,
+        ]))
+    };
+    let flags = read_u16(2)?;
+    // Must be a response (QR set) to our query, with no error (RCODE 0)
+    if read_u16(0)? != id || flags & 0x8000 == 0 || flags & 0x000f != 0 {
+        return None;
+    }
+    let questions = read_u16(4)?;
+    let answers = read_u16(6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(answer, pos)?.1 + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        pos = read_name(answer, pos)?.1;
+        let record_type = read_u16(pos)?;
+        let data_len = read_u16(pos + 8)? as usize;
+        let data = pos + 10;
+        if record_type == TYPE_SRV {
+            let priority = read_u16(data)?;
+            let weight = read_u16(data + 2)?;
+            let port = read_u16(data + 4)?;
+            let target = read_name(answer, data + 6)?.0;
+            records.push((priority, weight, format!("{}:{}", target, port)));
+        }
+        pos = data + data_len;
+    }
+    let best_priority = records.iter().map(|&(priority, _, _)| priority).min();
+    Some(
+        records
+            .into_iter()
+            .map(|(priority, weight, addr)| Target {
+                addr,
+                weight: weight as usize,
+                tier: if Some(priority) == best_priority {
+                    Tier::Primary
+                } else {
+                    Tier::Backup
+                },
+            })
+            .collect(),
+    )
+}
+
+/// Reads a (possibly compressed) domain name starting at pos, and returns it along with the
+/// position just past it
+fn read_name(message: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    // Where to carry on reading once the name is done, if we've followed a compression pointer
+    let mut end = None;
+    // Pointers can form loops, so give up after a reasonable number of them
+    for _ in 0..128 {
+        let len = *message.get(pos)? as usize;
+        if len == 0 {
+            return Some((labels.join("."), end.unwrap_or(pos + 1)));
+        }
+        if len & 0xc0 == 0xc0 {
+            let offset = ((len & 0x3f) << 8) | *message.get(pos + 1)? as usize;
+            end.get_or_insert(pos + 2);
+            pos = offset;
+            continue;
+        }
+        let label = message.get(pos + 1..pos + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        pos += 1 + len;
+    }
+    None
+}
@@ -0,0 +1,153 @@
+use crate::{rebuild_hash_ring, tls, ProxyState, UpstreamState};
+use std::net::SocketAddr;
+use tokio::time::{delay_for, Duration};
+
+/// Returns true if host_port names its host with a DNS name rather than an IP address (or a Unix
+/// socket)
+pub fn is_dns_name(host_port: &str) -> bool {
+    host_port.parse::<SocketAddr>().is_err() && tls::unix_socket_path(host_port).is_none()
+}
+
+/// Looks up the addresses an upstream given by DNS name currently resolves to, and returns an
+/// upstream (with the same settings) for each of them. Returns None if the upstream wasn't given by
+/// name, or if the lookup failed.
+async fn resolve(upstream: &UpstreamState) -> Option<Vec<UpstreamState>> {
+    let name = upstream.resolved_from.as_ref()?;
+    let host_port = tls::parse_upstream_address(name).0;
+    // Keep the tls:// prefix, if there is one
+    let scheme = &name[..name.len() - host_port.len()];
+    let mut addrs: Vec<SocketAddr> = match tokio::net::lookup_host(host_port).await {
+        Ok(addrs) => addrs.collect(),
+        Err(err) => {
+            log::warn!("Could not resolve upstream {}: {}", name, err);
+            return None;
+        }
+    };
+    addrs.sort_unstable();
+    addrs.dedup();
+    if addrs.is_empty() {
+        log::warn!("Upstream {} doesn't resolve to any addresses", name);
+        return None;
+    }
+    Some(
+        addrs
+            .into_iter()
+            .map(|addr| UpstreamState {
+                tls: upstream.tls.clone(),
+                draining: upstream.draining,
+                tier: upstream.tier,
+                pool: upstream.pool.clone(),
+                proxy_protocol: upstream.proxy_protocol,
+                health_check_interval: upstream.health_check_interval,
+                resolved_from: Some(name.clone()),
+                discovered_by: upstream.discovered_by.clone(),
+                ..UpstreamState::new(format!("{}{}", scheme, addr), upstream.weight)
+            })
+            .collect(),
+    )
+}
+
+/// Replaces each upstream given by DNS name with one upstream per address the name resolves to.
+/// Names that can't be resolved are kept as they are (connecting to them looks the name up each
+/// time), and refresh tries them again later.
+pub async fn resolve_all(upstreams: Vec<UpstreamState>) -> Vec<UpstreamState> {
+    let mut resolved = Vec::with_capacity(upstreams.len());
+    for upstream in upstreams {
+        match resolve(&upstream).await {
+            Some(addrs) => {
+                log::info!(
+                    "Resolved upstream {} to {}",
+                    upstream.addr,
+                    addrs
+                        .iter()
+                        .map(|resolved| resolved.addr.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                resolved.extend(addrs);
+            }
+            None => resolved.push(upstream),
+        }
+    }
+    resolved
+}
+
+/// Looks up the upstreams given by DNS name again every interval, adding and removing upstreams as
+/// their records change. Addresses that are still there keep their health and connection stats.
+pub async fn refresh(state: &ProxyState, interval: Duration) {
+    loop {
+        delay_for(interval).await;
+        // One upstream for each name, to copy settings from
+        let mut named: Vec<UpstreamState> = Vec::new();
+        for upstream in state.upstream_addresses.read().await.iter() {
+            if upstream.resolved_from.is_some()
+                && !named
+                    .iter()
+                    .any(|other| other.resolved_from == upstream.resolved_from)
+            {
+                named.push(upstream.clone());
+            }
+        }
+
+        for template in named {
+            if let Some(resolved) = resolve(&template).await {
+                let name = template.resolved_from.as_deref().unwrap_or_default();
+                replace_group(
+                    state,
+                    |upstream| upstream.resolved_from == template.resolved_from,
+                    name,
+                    resolved,
+                )
+                .await;
+            }
+        }
+    }
+}
+
+/// Replaces the upstreams in a group (those for which in_group returns true) with new_upstreams.
+/// Upstreams whose address is in both keep their health and connection stats, but take their
+/// weight, tier, and pool from new_upstreams. source names where the group came from, for logging.
+pub async fn replace_group(
+    state: &ProxyState,
+    in_group: impl Fn(&UpstreamState) -> bool,
+    source: &str,
+    new_upstreams: Vec<UpstreamState>,
+) {
+    let mut changed = false;
+    let mut w_upstream_addresses = state.upstream_addresses.write().await;
+    w_upstream_addresses.retain(|upstream| {
+        let keep = !in_group(upstream) || new_upstreams.iter().any(|new| new.addr == upstream.addr);
+        if !keep {
+            log::info!(
+                "Removing upstream {}, which {} no longer lists",
+                upstream.addr,
+                source
+            );
+            changed = true;
+        }
+        keep
+    });
+    for new in new_upstreams {
+        match w_upstream_addresses
+            .iter_mut()
+            .find(|upstream| upstream.addr == new.addr)
+        {
+            Some(existing) => {
+                changed |= existing.weight != new.weight
+                    || existing.tier != new.tier
+                    || existing.pool != new.pool;
+                existing.weight = new.weight;
+                existing.tier = new.tier;
+                existing.pool = new.pool;
+            }
+            None => {
+                log::info!("Adding upstream {}, which {} now lists", new.addr, source);
+                w_upstream_addresses.push(new);
+                changed = true;
+            }
+        }
+    }
+    if changed {
+        rebuild_hash_ring(state, &w_upstream_addresses);
+    }
+}
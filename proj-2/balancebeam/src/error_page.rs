@@ -0,0 +1,178 @@
+use crate::response;
+use std::str::FromStr;
+
+/// A file to send in place of the body of the errors we make ourselves (502s when no upstream
+/// answers, 429s for rate limited clients, and so on) with some status (--error-page). Written as
+/// `<status>=<path>`, where status is a code like 502 or a class like 5xx, e.g.
+/// `502=/etc/balancebeam/502.html`. Errors passed on from upstreams are left as they are.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorPage {
+    /// e.g. "502", or "5xx" for all of 500-599
+    pub status: String,
+    pub path: String,
+}
+
+impl ErrorPage {
+    pub fn new(status: &str, path: &str) -> Result<ErrorPage, String> {
+        let status = status.trim().to_ascii_lowercase();
+        let valid = match status.strip_suffix("xx") {
+            Some(class) => matches!(class, "4" | "5"),
+            None => status
+                .parse::<u16>()
+                .is_ok_and(|code| (400..600).contains(&code)),
+        };
+        if !valid {
+            return Err(format!(
+                "invalid error page status \"{}\" (expected a code from 400 to 599, 4xx, or 5xx)",
+                status
+            ));
+        }
+        let path = path.trim();
+        if path.is_empty() {
+            return Err(format!("error page for {} needs a file", status));
+        }
+        Ok(ErrorPage {
+            status,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl FromStr for ErrorPage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (status, path) = s.split_once('=').ok_or_else(|| {
+            format!(
+                "invalid error page \"{}\" (expected e.g. \"502=/etc/balancebeam/502.html\")",
+                s
+            )
+        })?;
+        ErrorPage::new(status, path)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Html,
+    Json,
+    Text,
+}
+
+impl Format {
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Html => "text/html; charset=utf-8",
+            Format::Json => "application/json",
+            Format::Text => "text/plain; charset=utf-8",
+        }
+    }
+
+    /// Makes a value safe to put in a template of this format
+    fn escape(self, value: &str) -> String {
+        match self {
+            Format::Html => value
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&quot;")
+                .replace('\'', "&#39;"),
+            // The template is expected to put placeholders inside strings
+            Format::Json => {
+                let quoted = serde_json::Value::from(value).to_string();
+                quoted[1..quoted.len() - 1].to_string()
+            }
+            Format::Text => value.to_string(),
+        }
+    }
+}
+
+/// A loaded --error-page (or --maintenance-page) file. `{status}`, `{reason}`, and `{request_id}` in
+/// it are replaced with the response's status code, its reason phrase, and the ID of the request
+/// it answers.
+#[derive(Debug)]
+pub struct Page {
+    format: Format,
+    body: String,
+}
+
+impl Page {
+    pub fn load(path: &str) -> Result<Page, String> {
+        let body = std::fs::read_to_string(path)
+            .map_err(|err| format!("couldn't read error page {}: {}", path, err))?;
+        // Go by the file's extension, like a web server would
+        let extension = std::path::Path::new(path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+        let format = match extension.as_deref() {
+            Some("html") | Some("htm") => Format::Html,
+            Some("json") => Format::Json,
+            _ => Format::Text,
+        };
+        Ok(Page { format, body })
+    }
+
+    /// Makes the page the body of a response, filled in for its status
+    pub fn render(&self, request_id: Option<&str>, response: &mut http::Response<Vec<u8>>) {
+        let format = self.format;
+        let status = response.status();
+        let body = self
+            .body
+            .replace("{status}", status.as_str())
+            .replace(
+                "{reason}",
+                &format.escape(status.canonical_reason().unwrap_or("")),
+            )
+            .replace("{request_id}", &format.escape(request_id.unwrap_or("")))
+            .into_bytes();
+        let headers = response.headers_mut();
+        headers.insert(
+            http::header::CONTENT_TYPE,
+            http::HeaderValue::from_static(format.content_type()),
+        );
+        headers.insert(http::header::CONTENT_LENGTH, body.len().into());
+        *response.body_mut() = body;
+    }
+}
+
+/// The --error-page pages, read in at startup, with the statuses they're for
+#[derive(Debug)]
+pub struct ErrorPages {
+    pages: Vec<(String, Page)>,
+}
+
+impl ErrorPages {
+    pub fn new(pages: &[ErrorPage]) -> Result<ErrorPages, String> {
+        let pages = pages
+            .iter()
+            .map(|page| Ok((page.status.clone(), Page::load(&page.path)?)))
+            .collect::<Result<_, String>>()?;
+        Ok(ErrorPages { pages })
+    }
+
+    /// The page for a status: one for its exact code if there is one, or else one for its class.
+    /// Later pages replace earlier ones for the same status.
+    fn page(&self, status: http::StatusCode) -> Option<&Page> {
+        let code = status.as_str();
+        let class = format!("{}xx", &code[..1]);
+        let find = |wanted: &str| {
+            self.pages
+                .iter()
+                .rev()
+                .find(|(status, _)| status == wanted)
+                .map(|(_, page)| page)
+        };
+        find(code).or_else(|| find(&class))
+    }
+
+    /// Replaces the body of an error we made with its page, if it has one
+    pub fn apply(&self, request_id: Option<&str>, response: &mut http::Response<Vec<u8>>) {
+        if self.pages.is_empty() || response.extensions().get::<response::Generated>().is_none() {
+            return;
+        }
+        if let Some(page) = self.page(response.status()) {
+            page.render(request_id, response);
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Number of points each upstream gets on the ring. More points spread keys more evenly across
+/// upstreams, at the cost of a bigger ring.
+const POINTS_PER_NODE: usize = 100;
+
+/// A consistent hash ring. Each node (upstream) is hashed onto many points around the ring, and a
+/// key belongs to the node owning the first point at or after the key's hash. When a node is added
+/// or removed, only the keys that belonged to it move; everything else stays where it was.
+pub struct HashRing {
+    /// (hash, node index) pairs, sorted by hash
+    points: Vec<(u64, usize)>,
+}
+
+pub fn hash<T: Hash>(value: &T) -> u64 {
+    // DefaultHasher::new() always uses the same keys, so hashes are stable across restarts
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl HashRing {
+    /// Builds a ring from (node index, node name) pairs. Nodes are placed according to their names,
+    /// so a node lands in the same place no matter which other nodes are present.
+    pub fn new<'a>(nodes: impl Iterator<Item = (usize, &'a str)>) -> HashRing {
+        let mut points = Vec::new();
+        for (idx, name) in nodes {
+            for point in 0..POINTS_PER_NODE {
+                points.push((hash(&(name, point)), idx));
+            }
+        }
+        points.sort_unstable();
+        HashRing { points }
+    }
+
+    /// Returns the index of the node that key belongs to, or None if the ring is empty
+    pub fn get(&self, key: &str) -> Option<usize> {
+        if self.points.is_empty() {
+            return None;
+        }
+        let key_hash = hash(&key);
+        let pos = match self
+            .points
+            .binary_search_by(|(point_hash, _)| point_hash.cmp(&key_hash))
+        {
+            Ok(pos) | Err(pos) => pos % self.points.len(),
+        };
+        Some(self.points[pos].1)
+    }
+}
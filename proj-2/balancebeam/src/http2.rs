@@ -0,0 +1,718 @@
+use crate::access_log;
+use crate::body::{self, Framing};
+use crate::compress::{self, Coding, Encoder};
+use crate::throttle::Pacer;
+use crate::tls::ClientStream;
+use crate::{
+    body_too_large, connect_for_request, finish_response_head, log_response, prepare_request,
+    read_cacheable_body, read_response_head, record_failure, record_response, release_upstream,
+    request, response, send_bodyless_request, send_request_head, tag_response, tls, until,
+    upstream_protocol, ActiveConnection, ClientInfo, InFlightRequest, ProxyState, UpstreamConn,
+    UpstreamProtocol,
+};
+use bytes::Bytes;
+use std::future::poll_fn;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::time::Instant;
+
+/// Headers that only mean something for a single HTTP/1.1 connection. HTTP/2 doesn't allow them,
+/// so they're dropped from responses before being sent to HTTP/2 clients.
+const CONNECTION_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Serves a client that negotiated HTTP/2. Each stream the client opens gets its own upstream
+/// connection, which we speak HTTP/1.1 to, so the client can have several requests in flight at
+/// once over its single connection.
+pub async fn handle_connection<S: ClientStream>(client_conn: S, state: &Arc<ProxyState>) {
+    let client = ClientInfo::new(&client_conn);
+    log::info!("HTTP/2 connection received from {}", client.addr.ip());
+    let mut connection = match h2::server::handshake(client_conn).await {
+        Ok(connection) => connection,
+        Err(err) => {
+            log::info!("HTTP/2 handshake with client failed: {}", err);
+            return;
+        }
+    };
+
+    let mut going_away = false;
+    while let Some(accepted) = connection.accept().await {
+        let (request, respond) = match accepted {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                log::info!("Error reading request from HTTP/2 client: {}", err);
+                return;
+            }
+        };
+        // If we're shutting down, tell the client not to open any more streams. The ones it
+        // already has open are still served, and accept() returns None once they're done.
+        if !going_away && state.shutting_down.load(Ordering::SeqCst) {
+            connection.graceful_shutdown();
+            going_away = true;
+        }
+        let state = Arc::clone(state);
+        let client = client.clone();
+        tokio::spawn(async move {
+            handle_stream(&state, &client, request, respond).await;
+        });
+    }
+    log::debug!("HTTP/2 client finished sending requests. Shutting down connection");
+}
+
+/// Proxies a single request from an HTTP/2 client. Bodies are passed along as they arrive, in both
+/// directions.
+async fn handle_stream(
+    state: &Arc<ProxyState>,
+    client: &ClientInfo,
+    request: http::Request<h2::RecvStream>,
+    respond: h2::server::SendResponse<Bytes>,
+) {
+    let _in_flight = InFlightRequest::new(state);
+    let (parts, request_body) = request.into_parts();
+    if !state.header_limits.allows(&parts.headers) {
+        let status = http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE;
+        send_in_memory(state, client, respond, response::make_http_error(status));
+        return;
+    }
+    let (mut request, request_framing) = match to_http1_request(parts, &request_body) {
+        Ok(request) => request,
+        Err(status) => {
+            send_in_memory(state, client, respond, response::make_http_error(status));
+            return;
+        }
+    };
+
+    let client = &client.for_request(state, request.headers());
+    let mut access = access_log::Entry::new(state, client, &request);
+    if body_too_large(state, request_framing) {
+        let response = response::make_http_error(http::StatusCode::PAYLOAD_TOO_LARGE);
+        access.set_response(&response);
+        send_in_memory(state, client, respond, response);
+        return;
+    }
+
+    if let Err(response) = prepare_request(state, client, &mut request).await {
+        access.set_response(&response);
+        send_in_memory(state, client, respond, response);
+        return;
+    }
+    let pacer = state
+        .throttles
+        .as_ref()
+        .map(|throttles| throttles.pacer(client.ip));
+
+    // Answer from the cache if we can, without bothering an upstream
+    let cached = match &state.cache {
+        Some(cache) if request_framing == Framing::Empty => cache.get(&mut request),
+        _ => None,
+    };
+    if let Some(mut response) = cached {
+        state
+            .compression
+            .compress_in_memory(&request, &mut response);
+        access.set_response(&response);
+        access.set_bytes(response.body().len() as u64);
+        if let Some(pacer) = &pacer {
+            pacer.take(response.body().len()).await;
+        }
+        send_in_memory(state, client, respond, response);
+        return;
+    }
+
+    // Upstreams that speak HTTP/2 themselves get the stream passed on as it is
+    let pool = state.router.route(&request);
+    if upstream_protocol(state, pool) == UpstreamProtocol::H2c {
+        let forwarded = forward_h2c(
+            state,
+            client,
+            pool,
+            &request,
+            request_body,
+            respond,
+            &mut access,
+            pacer.as_deref(),
+        );
+        forwarded.await;
+        return;
+    }
+
+    // Send the request upstream, body and all. As over HTTP/1.1, a request without a body is sent
+    // in one go, so that it can be retried if the upstream fails.
+    let mut upstream = None;
+    let exchanged = if request_framing == Framing::Empty {
+        send_bodyless_request(state, client, &mut upstream, &request).await
+    } else {
+        send_request(
+            state,
+            client,
+            &mut upstream,
+            &request,
+            request_body,
+            request_framing,
+        )
+        .await
+    };
+    access.set_upstream(
+        upstream
+            .as_ref()
+            .map(|(_, connection)| connection.addr.as_str()),
+    );
+    let (mut response, mut response_framing) = match exchanged {
+        Ok(response) => response,
+        Err(response) => {
+            access.set_response(&response);
+            send_in_memory(state, client, respond, response);
+            return;
+        }
+    };
+    let (upstream_conn, _active_connection) = upstream.as_mut().unwrap();
+    let reusable = response_framing != Framing::UntilClose
+        && !request::has_connection_option(response.headers(), "close");
+
+    // As over HTTP/1.1, a response that can be cached is read in full and stored before the client
+    // gets it
+    let cached_body = read_cacheable_body(
+        state,
+        &request,
+        &mut response,
+        &mut response_framing,
+        upstream_conn,
+    );
+    match cached_body.await {
+        Ok(Some(body)) => {
+            *response.body_mut() = body;
+            state
+                .compression
+                .compress_in_memory(&request, &mut response);
+            if let Some(pacer) = &pacer {
+                pacer.take(response.body().len()).await;
+            }
+            access.set_bytes(response.body().len() as u64);
+            access.set_response(&response);
+            send_in_memory(state, client, respond, response);
+            if reusable {
+                release_upstream(state, upstream);
+            }
+            return;
+        }
+        Ok(None) => {}
+        Err(response) => {
+            access.set_response(&response);
+            send_in_memory(state, client, respond, response);
+            return;
+        }
+    }
+
+    // Pass the response back, compressing it on the way if the client accepts that
+    let coding = state
+        .compression
+        .choose(&request, &response, response_framing);
+    if let Some(coding) = coding {
+        compress::encode_streamed(response.headers_mut(), coding);
+    }
+    tag_response(client, response.headers_mut());
+    log_response(client, &response, " (HTTP/2)");
+    access.set_response(&response);
+    let sent = send_response(
+        respond,
+        response,
+        response_framing,
+        upstream_conn,
+        coding,
+        pacer.as_deref(),
+    );
+    match sent.await {
+        Ok(Some(sent)) => {
+            access.set_bytes(sent);
+            // The upstream connection is between requests again, so someone else can use it
+            if reusable {
+                release_upstream(state, upstream);
+            }
+        }
+        Ok(None) => {}
+        Err(err) => log::warn!("Failed to send response to HTTP/2 client: {}", err),
+    }
+}
+
+/// Passes a request from an HTTP/2 client to an upstream that speaks cleartext HTTP/2 (see
+/// UpstreamProtocol::H2c), such as a gRPC server. Each stream gets an upstream connection of its
+/// own, so the RPCs a client multiplexes over one connection are balanced one by one rather than all
+/// going wherever the connection did. Frames are relayed in both directions as they arrive, so
+/// streaming RPCs work, and so do trailers (where gRPC puts its status).
+#[allow(clippy::too_many_arguments)]
+async fn forward_h2c(
+    state: &Arc<ProxyState>,
+    client: &ClientInfo,
+    pool: Option<&str>,
+    request: &http::Request<Vec<u8>>,
+    request_body: h2::RecvStream,
+    respond: h2::server::SendResponse<Bytes>,
+    access: &mut access_log::Entry,
+    pacer: Option<&Pacer>,
+) {
+    let fail = |access: &mut access_log::Entry, respond, status| {
+        let response = response::make_http_error(status);
+        access.set_response(&response);
+        send_in_memory(state, client, respond, response);
+    };
+    let (upstream_conn, active_connection) =
+        match connect_for_request(state, client, request, pool, None).await {
+            Ok(upstream) => upstream,
+            Err(response) => {
+                access.set_response(&response);
+                send_in_memory(state, client, respond, response);
+                return;
+            }
+        };
+    let addr = &active_connection.addr;
+    access.set_upstream(Some(addr));
+    log::info!(
+        "{} -> {}: {} (HTTP/2)",
+        client,
+        addr,
+        request::format_request_line(request)
+    );
+    let upstream_request = match to_h2c_request(request, addr) {
+        Ok(upstream_request) => upstream_request,
+        Err(_) => return fail(access, respond, http::StatusCode::BAD_REQUEST),
+    };
+
+    let request_sent = Instant::now();
+    let deadline = state
+        .upstream_response_timeout
+        .map(|timeout| request_sent + timeout);
+    let exchange = async {
+        let (sender, connection) = h2::client::handshake(upstream_conn).await?;
+        tokio::spawn(async move {
+            if let Err(err) = connection.await {
+                log::debug!("HTTP/2 connection to upstream failed: {}", err);
+            }
+        });
+        let end_of_stream = request_body.is_end_stream();
+        let (response, body) = sender
+            .ready()
+            .await?
+            .send_request(upstream_request, end_of_stream)?;
+        // The upstream may start answering before the client is done sending (e.g. in a
+        // bidirectional streaming RPC), so the body is passed on alongside waiting for that
+        if !end_of_stream {
+            tokio::spawn(relay_request_body(request_body, body, state.max_body_size));
+        }
+        response.await
+    };
+    let (parts, mut body) = match until(deadline, exchange).await {
+        Some(Ok(response)) => response.into_parts(),
+        Some(Err(err)) => {
+            log::error!(
+                "Error getting a response from {} to {}: {}",
+                addr,
+                request::format_request_line(request),
+                err
+            );
+            active_connection.record_outcome(false);
+            record_failure(state, addr, &active_connection.passive_health).await;
+            return fail(access, respond, http::StatusCode::BAD_GATEWAY);
+        }
+        None => {
+            log::error!(
+                "Timed out waiting for {} to answer {}",
+                addr,
+                request::format_request_line(request)
+            );
+            active_connection.record_outcome(false);
+            return fail(access, respond, http::StatusCode::GATEWAY_TIMEOUT);
+        }
+    };
+    record_response(state, &active_connection, parts.status, request_sent).await;
+
+    let mut response = http::Response::from_parts(parts, Vec::new());
+    finish_response_head(state, &active_connection, request, response.headers_mut());
+    tag_response(client, response.headers_mut());
+    log_response(client, &response, " (HTTP/2)");
+    access.set_response(&response);
+    match relay_response(respond, response, &mut body, pacer).await {
+        Ok(Some(sent)) => access.set_bytes(sent),
+        Ok(None) => {}
+        Err(err) => log::warn!("Failed to send response to HTTP/2 client: {}", err),
+    }
+}
+
+/// Builds the head of the HTTP/2 request to send an h2c upstream from a request as we'd send it over
+/// HTTP/1.1. The Host header becomes the :authority (or if there isn't one, the upstream's address
+/// does).
+fn to_h2c_request(
+    request: &http::Request<Vec<u8>>,
+    upstream_addr: &str,
+) -> Result<http::Request<()>, http::Error> {
+    let authority = request
+        .headers()
+        .get(http::header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or(upstream_addr);
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |path| path.as_str());
+    let uri = http::Uri::builder()
+        .scheme("http")
+        .authority(authority)
+        .path_and_query(path)
+        .build()?;
+    let mut upstream_request = http::Request::builder()
+        .method(request.method())
+        .uri(uri)
+        .version(http::Version::HTTP_2)
+        .body(())?;
+    let headers = upstream_request.headers_mut();
+    *headers = request.headers().clone();
+    headers.remove(http::header::HOST);
+    for name in &CONNECTION_HEADERS {
+        headers.remove(*name);
+    }
+    Ok(upstream_request)
+}
+
+/// Passes a client's request body (and trailers) on to an h2c upstream as it arrives, resetting the
+/// upstream's stream if the client's fails or the body turns out to be longer than max_size
+async fn relay_request_body(
+    mut body: h2::RecvStream,
+    mut upstream: h2::SendStream<Bytes>,
+    max_size: Option<u64>,
+) {
+    let relayed: Result<(), String> = async {
+        let mut sent = 0;
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(|err| format!("client failed: {}", err))?;
+            sent += chunk.len() as u64;
+            if max_size.is_some_and(|max_size| sent > max_size) {
+                return Err("body too large".to_string());
+            }
+            let len = chunk.len();
+            let sent = send_data(&mut upstream, chunk).await;
+            if !sent.map_err(|err| format!("upstream failed: {}", err))? {
+                return Err("upstream reset the stream".to_string());
+            }
+            // Let the client send more
+            let _ = body.flow_control().release_capacity(len);
+        }
+        let trailers = body
+            .trailers()
+            .await
+            .map_err(|err| format!("client failed: {}", err))?;
+        match trailers {
+            Some(trailers) => upstream.send_trailers(trailers),
+            None => upstream.send_data(Bytes::new(), true),
+        }
+        .map_err(|err| format!("upstream failed: {}", err))
+    }
+    .await;
+    if let Err(err) = relayed {
+        log::info!("Error passing HTTP/2 request body upstream: {}", err);
+        upstream.send_reset(h2::Reason::CANCEL);
+    }
+}
+
+/// Sends a response head from an h2c upstream to the client, then passes the body (and trailers)
+/// on as they arrive, paced by pacer if given. Returns the size of the body, or None if the stream
+/// had to be abandoned partway through it.
+async fn relay_response(
+    mut respond: h2::server::SendResponse<Bytes>,
+    response: http::Response<Vec<u8>>,
+    body: &mut h2::RecvStream,
+    pacer: Option<&Pacer>,
+) -> Result<Option<u64>, h2::Error> {
+    let end_of_stream = body.is_end_stream();
+    let mut stream = respond.send_response(to_http2_response(response), end_of_stream)?;
+    if end_of_stream {
+        return Ok(Some(0));
+    }
+    let mut sent = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                // The client already has the response's headers, so all we can do is abort the
+                // stream, passing on why if the upstream said
+                log::warn!("Error reading response body from upstream: {}", err);
+                stream.send_reset(err.reason().unwrap_or(h2::Reason::INTERNAL_ERROR));
+                return Ok(None);
+            }
+        };
+        let len = chunk.len();
+        sent += len as u64;
+        if let Some(pacer) = pacer {
+            pacer.take(len).await;
+        }
+        if !send_data(&mut stream, chunk).await? {
+            // The client reset the stream. Dropping body resets the upstream's, too.
+            return Ok(None);
+        }
+        let _ = body.flow_control().release_capacity(len);
+    }
+    match body.trailers().await {
+        Ok(Some(trailers)) => stream.send_trailers(trailers)?,
+        Ok(None) => stream.send_data(Bytes::new(), true)?,
+        Err(err) => {
+            log::warn!("Error reading response trailers from upstream: {}", err);
+            stream.send_reset(err.reason().unwrap_or(h2::Reason::INTERNAL_ERROR));
+            return Ok(None);
+        }
+    }
+    Ok(Some(sent))
+}
+
+/// Sends an active health check request to an upstream that speaks cleartext HTTP/2, over conn, and
+/// returns its response, body and all
+pub async fn probe_h2c(
+    conn: tls::UpstreamStream,
+    request: &http::Request<Vec<u8>>,
+    upstream_addr: &str,
+) -> Result<http::Response<Vec<u8>>, h2::Error> {
+    let (sender, connection) = h2::client::handshake(conn).await?;
+    tokio::spawn(async move {
+        let _ = connection.await;
+    });
+    let head = to_h2c_request(request, upstream_addr).map_err(|_| h2::Reason::INTERNAL_ERROR)?;
+    let end_of_stream = request.body().is_empty();
+    let (response, mut stream) = sender.ready().await?.send_request(head, end_of_stream)?;
+    if !end_of_stream {
+        stream.send_data(Bytes::from(request.body().clone()), true)?;
+    }
+    let (parts, mut body) = response.await?.into_parts();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        let _ = body.flow_control().release_capacity(chunk.len());
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(http::Response::from_parts(parts, bytes))
+}
+
+/// Turns the head of an HTTP/2 request into its HTTP/1.1 equivalent to send upstream, and works
+/// out how to frame its body for HTTP/1.1
+fn to_http1_request(
+    mut parts: http::request::Parts,
+    body: &h2::RecvStream,
+) -> Result<(http::Request<Vec<u8>>, Framing), http::StatusCode> {
+    // HTTP/2 puts the host in the :authority pseudo-header rather than in Host, and the request
+    // line has to be in origin form ("/path?query")
+    if let Some(authority) = parts.uri.authority() {
+        if !parts.headers.contains_key(http::header::HOST) {
+            let host = http::HeaderValue::from_str(authority.as_str())
+                .map_err(|_| http::StatusCode::BAD_REQUEST)?;
+            parts.headers.insert(http::header::HOST, host);
+        }
+    }
+    let path = parts
+        .uri
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    parts.uri = path.parse().map_err(|_| http::StatusCode::BAD_REQUEST)?;
+    parts.version = http::Version::HTTP_11;
+    // HTTP/2 clients may only send "TE: trailers". Trailers are passed back to them, so that stays
+    // true, and some upstreams (e.g. gRPC servers) insist on seeing it.
+    // We can't send HTTP/2 clients interim responses, so we can't pass on the upstream's answer to
+    // Expect: 100-continue. The client sends its body regardless once it gives up waiting, so
+    // don't make the upstream wait for it either.
+    parts.headers.remove(http::header::EXPECT);
+
+    // HTTP/2 frames the body itself, so the client doesn't have to send a Content-Length. If it
+    // didn't, and there's a body coming, we don't know how long it is until it's over, so it goes
+    // upstream chunked.
+    let framing = match parts.headers.get(http::header::CONTENT_LENGTH) {
+        Some(value) => match value.to_str().ok().and_then(|value| value.parse().ok()) {
+            Some(0) => Framing::Empty,
+            Some(length) => Framing::Length(length),
+            None => return Err(http::StatusCode::BAD_REQUEST),
+        },
+        None if body.is_end_stream() => Framing::Empty,
+        None => {
+            parts.headers.insert(
+                http::header::TRANSFER_ENCODING,
+                http::HeaderValue::from_static("chunked"),
+            );
+            Framing::Chunked
+        }
+    };
+    Ok((http::Request::from_parts(parts, Vec::new()), framing))
+}
+
+/// Passes an HTTP/2 request's body (and trailers) upstream as it arrives. Returns the status to send
+/// the client if that fails, including if the body turns out to be longer than max_size.
+async fn send_request_body(
+    body: &mut h2::RecvStream,
+    upstream_conn: &mut UpstreamConn,
+    framing: Framing,
+    max_size: Option<u64>,
+) -> Result<(), http::StatusCode> {
+    let mut writer = body::Writer::new(framing);
+    let mut sent = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| http::StatusCode::BAD_REQUEST)?;
+        sent += chunk.len() as u64;
+        if max_size.is_some_and(|max_size| sent > max_size) {
+            return Err(http::StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        // Let the client send more
+        let _ = body.flow_control().release_capacity(chunk.len());
+        writer
+            .write(upstream_conn, &chunk)
+            .await
+            .map_err(|_| http::StatusCode::BAD_GATEWAY)?;
+    }
+    let trailers = body
+        .trailers()
+        .await
+        .map_err(|_| http::StatusCode::BAD_REQUEST)?
+        .unwrap_or_default();
+    writer
+        .finish(upstream_conn, &trailers)
+        .await
+        .map_err(|_| http::StatusCode::BAD_GATEWAY)
+}
+
+/// Sends a request with a body upstream, and reads the head of the response
+async fn send_request(
+    state: &Arc<ProxyState>,
+    client: &ClientInfo,
+    upstream: &mut Option<(UpstreamConn, ActiveConnection)>,
+    request: &http::Request<Vec<u8>>,
+    mut request_body: h2::RecvStream,
+    request_framing: Framing,
+) -> Result<(http::Response<Vec<u8>>, Framing), http::Response<Vec<u8>>> {
+    send_request_head(state, client, upstream, request, None).await?;
+    let (upstream_conn, active_connection) = upstream.as_mut().unwrap();
+    let max_body_size = state.max_body_size;
+    let sent = send_request_body(
+        &mut request_body,
+        upstream_conn,
+        request_framing,
+        max_body_size,
+    );
+    if let Err(status) = sent.await {
+        return Err(response::make_http_error(status));
+    }
+    read_response_head(
+        state,
+        client,
+        upstream_conn,
+        active_connection,
+        request,
+        false,
+    )
+    .await
+}
+
+/// Sends a response head to the client, then passes the body (and trailers) on from the upstream as
+/// it arrives (compressed with coding and paced by pacer, if given). Returns the size of the body as
+/// sent, or None if the stream had to be abandoned partway through it.
+async fn send_response(
+    mut respond: h2::server::SendResponse<Bytes>,
+    response: http::Response<Vec<u8>>,
+    framing: Framing,
+    upstream_conn: &mut UpstreamConn,
+    coding: Option<Coding>,
+    pacer: Option<&Pacer>,
+) -> Result<Option<u64>, h2::Error> {
+    let end_of_stream = framing == Framing::Empty;
+    let mut stream = respond.send_response(to_http2_response(response), end_of_stream)?;
+    if end_of_stream {
+        return Ok(Some(0));
+    }
+    let mut reader = body::Reader::new(framing);
+    let mut encoder = coding.map(Encoder::new);
+    let mut buffer = vec![0_u8; body::COPY_BUFFER_SIZE];
+    let mut sent = 0;
+    loop {
+        let bytes_read = match reader.read(upstream_conn, &mut buffer).await {
+            Ok(bytes_read) => bytes_read,
+            Err(err) => {
+                // The client already has the response's headers, so all we can do is abort the
+                // stream
+                log::warn!("Error reading response body from upstream: {:?}", err);
+                stream.send_reset(h2::Reason::INTERNAL_ERROR);
+                return Ok(None);
+            }
+        };
+        let data = match &mut encoder {
+            Some(encoder) if bytes_read == 0 => Bytes::from(encoder.finish()),
+            Some(encoder) => Bytes::from(encoder.write(&buffer[..bytes_read])),
+            None => Bytes::copy_from_slice(&buffer[..bytes_read]),
+        };
+        sent += data.len() as u64;
+        if let Some(pacer) = pacer {
+            pacer.take(data.len()).await;
+        }
+        if !send_data(&mut stream, data).await? {
+            // The client reset the stream
+            return Ok(None);
+        }
+        if bytes_read == 0 {
+            let trailers = reader.trailers();
+            if trailers.is_empty() {
+                stream.send_data(Bytes::new(), true)?;
+            } else {
+                stream.send_trailers(trailers.clone())?;
+            }
+            return Ok(Some(sent));
+        }
+    }
+}
+
+/// Sends data on a stream (without ending it), a flow control window's worth at a time, so that we
+/// hold on to no more than a buffer's worth of a body at a time. Returns false if the other end
+/// reset the stream first.
+async fn send_data(stream: &mut h2::SendStream<Bytes>, mut data: Bytes) -> Result<bool, h2::Error> {
+    while !data.is_empty() {
+        stream.reserve_capacity(data.len());
+        let capacity = match poll_fn(|cx| stream.poll_capacity(cx)).await {
+            Some(capacity) => capacity?,
+            None => return Ok(false),
+        };
+        if capacity > 0 {
+            stream.send_data(data.split_to(capacity.min(data.len())), false)?;
+        }
+    }
+    Ok(true)
+}
+
+/// Sends a response whose body is all in memory: one we made ourselves (an error), or one from the
+/// cache
+fn send_in_memory(
+    state: &ProxyState,
+    client: &ClientInfo,
+    mut respond: h2::server::SendResponse<Bytes>,
+    mut response: http::Response<Vec<u8>>,
+) {
+    state
+        .error_pages
+        .apply(client.request_id.as_deref(), &mut response);
+    tag_response(client, response.headers_mut());
+    log_response(client, &response, " (HTTP/2)");
+    let body = Bytes::from(response.body().clone());
+    let sent = respond
+        .send_response(to_http2_response(response), body.is_empty())
+        .and_then(|mut stream| match body.is_empty() {
+            true => Ok(()),
+            false => stream.send_data(body, true),
+        });
+    if let Err(err) = sent {
+        log::warn!("Failed to send response to HTTP/2 client: {}", err);
+    }
+}
+
+/// Strips a response down to a head that can be sent over HTTP/2
+fn to_http2_response(response: http::Response<Vec<u8>>) -> http::Response<()> {
+    let (mut parts, _body) = response.into_parts();
+    for name in &CONNECTION_HEADERS {
+        parts.headers.remove(*name);
+    }
+    parts.version = http::Version::HTTP_2;
+    http::Response::from_parts(parts, ())
+}
@@ -0,0 +1,122 @@
+use serde::Serialize;
+use std::io::Write;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The log target that request events (see Event) are logged under
+pub const REQUEST_TARGET: &str = "balancebeam::request";
+
+/// Set once at startup if we're logging JSON
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// How log lines are written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// Human-readable lines, colored if the output is a terminal
+    Text,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!(
+                "unknown log format \"{}\" (expected text or json)",
+                other
+            )),
+        }
+    }
+}
+
+/// Sets up the log macros to write in the given format. RUST_LOG picks which messages are shown
+/// (debug and up, if it isn't set).
+pub fn init(format: LogFormat) {
+    if std::env::var("RUST_LOG").is_err() {
+        std::env::set_var("RUST_LOG", "debug");
+    }
+    match format {
+        LogFormat::Text => pretty_env_logger::init(),
+        LogFormat::Json => {
+            JSON.store(true, Ordering::SeqCst);
+            env_logger::Builder::from_default_env()
+                .format(|buf, record| {
+                    // Request events are JSON already
+                    if record.target() == REQUEST_TARGET {
+                        return writeln!(buf, "{}", record.args());
+                    }
+                    let message = record.args().to_string();
+                    let line = Line::new(record.level(), record.target(), Message { message });
+                    writeln!(buf, "{}", serde_json::to_string(&line).unwrap())
+                })
+                .init();
+        }
+    }
+}
+
+/// Returns true if we're logging JSON. In that case, each request is logged as a single Event once
+/// it's done, rather than as lines of text along the way.
+pub fn json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// A line of JSON output: fields, plus what every line has
+#[derive(Serialize)]
+struct Line<'a, T> {
+    timestamp: String,
+    level: &'static str,
+    target: &'a str,
+    #[serde(flatten)]
+    fields: T,
+}
+
+impl<'a, T> Line<'a, T> {
+    fn new(level: log::Level, target: &'a str, fields: T) -> Line<'a, T> {
+        Line {
+            timestamp: chrono::DateTime::<chrono::Utc>::from(std::time::SystemTime::now())
+                .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            level: level.as_str(),
+            target,
+            fields,
+        }
+    }
+}
+
+/// The fields of a line logged with the log macros
+#[derive(Serialize)]
+struct Message {
+    message: String,
+}
+
+/// A request we've finished with, as logged in JSON
+#[derive(Serialize)]
+pub struct Event<'a> {
+    pub client_ip: IpAddr,
+    pub upstream: Option<&'a str>,
+    pub method: &'a str,
+    pub path: &'a str,
+    /// None if the client never got a response
+    pub status: Option<u16>,
+    pub latency_ms: f64,
+    pub request_id: &'a str,
+}
+
+impl Event<'_> {
+    /// Logs the event at info level (if we're logging JSON)
+    pub fn log(&self) {
+        if !json() || !log::log_enabled!(target: REQUEST_TARGET, log::Level::Info) {
+            return;
+        }
+        let line = Line::new(log::Level::Info, REQUEST_TARGET, self);
+        log::info!(
+            target: REQUEST_TARGET,
+            "{}",
+            serde_json::to_string(&line).unwrap()
+        );
+    }
+}
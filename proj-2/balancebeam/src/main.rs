@@ -1,31 +1,810 @@
+mod access_log;
+mod acl;
+mod adaptive;
+mod admin;
+mod auth;
+mod body;
+mod breaker;
+mod cache;
+mod cidr;
+mod compress;
+mod config;
+mod cors;
+mod discovery;
+mod dns;
+mod error_page;
+mod hash_ring;
+mod http2;
+mod logging;
+mod maintenance;
+mod metrics;
+mod path;
+mod pool;
+mod proxy_protocol;
+mod rate_limit;
+mod redis;
 mod request;
 mod response;
+mod rewrite;
+mod routing;
+mod systemd;
+mod throttle;
+mod tls;
+mod trace;
+mod udp;
+mod upgrade;
 
-use clap::Parser;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
+use hash_ring::HashRing;
+use parking_lot::Mutex;
 use rand::{Rng, SeedableRng};
 use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::task::Poll;
+use tls::ClientStream;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::RwLock;
 use tokio::time::{delay_for, Duration, Instant};
 
 #[derive(Debug, Clone)]
 struct UpstreamState {
+    /// The address as given, e.g. `10.0.0.1:8080`, or `tls://10.0.0.1:443` for an upstream we speak
+    /// TLS to
     addr: String,
+    /// TLS settings, for `tls://` upstreams
+    tls: Option<tls::UpstreamTls>,
+    /// If the upstream was given by DNS name, that name (e.g. `api.internal:8080`). addr is one of
+    /// the addresses it resolved to, or the name itself if it hasn't been resolved yet.
+    resolved_from: Option<String>,
+    /// The --discover source this upstream was found through, if it wasn't configured directly.
+    /// Such upstreams are added and removed by discovery::refresh, and left alone by reloads.
+    discovered_by: Option<String>,
     is_dead: bool,
+    /// A draining upstream gets no new requests, but the ones it's already handling are allowed to
+    /// finish. This lets a backend be taken out of rotation for a deploy without any errors.
+    /// Health checks don't change it; it's only set and cleared by the operator.
+    draining: bool,
+    /// Relative share of randomly-selected connections this upstream should get
+    weight: usize,
+    /// Backup upstreams only get requests when no primary upstream can take them
+    tier: Tier,
+    /// The pool the upstream is in, if any (see routing::Router). Upstreams in a pool only get the
+    /// requests routed to it; the rest get everything else.
+    pool: Option<String>,
+    /// If set, each connection to this upstream starts with a PROXY protocol header saying which
+    /// client it's for. Such connections aren't shared between clients.
+    proxy_protocol: Option<proxy_protocol::Version>,
+    /// Number of requests currently being proxied to this upstream (each over its own upstream
+    /// connection, so this is also the number of connections in use). It's shared (rather than
+    /// guarded by the upstream_addresses lock) so that ActiveConnection can decrement it when a
+    /// connection ends.
+    active_connections: Arc<AtomicUsize>,
+    /// Most requests to proxy to this upstream at once, if there's a limit. While it has this
+    /// many, it's passed over as if it were down, so that a small backend isn't overwhelmed.
+    max_requests: Option<usize>,
+    /// Recent response times, for the least-latency strategy
+    latency: Arc<Mutex<LatencyStats>>,
+    /// Recent share of failed requests, for --adaptive-weights
+    error_rate: Arc<Mutex<adaptive::ErrorRate>>,
+    /// Request counts and response times, for /metrics
+    metrics: Arc<metrics::UpstreamMetrics>,
+    /// Trips when too many requests fail (see --breaker-failures), to keep requests away until the
+    /// upstream recovers
+    breaker: Arc<Mutex<breaker::CircuitBreaker>>,
+    /// Recent request outcomes, for passive health checks
+    passive_health: Arc<Mutex<PassiveHealth>>,
+    /// How often to actively health check this upstream, if not every
+    /// --active-health-check-interval
+    health_check_interval: Option<Duration>,
+    /// When the upstream last came back after being marked dead, so that its share of requests can
+    /// be ramped up over --slow-start rather than all arriving at once
+    revived_at: Option<Instant>,
 }
 
-fn parse_upstream_state(s: &str) -> UpstreamState {
-    UpstreamState {
-        addr: s.to_string(),
-        is_dead: false,
+/// Counts an upstream's consecutive failed and successful requests, so that passive health checks
+/// only mark it dead (or alive again) once enough of them happen in a row
+#[derive(Debug, Default)]
+struct PassiveHealth {
+    consecutive_failures: usize,
+    consecutive_successes: usize,
+}
+
+/// How much weight each new response time gets in an upstream's moving average
+const LATENCY_EWMA_ALPHA: f64 = 0.3;
+/// How quickly an upstream's average response time decays towards zero while it isn't getting any
+/// traffic. Without this, an upstream that was slow once would rarely be picked again, and so would
+/// rarely get the chance to show it has recovered.
+const LATENCY_HALF_LIFE: Duration = Duration::from_secs(30);
+/// Added to every upstream's latency estimate when weighting them, so that upstreams we have no
+/// data for (or that are extremely fast) don't get infinite weight
+const LATENCY_FLOOR_SECS: f64 = 0.001;
+
+/// Cookie that --sticky-sessions uses to remember which upstream a client was sent to
+const STICKY_COOKIE: &str = "bb-upstream";
+
+/// How long we wait for an upstream to answer an Expect: 100-continue request before telling the
+/// client to send its body anyway. Upstreams that don't understand Expect never answer until
+/// they've had the body.
+const EXPECT_CONTINUE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long we spend telling a client we're turning its connection away (see reject_connection)
+const REJECT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Exponentially-weighted moving average of an upstream's response times
+#[derive(Debug, Default)]
+struct LatencyStats {
+    /// Average response time in seconds as of last_updated, or None if we haven't seen a response
+    average: Option<f64>,
+    last_updated: Option<Instant>,
+}
+
+impl LatencyStats {
+    /// Returns the average response time in seconds, decayed according to how long ago it was last
+    /// updated. Upstreams we haven't heard from count as instantaneous, so they get tried.
+    fn estimate(&self, now: Instant) -> f64 {
+        match (self.average, self.last_updated) {
+            (Some(average), Some(last_updated)) => {
+                let elapsed = now.duration_since(last_updated).as_secs_f64();
+                average * 0.5_f64.powf(elapsed / LATENCY_HALF_LIFE.as_secs_f64())
+            }
+            _ => 0.0,
+        }
+    }
+
+    fn record(&mut self, response_time: Duration) {
+        let now = Instant::now();
+        let sample = response_time.as_secs_f64();
+        self.average = Some(match self.average {
+            None => sample,
+            Some(_) => {
+                self.estimate(now) * (1.0 - LATENCY_EWMA_ALPHA) + sample * LATENCY_EWMA_ALPHA
+            }
+        });
+        self.last_updated = Some(now);
     }
 }
 
-#[derive(Debug, Clone)]
-struct UpstreamRpm {
-    count: usize,
-    instant: Instant,
+/// Parses an --upstream argument, which is an address optionally followed by options, e.g.
+/// `127.0.0.1:8080,weight=3,tier=backup,pool=api,health_interval=30,max_requests=100`. `tls://`
+/// upstreams also take `sni=<name>` (the name to send in SNI and verify the certificate against;
+/// defaults to the address's host) and `verify=false` (to accept any certificate).
+fn parse_upstream_state(s: &str) -> Result<UpstreamState, String> {
+    let mut parts = s.split(',');
+    let addr = parts.next().unwrap_or("");
+    if addr.is_empty() {
+        return Err("upstream address is empty".to_string());
+    }
+    let mut upstream = UpstreamState::new(addr.to_string(), 1);
+    for option in parts {
+        match option.split_once('=') {
+            Some(("weight", value)) => {
+                upstream.weight = match value.parse::<usize>() {
+                    Ok(weight) if weight > 0 => weight,
+                    _ => return Err(format!("invalid weight \"{}\" for {}", value, addr)),
+                }
+            }
+            Some(("tier", value)) => upstream.tier = value.parse()?,
+            Some(("pool", value)) if !value.is_empty() => upstream.pool = Some(value.to_string()),
+            Some(("max_requests", value)) => {
+                upstream.max_requests = match value.parse::<usize>() {
+                    Ok(max) if max > 0 => Some(max),
+                    _ => return Err(format!("invalid max_requests \"{}\" for {}", value, addr)),
+                }
+            }
+            Some(("proxy_protocol", value)) => upstream.proxy_protocol = Some(value.parse()?),
+            Some(("health_interval", value)) => {
+                upstream.health_check_interval = match value.parse::<u64>() {
+                    Ok(interval) if interval > 0 => Some(Duration::from_secs(interval)),
+                    _ => {
+                        return Err(format!(
+                            "invalid health_interval \"{}\" for {}",
+                            value, addr
+                        ))
+                    }
+                }
+            }
+            Some(("sni", value)) => upstream.tls_options()?.server_name = value.to_string(),
+            Some(("verify", value)) => {
+                upstream.tls_options()?.verify = value
+                    .parse()
+                    .map_err(|_| format!("invalid verify \"{}\" for {}", value, addr))?
+            }
+            _ => return Err(format!("unknown upstream option \"{}\"", option)),
+        }
+    }
+    upstream.check_tls()?;
+    Ok(upstream)
+}
+
+/// A header to add to active health check requests
+type HeaderLine = (http::header::HeaderName, http::HeaderValue);
+
+fn parse_header(name: &str, value: &str) -> Result<HeaderLine, String> {
+    let name = http::header::HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|_| format!("invalid header name \"{}\"", name.trim()))?;
+    let value = http::HeaderValue::from_str(value.trim())
+        .map_err(|_| format!("invalid value \"{}\" for header {}", value.trim(), name))?;
+    Ok((name, value))
+}
+
+/// Parses a --health-check-header argument, e.g. `Authorization: Bearer abc123`
+fn parse_header_line(s: &str) -> Result<HeaderLine, String> {
+    match s.split_once(':') {
+        Some((name, value)) => parse_header(name, value),
+        None => Err(format!("header \"{}\" should look like \"Name: value\"", s)),
+    }
+}
+
+impl UpstreamState {
+    /// Returns true if new client connections may be sent to this upstream
+    fn accepts_connections(&self) -> bool {
+        !self.is_dead && !self.draining
+    }
+
+    /// Whether a request can be sent here right now: the upstream accepts connections, isn't at its
+    /// max_requests, and its circuit breaker isn't open
+    fn takes_requests(&self, now: Instant) -> bool {
+        self.accepts_connections() && !self.is_saturated() && !self.breaker.lock().is_open(now)
+    }
+
+    /// Whether the upstream already has as many requests as its max_requests allows
+    fn is_saturated(&self) -> bool {
+        self.max_requests
+            .is_some_and(|max| self.active_connections.load(Ordering::SeqCst) >= max)
+    }
+
+    /// Whether the upstream would take requests, except that it's at its max_requests (so a request
+    /// waiting in the queue may get it once one finishes)
+    fn is_busy(&self, now: Instant) -> bool {
+        self.accepts_connections() && self.is_saturated() && !self.breaker.lock().is_open(now)
+    }
+
+    /// Fraction of its usual share of requests this upstream should get, given that it's ramping up
+    /// over slow_start after being revived
+    fn slow_start_share(&self, slow_start: Duration, now: Instant) -> f64 {
+        match self.revived_at {
+            Some(revived_at) if slow_start > Duration::from_secs(0) => {
+                (now.duration_since(revived_at).as_secs_f64() / slow_start.as_secs_f64()).min(1.0)
+            }
+            _ => 1.0,
+        }
+    }
+
+    /// Marks the upstream dead or alive, noting when it was revived
+    fn set_dead(&mut self, dead: bool) {
+        if self.is_dead && !dead {
+            self.revived_at = Some(Instant::now());
+        }
+        self.is_dead = dead;
+    }
+
+    fn new(addr: String, weight: usize) -> UpstreamState {
+        let (host_port, tls) = tls::parse_upstream_address(&addr);
+        UpstreamState {
+            resolved_from: Some(addr.clone()).filter(|_| dns::is_dns_name(host_port)),
+            discovered_by: None,
+            tls,
+            addr,
+            is_dead: false,
+            draining: false,
+            weight,
+            tier: Tier::Primary,
+            pool: None,
+            proxy_protocol: None,
+            active_connections: Arc::new(AtomicUsize::new(0)),
+            max_requests: None,
+            latency: Arc::new(Mutex::new(LatencyStats::default())),
+            error_rate: Arc::new(Mutex::new(adaptive::ErrorRate::default())),
+            metrics: Arc::new(metrics::UpstreamMetrics::default()),
+            breaker: Arc::new(Mutex::new(breaker::CircuitBreaker::default())),
+            passive_health: Arc::new(Mutex::new(PassiveHealth::default())),
+            health_check_interval: None,
+            revived_at: None,
+        }
+    }
+
+    /// The host:port to connect to (addr without any `tls://` prefix)
+    fn host_port(&self) -> &str {
+        tls::parse_upstream_address(&self.addr).0
+    }
+
+    /// The host:port to send in Host headers: the one the upstream was given as (its DNS name if
+    /// it has one), or localhost for Unix socket upstreams
+    fn configured_host_port(&self) -> &str {
+        if tls::unix_socket_path(&self.addr).is_some() {
+            return "localhost";
+        }
+        tls::parse_upstream_address(self.resolved_from.as_deref().unwrap_or(&self.addr)).0
+    }
+
+    /// Returns the TLS settings to change, or an error if this isn't a `tls://` upstream
+    fn tls_options(&mut self) -> Result<&mut tls::UpstreamTls, String> {
+        let addr = &self.addr;
+        self.tls
+            .as_mut()
+            .ok_or_else(|| format!("TLS options given for non-TLS upstream {}", addr))
+    }
+
+    /// Returns an error if the TLS settings can't be used to connect
+    fn check_tls(&self) -> Result<(), String> {
+        match &self.tls {
+            Some(tls) => tls.check().map_err(|err| format!("{}: {}", self.addr, err)),
+            None => Ok(()),
+        }
+    }
+
+    /// Connects to this upstream, doing a TLS handshake if it's a `tls://` upstream. If it wants a
+    /// PROXY protocol header, that names origin as the connection's (or, without one, says the
+    /// connection is our own).
+    async fn connect(
+        &self,
+        connector: &tls::UpstreamConnector,
+        origin: Option<&proxy_protocol::Origin>,
+    ) -> std::io::Result<tls::UpstreamStream> {
+        let proxy_header = self
+            .proxy_protocol
+            .map(|version| proxy_protocol::encode_header(version, origin));
+        connector
+            .connect(self.host_port(), self.tls.as_ref(), proxy_header.as_deref())
+            .await
+    }
+}
+
+/// A request's place in an upstream's active_connections. It's taken as soon as the upstream is
+/// picked, before connecting, so that requests picking the same upstream at once can't take it
+/// past its max_requests.
+struct RequestSlot {
+    active_connections: Arc<AtomicUsize>,
+    /// The queue's slot_freed, to wake a queued request once this slot is given back
+    slot_freed: Arc<tokio::sync::Notify>,
+}
+
+impl RequestSlot {
+    /// Takes a slot whether or not the upstream has room
+    fn take(upstream: &UpstreamState, queue: &RequestQueue) -> RequestSlot {
+        upstream.active_connections.fetch_add(1, Ordering::SeqCst);
+        RequestSlot {
+            active_connections: Arc::clone(&upstream.active_connections),
+            slot_freed: Arc::clone(&queue.slot_freed),
+        }
+    }
+
+    /// Takes a slot, unless the upstream is already at its max_requests
+    fn reserve(upstream: &UpstreamState, queue: &RequestQueue) -> Option<RequestSlot> {
+        let max = match upstream.max_requests {
+            Some(max) => max,
+            None => return Some(RequestSlot::take(upstream, queue)),
+        };
+        upstream
+            .active_connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                Some(count + 1).filter(|_| count < max)
+            })
+            .ok()?;
+        Some(RequestSlot {
+            active_connections: Arc::clone(&upstream.active_connections),
+            slot_freed: Arc::clone(&queue.slot_freed),
+        })
+    }
+}
+
+impl Drop for RequestSlot {
+    fn drop(&mut self) {
+        self.active_connections.fetch_sub(1, Ordering::SeqCst);
+        self.slot_freed.notify();
+    }
+}
+
+/// Requests waiting for an upstream to have room, when every upstream that would take them is at
+/// its max_requests (see --request-queue-size)
+struct RequestQueue {
+    max_size: usize,
+    timeout: Duration,
+    /// Number of requests waiting
+    waiting: AtomicUsize,
+    /// Notified whenever a RequestSlot is given back. Each notification wakes one waiting request
+    /// to try again.
+    slot_freed: Arc<tokio::sync::Notify>,
+}
+
+impl RequestQueue {
+    fn new(max_size: usize, timeout: Duration) -> RequestQueue {
+        RequestQueue {
+            max_size,
+            timeout,
+            waiting: AtomicUsize::new(0),
+            slot_freed: Arc::new(tokio::sync::Notify::new()),
+        }
+    }
+
+    /// Takes a place in the queue, or returns None if it's full
+    fn join(&self) -> Option<QueuePlace<'_>> {
+        let max = self.max_size;
+        self.waiting
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                Some(count + 1).filter(|_| count < max)
+            })
+            .ok()?;
+        Some(QueuePlace {
+            queue: self,
+            deadline: Instant::now() + self.timeout,
+        })
+    }
+}
+
+/// A request's place in the RequestQueue, given up when it's dropped
+struct QueuePlace<'a> {
+    queue: &'a RequestQueue,
+    /// When the request stops waiting
+    deadline: Instant,
+}
+
+impl QueuePlace<'_> {
+    /// Waits for a slot to be given back, returning false if the request has waited too long
+    async fn wait(&self) -> bool {
+        tokio::time::timeout_at(self.deadline, self.queue.slot_freed.notified())
+            .await
+            .is_ok()
+    }
+}
+
+impl Drop for QueuePlace<'_> {
+    fn drop(&mut self) {
+        self.queue.waiting.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Why connect_to_upstream couldn't give a request an upstream
+#[derive(Debug)]
+enum ConnectError {
+    /// There were no upstreams left to try (or none we could connect to)
+    NoUpstreams,
+    /// There were no upstreams left to try, and connecting to at least one of them timed out
+    Timeout,
+    /// Every upstream that would take the request was at its max_requests, and the queue was full
+    QueueFull,
+    /// The request waited in the queue for --request-queue-timeout without an upstream having room
+    QueueTimeout,
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectError::NoUpstreams => write!(f, "No more upstreams to connect"),
+            ConnectError::Timeout => write!(f, "Timed out connecting to upstreams"),
+            ConnectError::QueueFull => write!(f, "Every upstream is busy and the queue is full"),
+            ConnectError::QueueTimeout => write!(f, "Timed out waiting for a busy upstream"),
+        }
+    }
+}
+
+impl ConnectError {
+    /// The response to send a client whose request couldn't be given an upstream
+    fn response(&self, queue: &RequestQueue) -> http::Response<Vec<u8>> {
+        match self {
+            ConnectError::NoUpstreams => response::make_http_error(http::StatusCode::BAD_GATEWAY),
+            ConnectError::Timeout => response::make_http_error(http::StatusCode::GATEWAY_TIMEOUT),
+            ConnectError::QueueFull if queue.max_size == 0 => response::make_unavailable_response(
+                "Every upstream is handling as many requests as it can. Please try again shortly.",
+            ),
+            ConnectError::QueueFull => response::make_unavailable_response(&format!(
+                "Every upstream is handling as many requests as it can, and {} more are already \
+                 waiting. Please try again shortly.",
+                queue.max_size
+            )),
+            ConnectError::QueueTimeout => response::make_unavailable_response(&format!(
+                "Every upstream was handling as many requests as it can, and none had room for \
+                 this one within {} ms. Please try again shortly.",
+                queue.timeout.as_millis()
+            )),
+        }
+    }
+}
+
+/// Counts a request towards an upstream's active_connections for as long as it's being proxied,
+/// and records the upstream's response times and whether the request succeeded
+struct ActiveConnection {
+    /// The upstream's address, as given on the command line
+    addr: String,
+    _slot: RequestSlot,
+    latency: Arc<Mutex<LatencyStats>>,
+    error_rate: Arc<Mutex<adaptive::ErrorRate>>,
+    metrics: Arc<metrics::UpstreamMetrics>,
+    breaker: Arc<Mutex<breaker::CircuitBreaker>>,
+    breaker_settings: breaker::Settings,
+    passive_health: Arc<Mutex<PassiveHealth>>,
+    /// Whether the connection may be handed to another client once this one is done with it (it
+    /// can't if it started with a PROXY protocol header naming this client)
+    reusable: bool,
+}
+
+impl ActiveConnection {
+    fn new(
+        upstream: &UpstreamState,
+        slot: RequestSlot,
+        breaker_settings: &breaker::Settings,
+    ) -> ActiveConnection {
+        upstream
+            .breaker
+            .lock()
+            .start_request(breaker_settings, Instant::now());
+        ActiveConnection {
+            addr: upstream.addr.clone(),
+            _slot: slot,
+            latency: Arc::clone(&upstream.latency),
+            error_rate: Arc::clone(&upstream.error_rate),
+            metrics: Arc::clone(&upstream.metrics),
+            breaker: Arc::clone(&upstream.breaker),
+            breaker_settings: *breaker_settings,
+            passive_health: Arc::clone(&upstream.passive_health),
+            reusable: upstream.proxy_protocol.is_none(),
+        }
+    }
+
+    fn record_response_time(&self, response_time: Duration) {
+        self.latency.lock().record(response_time);
+        self.metrics.record_response_time(response_time);
+    }
+
+    /// Records whether the upstream handled the request, for its circuit breaker. Errors and 5xx
+    /// responses count as failures.
+    fn record_outcome(&self, success: bool) {
+        self.metrics.record_outcome(success);
+        self.error_rate.lock().record(success);
+        let now = Instant::now();
+        let transition = self
+            .breaker
+            .lock()
+            .record(success, &self.breaker_settings, now);
+        match transition {
+            Some(breaker::Transition::Tripped) => log::warn!(
+                "Circuit breaker for upstream {} tripped; keeping requests away for {:?}",
+                self.addr,
+                self.breaker_settings.open_time
+            ),
+            Some(breaker::Transition::Reset) => {
+                log::info!("Circuit breaker for upstream {} closed again", self.addr)
+            }
+            None => {}
+        }
+    }
+}
+
+/// An address to listen on (see --bind)
+#[derive(Debug, Clone, PartialEq)]
+struct Bind {
+    addr: String,
+    /// Whether connections start with a PROXY protocol header (of either version), from which we
+    /// take the client's address instead of from the connection
+    proxy_protocol: bool,
+}
+
+impl std::str::FromStr for Bind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let addr = parts.next().unwrap_or("");
+        if addr.is_empty() {
+            return Err("bind address is empty".to_string());
+        }
+        let mut bind = Bind {
+            addr: addr.to_string(),
+            proxy_protocol: false,
+        };
+        for option in parts {
+            match option {
+                "proxy_protocol" => bind.proxy_protocol = true,
+                _ => return Err(format!("unknown bind option \"{}\"", option)),
+            }
+        }
+        Ok(bind)
+    }
+}
+
+/// Which set of headers balancebeam uses to tell upstreams about the original client connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ForwardedHeaderStyle {
+    /// The standardized `Forwarded` header from RFC 7239
+    Rfc7239,
+    /// The de-facto `X-Forwarded-*` headers
+    Legacy,
+    /// Both of the above
+    Both,
+}
+
+impl std::str::FromStr for ForwardedHeaderStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rfc7239" => Ok(ForwardedHeaderStyle::Rfc7239),
+            "legacy" => Ok(ForwardedHeaderStyle::Legacy),
+            "both" => Ok(ForwardedHeaderStyle::Both),
+            other => Err(format!(
+                "unknown forwarded header style \"{}\" (expected rfc7239, legacy, or both)",
+                other
+            )),
+        }
+    }
+}
+
+/// What balancebeam proxies
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    /// HTTP requests, each of which may go to a different upstream
+    Http,
+    /// Raw TCP connections, each copied as-is to and from a single upstream. This can front
+    /// protocols we don't understand (e.g. Redis or PostgreSQL).
+    Tcp,
+    /// UDP datagrams (e.g. DNS or syslog), relayed between each client and a single upstream for as
+    /// long as they keep talking
+    Udp,
+}
+
+impl std::str::FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "http" => Ok(Mode::Http),
+            "tcp" => Ok(Mode::Tcp),
+            "udp" => Ok(Mode::Udp),
+            other => Err(format!(
+                "unknown mode \"{}\" (expected http, tcp, or udp)",
+                other
+            )),
+        }
+    }
+}
+
+/// Which group of upstreams an upstream belongs to
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Tier {
+    Primary,
+    /// Only used when every primary upstream is dead, draining, or has its circuit breaker open
+    Backup,
+}
+
+impl Tier {
+    fn name(&self) -> &'static str {
+        match self {
+            Tier::Primary => "primary",
+            Tier::Backup => "backup",
+        }
+    }
+}
+
+impl std::str::FromStr for Tier {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "primary" => Ok(Tier::Primary),
+            "backup" => Ok(Tier::Backup),
+            other => Err(format!(
+                "unknown tier \"{}\" (expected primary or backup)",
+                other
+            )),
+        }
+    }
+}
+
+/// A set of HTTP status codes, written as a comma-separated list of codes and ranges (e.g.
+/// `200-299,301`)
+#[derive(Debug, Clone, PartialEq)]
+struct StatusSet(Vec<(u16, u16)>);
+
+impl StatusSet {
+    fn contains(&self, status: http::StatusCode) -> bool {
+        let status = status.as_u16();
+        self.0
+            .iter()
+            .any(|&(low, high)| low <= status && status <= high)
+    }
+}
+
+impl std::str::FromStr for StatusSet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_status = |status: &str| match status.trim().parse::<u16>() {
+            Ok(status) if (100..600).contains(&status) => Ok(status),
+            _ => Err(format!("invalid status code \"{}\"", status.trim())),
+        };
+        s.split(',')
+            .map(|part| match part.split_once('-') {
+                Some((low, high)) => Ok((parse_status(low)?, parse_status(high)?)),
+                None => parse_status(part).map(|status| (status, status)),
+            })
+            .collect::<Result<_, _>>()
+            .map(StatusSet)
+    }
+}
+
+/// How connect_to_upstream chooses which live upstream to send a request to
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LoadBalancingStrategy {
+    /// Pick a live upstream at random, in proportion to its weight
+    Random,
+    /// Cycle through the live upstreams in order
+    RoundRobin,
+    /// Pick the live upstream with the fewest active connections (ties are broken randomly)
+    LeastConnections,
+    /// Power of two choices: sample two live upstreams at random and pick whichever has fewer
+    /// active connections. This avoids both the herding of least-connections (where every new
+    /// connection piles onto the same momentarily-idle upstream) and the imbalance of pure random.
+    PowerOfTwoChoices,
+    /// Pick a live upstream at random, biased towards those with lower recent response times
+    LeastLatency,
+    /// Consistently hash the client's IP address, so that each client sticks to one upstream
+    /// (until that upstream dies)
+    IpHash,
+}
+
+impl LoadBalancingStrategy {
+    fn name(&self) -> &'static str {
+        match self {
+            LoadBalancingStrategy::Random => "random",
+            LoadBalancingStrategy::RoundRobin => "round-robin",
+            LoadBalancingStrategy::LeastConnections => "least-connections",
+            LoadBalancingStrategy::PowerOfTwoChoices => "p2c",
+            LoadBalancingStrategy::LeastLatency => "least-latency",
+            LoadBalancingStrategy::IpHash => "ip-hash",
+        }
+    }
+}
+
+impl std::str::FromStr for LoadBalancingStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(LoadBalancingStrategy::Random),
+            "round-robin" => Ok(LoadBalancingStrategy::RoundRobin),
+            "least-connections" => Ok(LoadBalancingStrategy::LeastConnections),
+            "p2c" => Ok(LoadBalancingStrategy::PowerOfTwoChoices),
+            "least-latency" => Ok(LoadBalancingStrategy::LeastLatency),
+            "ip-hash" => Ok(LoadBalancingStrategy::IpHash),
+            other => Err(format!(
+                "unknown load balancing strategy \"{}\" (expected random, round-robin, \
+                least-connections, p2c, least-latency, or ip-hash)",
+                other
+            )),
+        }
+    }
+}
+
+/// What we speak to upstreams
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UpstreamProtocol {
+    /// HTTP/1.1, whatever the client spoke to us
+    Http1,
+    /// Cleartext HTTP/2 (with prior knowledge), as gRPC servers expect. Only HTTP/2 clients' requests
+    /// can be passed on this way, each stream (RPC) to an upstream of its own; see
+    /// http2::forward_h2c.
+    H2c,
+}
+
+impl std::str::FromStr for UpstreamProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "http1" => Ok(UpstreamProtocol::Http1),
+            "h2c" => Ok(UpstreamProtocol::H2c),
+            other => Err(format!(
+                "unknown upstream protocol \"{}\" (expected http1 or h2c)",
+                other
+            )),
+        }
+    }
 }
 
 /// Contains information parsed from the command-line invocation of balancebeam. The Clap macros
@@ -33,14 +812,34 @@ struct UpstreamRpm {
 #[derive(Parser, Debug)]
 #[clap(name = "balancebeam", about = "Fun with load balancing")]
 struct CmdOptions {
+    #[clap(
+        long,
+        help = "TOML file to read settings from. Flags given on the command line override the \
+                file's values."
+    )]
+    config: Option<String>,
     #[clap(
         short,
         long,
-        help = "IP/port to bind to",
+        help = "IP/port to bind to (may be given more than once, to listen on several addresses). \
+                Add ,proxy_protocol (e.g. 0.0.0.0:1100,proxy_protocol) if connections to it come \
+                through a load balancer that starts each with a PROXY protocol header.",
         default_value = "0.0.0.0:1100"
     )]
-    bind: String,
-    #[clap(short, long, help = "Upstream host to forward requests to", parse(from_str = parse_upstream_state))]
+    bind: Vec<Bind>,
+    #[clap(
+        short,
+        long,
+        help = "Upstream host to forward requests to, optionally with a weight for random \
+                selection (e.g. 127.0.0.1:8080,weight=3), a tier (tier=backup, for upstreams that \
+                only get requests when no primary upstream is alive), a pool (pool=api, to only \
+                get requests that a --route sends there), and its own health check interval in \
+                seconds (health_interval=30). Use tls://host:port to speak TLS to it, \
+                optionally with sni=<name> and verify=false, or unix:///path/to.sock to connect to \
+                a Unix domain socket. Add proxy_protocol=v1 or proxy_protocol=v2 to tell it each \
+                client's address in a PROXY protocol header.",
+        parse(try_from_str = parse_upstream_state)
+    )]
     upstream: Vec<UpstreamState>,
     #[clap(
         long,
@@ -48,305 +847,3759 @@ struct CmdOptions {
         default_value = "10"
     )]
     active_health_check_interval: usize,
+    #[clap(
+        long,
+        help = "Vary each upstream's health check interval randomly by up to this percentage, so \
+                that upstreams aren't all checked at the same moment",
+        default_value = "0"
+    )]
+    health_check_jitter: u8,
     #[clap(
         long,
         help = "Path to send request to for active health checks",
         default_value = "/"
     )]
     active_health_check_path: String,
+    #[clap(
+        long,
+        help = "Method to send active health check requests with",
+        default_value = "GET"
+    )]
+    health_check_method: http::Method,
+    #[clap(
+        long,
+        help = "Header to add to active health check requests, e.g. \"Authorization: Bearer abc\" \
+                (may be given more than once)",
+        parse(try_from_str = parse_header_line)
+    )]
+    health_check_header: Vec<HeaderLine>,
+    #[clap(long, help = "Body to send with active health check requests")]
+    health_check_request_body: Option<String>,
+    #[clap(
+        long,
+        help = "How long (in seconds) an upstream has to answer an active health check before it's \
+                considered dead",
+        default_value = "5"
+    )]
+    health_check_timeout: u64,
+    #[clap(
+        long,
+        help = "Status codes that count as healthy in answer to an active health check, as a \
+                comma-separated list of codes and ranges (e.g. 200-299,301)",
+        default_value = "200"
+    )]
+    health_check_status: StatusSet,
+    #[clap(
+        long,
+        help = "Text that an upstream's answer to an active health check must contain to count as \
+                healthy"
+    )]
+    health_check_body: Option<String>,
+    #[clap(
+        long,
+        help = "Maximum number of dead upstreams to revive per active health check (0 = unlimited)",
+        default_value = "0"
+    )]
+    max_revivals_per_health_check: usize,
+    #[clap(
+        long,
+        help = "Number of failed requests in a row (connection errors, or no response) after which \
+                an upstream is marked dead",
+        default_value = "1"
+    )]
+    passive_failure_threshold: usize,
+    #[clap(
+        long,
+        help = "Number of successful requests in a row after which an upstream that was marked dead \
+                is brought back",
+        default_value = "1"
+    )]
+    passive_success_threshold: usize,
     #[clap(
         long,
         help = "Maximum number of requests to accept per IP per minute (0 = unlimited)",
         default_value = "0"
     )]
     max_requests_per_minute: usize,
+    #[clap(
+        long,
+        help = "How many requests a client can make in a burst before being held to \
+                --max-requests-per-minute (defaults to --max-requests-per-minute)"
+    )]
+    rate_limit_burst: Option<usize>,
+    #[clap(
+        long,
+        help = "How to count requests against --max-requests-per-minute: token-bucket (which \
+                allows bursts; see --rate-limit-burst) or sliding-window (which keeps to the limit \
+                over any minute)",
+        default_value = "token-bucket"
+    )]
+    rate_limit_algorithm: rate_limit::Algorithm,
+    #[clap(
+        long,
+        help = "Most clients to keep track of for rate limiting. Past this, the client that made a \
+                request longest ago is forgotten (and so gets its full allowance back).",
+        default_value = "100000"
+    )]
+    rate_limit_max_clients: usize,
+    #[clap(
+        long,
+        help = "Clients (CIDR blocks, e.g. 10.0.0.0/8) that are never rate limited, such as health \
+                checkers. May be given more than once."
+    )]
+    rate_limit_allow: Vec<cidr::Cidr>,
+    #[clap(
+        long,
+        help = "Clients (CIDR blocks) whose requests are always refused with 403 Forbidden, whether \
+                or not there's a rate limit. May be given more than once."
+    )]
+    rate_limit_deny: Vec<cidr::Cidr>,
+    #[clap(
+        long,
+        help = "Clients (CIDR blocks) that may connect at all; if given, connections from anywhere \
+                else are refused as soon as they're accepted. May be given more than once; \
+                reloaded on SIGHUP."
+    )]
+    allow_client: Vec<cidr::Cidr>,
+    #[clap(
+        long,
+        help = "Clients (CIDR blocks) whose connections are refused as soon as they're accepted, \
+                even if --allow-client covers them. May be given more than once; reloaded on \
+                SIGHUP."
+    )]
+    deny_client: Vec<cidr::Cidr>,
+    #[clap(
+        long,
+        help = "What clients that --allow-client and --deny-client refuse get: forbidden (a 403 \
+                for plain HTTP connections; others are closed) or drop (the connection is closed)",
+        default_value = "forbidden"
+    )]
+    denied_client_action: acl::RefusalAction,
+    #[clap(
+        long,
+        help = "A separate limit on requests to some paths and/or with some method, on top of \
+                --max-requests-per-minute, written as [METHOD] [PATH PREFIX]=REQUESTS PER MINUTE \
+                (e.g. \"POST /login=10\"). May be given more than once; a request counts against \
+                every limit it matches."
+    )]
+    route_rate_limit: Vec<rate_limit::RouteRule>,
+    #[clap(
+        long,
+        help = "Limit the methods that may be used on some paths, written as allow METHODS [PATH \
+                PREFIX] (e.g. \"allow GET,HEAD /public\": anything else gets a 405) or deny \
+                METHODS [PATH PREFIX] (e.g. \"deny TRACE\": those get a 403). May be given more \
+                than once; deny rules win, and then the allow rule with the longest prefix."
+    )]
+    method_rule: Vec<acl::MethodRule>,
+    #[clap(
+        long,
+        help = "Require credentials for the paths under a prefix, written as <scheme> <path \
+                prefix> with scheme basic (HTTP Basic auth, checked against \
+                --basic-auth-file), jwt (a bearer token, checked with --jwt-secret or \
+                --jwt-jwks), or none (e.g. \"jwt /api\" and \"none /api/health\"). May be \
+                given more than once; the rule with the longest matching prefix applies. Requests \
+                without good credentials get a 401."
+    )]
+    auth: Vec<auth::AuthRule>,
+    #[clap(
+        long,
+        help = "htpasswd file of users for basic --auth rules (MD5 or SHA-1 hashes, from \
+                htpasswd -m or -s)"
+    )]
+    basic_auth_file: Option<String>,
+    #[clap(
+        long,
+        help = "Realm to name in the WWW-Authenticate header of 401 responses",
+        default_value = "balancebeam"
+    )]
+    auth_realm: String,
+    #[clap(
+        long,
+        help = "Shared secret that jwt --auth tokens signed with HS256/384/512 use"
+    )]
+    jwt_secret: Option<String>,
+    #[clap(
+        long,
+        help = "JWKS file of public keys that jwt --auth tokens signed with RS256/384/512 or \
+                ES256/384 use"
+    )]
+    jwt_jwks: Option<String>,
+    #[clap(long, help = "Only accept jwt --auth tokens whose iss claim is this")]
+    jwt_issuer: Option<String>,
+    #[clap(
+        long,
+        help = "Only accept jwt --auth tokens whose aud claim includes this"
+    )]
+    jwt_audience: Option<String>,
+    #[clap(
+        long,
+        help = "Send a file in place of the body of the errors balancebeam makes itself (e.g. a \
+                502 when no upstream answers) with some status, written as <status>=<path> \
+                (e.g. \"502=/etc/balancebeam/502.html\" or \"5xx=errors.json\"). {status}, \
+                {reason}, and {request_id} in the file are filled in, and its Content-Type \
+                follows its extension (.html, .json, or anything else for plain text). May be \
+                given more than once; a page for an exact status beats one for its class."
+    )]
+    error_page: Vec<error_page::ErrorPage>,
+    #[clap(
+        long,
+        help = "Start in maintenance mode, answering requests with a 503 instead of forwarding \
+                them (see --maintenance-page). The admin API's /maintenance/on and \
+                /maintenance/off turn it on and off while running."
+    )]
+    maintenance: bool,
+    #[clap(
+        long,
+        help = "Only put the paths under this prefix into maintenance mode, rather than all of \
+                them. May be given more than once."
+    )]
+    maintenance_path: Vec<String>,
+    #[clap(
+        long,
+        help = "Clients (CIDR blocks) whose requests are still forwarded in maintenance mode, e.g. \
+                the admins doing the maintenance. May be given more than once."
+    )]
+    maintenance_allow: Vec<cidr::Cidr>,
+    #[clap(
+        long,
+        help = "File to send as the body of maintenance mode 503s, filled in as for --error-page"
+    )]
+    maintenance_page: Option<String>,
+    #[clap(
+        long,
+        help = "Seconds to tell clients (in Retry-After) to wait before trying again in maintenance \
+                mode"
+    )]
+    maintenance_retry_after: Option<u64>,
+    #[clap(
+        long,
+        help = "Rate limit by the value of this request header (e.g. X-Api-Key or Authorization) \
+                rather than by client IP, so that clients sharing an address (e.g. behind a NAT) \
+                get separate limits. Requests without the header are limited by IP."
+    )]
+    rate_limit_header: Option<http::header::HeaderName>,
+    #[clap(
+        long,
+        help = "Keep rate limit counters in this Redis server (redis://[:password@]host[:port][/db]) \
+                rather than in memory, so that every balancebeam using it shares them"
+    )]
+    rate_limit_redis: Option<redis::RedisAddress>,
+    #[clap(
+        long,
+        help = "Give 429 Too Many Requests responses a JSON body (with the error and the number of \
+                seconds to wait before retrying) instead of a plain text one"
+    )]
+    rate_limit_json_errors: bool,
+    #[clap(
+        long,
+        help = "Most bytes per second of response bodies to send each client IP, across all of its \
+                connections (0 = no limit)",
+        default_value = "0"
+    )]
+    client_bandwidth: u64,
+    #[clap(
+        long,
+        help = "Most bytes of GET and HEAD responses to keep in memory, to answer repeated requests \
+                without bothering an upstream (0 = no cache)",
+        default_value = "0"
+    )]
+    cache_size: usize,
+    #[clap(
+        long,
+        help = "How long (in seconds) a cached response is served, if the upstream's \
+                Cache-Control and Expires headers don't say",
+        default_value = "60"
+    )]
+    cache_ttl: u64,
+    #[clap(
+        long,
+        help = "Largest response body (in bytes) to cache",
+        default_value = "1048576"
+    )]
+    cache_max_entry_size: u64,
+    #[clap(
+        long,
+        help = "Compress response bodies with gzip for clients that accept it (Accept-Encoding)"
+    )]
+    compress_gzip: bool,
+    #[clap(
+        long,
+        help = "Compress response bodies with deflate for clients that accept it. Clients that \
+                accept both get whichever they prefer, or gzip if they don't mind. (Brotli isn't \
+                offered.)"
+    )]
+    compress_deflate: bool,
+    #[clap(
+        long,
+        help = "Smallest response body (in bytes) worth compressing",
+        default_value = "1024"
+    )]
+    compress_min_size: u64,
+    #[clap(
+        long,
+        help = "Content type of responses to compress (e.g. text/* or application/json). May be \
+                given more than once. By default, text, JSON, JavaScript, XML, WebAssembly, and \
+                SVG are compressed."
+    )]
+    compress_type: Vec<String>,
+    #[clap(
+        long,
+        help = "Origin whose pages may call us from a browser (e.g. https://app.example.com, \
+                https://*.example.com for its subdomains, or * for any). With this set, CORS \
+                preflight requests are answered here, and responses to allowed origins get \
+                Access-Control-* headers. May be given more than once."
+    )]
+    cors_origin: Vec<String>,
+    #[clap(
+        long,
+        help = "Methods that --cors-origin origins may use",
+        default_value = "GET, HEAD, POST, PUT, PATCH, DELETE"
+    )]
+    cors_methods: String,
+    #[clap(
+        long,
+        help = "Request headers that --cors-origin origins may send (by default, any that a \
+                preflight asks for)"
+    )]
+    cors_headers: Option<String>,
+    #[clap(
+        long,
+        help = "Response headers that --cors-origin origins' scripts may read, beyond the basic \
+                ones"
+    )]
+    cors_expose_headers: Option<String>,
+    #[clap(
+        long,
+        help = "Let --cors-origin origins send cookies and other credentials"
+    )]
+    cors_credentials: bool,
+    #[clap(
+        long,
+        help = "How long (in seconds) browsers may remember a preflight's answer",
+        default_value = "600"
+    )]
+    cors_max_age: u64,
+    #[clap(
+        long,
+        help = "Which client connection headers to send upstream: Forwarded (rfc7239), \
+                X-Forwarded-For/-Proto/-Host/-Port (legacy), or both",
+        default_value = "legacy"
+    )]
+    forwarded_header_style: ForwardedHeaderStyle,
+    #[clap(
+        long,
+        help = "Change a header of every request sent upstream, written as set <Name>: <value>, \
+                add <Name>: <value>, or remove <Name> (e.g. \"remove X-Debug\"). May be given \
+                more than once; rules apply in order."
+    )]
+    request_header: Vec<rewrite::HeaderRule>,
+    #[clap(
+        long,
+        help = "Change a header of every response from an upstream, like --request-header (e.g. \
+                \"remove Server\" or \"set Strict-Transport-Security: max-age=63072000\")"
+    )]
+    response_header: Vec<rewrite::HeaderRule>,
+    #[clap(
+        long,
+        help = "Change the path of every request sent upstream, written as strip <prefix> (e.g. \
+                \"strip /api\" sends /api/users upstream as /users) or replace <regex> \
+                <replacement> (e.g. \"replace ^/v1/(.*)$ /api/$1\"). May be given more than once; \
+                rules apply in order. Routes still match the path the client asked for."
+    )]
+    rewrite_path: Vec<rewrite::PathRewrite>,
+    #[clap(
+        long,
+        help = "Addresses (CIDR blocks, e.g. 10.0.0.0/8) of proxies in front of us whose \
+                X-Forwarded-For headers can be believed. For requests from them, the client's \
+                address for logging, rate limiting, and ip-hash is taken from X-Forwarded-For. \
+                May be given more than once."
+    )]
+    trusted_proxies: Vec<cidr::Cidr>,
+    #[clap(
+        long,
+        help = "Reject POST/PUT/PATCH requests without a Content-Length or Transfer-Encoding header \
+                with 411 Length Required, instead of treating their bodies as empty"
+    )]
+    require_content_length: bool,
+    #[clap(
+        long,
+        help = "Largest request body to accept, in bytes (0 = unlimited). Bigger requests get 413 \
+                Payload Too Large.",
+        default_value = "0"
+    )]
+    max_body_size: u64,
+    #[clap(
+        long,
+        help = "Longest a request's line and headers may be, in bytes. Longer requests get 431 \
+                Request Header Fields Too Large.",
+        default_value = "8000"
+    )]
+    max_header_size: usize,
+    #[clap(
+        long,
+        help = "Most headers a request may have. Requests with more get 431 Request Header Fields \
+                Too Large.",
+        default_value = "32"
+    )]
+    max_headers: usize,
+    #[clap(
+        long,
+        help = "IP/port to serve the admin API on (disabled if not given)"
+    )]
+    admin_bind: Option<String>,
+    #[clap(
+        long,
+        help = "File to log each request to, in Apache's combined log format (plus the request's \
+                duration, upstream, and ID). It's reopened on SIGUSR1, for log rotation."
+    )]
+    access_log: Option<String>,
+    #[clap(
+        long,
+        help = "How to write log messages: as text, or as one JSON object per line (json). In \
+                json mode, each request is logged as a single event, with its client_ip, upstream, \
+                method, path, status, latency_ms, and request_id.",
+        default_value = "text"
+    )]
+    log_format: logging::LogFormat,
+    #[clap(
+        long,
+        help = "OTLP/HTTP collector to export traces to, e.g. http://127.0.0.1:4318 (disabled if not \
+                given). Each request gets a span (with child spans for connecting to its upstream, \
+                sending the request, and reading the response), and its W3C traceparent header is \
+                passed upstream."
+    )]
+    otlp_endpoint: Option<trace::OtlpEndpoint>,
+    #[clap(
+        long,
+        help = "PEM certificate chain to serve HTTPS with (requires --tls-key). Upstreams are \
+                still spoken to over plain HTTP, unless they're given as tls://host:port."
+    )]
+    tls_cert: Option<String>,
+    #[clap(long, help = "PEM private key for --tls-cert")]
+    tls_key: Option<String>,
+    #[clap(
+        long,
+        help = "Require clients to present a TLS certificate signed by a CA in this PEM bundle"
+    )]
+    tls_client_ca: Option<String>,
+    #[clap(
+        long,
+        help = "Offer HTTP/2 to TLS clients (through ALPN), and accept cleartext HTTP/2 with prior \
+                knowledge (as gRPC clients send it) from plain ones. Requests are forwarded to \
+                upstreams as HTTP/1.1, unless --upstream-protocol is h2c."
+    )]
+    http2: bool,
+    #[clap(
+        long,
+        help = "PEM bundle of CAs to verify tls:// upstreams' certificates against (by default, the \
+                usual public CAs)"
+    )]
+    upstream_tls_ca: Option<String>,
+    #[clap(
+        long,
+        help = "Most idle connections to keep open to each upstream, for later clients to reuse \
+                (0 = open a new connection for every client)",
+        default_value = "8"
+    )]
+    upstream_max_idle: usize,
+    #[clap(
+        long,
+        help = "How long (in seconds) an idle upstream connection is kept open for reuse",
+        default_value = "30"
+    )]
+    upstream_idle_timeout: u64,
+    #[clap(
+        long,
+        help = "Consecutive failed requests (errors or 5xx responses) that trip an upstream's \
+                circuit breaker, keeping requests away from it (0 = no limit)",
+        default_value = "0"
+    )]
+    breaker_failures: usize,
+    #[clap(
+        long,
+        help = "Percentage of an upstream's last 20 requests that may fail before its circuit \
+                breaker trips (0 = no limit)",
+        default_value = "0"
+    )]
+    breaker_error_rate: u8,
+    #[clap(
+        long,
+        help = "How long (in seconds) a tripped circuit breaker keeps requests away before letting \
+                a single probe request through to see if the upstream has recovered",
+        default_value = "10"
+    )]
+    breaker_open_time: u64,
+    #[clap(
+        long,
+        help = "If an upstream hasn't answered an idempotent request without a body after this many \
+                milliseconds, send the request to a second upstream too, and use whichever answers \
+                first (0 = never)",
+        default_value = "0"
+    )]
+    hedge_after: u64,
+    #[clap(
+        long,
+        help = "How long (in seconds) to wait for a connection to an upstream before trying \
+                another, with a 504 if none can be reached in time (0 = no limit)",
+        default_value = "10"
+    )]
+    upstream_connect_timeout: u64,
+    #[clap(
+        long,
+        help = "How long (in seconds) an upstream has to take a request's head and send back the \
+                head of its response before the client gets a 504 (0 = no limit)",
+        default_value = "60"
+    )]
+    upstream_response_timeout: u64,
+    #[clap(
+        long,
+        help = "How often (in seconds) to look up upstreams given by DNS name again, adding and \
+                removing upstreams as their addresses change (0 = only at startup and on reload)",
+        default_value = "30"
+    )]
+    dns_refresh_interval: u64,
+    #[clap(
+        long,
+        help = "Also send requests to the upstreams listed by a DNS SRV record \
+                (srv:_http._tcp.api.internal, or srv:<name>@<DNS server ip:port>) or a Consul \
+                service (consul:127.0.0.1:8500/api). May be given more than once."
+    )]
+    discover: Vec<discovery::Source>,
+    #[clap(
+        long,
+        help = "How often (in seconds) to look up --discover sources again",
+        default_value = "30"
+    )]
+    discovery_interval: u64,
+    #[clap(
+        long,
+        help = "Over how many seconds an upstream that comes back after being marked dead is \
+                ramped up to its full share of requests (0 = give it its full share at once)",
+        default_value = "0"
+    )]
+    slow_start: u64,
+    #[clap(
+        long,
+        help = "Keep adjusting upstreams' weights by how they're doing, sending fewer requests \
+                to upstreams that are slower or fail more often than the others"
+    )]
+    adaptive_weights: bool,
+    #[clap(
+        long,
+        help = "With --adaptive-weights, the smallest percentage of its usual share of requests \
+                an upstream is given however badly it's doing, so that we notice when it recovers",
+        default_value = "5"
+    )]
+    adaptive_min_share: u8,
+    #[clap(
+        long,
+        help = "On SIGTERM, how long to wait (in seconds) for in-flight requests to finish before \
+                exiting",
+        default_value = "30"
+    )]
+    shutdown_timeout: u64,
+    #[clap(
+        long,
+        help = "Most client connections to have open at once (0 = unlimited). Past this, a new \
+                connection waits up to --connection-queue-timeout for another to close, and is \
+                then turned away (with a 503 for plain HTTP clients).",
+        default_value = "0"
+    )]
+    max_connections: usize,
+    #[clap(
+        long,
+        help = "How long (in milliseconds) a new connection waits for a slot once \
+                --max-connections are open. We stop accepting while it waits, so connections \
+                after it queue up in the listen backlog.",
+        default_value = "0"
+    )]
+    connection_queue_timeout: u64,
+    #[clap(
+        long,
+        help = "How many requests may wait for an upstream to have room when every upstream is at \
+                its max_requests (0 = none). Requests past this get a 503.",
+        default_value = "0"
+    )]
+    request_queue_size: usize,
+    #[clap(
+        long,
+        help = "How long (in milliseconds) a queued request waits for an upstream to have room \
+                before it gets a 503",
+        default_value = "5000"
+    )]
+    request_queue_timeout: u64,
+    #[clap(
+        long,
+        help = "How long (in seconds) a client connection may go without sending us a complete \
+                request head before we close it, whether it's sitting idle between requests or \
+                sending its headers very slowly (0 = no limit)",
+        default_value = "60"
+    )]
+    client_idle_timeout: u64,
+    #[clap(
+        long,
+        help = "Whether to proxy HTTP requests (http), to pass TCP connections through to \
+                upstreams as they are (tcp), or to relay UDP datagrams (udp). In tcp mode, active \
+                health checks only check that upstreams accept connections; in udp mode, they \
+                simply bring back upstreams that were marked dead for refusing datagrams.",
+        default_value = "http"
+    )]
+    mode: Mode,
+    #[clap(
+        long,
+        help = "In udp mode, how long (in seconds) a client's session with its upstream lasts \
+                once neither side has sent anything",
+        default_value = "30"
+    )]
+    udp_session_timeout: u64,
+    #[clap(
+        long,
+        help = "How to pick an upstream for each request (random, round-robin, \
+                least-connections, p2c, least-latency, or ip-hash)",
+        default_value = "random"
+    )]
+    strategy: LoadBalancingStrategy,
+    #[clap(
+        long,
+        help = "What to speak to upstreams: http1, or h2c (cleartext HTTP/2, e.g. for gRPC \
+                servers, which only HTTP/2 clients' requests are sent to; see --http2)",
+        default_value = "http1"
+    )]
+    upstream_protocol: UpstreamProtocol,
+    #[clap(
+        long,
+        help = "Settings for a pool of upstreams (those given with pool=<name>), written as \
+                <name>[,strategy=<strategy>][,health_path=<path>][,health_interval=<seconds>]\
+                [,protocol=<http1|h2c>] (e.g. api,strategy=least-connections,health_path=/healthz \
+                or grpc,protocol=h2c). Anything left out \
+                comes from the global settings. May be given more than once."
+    )]
+    pool: Vec<routing::Pool>,
+    #[clap(
+        long,
+        help = "Send requests whose path starts with a prefix, and/or that are for a host (by \
+                their Host header), to a pool of upstreams, written as [<host>]<path \
+                prefix>=<pool> (e.g. /api=api, shop.example.com=shop, or \
+                *.example.com/static=cdn). Routes for a host beat routes for any host (exact \
+                hosts before *. wildcards), and then the longest matching prefix wins. Requests \
+                no route matches go to the upstreams that aren't in a pool. May be given more \
+                than once."
+    )]
+    route: Vec<routing::Route>,
+    #[clap(
+        long,
+        help = "Pool of upstreams (those given with pool=<name>) running a new version, to send \
+                --canary-percent of the requests no --route matches to"
+    )]
+    canary_pool: Option<String>,
+    #[clap(
+        long,
+        help = "Percentage of requests to send to --canary-pool (e.g. 5, or 0.5)",
+        default_value = "0"
+    )]
+    canary_percent: f64,
+    #[clap(
+        long,
+        help = "Pick the clients that go to --canary-pool by IP address, so that each client \
+                keeps seeing the same version, instead of request by request"
+    )]
+    canary_sticky: bool,
+    #[clap(
+        long,
+        help = "Pool of upstreams (those given with pool=<name>) to send a copy of \
+                --mirror-percent of requests to, throwing their responses away. Only requests \
+                without a body are copied."
+    )]
+    mirror_pool: Option<String>,
+    #[clap(
+        long,
+        help = "Percentage of requests to copy to --mirror-pool",
+        default_value = "100"
+    )]
+    mirror_percent: f64,
+    #[clap(
+        long,
+        help = "Pin clients to an upstream with a bb-upstream cookie, for as long as that upstream \
+                stays alive"
+    )]
+    sticky_sessions: bool,
+}
+
+/// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
+/// to, what servers have failed, rate limiting counts, etc.)
+///
+/// You should add fields to this struct in later milestones.
+struct ProxyState {
+    /// How frequently we check whether upstream servers are alive (Milestone 4)
+    #[allow(dead_code)]
+    active_health_check_interval: usize,
+    /// Fraction by which each health check interval is randomly lengthened or shortened
+    health_check_jitter: f64,
+    /// Where we should send requests when doing active health checks (Milestone 4)
+    #[allow(dead_code)]
+    active_health_check_path: String,
+    /// The rest of the active health check request
+    health_check_method: http::Method,
+    health_check_headers: Vec<HeaderLine>,
+    health_check_request_body: Option<String>,
+    /// How long an upstream has to answer an active health check
+    health_check_timeout: Duration,
+    /// Which responses to an active health check count as healthy
+    health_check_status: StatusSet,
+    health_check_body: Option<String>,
+    /// How many dead upstreams a single round of active health checks may bring back, so that a
+    /// fleet recovering all at once is brought back in staggered batches
+    max_revivals_per_health_check: usize,
+    /// How many requests in a row must fail before passive health checks mark an upstream dead
+    passive_failure_threshold: usize,
+    /// How many requests in a row must succeed before passive health checks revive an upstream
+    passive_success_threshold: usize,
+    /// How many requests an individual IP can make (Milestone 5), if there's a limit
+    rate_limit: Option<rate_limit::Limit>,
+    /// Whether 429 responses have a JSON body
+    rate_limit_json_errors: bool,
+    /// Paces the response bodies sent to each client IP, if there's a --client-bandwidth
+    throttles: Option<throttle::Throttles>,
+    /// Responses to answer repeated requests with, if caching is on
+    cache: Option<cache::Cache>,
+    /// Which responses are compressed on their way to clients
+    compression: compress::Settings,
+    /// Which origins may call us from a browser, and how
+    cors: cors::Settings,
+    /// The header whose value identifies clients for rate limiting, instead of their IP
+    rate_limit_header: Option<http::header::HeaderName>,
+    /// Limits on requests to particular routes, counted separately from rate_limit
+    route_limits: Vec<rate_limit::RouteLimiter>,
+    /// Whether to send X-Forwarded-For, Forwarded, or both to upstreams
+    forwarded_header_style: ForwardedHeaderStyle,
+    /// Changes to make to the headers of requests sent upstream
+    request_header_rules: Vec<rewrite::HeaderRule>,
+    /// Changes to make to the headers of upstreams' responses
+    response_header_rules: Vec<rewrite::HeaderRule>,
+    /// Changes to make to the paths of requests sent upstream
+    path_rewrites: Vec<rewrite::PathRewrite>,
+    /// Which methods may be used on which paths
+    method_rules: Vec<acl::MethodRule>,
+    /// Which paths need credentials, and how to check them
+    auth: auth::Auth,
+    /// Bodies to send in place of the ones we give the errors we make ourselves
+    error_pages: error_page::ErrorPages,
+    /// Proxies whose X-Forwarded-For headers we believe (see ClientInfo::for_request)
+    trusted_proxies: Vec<cidr::Cidr>,
+    /// Whether body-bearing requests without framing headers get a 411 (rather than being
+    /// forwarded with an empty body)
+    require_content_length: bool,
+    /// Whether clients may speak HTTP/2 (see --http2)
+    http2: bool,
+    /// Largest request body we pass upstream, if there's a limit
+    max_body_size: Option<u64>,
+    /// How big client requests' headers may be
+    header_limits: request::HeaderLimits,
+    /// Whether requests get a 503 instead of being forwarded (set by --maintenance and the admin
+    /// API)
+    maintenance: maintenance::Maintenance,
+    /// Set by the admin API's drain endpoint. While draining, readiness checks fail so that new
+    /// traffic goes elsewhere, but connections keep being served as usual.
+    draining: AtomicBool,
+    /// Set once we get a SIGTERM. Connections are closed as soon as their current request is done.
+    shutting_down: AtomicBool,
+    /// Number of requests we've read from clients but haven't yet sent a response to
+    in_flight_requests: AtomicUsize,
+    /// With --max-connections, a permit for each connection we can have open (see
+    /// reserve_connection_slot)
+    connection_slots: Option<Arc<tokio::sync::Semaphore>>,
+    /// How long a new connection waits for a slot
+    connection_queue_timeout: Duration,
+    /// Requests waiting for an upstream under its max_requests
+    request_queue: RequestQueue,
+    /// How long a client connection has to send each request head, if there's a limit
+    client_idle_timeout: Option<Duration>,
+    /// When we started, for the status page's uptime
+    started_at: Instant,
+    /// Counters served by the admin API's /metrics
+    metrics: metrics::Metrics,
+    /// Where to log requests to, if anywhere
+    access_log: Option<Arc<access_log::AccessLog>>,
+    /// Where to send request spans, if anywhere
+    tracer: Option<trace::Tracer>,
+    /// Whether we're proxying HTTP, raw TCP, or UDP
+    mode: Mode,
+    /// How long a UDP session lasts without traffic
+    udp_session_timeout: Duration,
+    /// How to pick an upstream for each request, unless its pool says otherwise
+    strategy: LoadBalancingStrategy,
+    /// What to speak to upstreams, unless their pool says otherwise
+    upstream_protocol: UpstreamProtocol,
+    /// Which pool of upstreams each request goes to, and the pools' own settings
+    router: routing::Router,
+    /// Number of round-robin selections made so far; the next live upstream is picked by taking
+    /// this modulo the number of live upstreams. (Pools with settings of their own have their own
+    /// counters.)
+    round_robin_counter: AtomicUsize,
+    /// Consistent hash rings over the live upstreams in each pool (None for those not in a pool),
+    /// for the ip-hash strategy. These must be rebuilt (see rebuild_hash_ring) whenever an upstream
+    /// is marked dead or alive.
+    hash_ring: Mutex<HashMap<Option<String>, HashRing>>,
+    /// Whether to route clients back to the upstream named in their bb-upstream cookie
+    sticky_sessions: bool,
+    /// When upstreams' circuit breakers trip
+    breaker_settings: breaker::Settings,
+    /// How long to wait for an upstream to answer before hedging the request (see read_hedged), if
+    /// hedging is on
+    hedge_after: Option<Duration>,
+    /// How long to wait for a connection to an upstream, if there's a limit
+    upstream_connect_timeout: Option<Duration>,
+    /// How long an upstream has to take a request and start answering it, if there's a limit
+    upstream_response_timeout: Option<Duration>,
+    /// How long revived upstreams take to ramp up to their full share of requests
+    slow_start: Duration,
+    /// With --adaptive-weights, the least fraction of its usual share of requests an upstream is
+    /// given (None if weights aren't adapted)
+    adaptive_min_share: Option<f64>,
+    /// How often to re-resolve upstreams given by DNS name, if at all
+    dns_refresh_interval: Option<Duration>,
+    /// Opens connections to upstreams (over TLS, for tls:// upstreams)
+    upstream_connector: tls::UpstreamConnector,
+    /// Upstream connections left open by earlier clients
+    upstream_pool: pool::Pool,
+    /// Addresses of servers that we are proxying to
+    upstream_addresses: RwLock<Vec<UpstreamState>>,
+    /// Clients' counters for rate_limit
+    rate_limit_store: Box<dyn rate_limit::RateLimiterStore>,
+    /// Clients exempt from rate limiting or refused outright (reloaded on SIGHUP)
+    client_lists: RwLock<rate_limit::ClientLists>,
+    /// Clients that may connect at all (reloaded on SIGHUP)
+    client_access: RwLock<acl::ClientAccess>,
+    /// How connections from clients that client_access refuses are turned away
+    denied_client_action: acl::RefusalAction,
+}
+
+#[tokio::main]
+async fn main() {
+    // Parse the command line arguments passed to this program
+    let matches = CmdOptions::command().get_matches();
+    let options = load_options(&matches);
+
+    // Initialize the logging library. You can print log messages using the `log` macros:
+    // https://docs.rs/log/0.4.8/log/ You are welcome to continue using print! statements; this
+    // just looks a little prettier.
+    logging::init(
+        options
+            .as_ref()
+            .map_or(logging::LogFormat::Text, |options| options.log_format),
+    );
+    let options = match options {
+        Ok(options) => options,
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    if options.upstream.is_empty() && options.discover.is_empty() {
+        log::error!(
+            "At least one upstream server must be specified using the --upstream option (or found \
+            using --discover)."
+        );
+        std::process::exit(1);
+    }
+    if options.max_header_size == 0 || options.max_headers == 0 {
+        log::error!("--max-header-size and --max-headers must be greater than 0");
+        std::process::exit(1);
+    }
+    if options.passive_failure_threshold == 0 || options.passive_success_threshold == 0 {
+        log::error!(
+            "--passive-failure-threshold and --passive-success-threshold must be greater than 0"
+        );
+        std::process::exit(1);
+    }
+    if options.health_check_jitter > 100 {
+        log::error!("--health-check-jitter can't be more than 100 percent");
+        std::process::exit(1);
+    }
+    if options.adaptive_min_share > 100 {
+        log::error!("--adaptive-min-share can't be more than 100 percent");
+        std::process::exit(1);
+    }
+
+    let tls_acceptor = match (&options.tls_cert, &options.tls_key) {
+        (Some(cert), Some(key)) => {
+            match tls::make_acceptor(cert, key, options.tls_client_ca.as_deref(), options.http2) {
+                Ok(acceptor) => Some(acceptor),
+                Err(err) => {
+                    log::error!("Could not set up TLS: {}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        (None, None) if options.tls_client_ca.is_some() => {
+            log::error!("--tls-client-ca requires --tls-cert and --tls-key");
+            std::process::exit(1);
+        }
+        (None, None) => None,
+        _ => {
+            log::error!("--tls-cert and --tls-key must be given together");
+            std::process::exit(1);
+        }
+    };
+
+    let access_log = match options
+        .access_log
+        .as_deref()
+        .map(access_log::AccessLog::open)
+    {
+        Some(Ok(access_log)) => Some(Arc::new(access_log)),
+        Some(Err(err)) => {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+        None => None,
+    };
+
+    let upstream_connector = match tls::UpstreamConnector::new(options.upstream_tls_ca.as_deref()) {
+        Ok(connector) => connector,
+        Err(err) => {
+            log::error!("Could not set up TLS to upstreams: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    // Start listening for connections. If systemd started us through socket activation, it has
+    // already bound our sockets, and --bind/--admin-bind are ignored. In udp mode, there are no
+    // connections to accept; datagram sockets are bound below instead.
+    let mut activated_sockets = systemd::ActivatedSockets::from_env();
+    let bind_addrs: Vec<String> = options.bind.iter().map(|bind| bind.addr.clone()).collect();
+    let mut listeners = if options.mode == Mode::Udp {
+        Vec::new()
+    } else {
+        match upgrade::bind_or_inherit(
+            &bind_addrs,
+            upgrade::LISTENER_FDS_VAR,
+            activated_sockets.take("proxy"),
+        )
+        .await
+        {
+            Ok(listeners) => listeners,
+            Err(err) => {
+                log::error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+    };
+    for listener in &listeners {
+        log::info!(
+            "Listening for requests on {}",
+            listener.local_addr().unwrap()
+        );
+    }
+
+    // Handle incoming connections
+    let mut upstreams = dns::resolve_all(options.upstream).await;
+    upstreams.extend(discovery::discover_all(&options.discover).await);
+    let hash_ring = Mutex::new(build_hash_ring(&upstreams));
+    let upstream_pools: Vec<&str> = upstreams
+        .iter()
+        .filter_map(|upstream| upstream.pool.as_deref())
+        .collect();
+    if options.canary_percent > 0.0 && options.canary_pool.is_none() {
+        log::error!("--canary-percent needs a --canary-pool to send requests to");
+        std::process::exit(1);
+    }
+    let (percent, sticky) = (options.canary_percent, options.canary_sticky);
+    let canary = options.canary_pool.map(|pool| routing::Canary {
+        pool,
+        percent,
+        sticky,
+    });
+    let percent = options.mirror_percent;
+    let mirror = options
+        .mirror_pool
+        .map(|pool| routing::Mirror { pool, percent });
+    let router =
+        match routing::Router::new(options.route, options.pool, canary, mirror, &upstream_pools) {
+            Ok(router) => router,
+            Err(err) => {
+                log::error!("{}", err);
+                std::process::exit(1);
+            }
+        };
+    let auth_options = auth::Options {
+        basic_auth_file: options.basic_auth_file.as_deref(),
+        realm: &options.auth_realm,
+        jwt_secret: options.jwt_secret.as_deref(),
+        jwt_jwks: options.jwt_jwks.as_deref(),
+        jwt_issuer: options.jwt_issuer.as_deref(),
+        jwt_audience: options.jwt_audience.as_deref(),
+    };
+    let auth = match auth::Auth::new(options.auth, auth_options) {
+        Ok(auth) => auth,
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let error_pages = match error_page::ErrorPages::new(&options.error_page) {
+        Ok(error_pages) => error_pages,
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let maintenance = match maintenance::Maintenance::new(
+        options.maintenance,
+        options.maintenance_path,
+        options.maintenance_allow,
+        options.maintenance_page.as_deref(),
+        options.maintenance_retry_after,
+    ) {
+        Ok(maintenance) => maintenance,
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let cors = cors::Settings::new(
+        &options.cors_origin,
+        &options.cors_methods,
+        options.cors_headers.as_deref(),
+        options.cors_expose_headers.as_deref(),
+        options.cors_credentials,
+        options.cors_max_age,
+    );
+    let cors = match cors {
+        Ok(cors) => cors,
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let (algorithm, max_clients) = (options.rate_limit_algorithm, options.rate_limit_max_clients);
+    let redis = options
+        .rate_limit_redis
+        .map(|address| Arc::new(redis::Client::new(address)));
+    // Each limit counts clients separately, so each gets its own store (or its own keys in Redis)
+    let make_store = |prefix: String| -> Box<dyn rate_limit::RateLimiterStore> {
+        match &redis {
+            Some(client) => Box::new(rate_limit::RedisStore::new(Arc::clone(client), prefix)),
+            None => Box::new(rate_limit::MemoryStore::new(max_clients)),
+        }
+    };
+    let route_limits = options
+        .route_rate_limit
+        .into_iter()
+        .map(|rule| {
+            let store = make_store(rate_limit::RouteLimiter::key_prefix(&rule));
+            rate_limit::RouteLimiter::new(rule, algorithm, store)
+        })
+        .collect();
+    let cache_settings = cache::Settings {
+        ttl: Duration::from_secs(options.cache_ttl),
+        max_entry_size: options.cache_max_entry_size,
+        max_size: options.cache_size,
+    };
+    let state = Arc::new(ProxyState {
+        upstream_connector,
+        upstream_pool: pool::Pool::new(
+            options.upstream_max_idle,
+            Duration::from_secs(options.upstream_idle_timeout),
+        ),
+        upstream_addresses: RwLock::new(upstreams),
+        rate_limit_store: make_store(String::new()),
+        client_lists: RwLock::new(rate_limit::ClientLists {
+            allow: options.rate_limit_allow,
+            deny: options.rate_limit_deny,
+        }),
+        client_access: RwLock::new(acl::ClientAccess {
+            allow: options.allow_client,
+            deny: options.deny_client,
+        }),
+        denied_client_action: options.denied_client_action,
+        active_health_check_interval: options.active_health_check_interval,
+        health_check_jitter: f64::from(options.health_check_jitter) / 100.0,
+        active_health_check_path: options.active_health_check_path,
+        health_check_method: options.health_check_method,
+        health_check_headers: options.health_check_header,
+        health_check_request_body: options.health_check_request_body,
+        health_check_timeout: Duration::from_secs(options.health_check_timeout),
+        health_check_status: options.health_check_status,
+        health_check_body: options.health_check_body,
+        max_revivals_per_health_check: options.max_revivals_per_health_check,
+        passive_failure_threshold: options.passive_failure_threshold,
+        passive_success_threshold: options.passive_success_threshold,
+        rate_limit: Some(rate_limit::Limit {
+            per_minute: options.max_requests_per_minute,
+            burst: options
+                .rate_limit_burst
+                .unwrap_or(options.max_requests_per_minute),
+            algorithm: options.rate_limit_algorithm,
+        })
+        .filter(|limit| limit.per_minute > 0),
+        route_limits,
+        rate_limit_header: options.rate_limit_header,
+        rate_limit_json_errors: options.rate_limit_json_errors,
+        throttles: Some(options.client_bandwidth)
+            .filter(|&bandwidth| bandwidth > 0)
+            .map(throttle::Throttles::new),
+        cache: Some(cache_settings)
+            .filter(|settings| settings.max_size > 0)
+            .map(cache::Cache::new),
+        compression: compress::Settings::new(
+            options.compress_gzip,
+            options.compress_deflate,
+            options.compress_min_size,
+            &options.compress_type,
+        ),
+        cors,
+        forwarded_header_style: options.forwarded_header_style,
+        request_header_rules: options.request_header,
+        response_header_rules: options.response_header,
+        path_rewrites: options.rewrite_path,
+        method_rules: options.method_rule,
+        auth,
+        error_pages,
+        trusted_proxies: options.trusted_proxies,
+        require_content_length: options.require_content_length,
+        http2: options.http2,
+        max_body_size: Some(options.max_body_size).filter(|&size| size > 0),
+        header_limits: request::HeaderLimits {
+            max_size: options.max_header_size,
+            max_count: options.max_headers,
+        },
+        maintenance,
+        draining: AtomicBool::new(false),
+        shutting_down: AtomicBool::new(false),
+        in_flight_requests: AtomicUsize::new(0),
+        connection_slots: Some(options.max_connections)
+            .filter(|&max| max > 0)
+            .map(|max| Arc::new(tokio::sync::Semaphore::new(max))),
+        connection_queue_timeout: Duration::from_millis(options.connection_queue_timeout),
+        client_idle_timeout: Some(Duration::from_secs(options.client_idle_timeout))
+            .filter(|timeout| *timeout > Duration::from_secs(0)),
+        request_queue: RequestQueue::new(
+            options.request_queue_size,
+            Duration::from_millis(options.request_queue_timeout),
+        ),
+        started_at: Instant::now(),
+        metrics: metrics::Metrics::default(),
+        access_log,
+        tracer: options.otlp_endpoint.clone().map(trace::Tracer::start),
+        mode: options.mode,
+        udp_session_timeout: Duration::from_secs(options.udp_session_timeout.max(1)),
+        strategy: options.strategy,
+        upstream_protocol: options.upstream_protocol,
+        router,
+        round_robin_counter: AtomicUsize::new(0),
+        hash_ring,
+        sticky_sessions: options.sticky_sessions,
+        breaker_settings: breaker::Settings {
+            max_failures: options.breaker_failures,
+            max_error_rate: Some(options.breaker_error_rate)
+                .filter(|&percent| percent > 0)
+                .map(|percent| f64::from(percent) / 100.0),
+            open_time: Duration::from_secs(options.breaker_open_time),
+        },
+        hedge_after: Some(Duration::from_millis(options.hedge_after))
+            .filter(|hedge_after| *hedge_after > Duration::from_secs(0)),
+        upstream_connect_timeout: Some(Duration::from_secs(options.upstream_connect_timeout))
+            .filter(|timeout| *timeout > Duration::from_secs(0)),
+        upstream_response_timeout: Some(Duration::from_secs(options.upstream_response_timeout))
+            .filter(|timeout| *timeout > Duration::from_secs(0)),
+        slow_start: Duration::from_secs(options.slow_start),
+        adaptive_min_share: match options.adaptive_weights {
+            true => Some(f64::from(options.adaptive_min_share) / 100.0),
+            false => None,
+        },
+        dns_refresh_interval: Some(Duration::from_secs(options.dns_refresh_interval))
+            .filter(|interval| *interval > Duration::from_secs(0)),
+    });
+
+    // The admin API is served if it was asked for, or if we were given a socket for it
+    let mut admin_listener_fd = None;
+    let admin_activated_fds = activated_sockets.take("admin");
+    if options.admin_bind.is_some()
+        || !admin_activated_fds.is_empty()
+        || upgrade::has_inherited(upgrade::ADMIN_LISTENER_FDS_VAR)
+    {
+        let admin_binds: Vec<String> = options.admin_bind.iter().cloned().collect();
+        let admin_listener = match upgrade::bind_or_inherit(
+            &admin_binds,
+            upgrade::ADMIN_LISTENER_FDS_VAR,
+            admin_activated_fds,
+        )
+        .await
+        {
+            Ok(mut listeners) if !listeners.is_empty() => listeners.remove(0),
+            Ok(_) => {
+                log::error!("No socket to serve the admin API on");
+                std::process::exit(1);
+            }
+            Err(err) => {
+                log::error!("Admin API: {}", err);
+                std::process::exit(1);
+            }
+        };
+        log::info!(
+            "Serving admin API on {}",
+            admin_listener.local_addr().unwrap()
+        );
+        admin_listener_fd = Some(admin_listener.as_raw_fd());
+        tokio::spawn(admin::serve(admin_listener, Arc::clone(&state)));
+    }
+
+    if state.mode == Mode::Udp {
+        let sockets = match udp::bind(&bind_addrs).await {
+            Ok(sockets) => sockets,
+            Err(err) => {
+                log::error!("{}", err);
+                std::process::exit(1);
+            }
+        };
+        for socket in sockets {
+            log::info!(
+                "Listening for UDP datagrams on {}",
+                socket.local_addr().unwrap()
+            );
+            tokio::spawn(udp::serve(socket, Arc::clone(&state)));
+        }
+    }
+
+    let shared_state = Arc::clone(&state);
+    tokio::spawn(async move {
+        active_health_check(&shared_state).await;
+    });
+
+    if !options.discover.is_empty() {
+        let shared_state = Arc::clone(&state);
+        let sources = options.discover.clone();
+        let interval = Duration::from_secs(options.discovery_interval.max(1));
+        tokio::spawn(async move {
+            discovery::refresh(&shared_state, &sources, interval).await;
+        });
+    }
+
+    if let Some(interval) = state.dns_refresh_interval {
+        let shared_state = Arc::clone(&state);
+        tokio::spawn(async move {
+            dns::refresh(&shared_state, interval).await;
+        });
+    }
+
+    let shared_state = Arc::clone(&state);
+    let shared_matches = matches.clone();
+    tokio::spawn(async move {
+        reload_on_signal(
+            &shared_state,
+            &shared_matches,
+            SignalKind::hangup(),
+            Reload::Upstreams,
+        )
+        .await;
+    });
+
+    let shared_state = Arc::clone(&state);
+    tokio::spawn(async move {
+        reload_on_signal(
+            &shared_state,
+            &matches,
+            SignalKind::user_defined1(),
+            Reload::DrainStates,
+        )
+        .await;
+    });
+
+    if let Some(access_log) = &state.access_log {
+        tokio::spawn(access_log::reopen_on_signal(Arc::clone(access_log)));
+    }
+
+    if state.rate_limit.is_some() || !state.route_limits.is_empty() {
+        let shared_state = Arc::clone(&state);
+        tokio::spawn(async move {
+            clear_rate_limit(&shared_state).await;
+        });
+    }
+
+    let mut terminations = match signal(SignalKind::terminate()) {
+        Ok(terminations) => terminations,
+        Err(err) => {
+            log::error!("Could not install SIGTERM handler: {}", err);
+            std::process::exit(1);
+        }
+    };
+    // SIGUSR2 upgrades balancebeam in place: we start a new process from the binary at our path,
+    // hand it our listening sockets, and once it says it's accepting connections, shut down just
+    // like for SIGTERM. Until then (or if it exits instead), we carry on serving.
+    let mut upgrades = match signal(SignalKind::user_defined2()) {
+        Ok(upgrades) => upgrades,
+        Err(err) => {
+            log::error!("Could not install SIGUSR2 handler: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let (handover_tx, mut handovers) = tokio::sync::mpsc::unbounded_channel();
+    let mut upgrading = false;
+    upgrade::notify_predecessor();
+    loop {
+        let accepted = tokio::select! {
+            accepted = accept_any(&mut listeners) => accepted,
+            _ = terminations.recv() => {
+                log::info!("Received SIGTERM");
+                break;
+            }
+            _ = upgrades.recv() => {
+                if state.mode == Mode::Udp {
+                    log::error!("In-place upgrades aren't supported in udp mode");
+                    continue;
+                }
+                if upgrading {
+                    log::warn!("Already waiting for a new balancebeam process to be ready");
+                    continue;
+                }
+                match upgrade::spawn_successor(&listeners, admin_listener_fd) {
+                    Ok(successor) => {
+                        let pid = successor.pid();
+                        log::info!(
+                            "Started new balancebeam process {}, waiting for it to be ready",
+                            pid
+                        );
+                        upgrading = true;
+                        let handover_tx = handover_tx.clone();
+                        tokio::task::spawn_blocking(move || {
+                            let _ = handover_tx.send((pid, successor.wait_ready()));
+                        });
+                        continue;
+                    }
+                    Err(err) => {
+                        log::error!("Could not start new balancebeam process: {}", err);
+                        continue;
+                    }
+                }
+            }
+            Some((pid, ready)) = handovers.recv() => {
+                upgrading = false;
+                match ready {
+                    Ok(()) => {
+                        log::info!("New balancebeam process {} is ready, handing over", pid);
+                        break;
+                    }
+                    Err(err) => {
+                        log::error!(
+                            "New balancebeam process {} didn't take over: {}. Carrying on.",
+                            pid,
+                            err
+                        );
+                        continue;
+                    }
+                }
+            }
+        };
+        let (mut stream, listener_idx) = match accepted {
+            Ok((socket, addr, listener_idx)) => {
+                log::debug!("Accepted connection from {}", addr);
+                (socket, listener_idx)
+            }
+            Err(err) => {
+                log::error!("Couldn't get client: {}", err);
+                continue;
+            }
+        };
+        let connection_slot = match reserve_connection_slot(&state).await {
+            Ok(slot) => slot,
+            Err(()) => {
+                reject_connection(stream, &state, tls_acceptor.is_none()).await;
+                continue;
+            }
+        };
+        // Listeners we were handed by systemd may not match up with --bind
+        let expects_proxy_protocol = options
+            .bind
+            .get(listener_idx)
+            .is_some_and(|bind| bind.proxy_protocol);
+
+        let shared_state = Arc::clone(&state);
+        let tls_acceptor = tls_acceptor.clone();
+        tokio::spawn(async move {
+            let _connection_slot = connection_slot;
+            // The PROXY protocol header comes before anything else, TLS handshake included
+            let origin = if expects_proxy_protocol {
+                match proxy_protocol::read_header(&mut stream).await {
+                    Ok(origin) => origin,
+                    Err(err) => {
+                        log::info!(
+                            "Dropping connection from {}: {}",
+                            stream
+                                .peer_addr()
+                                .map_or("?".to_string(), |addr| addr.to_string()),
+                            err
+                        );
+                        return;
+                    }
+                }
+            } else {
+                None
+            };
+            let client_ip = match &origin {
+                Some(origin) => Some(origin.source.ip()),
+                None => stream.peer_addr().ok().map(|addr| addr.ip()),
+            };
+            if let Some(client_ip) = client_ip {
+                if !shared_state.client_access.read().await.permits(client_ip) {
+                    refuse_client(stream, client_ip, &shared_state, tls_acceptor.is_none()).await;
+                    return;
+                }
+            }
+            match tls_acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => {
+                        let http2 = tls::negotiated_http2(&stream);
+                        let stream = proxy_protocol::Proxied::new(stream, origin);
+                        serve_client(stream, http2, &shared_state).await
+                    }
+                    Err(err) => log::info!("TLS handshake with client failed: {}", err),
+                },
+                None => {
+                    let stream = proxy_protocol::Proxied::new(stream, origin);
+                    serve_client(stream, false, &shared_state).await
+                }
+            }
+        });
+    }
+
+    // Stop accepting connections, then give in-flight requests a chance to finish
+    drop(listeners);
+    shut_down(&state, Duration::from_secs(options.shutdown_timeout)).await;
+}
+
+/// Serves a newly-accepted client connection, however --mode says to. http2 is whether the client
+/// chose HTTP/2 during its TLS handshake.
+async fn serve_client<S: ClientStream + 'static>(
+    client_conn: S,
+    http2: bool,
+    state: &Arc<ProxyState>,
+) {
+    let _connection = metrics::OpenConnection::new(&state.metrics);
+    match state.mode {
+        Mode::Tcp => proxy_tcp_connection(client_conn, state).await,
+        _ if http2 => http2::handle_connection(client_conn, state).await,
+        _ => handle_connection(client_conn, state).await,
+    }
+}
+
+/// Returns true if a client has started speaking HTTP/2 to us without asking first (as gRPC clients
+/// do over cleartext), by sending the HTTP/2 connection preface where a request would go. What's
+/// been read is left in the buffer, for whichever protocol turns out to be right. Returns None if
+/// the client sent nothing within --client-idle-timeout.
+async fn starts_with_http2_preface<S: ClientStream>(
+    state: &ProxyState,
+    client_conn: &mut BufReader<S>,
+) -> Option<bool> {
+    use tokio::io::AsyncBufRead;
+    // An HTTP/1 request can't start this way, since PRI isn't a method
+    const PREFACE_START: &[u8] = b"PRI * HTTP/2.0";
+    let deadline = state
+        .client_idle_timeout
+        .map(|timeout| Instant::now() + timeout);
+    let peek = std::future::poll_fn(|cx| {
+        std::pin::Pin::new(&mut *client_conn)
+            .poll_fill_buf(cx)
+            .map_ok(|buffered| buffered.starts_with(PREFACE_START))
+    });
+    // Errors are left for whoever reads from the connection next to run into
+    until(deadline, peek)
+        .await
+        .map(|peeked| peeked.unwrap_or(false))
+}
+
+/// Takes one of the --max-connections slots for a new connection, waiting up to
+/// --connection-queue-timeout for one if they're all taken. The slot is freed when the returned
+/// permit is dropped (there's no permit without a limit). Returns an error if no slot came free.
+async fn reserve_connection_slot(
+    state: &ProxyState,
+) -> Result<Option<tokio::sync::OwnedSemaphorePermit>, ()> {
+    let slots = match &state.connection_slots {
+        Some(slots) => slots,
+        None => return Ok(None),
+    };
+    if let Ok(permit) = Arc::clone(slots).try_acquire_owned() {
+        return Ok(Some(permit));
+    }
+    if state.connection_queue_timeout == Duration::from_secs(0) {
+        return Err(());
+    }
+    // This holds up the accept loop, rather than waiting in a task of its own, so that an accept
+    // storm queues up in the kernel's listen backlog instead of in tasks that use up our memory
+    log::debug!("All connection slots are taken; waiting for one");
+    tokio::time::timeout(
+        state.connection_queue_timeout,
+        Arc::clone(slots).acquire_owned(),
+    )
+    .await
+    .map(Some)
+    .map_err(|_| ())
+}
+
+/// Turns away a connection we have no room for. A plain HTTP client gets a 503 first; anything else
+/// (TLS, or --mode tcp) is just closed, since we can't say anything to it without doing the work of
+/// serving it.
+async fn reject_connection(mut stream: TcpStream, state: &ProxyState, plain_http: bool) {
+    log::debug!("Turning away a connection: no connection slots are free");
+    state.metrics.record_rejected_connection();
+    if plain_http && state.mode != Mode::Tcp {
+        send_rejection(state, &mut stream, http::StatusCode::SERVICE_UNAVAILABLE).await;
+    }
+}
+
+/// Turns away a connection from a client that --allow-client and --deny-client don't let in. A
+/// plain HTTP client gets a 403 first, unless --denied-client-action is drop.
+async fn refuse_client(mut stream: TcpStream, ip: IpAddr, state: &ProxyState, plain_http: bool) {
+    log::info!("Refusing connection from denied client {}", ip);
+    if plain_http
+        && state.mode != Mode::Tcp
+        && state.denied_client_action == acl::RefusalAction::Forbidden
+    {
+        send_rejection(state, &mut stream, http::StatusCode::FORBIDDEN).await;
+    }
+}
+
+/// Answers a connection we're turning away with an error, before closing it
+async fn send_rejection(state: &ProxyState, stream: &mut TcpStream, status: http::StatusCode) {
+    let mut response = response::make_http_error(status);
+    state.error_pages.apply(None, &mut response);
+    response.headers_mut().insert(
+        http::header::CONNECTION,
+        http::HeaderValue::from_static("close"),
+    );
+    // The response fits in the socket's send buffer, so this only waits if something's wrong
+    let write = response::write_to_stream(&response, stream);
+    let _ = tokio::time::timeout(REJECT_TIMEOUT, write).await;
+}
+
+/// Waits for a client to connect to any of listeners, and returns the connection along with the
+/// index of the listener it came in on
+async fn accept_any(
+    listeners: &mut [TcpListener],
+) -> std::io::Result<(TcpStream, SocketAddr, usize)> {
+    std::future::poll_fn(|cx| {
+        for (idx, listener) in listeners.iter_mut().enumerate() {
+            if let Poll::Ready(accepted) = listener.poll_accept(cx) {
+                return Poll::Ready(accepted.map(|(stream, addr)| (stream, addr, idx)));
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// Called on SIGTERM (or once we've handed over to a new process), after we've stopped accepting
+/// connections. Waits up to timeout for in-flight requests to finish, then exits. Idle keep-alive
+/// connections are simply closed.
+async fn shut_down(state: &ProxyState, timeout: Duration) {
+    state.shutting_down.store(true, Ordering::SeqCst);
+    let deadline = Instant::now() + timeout;
+    log::info!(
+        "Shutting down, waiting up to {:?} for {} in-flight requests",
+        timeout,
+        state.in_flight_requests.load(Ordering::SeqCst)
+    );
+    while state.in_flight_requests.load(Ordering::SeqCst) > 0 {
+        if Instant::now() >= deadline {
+            log::warn!(
+                "Shutdown timeout reached; abandoning {} in-flight requests",
+                state.in_flight_requests.load(Ordering::SeqCst)
+            );
+            std::process::exit(1);
+        }
+        delay_for(Duration::from_millis(50)).await;
+    }
+    log::info!("All requests finished, exiting");
+    std::process::exit(0);
+}
+
+/// Counts a request towards ProxyState::in_flight_requests for as long as it's alive
+struct InFlightRequest<'a> {
+    in_flight_requests: &'a AtomicUsize,
+}
+
+impl InFlightRequest<'_> {
+    fn new(state: &ProxyState) -> InFlightRequest<'_> {
+        state.in_flight_requests.fetch_add(1, Ordering::SeqCst);
+        InFlightRequest {
+            in_flight_requests: &state.in_flight_requests,
+        }
+    }
+}
+
+impl Drop for InFlightRequest<'_> {
+    fn drop(&mut self) {
+        self.in_flight_requests.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Builds our options from the command line, filling in whatever it leaves out from the --config
+/// file (if one was given)
+fn load_options(matches: &ArgMatches) -> Result<CmdOptions, String> {
+    let mut options = CmdOptions::from_arg_matches(matches).map_err(|err| err.to_string())?;
+    if let Some(path) = options.config.clone() {
+        config::load(&path)
+            .and_then(|config| config.apply(&mut options, matches))
+            .map_err(|err| format!("Could not load config file {}: {}", path, err))?;
+    }
+    Ok(options)
+}
+
+/// Which parts of the configuration a signal reloads. (Other settings are only read at startup.)
+#[derive(Debug, Clone, Copy)]
+enum Reload {
+    /// SIGHUP: bring the whole upstream list (and the client allow and deny lists) in line with
+    /// the config
+    Upstreams,
+    /// SIGUSR1: only pick up which upstreams the config says are draining
+    DrainStates,
+}
+
+/// Re-reads the config file whenever we get the given signal, and applies the new configuration
+async fn reload_on_signal(
+    state: &Arc<ProxyState>,
+    matches: &ArgMatches,
+    kind: SignalKind,
+    reload: Reload,
+) {
+    let mut signals = match signal(kind) {
+        Ok(signals) => signals,
+        Err(err) => {
+            log::error!("Could not install {:?} signal handler: {}", reload, err);
+            return;
+        }
+    };
+    while signals.recv().await.is_some() {
+        log::info!("Received signal, reloading configuration ({:?})", reload);
+        match load_options(matches) {
+            Ok(options) if options.upstream.is_empty() && options.discover.is_empty() => {
+                log::error!("Not reloading: the new configuration has no upstreams")
+            }
+            Ok(options) => {
+                let client_lists = rate_limit::ClientLists {
+                    allow: options.rate_limit_allow,
+                    deny: options.rate_limit_deny,
+                };
+                let client_access = acl::ClientAccess {
+                    allow: options.allow_client,
+                    deny: options.deny_client,
+                };
+                let upstreams = dns::resolve_all(options.upstream).await;
+                match reload {
+                    Reload::Upstreams => {
+                        reload_upstreams(state, upstreams).await;
+                        reload_client_lists(state, client_lists).await;
+                        reload_client_access(state, client_access).await;
+                    }
+                    Reload::DrainStates => reload_drain_states(state, &upstreams).await,
+                }
+            }
+            Err(err) => log::error!("Not reloading: {}", err),
+        }
+    }
+}
+
+/// Replaces the rate limit allow and deny lists
+async fn reload_client_lists(state: &ProxyState, client_lists: rate_limit::ClientLists) {
+    let mut w_client_lists = state.client_lists.write().await;
+    if *w_client_lists != client_lists {
+        log::info!(
+            "Rate limit lists changed: {} allowed and {} denied blocks",
+            client_lists.allow.len(),
+            client_lists.deny.len()
+        );
+        *w_client_lists = client_lists;
+    }
+}
+
+/// Replaces the lists of clients that may connect
+async fn reload_client_access(state: &ProxyState, client_access: acl::ClientAccess) {
+    let mut w_client_access = state.client_access.write().await;
+    if *w_client_access != client_access {
+        log::info!(
+            "Client access lists changed: {} allowed and {} denied blocks",
+            client_access.allow.len(),
+            client_access.deny.len()
+        );
+        *w_client_access = client_access;
+    }
+}
+
+/// Starts or stops draining each upstream that's in both the current list and new_upstreams, to
+/// match new_upstreams
+async fn reload_drain_states(state: &ProxyState, new_upstreams: &[UpstreamState]) {
+    let mut w_upstream_addresses = state.upstream_addresses.write().await;
+    for upstream in w_upstream_addresses.iter_mut() {
+        if let Some(new) = new_upstreams.iter().find(|new| new.addr == upstream.addr) {
+            set_draining(upstream, new.draining);
+        }
+    }
+    rebuild_hash_ring(state, &w_upstream_addresses);
+}
+
+/// Starts or stops draining an upstream, logging the change
+fn set_draining(upstream: &mut UpstreamState, draining: bool) {
+    if upstream.draining == draining {
+        return;
+    }
+    if draining {
+        log::info!(
+            "Draining upstream {} ({} connections left)",
+            upstream.addr,
+            upstream.active_connections.load(Ordering::SeqCst)
+        );
+    } else {
+        log::info!("Upstream {} is no longer draining", upstream.addr);
+    }
+    upstream.draining = draining;
+}
+
+/// Replaces the upstream list with new_upstreams. Upstreams we already had keep their health and
+/// connection stats (only their weight, TLS settings, request cap, and drain state are updated).
+/// Removed upstreams stop getting new connections, but clients already connected to them are
+/// served until they hang up.
+async fn reload_upstreams(state: &ProxyState, new_upstreams: Vec<UpstreamState>) {
+    let mut w_upstream_addresses = state.upstream_addresses.write().await;
+    for upstream in w_upstream_addresses.iter() {
+        if upstream.discovered_by.is_none()
+            && !new_upstreams.iter().any(|new| new.addr == upstream.addr)
+        {
+            log::info!(
+                "Removing upstream {} ({} connections left to drain)",
+                upstream.addr,
+                upstream.active_connections.load(Ordering::SeqCst)
+            );
+        }
+    }
+    // Discovered upstreams aren't in the config, so they're kept as they are
+    let mut upstreams: Vec<UpstreamState> = w_upstream_addresses
+        .iter()
+        .filter(|upstream| upstream.discovered_by.is_some())
+        .cloned()
+        .collect();
+    for new in new_upstreams {
+        match w_upstream_addresses
+            .iter()
+            .find(|upstream| upstream.addr == new.addr)
+        {
+            Some(existing) => {
+                let mut upstream = UpstreamState {
+                    weight: new.weight,
+                    tier: new.tier,
+                    pool: new.pool,
+                    tls: new.tls,
+                    proxy_protocol: new.proxy_protocol,
+                    health_check_interval: new.health_check_interval,
+                    max_requests: new.max_requests,
+                    ..existing.clone()
+                };
+                set_draining(&mut upstream, new.draining);
+                upstreams.push(upstream);
+            }
+            None => {
+                log::info!("Adding upstream {}", new.addr);
+                upstreams.push(new);
+            }
+        }
+    }
+    *w_upstream_addresses = upstreams;
+    rebuild_hash_ring(state, &w_upstream_addresses);
+}
+
+/// Explains how connect_to_upstream picked the upstream it connected to. This is logged for each
+/// request, which makes it much easier to diagnose uneven load.
+#[derive(Debug)]
+struct UpstreamSelection {
+    /// Name of the selection algorithm that was used
+    strategy: &'static str,
+    /// Upstreams that were alive and not draining (and therefore eligible) when selection started
+    candidates: Vec<Candidate>,
+    /// Upstreams we picked but then failed to connect to (these count towards marking them dead, and
+    /// we retry with another)
+    failed: Vec<String>,
+}
+
+impl UpstreamSelection {
+    /// Starts a selection among the upstreams for which eligible returns true
+    fn new(
+        strategy: &'static str,
+        upstreams: &[UpstreamState],
+        eligible: impl Fn(&UpstreamState) -> bool,
+    ) -> UpstreamSelection {
+        UpstreamSelection {
+            strategy,
+            candidates: upstreams
+                .iter()
+                .filter(|upstream| eligible(upstream))
+                .map(|upstream| Candidate {
+                    addr: upstream.addr.clone(),
+                    active_connections: upstream.active_connections.load(Ordering::SeqCst),
+                    weight: upstream.weight,
+                })
+                .collect(),
+            failed: Vec::new(),
+        }
+    }
+}
+
+/// An upstream as it stood when an UpstreamSelection started: the in-flight count and weight are
+/// what least-connections and random selection go by
+#[derive(Debug)]
+struct Candidate {
+    addr: String,
+    active_connections: usize,
+    weight: usize,
+}
+
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}(active={} weight={})",
+            self.addr, self.active_connections, self.weight
+        )
+    }
+}
+
+impl fmt::Display for UpstreamSelection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "strategy={} candidates=[{}] failed=[{}]",
+            self.strategy,
+            self.candidates
+                .iter()
+                .map(Candidate::to_string)
+                .collect::<Vec<String>>()
+                .join(", "),
+            self.failed.join(", ")
+        )
+    }
+}
+
+fn build_hash_ring(upstreams: &[UpstreamState]) -> HashMap<Option<String>, HashRing> {
+    let mut pools: Vec<Option<&str>> = upstreams
+        .iter()
+        .map(|upstream| upstream.pool.as_deref())
+        .collect();
+    pools.sort_unstable();
+    pools.dedup();
+    pools
+        .into_iter()
+        .map(|pool| {
+            let ring = HashRing::new(
+                upstreams
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, upstream)| {
+                        upstream.accepts_connections() && upstream.pool.as_deref() == pool
+                    })
+                    .map(|(idx, upstream)| (idx, upstream.addr.as_str())),
+            );
+            (pool.map(str::to_string), ring)
+        })
+        .collect()
+}
+
+/// Value of the sticky-session cookie for an upstream. This is a hash of the upstream's address,
+/// rather than the address itself, so that we don't tell clients about our internal network. It
+/// doesn't depend on the upstream's position in the list, so it stays valid as upstreams come and
+/// go.
+fn sticky_cookie_value(upstream_addr: &str) -> String {
+    format!("{:016x}", hash_ring::hash(&upstream_addr))
+}
+
+/// Rebuilds the hash ring after upstreams have been marked dead or alive. Callers should still hold
+/// the upstream_addresses write lock, so that nobody selects an upstream using a stale ring.
+fn rebuild_hash_ring(state: &ProxyState, upstreams: &[UpstreamState]) {
+    *state.hash_ring.lock() = build_hash_ring(upstreams);
+}
+
+/// Picks one of the upstreams in pool for which eligible returns true (and that selection hasn't
+/// recorded as failed), or returns None if there are none. If pinned is given (the client's
+/// sticky-session cookie) and names one of them, that upstream is used; otherwise the pool's
+/// strategy (or the configured one) decides.
+async fn select_upstream(
+    state: &ProxyState,
+    client_ip: IpAddr,
+    pool: Option<&str>,
+    pinned: Option<&str>,
+    eligible: impl Fn(&UpstreamState) -> bool,
+    selection: &mut UpstreamSelection,
+    rng: &mut rand::rngs::StdRng,
+) -> Option<UpstreamState> {
+    let r_upstream_addresses = state.upstream_addresses.read().await;
+    let mut alive: Vec<usize> = (0..r_upstream_addresses.len())
+        .filter(|&idx| {
+            eligible(&r_upstream_addresses[idx])
+                && r_upstream_addresses[idx].pool.as_deref() == pool
+                && !selection.failed.contains(&r_upstream_addresses[idx].addr)
+        })
+        .collect();
+    if alive.is_empty() {
+        return None;
+    }
+    // Backups are only used once no primary upstream is left
+    if alive
+        .iter()
+        .any(|&idx| r_upstream_addresses[idx].tier == Tier::Primary)
+    {
+        alive.retain(|&idx| r_upstream_addresses[idx].tier == Tier::Primary);
+    }
+    // Leave out each slow-starting upstream with a chance that shrinks as it ramps up, so
+    // that whatever the strategy, it gets that fraction of its usual share. Upstreams that are
+    // doing worse than the others are thinned out the same way under --adaptive-weights. (They're
+    // kept if nothing else is left.)
+    let now = Instant::now();
+    let scores: Vec<f64> = match state.adaptive_min_share {
+        Some(_) => alive
+            .iter()
+            .map(|&idx| {
+                let upstream = &r_upstream_addresses[idx];
+                let latency = upstream.latency.lock().estimate(now);
+                adaptive::score(latency, upstream.error_rate.lock().estimate(now))
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    let best_score = scores.iter().copied().fold(0.0, f64::max);
+    let warm: Vec<usize> = alive
+        .iter()
+        .enumerate()
+        .filter(|&(i, &idx)| {
+            let mut share = r_upstream_addresses[idx].slow_start_share(state.slow_start, now);
+            if let Some(min_share) = state.adaptive_min_share {
+                share *= adaptive::share(scores[i], best_score, min_share);
+            }
+            share >= 1.0 || rng.gen::<f64>() < share
+        })
+        .map(|(_, &idx)| idx)
+        .collect();
+    if !warm.is_empty() {
+        alive = warm;
+    }
+    let count = |idx: usize| {
+        r_upstream_addresses[idx]
+            .active_connections
+            .load(Ordering::SeqCst)
+    };
+    let pinned_idx = pinned.and_then(|cookie| {
+        alive
+            .iter()
+            .copied()
+            .find(|&idx| sticky_cookie_value(&r_upstream_addresses[idx].addr) == cookie)
+    });
+    let pool_settings = state.router.pool(pool);
+    let strategy = pool_settings
+        .and_then(|pool| pool.strategy)
+        .unwrap_or(state.strategy);
+    let upstream_idx = if let Some(idx) = pinned_idx {
+        selection.strategy = "sticky";
+        idx
+    } else {
+        match strategy {
+            LoadBalancingStrategy::Random => {
+                let weight = |idx: usize| r_upstream_addresses[idx].weight;
+                let total_weight: usize = alive.iter().map(|&idx| weight(idx)).sum();
+                // Walk the upstreams until we reach the one whose share of the total weight
+                // contains our random number
+                let mut remaining = rng.gen_range(0, total_weight);
+                let mut chosen = alive[0];
+                for &idx in &alive {
+                    if remaining < weight(idx) {
+                        chosen = idx;
+                        break;
+                    }
+                    remaining -= weight(idx);
+                }
+                chosen
+            }
+            LoadBalancingStrategy::RoundRobin => {
+                let counter = pool_settings
+                    .map_or(&state.round_robin_counter, |pool| &pool.round_robin_counter);
+                alive[counter.fetch_add(1, Ordering::SeqCst) % alive.len()]
+            }
+            LoadBalancingStrategy::LeastConnections => {
+                let fewest = alive.iter().map(|&idx| count(idx)).min().unwrap();
+                let least_loaded: Vec<usize> = alive
+                    .into_iter()
+                    .filter(|&idx| count(idx) == fewest)
+                    .collect();
+                least_loaded[rng.gen_range(0, least_loaded.len())]
+            }
+            LoadBalancingStrategy::LeastLatency => {
+                // Weight each upstream by the inverse of its response time, so an upstream
+                // that's twice as fast gets twice as many connections
+                let now = Instant::now();
+                let weights: Vec<f64> = alive
+                    .iter()
+                    .map(|&idx| {
+                        let latency = r_upstream_addresses[idx].latency.lock().estimate(now);
+                        1.0 / (latency + LATENCY_FLOOR_SECS)
+                    })
+                    .collect();
+                let mut remaining = rng.gen::<f64>() * weights.iter().sum::<f64>();
+                let mut chosen = alive[alive.len() - 1];
+                for (&idx, weight) in alive.iter().zip(weights) {
+                    if remaining < weight {
+                        chosen = idx;
+                        break;
+                    }
+                    remaining -= weight;
+                }
+                chosen
+            }
+            // The ring doesn't know about circuit breakers, so the upstream it picks may not
+            // be one we can use
+            LoadBalancingStrategy::IpHash => state
+                .hash_ring
+                .lock()
+                .get(&pool.map(str::to_string))
+                .and_then(|ring| ring.get(&client_ip.to_string()))
+                .filter(|idx| alive.contains(idx))
+                .unwrap_or(alive[0]),
+            LoadBalancingStrategy::PowerOfTwoChoices => {
+                let first = rng.gen_range(0, alive.len());
+                if alive.len() == 1 {
+                    alive[first]
+                } else {
+                    // Pick a second, different upstream
+                    let second = (first + rng.gen_range(1, alive.len())) % alive.len();
+                    if count(alive[second]) < count(alive[first]) {
+                        alive[second]
+                    } else {
+                        alive[first]
+                    }
+                }
+            }
+        }
+    };
+    Some(r_upstream_addresses[upstream_idx].clone())
+}
+
+/// Picks an upstream in pool (or, for None, one that isn't in a pool) and connects to it on the
+/// client's behalf. If pinned is given (the client's sticky-session cookie) and names a live
+/// upstream, that upstream is used; otherwise the pool's strategy decides. The upstream named by
+/// avoid (if any) is never picked.
+async fn connect_to_upstream(
+    state: &Arc<ProxyState>,
+    client: &ClientInfo,
+    pool: Option<&str>,
+    pinned: Option<&str>,
+    avoid: Option<&str>,
+    may_queue: bool,
+) -> Result<(UpstreamConn, UpstreamSelection, ActiveConnection), ConnectError> {
+    let mut rng = rand::rngs::StdRng::from_entropy();
+    let eligible = |upstream: &UpstreamState| {
+        upstream.takes_requests(Instant::now())
+            && upstream.pool.as_deref() == pool
+            && Some(upstream.addr.as_str()) != avoid
+    };
+    let strategy = state
+        .router
+        .pool(pool)
+        .and_then(|pool| pool.strategy)
+        .unwrap_or(state.strategy);
+    let mut selection = UpstreamSelection::new(
+        strategy.name(),
+        &state.upstream_addresses.read().await,
+        eligible,
+    );
+
+    let mut queue_place = None;
+    // Whether connecting to any upstream timed out, in which case running out of upstreams is a 504
+    let mut timed_out = false;
+    loop {
+        let upstream = match select_upstream(
+            state,
+            client.ip,
+            pool,
+            pinned,
+            &eligible,
+            &mut selection,
+            &mut rng,
+        )
+        .await
+        {
+            Some(upstream) => upstream,
+            None => {
+                // If some upstream would take the request once it has room, wait for that
+                let now = Instant::now();
+                let busy = state
+                    .upstream_addresses
+                    .read()
+                    .await
+                    .iter()
+                    .any(|upstream| {
+                        upstream.is_busy(now)
+                            && upstream.pool.as_deref() == pool
+                            && Some(upstream.addr.as_str()) != avoid
+                            && !selection.failed.contains(&upstream.addr)
+                    });
+                if !busy {
+                    return Err(if timed_out {
+                        ConnectError::Timeout
+                    } else {
+                        ConnectError::NoUpstreams
+                    });
+                }
+                if !may_queue {
+                    return Err(ConnectError::QueueFull);
+                }
+                if queue_place.is_none() {
+                    queue_place = Some(state.request_queue.join().ok_or(ConnectError::QueueFull)?);
+                    log::debug!("Every upstream is busy; queueing request from {}", client);
+                }
+                if !queue_place.as_ref().unwrap().wait().await {
+                    return Err(ConnectError::QueueTimeout);
+                }
+                continue;
+            }
+        };
+        let slot = match RequestSlot::reserve(&upstream, &state.request_queue) {
+            Some(slot) => slot,
+            // Other requests took the upstream's last slots after it was picked. It won't be
+            // eligible next time round unless they've finished.
+            None => continue,
+        };
+        let pooled = match upstream.proxy_protocol {
+            Some(_) => None,
+            None => state.upstream_pool.take(&upstream.addr).await,
+        };
+        if let Some(upstream_conn) = pooled {
+            log::debug!("Reusing idle connection to {}", upstream.addr);
+            return Ok((
+                upstream_conn,
+                selection,
+                ActiveConnection::new(&upstream, slot, &state.breaker_settings),
+            ));
+        }
+        let upstream_ip = upstream.addr.clone();
+        let origin = proxy_protocol::Origin {
+            source: client.addr,
+            destination: client.proxy_addr,
+        };
+        let deadline = state
+            .upstream_connect_timeout
+            .map(|timeout| Instant::now() + timeout);
+        let connected = until(
+            deadline,
+            upstream.connect(&state.upstream_connector, Some(&origin)),
+        )
+        .await
+        .unwrap_or_else(|| {
+            timed_out = true;
+            Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "timed out connecting",
+            ))
+        });
+        match connected {
+            Ok(stream) => {
+                let upstream_conn = BufReader::new(stream);
+                return Ok((
+                    upstream_conn,
+                    selection,
+                    ActiveConnection::new(&upstream, slot, &state.breaker_settings),
+                ));
+            }
+            Err(err) => {
+                log::error!("Failed to connect to upstream {}: {}", &upstream_ip, err);
+                record_failure(state, &upstream_ip, &upstream.passive_health).await;
+                selection.failed.push(upstream_ip);
+            }
+        }
+    }
+}
+
+/// Runs future until deadline (if there is one), returning None if it isn't done by then
+async fn until<F: std::future::Future>(deadline: Option<Instant>, future: F) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// Records that we failed to connect to or get a response from an upstream. Once
+/// --passive-failure-threshold of these happen in a row, it's marked dead, and gets no more
+/// requests until an active health check (or enough successes; see record_success) brings it back.
+async fn record_failure(state: &ProxyState, addr: &str, passive_health: &Mutex<PassiveHealth>) {
+    let failures = {
+        let mut passive_health = passive_health.lock();
+        passive_health.consecutive_successes = 0;
+        passive_health.consecutive_failures += 1;
+        passive_health.consecutive_failures
+    };
+    if failures >= state.passive_failure_threshold {
+        set_dead(state, addr, true).await;
+    }
+}
+
+/// Records that an upstream answered a request. If it had been marked dead (e.g. requests that were
+/// already in flight to it succeed after all), it's brought back once --passive-success-threshold
+/// of these happen in a row.
+async fn record_success(state: &ProxyState, addr: &str, passive_health: &Mutex<PassiveHealth>) {
+    let successes = {
+        let mut passive_health = passive_health.lock();
+        passive_health.consecutive_failures = 0;
+        passive_health.consecutive_successes += 1;
+        passive_health.consecutive_successes
+    };
+    // Only the success that reaches the threshold needs to check, so that the upstream list isn't
+    // locked for every request
+    if successes == state.passive_success_threshold {
+        set_dead(state, addr, false).await;
+    }
+}
+
+/// Marks an upstream dead or alive, if it isn't already
+async fn set_dead(state: &ProxyState, addr: &str, dead: bool) {
+    if !state
+        .upstream_addresses
+        .read()
+        .await
+        .iter()
+        .any(|upstream| upstream.addr == addr && upstream.is_dead != dead)
+    {
+        return;
+    }
+    let mut w_upstream_addresses = state.upstream_addresses.write().await;
+    // The upstream list may have been reloaded since the upstream was picked, so find it by address
+    // rather than by its old index
+    if let Some(upstream) = w_upstream_addresses
+        .iter_mut()
+        .find(|candidate| candidate.addr == addr)
+    {
+        if dead {
+            log::warn!("Passive health check marking upstream {} dead", addr);
+        } else {
+            log::info!("Passive health check marking upstream {} alive again", addr);
+        }
+        upstream.set_dead(dead);
+    }
+    rebuild_hash_ring(state, &w_upstream_addresses);
+}
+
+async fn send_response<S: ClientStream>(
+    state: &ProxyState,
+    client_conn: &mut S,
+    client: &ClientInfo,
+    mut response: http::Response<Vec<u8>>,
+) {
+    state
+        .error_pages
+        .apply(client.request_id.as_deref(), &mut response);
+    tag_response(client, response.headers_mut());
+    log_response(client, &response, "");
+    if let Err(error) = response::write_to_stream(&response, client_conn).await {
+        log::warn!("Failed to send response to client: {}", error);
+    }
+}
+
+/// Where a client connection came from, for the headers we add to its requests
+#[derive(Debug, Clone)]
+struct ClientInfo {
+    /// The other end of the connection
+    addr: SocketAddr,
+    /// The client's IP address, for logging, rate limiting, and ip-hash: addr's, unless addr is a
+    /// trusted proxy that told us whom it's forwarding for (see for_request)
+    ip: IpAddr,
+    /// Our end of the connection
+    proxy_addr: SocketAddr,
+    /// The scheme the client connected with (see ClientStream::proto)
+    proto: &'static str,
+    /// The X-Request-Id of the request being handled, if any (see for_request)
+    request_id: Option<String>,
+    /// With --otlp-endpoint, the span of the request being handled, and the ID of its parent span
+    /// (from the request's traceparent header), if it has one
+    trace: Option<trace::SpanContext>,
+    trace_parent: Option<u64>,
+}
+
+impl ClientInfo {
+    fn new<S: ClientStream>(client_conn: &S) -> ClientInfo {
+        let addr = client_conn.peer_addr().unwrap();
+        ClientInfo {
+            addr,
+            ip: addr.ip(),
+            proxy_addr: client_conn.local_addr().unwrap(),
+            proto: client_conn.proto(),
+            request_id: None,
+            trace: None,
+            trace_parent: None,
+        }
+    }
+
+    /// The client a request on this connection came from. If the connection is from one of
+    /// --trusted-proxies, that's the last address in the request's X-Forwarded-For that isn't
+    /// another trusted proxy. (Anything to the left of that could have been made up by the client.)
+    ///
+    /// The request is also given an ID, which is passed upstream in X-Request-Id and tags our log
+    /// lines for it. If the client (or a proxy in front of us) already gave it one, that's kept.
+    /// Likewise, if we're exporting traces, it's given a span, in the trace its traceparent header
+    /// names if it has one.
+    fn for_request(&self, state: &ProxyState, headers: &http::HeaderMap) -> ClientInfo {
+        let is_trusted = |ip: IpAddr| state.trusted_proxies.iter().any(|cidr| cidr.contains(ip));
+        let mut ip = self.addr.ip();
+        if is_trusted(ip) {
+            let hops: Vec<&str> = headers
+                .get_all("x-forwarded-for")
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .collect();
+            for hop in hops.into_iter().rev() {
+                // Stop at anything malformed, and go with the last address we could read
+                match hop.trim().parse() {
+                    Ok(hop) => ip = hop,
+                    Err(_) => break,
+                }
+                if !is_trusted(ip) {
+                    break;
+                }
+            }
+        }
+        let request_id = match request::get_request_id(headers) {
+            Some(request_id) => request_id.to_string(),
+            None => generate_request_id(),
+        };
+        let (trace, trace_parent) = match state.tracer {
+            Some(_) => {
+                let (context, parent) = trace::SpanContext::for_request(headers);
+                (Some(context), parent)
+            }
+            None => (None, None),
+        };
+        ClientInfo {
+            ip,
+            request_id: Some(request_id),
+            trace,
+            trace_parent,
+            ..self.clone()
+        }
+    }
+}
+
+/// Shows the client's IP address, followed by the ID of its request, if there is one
+impl fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.ip)?;
+        if let Some(request_id) = &self.request_id {
+            write!(f, " [{}]", request_id)?;
+        }
+        Ok(())
+    }
+}
+
+/// Logs the response we're about to send a client. With --log-format json, a request we've given
+/// an ID is logged as a single event once it's done (see access_log::Entry), so its response isn't
+/// logged separately.
+fn log_response(client: &ClientInfo, response: &http::Response<Vec<u8>>, suffix: &str) {
+    if logging::json() && client.request_id.is_some() {
+        return;
+    }
+    log::info!(
+        "{} <- {}{}",
+        client,
+        response::format_response_line(response),
+        suffix
+    );
+}
+
+/// Passes a request's ID back to the client in the response, unless the upstream already did
+fn tag_response(client: &ClientInfo, headers: &mut http::HeaderMap) {
+    if let Some(request_id) = &client.request_id {
+        if !headers.contains_key("x-request-id") {
+            headers.insert(
+                "x-request-id",
+                http::HeaderValue::from_str(request_id).unwrap(),
+            );
+        }
+    }
+}
+
+/// Makes up a random (version 4) UUID to identify a request by
+fn generate_request_id() -> String {
+    let bits: u128 = rand::thread_rng().gen();
+    // Set the version (4) and variant (RFC 4122) bits
+    let bits = (bits & !(0xf000 << 64) & !(0xc << 60)) | (0x4000 << 64) | (0x8 << 60);
+    let hex = format!("{:032x}", bits);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// A connection to an upstream, buffered so that we can read a response's headers without reading
+/// past them
+type UpstreamConn = BufReader<tls::UpstreamStream>;
+
+async fn handle_connection<S: ClientStream>(client_conn: S, state: &Arc<ProxyState>) {
+    let connection = ClientInfo::new(&client_conn);
+    log::info!("Connection received from {}", connection.addr.ip());
+    // Buffered so that we can read a request's headers without reading past them
+    let mut client_conn = BufReader::new(client_conn);
+    if state.http2 {
+        match starts_with_http2_preface(state, &mut client_conn).await {
+            Some(true) => return http2::handle_connection(client_conn, state).await,
+            Some(false) => {}
+            None => {
+                log::debug!("Closing idle connection from {}", connection.addr.ip());
+                return;
+            }
+        }
+    }
+    let pacer = state
+        .throttles
+        .as_ref()
+        .map(|throttles| throttles.pacer(connection.ip));
+
+    // The client may now send us one or more requests. Keep trying to read requests until the
+    // client hangs up or we get an error.
+    loop {
+        // Each request picks its own upstream (see send_request_head), so that a long-lived client
+        // connection still spreads its requests out, and isn't tied to an upstream that dies
+        let mut upstream: Option<(UpstreamConn, ActiveConnection)> = None;
+
+        // Read a request from the client. Only the headers are read here; the body is passed
+        // upstream as it arrives. A client that takes longer than --client-idle-timeout to send
+        // them (whether it's idle or trickling them in) is hung up on, so that it can't hold the
+        // connection open forever.
+        let require_length = state.require_content_length;
+        let limits = &state.header_limits;
+        let deadline = state
+            .client_idle_timeout
+            .map(|timeout| Instant::now() + timeout);
+        let read = request::read_head(&mut client_conn, require_length, limits);
+        let read = match until(deadline, read).await {
+            Some(read) => read,
+            None => {
+                log::debug!("Closing idle connection from {}", connection.addr.ip());
+                return;
+            }
+        };
+        let (mut request, request_framing) = match read {
+            Ok(request) => request,
+            // Handle case where client closed connection and is no longer sending requests
+            Err(request::Error::IncompleteRequest(0)) => {
+                log::debug!("Client finished sending requests. Shutting down connection");
+                return;
+            }
+            // Handle I/O error in reading from the client
+            Err(request::Error::Io(io_err)) => {
+                log::info!("Error reading request from client stream: {}", io_err);
+                return;
+            }
+            // We don't know where this request's body ends, so whatever the client sends next
+            // can't be trusted to be the start of another request. Reply and hang up.
+            Err(request::Error::LengthRequired) => {
+                log::debug!("Rejecting body-bearing request without framing headers");
+                let response = response::make_http_error(http::StatusCode::LENGTH_REQUIRED);
+                send_response(state, &mut client_conn, &connection, response).await;
+                return;
+            }
+            // Nor do we if the framing headers are ambiguous (an upstream could read them
+            // differently, and take part of the body for another request)
+            Err(
+                error @ (request::Error::InvalidContentLength
+                | request::Error::InvalidTransferEncoding),
+            ) => {
+                log::debug!("Rejecting request with ambiguous framing: {}", error);
+                let response = response::make_http_error(http::StatusCode::BAD_REQUEST);
+                send_response(state, &mut client_conn, &connection, response).await;
+                return;
+            }
+            // The rest of the oversized headers are still waiting to be read, so the same goes
+            // here
+            Err(request::Error::HeadersTooLarge) => {
+                log::debug!("Rejecting request with oversized headers");
+                let response =
+                    response::make_http_error(http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+                send_response(state, &mut client_conn, &connection, response).await;
+                return;
+            }
+            Err(error) => {
+                log::debug!("Error parsing request: {}", error);
+                let response = response::make_http_error(match error {
+                    request::Error::IncompleteRequest(_)
+                    | request::Error::MalformedRequest(_)
+                    | request::Error::InvalidContentLength
+                    | request::Error::InvalidTransferEncoding
+                    | request::Error::ContentLengthMismatch
+                    | request::Error::InvalidChunkedBody => http::StatusCode::BAD_REQUEST,
+                    request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
+                    request::Error::LengthRequired => http::StatusCode::LENGTH_REQUIRED,
+                    request::Error::HeadersTooLarge => {
+                        http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+                    }
+                    request::Error::Io(_) => http::StatusCode::SERVICE_UNAVAILABLE,
+                });
+                send_response(state, &mut client_conn, &connection, response).await;
+                continue;
+            }
+        };
+        let client = connection.for_request(state, request.headers());
+        let mut access = access_log::Entry::new(state, &client, &request);
+        // If the client says up front that its body is too big, we don't have to read any of it
+        // (or bother an upstream with it). We can't tell where the body ends if we don't read it,
+        // though, so the connection has to be closed.
+        if body_too_large(state, request_framing) {
+            log::debug!("Rejecting request with an oversized body");
+            let response = response::make_http_error(http::StatusCode::PAYLOAD_TOO_LARGE);
+            access.set_response(&response);
+            send_response(state, &mut client_conn, &client, response).await;
+            return;
+        }
+        let in_flight = InFlightRequest::new(state);
+        let upgrade_requested = request::is_upgrade_request(&request);
+        if let Err(mut response) = prepare_request(state, &client, &mut request).await {
+            // The request was turned away (e.g. rate limited) before any of its body was read. The
+            // body can be skipped, keeping the connection for the client's next request, unless
+            // the client is holding it back for a 100 Continue, in which case we can't tell
+            // whether it'll send it.
+            let last_response = state.shutting_down.load(Ordering::SeqCst)
+                || (request_framing != body::Framing::Empty && request::expects_continue(&request));
+            if last_response {
+                response.headers_mut().insert(
+                    http::header::CONNECTION,
+                    http::HeaderValue::from_static("close"),
+                );
+            }
+            access.set_response(&response);
+            send_response(state, &mut client_conn, &client, response).await;
+            if last_response {
+                return;
+            }
+            let mut sink = tokio::io::sink();
+            let skipped = body::copy(
+                &mut client_conn,
+                &mut sink,
+                request_framing,
+                state.max_body_size,
+                None,
+            );
+            if let Err(error) = skipped.await {
+                log::debug!("Error skipping the body of a refused request: {:?}", error);
+                return;
+            }
+            continue;
+        }
+
+        // Answer from the cache if we can, without bothering an upstream
+        let cached = match &state.cache {
+            Some(cache) if request_framing == body::Framing::Empty => cache.get(&mut request),
+            _ => None,
+        };
+        if let Some(mut response) = cached {
+            state
+                .compression
+                .compress_in_memory(&request, &mut response);
+            let last_response = state.shutting_down.load(Ordering::SeqCst);
+            if last_response {
+                response.headers_mut().insert(
+                    http::header::CONNECTION,
+                    http::HeaderValue::from_static("close"),
+                );
+            }
+            access.set_response(&response);
+            access.set_bytes(response.body().len() as u64);
+            if let Some(pacer) = &pacer {
+                pacer.take(response.body().len()).await;
+            }
+            send_response(state, &mut client_conn, &client, response).await;
+            if last_response {
+                return;
+            }
+            continue;
+        }
+
+        // Send the request upstream, body and all. A request without a body is sent in one go, so
+        // that it can be retried if the upstream fails.
+        let mut bodyless_response = None;
+        let sent = if request_framing == body::Framing::Empty {
+            send_bodyless_request(state, &client, &mut upstream, &request)
+                .await
+                .map(|response| bodyless_response = Some(response))
+        } else {
+            send_request_head(state, &client, &mut upstream, &request, None).await
+        };
+        access.set_upstream(
+            upstream
+                .as_ref()
+                .map(|(_, connection)| connection.addr.as_str()),
+        );
+        if let Err(response) = sent {
+            access.set_response(&response);
+            send_response(state, &mut client_conn, &client, response).await;
+            return;
+        }
+        let (upstream_conn, active_connection) = upstream.as_mut().unwrap();
+
+        // A client that sent Expect: 100-continue holds its body back until it hears that the
+        // upstream wants it. The upstream may answer with a final response instead, in which case
+        // the body is never sent.
+        let mut early_response = None;
+        if request_framing != body::Framing::Empty && request::expects_continue(&request) {
+            match wait_for_continue(state, &client, upstream_conn, active_connection, &request)
+                .await
+            {
+                Ok(None) => {
+                    if let Err(error) = send_continue(&mut client_conn).await {
+                        log::warn!("Failed to send 100 Continue to client: {}", error);
+                        return;
+                    }
+                }
+                Ok(Some(response)) => early_response = Some(response),
+                Err(response) => {
+                    access.set_response(&response);
+                    send_response(state, &mut client_conn, &client, response).await;
+                    return;
+                }
+            }
+        }
+        let body_skipped = early_response.is_some();
+        let (mut response, mut response_framing) = match early_response.or(bodyless_response) {
+            Some(response) => response,
+            None => {
+                let copied = body::copy(
+                    &mut client_conn,
+                    upstream_conn,
+                    request_framing,
+                    state.max_body_size,
+                    None,
+                )
+                .await;
+                if let Err(error) = copied {
+                    log::info!("Error passing request body upstream: {:?}", error);
+                    // If the client is the one that failed, tell it so. (If it hung up, this is
+                    // harmless.) The upstream is left with part of a request, so it gets hung up
+                    // on either way.
+                    let status = match error {
+                        body::Error::WriteError(_) => http::StatusCode::BAD_GATEWAY,
+                        body::Error::BodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
+                        _ => http::StatusCode::BAD_REQUEST,
+                    };
+                    let response = response::make_http_error(status);
+                    access.set_response(&response);
+                    send_response(state, &mut client_conn, &client, response).await;
+                    return;
+                }
+                log::debug!("Forwarded request to server");
+
+                // Read the server's response
+                let read = read_response_head(
+                    state,
+                    &client,
+                    upstream_conn,
+                    active_connection,
+                    &request,
+                    false,
+                );
+                match read.await {
+                    Ok(response) => response,
+                    Err(response) => {
+                        access.set_response(&response);
+                        send_response(state, &mut client_conn, &client, response).await;
+                        return;
+                    }
+                }
+            }
+        };
+
+        // A response that can be cached is read in full and stored before the client gets it
+        let mut cached_body = match read_cacheable_body(
+            state,
+            &request,
+            &mut response,
+            &mut response_framing,
+            upstream_conn,
+        )
+        .await
+        {
+            Ok(body) => body,
+            Err(response) => {
+                access.set_response(&response);
+                send_response(state, &mut client_conn, &client, response).await;
+                return;
+            }
+        };
+
+        // If the upstream agreed to switch protocols, the connection stops being HTTP once this
+        // response is sent
+        let upgrading =
+            upgrade_requested && response.status() == http::StatusCode::SWITCHING_PROTOCOLS;
+
+        // If we're shutting down, this is the last request we'll take on this connection. The
+        // same goes if the response's body only ends when the upstream hangs up, since closing the
+        // connection is the only way we can tell the client where it ends too, and if the client
+        // might still send the body the upstream turned down.
+        let shutting_down = state.shutting_down.load(Ordering::SeqCst);
+        let last_response = (shutting_down && !upgrading)
+            || response_framing == body::Framing::UntilClose
+            || body_skipped;
+        if last_response {
+            response.headers_mut().insert(
+                http::header::CONNECTION,
+                http::HeaderValue::from_static("close"),
+            );
+        }
+
+        // Compress the body on its way to the client, if the client accepts that and it's worth it
+        let coding = state
+            .compression
+            .choose(&request, &response, response_framing);
+        if let Some(coding) = coding {
+            match &mut cached_body {
+                Some(body) => {
+                    *body = compress::encode(response.headers_mut(), coding, body);
+                    response_framing = body::Framing::Length(body.len() as u64);
+                }
+                None => compress::encode_streamed(response.headers_mut(), coding),
+            }
+        }
+
+        // Forward the response to the client, passing the body on as it arrives
+        tag_response(&client, response.headers_mut());
+        log_response(&client, &response, "");
+        access.set_response(&response);
+        if let Err(error) = response::write_head(&response, &mut client_conn).await {
+            log::warn!("Failed to send response to client: {}", error);
+            return;
+        }
+        let copied = async {
+            match cached_body {
+                Some(body) => {
+                    if let Some(pacer) = &pacer {
+                        pacer.take(body.len()).await;
+                    }
+                    body::write_all(&mut client_conn, &body, response_framing)
+                        .await
+                        .map(|_| body.len() as u64)
+                        .map_err(body::Error::WriteError)
+                }
+                None => {
+                    let pacer = pacer.as_deref();
+                    match coding {
+                        Some(coding) => {
+                            let copied = compress::copy(
+                                upstream_conn,
+                                &mut client_conn,
+                                response_framing,
+                                coding,
+                                pacer,
+                            );
+                            copied.await
+                        }
+                        None => {
+                            let copied = body::copy(
+                                upstream_conn,
+                                &mut client_conn,
+                                response_framing,
+                                None,
+                                pacer,
+                            );
+                            copied.await
+                        }
+                    }
+                }
+            }
+        };
+        match copied.await {
+            Ok(copied) => access.set_bytes(copied),
+            Err(error) => {
+                // The client already has the response's headers, so all we can do is hang up
+                log::warn!("Error passing response body to client: {:?}", error);
+                return;
+            }
+        }
+        log::debug!("Forwarded response to client");
+        if upgrading {
+            // A tunnel can stay open indefinitely, so it doesn't hold up shutdown, and the request
+            // is logged now rather than once the tunnel closes
+            drop(in_flight);
+            drop(access);
+            let (upstream_conn, _active_connection) = upstream.take().unwrap();
+            log::debug!("Switched protocols; tunneling between client and upstream");
+            tunnel(client_conn, upstream_conn).await;
+            return;
+        }
+        if last_response {
+            return;
+        }
+        // The upstream connection can go back to the pool for the next request (ours or anyone
+        // else's), unless either side asked for it to be closed after this exchange
+        if !request::has_connection_option(request.headers(), "close")
+            && !request::has_connection_option(response.headers(), "close")
+        {
+            release_upstream(state, upstream);
+        }
+    }
 }
 
-/// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
-/// to, what servers have failed, rate limiting counts, etc.)
-///
-/// You should add fields to this struct in later milestones.
-struct ProxyState {
-    /// How frequently we check whether upstream servers are alive (Milestone 4)
-    #[allow(dead_code)]
-    active_health_check_interval: usize,
-    /// Where we should send requests when doing active health checks (Milestone 4)
-    #[allow(dead_code)]
-    active_health_check_path: String,
-    /// Maximum number of requests an individual IP can make in a minute (Milestone 5)
-    #[allow(dead_code)]
-    max_requests_per_minute: usize,
-    /// Addresses of servers that we are proxying to
-    upstream_addresses: RwLock<Vec<UpstreamState>>,
-    /// Client addresses for rate limiting
-    #[allow(dead_code)]
-    client_addresses: RwLock<HashMap<String, UpstreamRpm>>,
-}
-
-#[tokio::main]
-async fn main() {
-    // Initialize the logging library. You can print log messages using the `log` macros:
-    // https://docs.rs/log/0.4.8/log/ You are welcome to continue using print! statements; this
-    // just looks a little prettier.
-    if let Err(_) = std::env::var("RUST_LOG") {
-        std::env::set_var("RUST_LOG", "debug");
+/// If the upstream's response to a request can be cached, reads its body in full and stores it,
+/// returning the body to send the client. Returns None if the response isn't cached (so its body
+/// is still waiting to be read), or the error response to send the client instead if the body
+/// couldn't be read.
+///
+/// If the upstream said that a stale cached response is still good, response and framing are
+/// replaced with that response's.
+async fn read_cacheable_body(
+    state: &ProxyState,
+    request: &http::Request<Vec<u8>>,
+    response: &mut http::Response<Vec<u8>>,
+    framing: &mut body::Framing,
+    upstream_conn: &mut UpstreamConn,
+) -> Result<Option<Vec<u8>>, http::Response<Vec<u8>>> {
+    let cache = match &state.cache {
+        Some(cache) => cache,
+        None => return Ok(None),
+    };
+    if let Some(mut revalidated) = cache.revalidated(request, response) {
+        log::debug!("Upstream revalidated the cached response");
+        let body = std::mem::take(revalidated.body_mut());
+        *response = revalidated;
+        *framing = body::Framing::Length(body.len() as u64);
+        return Ok(Some(body));
     }
-    pretty_env_logger::init();
-
-    // Parse the command line arguments passed to this program
-    let options = CmdOptions::parse();
-    if options.upstream.len() < 1 {
-        log::error!("At least one upstream server must be specified using the --upstream option.");
-        std::process::exit(1);
+    let framing = *framing;
+    if !cache.accepts(request, response, framing) {
+        return Ok(None);
     }
-
-    // Start listening for connections
-    let mut listener = match TcpListener::bind(&options.bind).await {
-        Ok(listener) => listener,
-        Err(err) => {
-            log::error!("Could not bind to {}: {}", options.bind, err);
-            std::process::exit(1);
-        }
+    let max_size = match framing {
+        body::Framing::Length(length) => length as usize,
+        _ => 0,
     };
-    log::info!("Listening for requests on {}", options.bind);
-
-    // Handle incoming connections
-    let state = Arc::new(ProxyState {
-        upstream_addresses: RwLock::new(options.upstream),
-        client_addresses: RwLock::new(HashMap::new()),
-        active_health_check_interval: options.active_health_check_interval,
-        active_health_check_path: options.active_health_check_path,
-        max_requests_per_minute: options.max_requests_per_minute,
-    });
+    match body::read_to_end(upstream_conn, framing, max_size).await {
+        Ok(body) => {
+            cache.insert(request, response, body.clone());
+            Ok(Some(body))
+        }
+        Err(error) => {
+            log::warn!("Error reading response body from upstream: {:?}", error);
+            Err(response::make_http_error(http::StatusCode::BAD_GATEWAY))
+        }
+    }
+}
 
-    let shared_state = Arc::clone(&state);
-    tokio::spawn(async move {
-        active_health_check(&shared_state).await;
-    });
+/// Hands an upstream connection to the pool once a request is done with it. It must be between
+/// requests.
+fn release_upstream(state: &ProxyState, upstream: Option<(UpstreamConn, ActiveConnection)>) {
+    if let Some((upstream_conn, active_connection)) = upstream {
+        if !active_connection.reusable {
+            return;
+        }
+        state
+            .upstream_pool
+            .put(&active_connection.addr, upstream_conn);
+    }
+}
 
-    if state.max_requests_per_minute > 0 {
-        let shared_state = Arc::clone(&state);
-        tokio::spawn(async move {
-            clear_rate_limit(&shared_state).await;
-        });
+/// Returns true if a request body framed this way is known to be over the --max-body-size limit
+/// before any of it is read. (Chunked bodies are only caught as they're copied, by body::copy.)
+fn body_too_large(state: &ProxyState, framing: body::Framing) -> bool {
+    match (framing, state.max_body_size) {
+        (body::Framing::Length(length), Some(max_size)) => length > max_size,
+        _ => false,
     }
+}
 
-    loop {
-        let stream = match listener.accept().await {
-            Ok((socket, addr)) => {
-                println!("new client: {:?}", addr);
-                socket
-            }
+/// Passes a client connection through to an upstream without looking at what's sent over it (for
+/// --mode tcp). The connection counts as an in-flight request until it closes, so that shutdown
+/// waits for it.
+async fn proxy_tcp_connection<S: ClientStream>(client_conn: S, state: &Arc<ProxyState>) {
+    let client = ClientInfo::new(&client_conn);
+    let client_ip = client.addr.ip();
+    let _in_flight = InFlightRequest::new(state);
+    let (upstream_conn, selection, _active_connection) =
+        match connect_to_upstream(state, &client, None, None, None, true).await {
+            Ok(connected) => connected,
             Err(err) => {
-                log::error!("Couldn't get client: {}", err);
-                continue;
+                log::warn!("Dropping TCP connection from {}: {}", client_ip, err);
+                return;
             }
         };
+    log::info!(
+        "{} -> {}: TCP connection ({})",
+        client_ip,
+        upstream_conn.get_ref().peer_host(),
+        selection
+    );
+    tunnel(client_conn, upstream_conn).await;
+}
 
-        let shared_state = Arc::clone(&state);
-        tokio::spawn(async move {
-            handle_connection(stream, &shared_state).await;
-        });
+/// Copies bytes between the client and upstream in both directions, until both sides have hung
+/// up. This is how upgraded connections (e.g. WebSockets) are proxied, since we don't understand
+/// whatever protocol they've switched to.
+async fn tunnel<C, U>(client_conn: C, upstream_conn: U)
+where
+    C: AsyncRead + AsyncWrite,
+    U: AsyncRead + AsyncWrite,
+{
+    let (mut client_read, mut client_write) = tokio::io::split(client_conn);
+    let (mut upstream_read, mut upstream_write) = tokio::io::split(upstream_conn);
+    // When one side finishes sending, pass the half-close on to the other side, but keep copying
+    // in the other direction
+    let client_to_upstream = async {
+        let copied = tokio::io::copy(&mut client_read, &mut upstream_write).await;
+        let _ = upstream_write.shutdown().await;
+        copied
+    };
+    let upstream_to_client = async {
+        let copied = tokio::io::copy(&mut upstream_read, &mut client_write).await;
+        let _ = client_write.shutdown().await;
+        copied
+    };
+    match tokio::join!(client_to_upstream, upstream_to_client) {
+        (Ok(sent), Ok(received)) => log::debug!(
+            "Tunnel closed after sending {} bytes upstream and {} bytes to the client",
+            sent,
+            received
+        ),
+        (Err(err), _) | (_, Err(err)) => log::info!("Tunnel closed with an error: {}", err),
     }
 }
 
-async fn connect_to_upstream(state: &Arc<ProxyState>) -> Result<TcpStream, std::io::Error> {
-    let mut rng = rand::rngs::StdRng::from_entropy();
-
-    loop {
-        {
-            let r_upstream_addresses = state.upstream_addresses.read().await;
-            if r_upstream_addresses.iter().filter(|x| !x.is_dead).count() == 0 {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "No more upstreams to connect",
-                ));
-            }
-        }
+/// Gets a client's request ready to be sent upstream (by any number of calls to
+/// send_request_head). If it mustn't be forwarded, returns the error response to send the client
+/// instead.
+async fn prepare_request(
+    state: &Arc<ProxyState>,
+    client: &ClientInfo,
+    request: &mut http::Request<Vec<u8>>,
+) -> Result<(), http::Response<Vec<u8>>> {
+    if let Some(request_id) = &client.request_id {
+        let request_id = http::HeaderValue::from_str(request_id).unwrap();
+        request.headers_mut().insert("x-request-id", request_id);
+    }
+    // The upstream's spans go under ours. Any tracestate is passed on as is, since we don't add to
+    // it.
+    if let Some(trace) = &client.trace {
+        let traceparent = http::HeaderValue::from_str(&trace.traceparent()).unwrap();
+        request.headers_mut().insert("traceparent", traceparent);
+    }
+    if let Some(response) =
+        state
+            .maintenance
+            .check(client.ip, client.request_id.as_deref(), request)
+    {
+        return Err(response);
+    }
+    rate_limit_client(client.ip, request, state).await?;
+    if let Some(response) = state.cors.preflight(request) {
+        return Err(response);
+    }
+    if let Some(response) = acl::check_method(&state.method_rules, request) {
+        return Err(response);
+    }
+    if let Some(response) = state.auth.check(request) {
+        return Err(response);
+    }
 
-        let (upstream_ip, upstream_idx) = loop {
-            let r_upstream_addresses = state.upstream_addresses.read().await;
-            let upstream_idx = rng.gen_range(0, r_upstream_addresses.len());
-            let upstream = &r_upstream_addresses[upstream_idx];
-            if !upstream.is_dead {
-                break (upstream.addr.clone(), upstream_idx);
-            }
-        };
-        match TcpStream::connect(&upstream_ip).await {
-            Ok(stream) => {
-                return Ok(stream);
+    // Add X-Forwarded-* and/or Forwarded headers so that the upstream server knows the client's
+    // IP address, and can rebuild the URL the client asked for. (We're the ones connecting
+    // directly to the upstream server, so without these headers, the upstream server will only
+    // know our IP, not the client's.)
+    let host = request
+        .headers()
+        .get(http::header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .map(str::to_string);
+    if state.forwarded_header_style != ForwardedHeaderStyle::Rfc7239 {
+        request::extend_header_value(request, "x-forwarded-for", &client.addr.ip().to_string());
+        // Unlike X-Forwarded-For, these describe a single hop, and the hop the upstream wants is
+        // the one to us, so whatever the client sent is replaced
+        let headers = request.headers_mut();
+        headers.insert(
+            "x-forwarded-proto",
+            http::HeaderValue::from_static(client.proto),
+        );
+        match host.as_deref().map(http::HeaderValue::from_str) {
+            Some(Ok(host)) => {
+                headers.insert("x-forwarded-host", host);
             }
-            Err(err) => {
-                log::error!("Failed to connect to upstream {}: {}", &upstream_ip, err);
-                let mut w_upstream_addresses = state.upstream_addresses.write().await;
-                w_upstream_addresses[upstream_idx].is_dead = true;
+            _ => {
+                headers.remove("x-forwarded-host");
             }
         }
+        headers.insert(
+            "x-forwarded-port",
+            http::HeaderValue::from(client.proxy_addr.port()),
+        );
+    }
+    if state.forwarded_header_style != ForwardedHeaderStyle::Legacy {
+        request::extend_header_value(
+            request,
+            "forwarded",
+            &format_forwarded_element(
+                client.addr.ip(),
+                client.proto,
+                client.proxy_addr,
+                host.as_deref(),
+            ),
+        );
     }
+    rewrite::apply_header_rules(&state.request_header_rules, request.headers_mut());
+    state.router.assign_canary(client.ip, request);
+    rewrite::rewrite_path(&state.path_rewrites, request);
+    Ok(())
 }
 
-async fn send_response(client_conn: &mut TcpStream, response: &http::Response<Vec<u8>>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    log::info!(
-        "{} <- {}",
-        client_ip,
-        response::format_response_line(&response)
-    );
-    if let Err(error) = response::write_to_stream(&response, client_conn).await {
-        log::warn!("Failed to send response to client: {}", error);
-        return;
+/// Opens a connection to an upstream in the pool a request is routed to (or the one its sticky
+/// cookie names), or to one of the other upstreams if that's the canary pool and it's down
+async fn connect_for_request(
+    state: &Arc<ProxyState>,
+    client: &ClientInfo,
+    request: &http::Request<Vec<u8>>,
+    pool: Option<&str>,
+    avoid: Option<&str>,
+) -> Result<(UpstreamConn, ActiveConnection), http::Response<Vec<u8>>> {
+    let pinned = if state.sticky_sessions {
+        request::get_cookie(request, STICKY_COOKIE)
+    } else {
+        None
+    };
+    let mut span = trace::Span::child(state.tracer.as_ref(), client.trace.as_ref(), "connect");
+    let mut connected =
+        connect_to_upstream(state, client, pool, pinned.as_deref(), avoid, true).await;
+    // Rather than fail requests while the canary is down, send them to the stable version
+    if matches!(connected, Err(ConnectError::NoUpstreams)) && state.router.is_canary(pool) {
+        log::warn!(
+            "No upstream in canary pool {} is available; sending {} to the other upstreams",
+            pool.unwrap(),
+            request::format_request_line(request)
+        );
+        connected = connect_to_upstream(state, client, None, pinned.as_deref(), avoid, true).await;
+    }
+    match connected {
+        Ok((upstream_conn, selection, active_connection)) => {
+            span.set_attribute("server.address", active_connection.addr.as_str());
+            log::debug!(
+                "Selected upstream {} for {}: {}",
+                upstream_conn.get_ref().peer_name(),
+                client,
+                selection
+            );
+            Ok((upstream_conn, active_connection))
+        }
+        Err(error) => {
+            span.set_error();
+            Err(error.response(&state.request_queue))
+        }
     }
 }
 
-async fn handle_connection(mut client_conn: TcpStream, state: &Arc<ProxyState>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    log::info!("Connection received from {}", client_ip);
+/// What to speak to the upstreams in a pool
+fn upstream_protocol(state: &ProxyState, pool: Option<&str>) -> UpstreamProtocol {
+    state
+        .router
+        .pool(pool)
+        .and_then(|pool| pool.protocol)
+        .unwrap_or(state.upstream_protocol)
+}
 
-    // Open a connection to a random destination server
-    let mut upstream_conn = match connect_to_upstream(state).await {
-        Ok(stream) => stream,
-        Err(_error) => {
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+/// Sends a client's request line and headers to its upstream (picking one and connecting to it
+/// first, if upstream is None). The caller should then pass the request's body on. If the request
+/// can't be forwarded, returns the error response to send the client instead, after which the
+/// client connection should be closed. upstream is still set in that case if it's the upstream that
+/// failed (rather than there being no upstream to send the request to). The upstream named by
+/// avoid (if any) isn't picked.
+async fn send_request_head(
+    state: &Arc<ProxyState>,
+    client: &ClientInfo,
+    upstream: &mut Option<(UpstreamConn, ActiveConnection)>,
+    request: &http::Request<Vec<u8>>,
+    avoid: Option<&str>,
+) -> Result<(), http::Response<Vec<u8>>> {
+    // Open a connection to a destination server
+    if upstream.is_none() {
+        let pool = state.router.route(request);
+        if upstream_protocol(state, pool) == UpstreamProtocol::H2c {
+            log::debug!(
+                "Refusing {} from {}: its upstreams only speak HTTP/2",
+                request::format_request_line(request),
+                client
+            );
+            return Err(response::make_http_error(
+                http::StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+            ));
         }
-    };
-    let upstream_ip = upstream_conn.peer_addr().unwrap().ip().to_string();
-
-    // The client may now send us one or more requests. Keep trying to read requests until the
-    // client hangs up or we get an error.
-    loop {
-        // Read a request from the client
-        let mut request = match request::read_from_stream(&mut client_conn).await {
-            Ok(request) => request,
-            // Handle case where client closed connection and is no longer sending requests
-            Err(request::Error::IncompleteRequest(0)) => {
-                log::debug!("Client finished sending requests. Shutting down connection");
-                return;
-            }
-            // Handle I/O error in reading from the client
-            Err(request::Error::ConnectionError(io_err)) => {
-                log::info!("Error reading request from client stream: {}", io_err);
-                return;
-            }
-            Err(error) => {
-                log::debug!("Error parsing request: {:?}", error);
-                let response = response::make_http_error(match error {
-                    request::Error::IncompleteRequest(_)
-                    | request::Error::MalformedRequest(_)
-                    | request::Error::InvalidContentLength
-                    | request::Error::ContentLengthMismatch => http::StatusCode::BAD_REQUEST,
-                    request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
-                    request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
-                });
-                send_response(&mut client_conn, &response).await;
-                continue;
-            }
-        };
+        *upstream = Some(connect_for_request(state, client, request, pool, avoid).await?);
+    }
+    let (upstream_conn, active_connection) = upstream.as_mut().unwrap();
+    let upstream_ip = upstream_conn.get_ref().peer_host();
+    if !logging::json() {
         log::info!(
             "{} -> {}: {}",
-            client_ip,
+            client,
             upstream_ip,
-            request::format_request_line(&request)
+            request::format_request_line(request)
         );
+    }
 
-        if state.max_requests_per_minute > 0 && rate_limit_client(&client_ip, state).await.is_err()
-        {
-            let response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
-            send_response(&mut client_conn, &response).await;
-            return;
-        }
-
-        // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
-        // (We're the ones connecting directly to the upstream server, so without this header, the
-        // upstream server will only know our IP, not the client's.)
-        request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
-
-        // Forward the request to the server
-        if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await {
+    // Forward the request to the server
+    let mut span = trace::Span::child(state.tracer.as_ref(), client.trace.as_ref(), "send request");
+    span.set_attribute("server.address", active_connection.addr.as_str());
+    let deadline = state
+        .upstream_response_timeout
+        .map(|timeout| Instant::now() + timeout);
+    match until(deadline, request::write_head(request, upstream_conn)).await {
+        Some(Ok(())) => Ok(()),
+        Some(Err(error)) => {
+            span.set_error();
             log::error!(
                 "Failed to send request to upstream {}: {}",
                 upstream_ip,
                 error
             );
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+            active_connection.record_outcome(false);
+            Err(response::make_http_error(http::StatusCode::BAD_GATEWAY))
         }
-        log::debug!("Forwarded request to server");
+        None => {
+            span.set_error();
+            log::error!("Timed out sending request to upstream {}", upstream_ip);
+            active_connection.record_outcome(false);
+            Err(response::make_http_error(http::StatusCode::GATEWAY_TIMEOUT))
+        }
+    }
+}
 
-        // Read the server's response
-        let response = match response::read_from_stream(&mut upstream_conn, request.method()).await
-        {
-            Ok(response) => response,
+/// Sends a copy of a request to the mirror pool (see --mirror-pool) in the background, if it's
+/// picked for the sample. Whatever the mirror answers is thrown away, and if it fails, nobody but
+/// the logs hears of it.
+fn mirror_request(state: &Arc<ProxyState>, client: &ClientInfo, request: &http::Request<Vec<u8>>) {
+    let pool = match state.router.pick_mirror() {
+        // Copies are sent over HTTP/1.1, which h2c upstreams wouldn't understand
+        Some(pool) if upstream_protocol(state, Some(pool)) != UpstreamProtocol::H2c => {
+            pool.to_string()
+        }
+        _ => return,
+    };
+    let mut mirrored = http::Request::new(request.body().clone());
+    *mirrored.method_mut() = request.method().clone();
+    *mirrored.uri_mut() = request.uri().clone();
+    *mirrored.version_mut() = request.version();
+    *mirrored.headers_mut() = request.headers().clone();
+    let (state, client) = (Arc::clone(state), client.clone());
+    tokio::spawn(async move {
+        let connected = connect_to_upstream(&state, &client, Some(&pool), None, None, false).await;
+        let (mut upstream_conn, _selection, active_connection) = match connected {
+            Ok(upstream) => upstream,
             Err(error) => {
-                log::error!("Error reading response from server: {:?}", error);
-                let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-                send_response(&mut client_conn, &response).await;
+                log::debug!("Couldn't mirror request to pool {}: {}", pool, error);
                 return;
             }
         };
-        // Forward the response to the client
-        send_response(&mut client_conn, &response).await;
-        log::debug!("Forwarded response to client");
+        let deadline = state
+            .upstream_response_timeout
+            .map(|timeout| Instant::now() + timeout);
+        let exchange = async {
+            if let Err(error) = request::write_head(&mirrored, &mut upstream_conn).await {
+                return Err(format!("{}", error));
+            }
+            response::read_from_stream(&mut upstream_conn, mirrored.method())
+                .await
+                .map_err(|error| format!("{}", error))
+        };
+        match until(deadline, exchange).await {
+            Some(Ok(response)) => log::debug!(
+                "Mirror {} answered {} with {}",
+                active_connection.addr,
+                request::format_request_line(&mirrored),
+                response.status()
+            ),
+            Some(Err(error)) => log::debug!(
+                "Mirror {} failed on {}: {}",
+                active_connection.addr,
+                request::format_request_line(&mirrored),
+                error
+            ),
+            None => log::debug!(
+                "Mirror {} timed out on {}",
+                active_connection.addr,
+                request::format_request_line(&mirrored)
+            ),
+        }
+    });
+}
+
+/// Sends a request that has no body upstream and reads the head of the response, like
+/// send_request_head followed by read_response_head. If the upstream fails, that counts against it
+/// (see record_failure), and since there's no body that might have been used up, an idempotent
+/// request is retried on another upstream. If there's no other upstream to retry on, the client gets
+/// the response for the upstream's failure (e.g. a 504 if it timed out). A copy goes to the mirror
+/// pool, if there is one.
+async fn send_bodyless_request(
+    state: &Arc<ProxyState>,
+    client: &ClientInfo,
+    upstream: &mut Option<(UpstreamConn, ActiveConnection)>,
+    request: &http::Request<Vec<u8>>,
+) -> Result<(http::Response<Vec<u8>>, body::Framing), http::Response<Vec<u8>>> {
+    mirror_request(state, client, request);
+    let mut failed_upstream = None;
+    let mut failed_response = None;
+    loop {
+        let avoid = failed_upstream.as_deref();
+        let exchanged = match send_request_head(state, client, upstream, request, avoid).await {
+            Ok(()) => match state.hedge_after {
+                Some(hedge_after) if request.method().is_idempotent() => {
+                    read_hedged(state, client, upstream, request, hedge_after).await
+                }
+                _ => {
+                    let (upstream_conn, active_connection) = upstream.as_mut().unwrap();
+                    read_response_head(
+                        state,
+                        client,
+                        upstream_conn,
+                        active_connection,
+                        request,
+                        false,
+                    )
+                    .await
+                }
+            },
+            Err(response) => Err(response),
+        };
+        let active_connection = match (&exchanged, upstream.as_ref()) {
+            (Err(_), Some((_, active_connection))) => active_connection,
+            (Err(_), None) if failed_response.is_some() => return Err(failed_response.unwrap()),
+            _ => return exchanged,
+        };
+        let addr = &active_connection.addr;
+        record_failure(state, addr, &active_connection.passive_health).await;
+        if !request.method().is_idempotent() {
+            return exchanged;
+        }
+        log::warn!(
+            "Upstream {} failed; retrying {} from {} on another upstream",
+            addr,
+            request::format_request_line(request),
+            client
+        );
+        failed_upstream = Some(addr.clone());
+        failed_response = exchanged.err();
+        *upstream = None;
+    }
+}
+
+/// Reads the head of the response to a request without a body, like read_response_head. If the
+/// upstream hasn't answered within hedge_after, the request is sent to a second upstream as well,
+/// and whichever answers first wins: it's left in upstream, and the other connection is dropped
+/// (which cancels the request on it). An upstream that fails doesn't win if the other might still
+/// answer.
+async fn read_hedged(
+    state: &Arc<ProxyState>,
+    client: &ClientInfo,
+    upstream: &mut Option<(UpstreamConn, ActiveConnection)>,
+    request: &http::Request<Vec<u8>>,
+    hedge_after: Duration,
+) -> Result<(http::Response<Vec<u8>>, body::Framing), http::Response<Vec<u8>>> {
+    let (hedge_conn, hedge_connection, read) = {
+        let (upstream_conn, active_connection) = upstream.as_mut().unwrap();
+        let first_addr = active_connection.addr.clone();
+        let first = read_response_head(
+            state,
+            client,
+            upstream_conn,
+            active_connection,
+            request,
+            false,
+        );
+        tokio::pin!(first);
+        if let Ok(read) = tokio::time::timeout(hedge_after, &mut first).await {
+            return read;
+        }
+
+        // If there's no other upstream to send the request to, keep waiting for the first one
+        let pool = state.router.route(request);
+        let hedge = connect_to_upstream(state, client, pool, None, Some(&first_addr), false).await;
+        let (mut hedge_conn, _selection, hedge_connection) = match hedge {
+            Ok(hedge) => hedge,
+            Err(_) => return first.await,
+        };
+        log::debug!(
+            "{} hasn't answered after {:?}; hedging {} from {} to {}",
+            first_addr,
+            hedge_after,
+            request::format_request_line(request),
+            client,
+            hedge_connection.addr
+        );
+        if let Err(error) = request::write_head(request, &mut hedge_conn).await {
+            log::warn!(
+                "Failed to send hedged request to {}: {}",
+                hedge_connection.addr,
+                error
+            );
+            return first.await;
+        }
+        let read = {
+            let second = read_response_head(
+                state,
+                client,
+                &mut hedge_conn,
+                &hedge_connection,
+                request,
+                false,
+            );
+            tokio::pin!(second);
+            tokio::select! {
+                read = &mut first => match read {
+                    Ok(response) => return Ok(response),
+                    Err(_) => second.await,
+                },
+                read = &mut second => match read {
+                    Ok(response) => Ok(response),
+                    Err(_) => return first.await,
+                },
+            }
+        };
+        (hedge_conn, hedge_connection, read)
+    };
+    // The first upstream's response is abandoned partway, so its connection can't go back to the
+    // pool
+    log::debug!("Using the response from {}", hedge_connection.addr);
+    *upstream = Some((hedge_conn, hedge_connection));
+    read
+}
+
+/// Waits for the upstream to say whether it wants the body of an Expect: 100-continue request.
+/// Returns None if the client should go ahead and send it, either because the upstream sent
+/// 100 Continue or because it didn't answer within EXPECT_CONTINUE_TIMEOUT. Otherwise, returns the
+/// final response the upstream sent instead (or the error response to send the client, as with
+/// read_response_head).
+#[allow(clippy::type_complexity)]
+async fn wait_for_continue(
+    state: &Arc<ProxyState>,
+    client: &ClientInfo,
+    upstream_conn: &mut UpstreamConn,
+    active_connection: &ActiveConnection,
+    request: &http::Request<Vec<u8>>,
+) -> Result<Option<(http::Response<Vec<u8>>, body::Framing)>, http::Response<Vec<u8>>> {
+    // Only wait for the start of a response here; peeking leaves the stream as it was if we give
+    // up, so that a late 100 Continue is still read (and passed over) properly afterwards
+    let answer = body::peek(upstream_conn, |_| ());
+    if tokio::time::timeout(EXPECT_CONTINUE_TIMEOUT, answer)
+        .await
+        .is_err()
+    {
+        log::debug!("Upstream didn't answer Expect: 100-continue; telling the client to go ahead");
+        return Ok(None);
+    }
+    let (response, framing) = read_response_head(
+        state,
+        client,
+        upstream_conn,
+        active_connection,
+        request,
+        true,
+    )
+    .await?;
+    if response.status() == http::StatusCode::CONTINUE {
+        Ok(None)
+    } else {
+        log::debug!("Upstream turned down the body of an Expect: 100-continue request");
+        Ok(Some((response, framing)))
+    }
+}
+
+/// Tells a client that sent Expect: 100-continue to send its body
+async fn send_continue<S: ClientStream>(client_conn: &mut S) -> Result<(), std::io::Error> {
+    let response = http::Response::builder()
+        .status(http::StatusCode::CONTINUE)
+        .body(Vec::new())
+        .unwrap();
+    response::write_head(&response, client_conn).await?;
+    client_conn.flush().await
+}
+
+/// Reads the status line and headers of the upstream's response to request, once the request has
+/// been sent in full. The caller should then pass the response's body on. If the upstream doesn't
+/// send a valid response, returns the error response to send the client instead, after which the
+/// client connection should be closed.
+///
+/// Any 100 Continue the upstream sends first is passed over, since the client has either been told
+/// to go ahead already or never asked. If stop_at_continue is set, it is returned instead (see
+/// wait_for_continue).
+async fn read_response_head(
+    state: &Arc<ProxyState>,
+    client: &ClientInfo,
+    upstream_conn: &mut UpstreamConn,
+    active_connection: &ActiveConnection,
+    request: &http::Request<Vec<u8>>,
+    stop_at_continue: bool,
+) -> Result<(http::Response<Vec<u8>>, body::Framing), http::Response<Vec<u8>>> {
+    let request_sent = Instant::now();
+    let mut span = trace::Span::child(
+        state.tracer.as_ref(),
+        client.trace.as_ref(),
+        "read response",
+    );
+    span.set_attribute("server.address", active_connection.addr.as_str());
+    let deadline = state
+        .upstream_response_timeout
+        .map(|timeout| request_sent + timeout);
+    let (mut response, framing) = loop {
+        let read = match until(
+            deadline,
+            response::read_head(upstream_conn, request.method()),
+        )
+        .await
+        {
+            Some(read) => read,
+            None => {
+                log::error!(
+                    "Timed out waiting for {} to answer {}",
+                    active_connection.addr,
+                    request::format_request_line(request)
+                );
+                span.set_error();
+                active_connection.record_outcome(false);
+                return Err(response::make_http_error(http::StatusCode::GATEWAY_TIMEOUT));
+            }
+        };
+        match read {
+            Ok((response, _)) if response.status() == http::StatusCode::CONTINUE => {
+                if stop_at_continue {
+                    return Ok((response, body::Framing::Empty));
+                }
+            }
+            Ok(response) => {
+                span.set_attribute("http.response.status_code", response.0.status().as_u16());
+                let status = response.0.status();
+                record_response(state, active_connection, status, request_sent).await;
+                break response;
+            }
+            Err(error) => {
+                log::error!(
+                    "Error reading response from server to {}: {:?}",
+                    request::format_request_line(request),
+                    error
+                );
+                span.set_error();
+                active_connection.record_outcome(false);
+                return Err(response::make_http_error(http::StatusCode::BAD_GATEWAY));
+            }
+        }
+    };
+    finish_response_head(state, active_connection, request, response.headers_mut());
+    Ok((response, framing))
+}
+
+/// Counts an upstream's answer to a request (sent at request_sent) towards its stats and health
+async fn record_response(
+    state: &ProxyState,
+    active_connection: &ActiveConnection,
+    status: http::StatusCode,
+    request_sent: Instant,
+) {
+    active_connection.record_response_time(Instant::now() - request_sent);
+    active_connection.record_outcome(!status.is_server_error());
+    let addr = &active_connection.addr;
+    record_success(state, addr, &active_connection.passive_health).await;
+}
+
+/// Makes the changes we make to the headers of every response from an upstream
+fn finish_response_head(
+    state: &ProxyState,
+    active_connection: &ActiveConnection,
+    request: &http::Request<Vec<u8>>,
+    headers: &mut http::HeaderMap,
+) {
+    // Pin the client to this upstream, unless its cookie already does
+    if state.sticky_sessions {
+        let cookie_value = sticky_cookie_value(&active_connection.addr);
+        if request::get_cookie(request, STICKY_COOKIE).as_deref() != Some(cookie_value.as_str()) {
+            let set_cookie = format!("{}={}; Path=/; HttpOnly", STICKY_COOKIE, cookie_value);
+            headers.append(
+                http::header::SET_COOKIE,
+                http::HeaderValue::from_str(&set_cookie).unwrap(),
+            );
+        }
     }
+    state.cors.apply(request, headers);
+    rewrite::apply_header_rules(&state.response_header_rules, headers);
+}
+
+/// Formats a single RFC 7239 forwarded-element describing the hop from the client to us, including
+/// the Host the client asked for if there was one. IPv6 addresses and anything containing a port
+/// aren't valid tokens, so those values are quoted.
+fn format_forwarded_element(
+    client_ip: IpAddr,
+    proto: &str,
+    proxy_addr: SocketAddr,
+    host: Option<&str>,
+) -> String {
+    let for_node = match client_ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    };
+    let mut element = format!("for={};proto={};by=\"{}\"", for_node, proto, proxy_addr);
+    // A Host header can't legitimately contain anything that would need escaping in a quoted
+    // string, so if it does, leave it out rather than pass it on
+    if let Some(host) = host.filter(|host| !host.contains(['"', '\\'])) {
+        element.push_str(&format!(";host=\"{}\"", host));
+    }
+    element
 }
 
 async fn active_health_check(state: &Arc<ProxyState>) {
+    // When each upstream (by address) is next due for a health check
+    let mut next_checks: HashMap<String, Instant> = HashMap::new();
     loop {
-        delay_for(Duration::from_secs(
-            state.active_health_check_interval as u64,
-        ))
-        .await;
+        let now = Instant::now();
+        let upstreams = state.upstream_addresses.read().await.clone();
+        next_checks.retain(|addr, _| upstreams.iter().any(|upstream| &upstream.addr == addr));
+        let mut due = Vec::new();
+        for upstream in upstreams {
+            let next_check = next_checks
+                .entry(upstream.addr.clone())
+                .or_insert_with(|| now + health_check_delay(state, &upstream));
+            if *next_check <= now {
+                *next_check = now + health_check_delay(state, &upstream);
+                due.push(upstream);
+            }
+        }
+        if due.is_empty() {
+            // Wake up at least once a second, so that upstreams added by a reload get scheduled
+            let next_check = next_checks.values().min().copied();
+            let wake_at = next_check.map_or(now + Duration::from_secs(1), |next_check| {
+                next_check.min(now + Duration::from_secs(1))
+            });
+            tokio::time::delay_until(wake_at).await;
+            continue;
+        }
+
+        // Probe every due upstream at once, without holding the lock: a probe can take as long as
+        // --health-check-timeout, and proxying shouldn't stall in the meantime
+        let probes: Vec<_> = due
+            .into_iter()
+            .map(|upstream| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    let is_healthy = probe_upstream(&state, &upstream).await;
+                    (upstream.addr, is_healthy)
+                })
+            })
+            .collect();
+        let mut results = Vec::with_capacity(probes.len());
+        for probe in probes {
+            if let Ok(result) = probe.await {
+                results.push(result);
+            }
+        }
+
+        let mut revivals = 0;
+        let mut changed = false;
         let mut w_upstream_addresses = state.upstream_addresses.write().await;
-        for idx in 0..w_upstream_addresses.len() {
-            let upstream_ip = w_upstream_addresses[idx].addr.clone();
-            let request = http::Request::builder()
-                .method(http::Method::GET)
-                .uri(&state.active_health_check_path)
-                .header("Host", &upstream_ip)
-                .body(Vec::<u8>::new())
-                .unwrap();
-            let mut upstream = {
-                let upstream = TcpStream::connect(&upstream_ip).await;
-                if upstream.is_ok() {
-                    upstream.unwrap()
-                } else {
+        for (upstream_ip, is_healthy) in results {
+            // The upstream list may have been reloaded while the probes were running
+            let upstream = match w_upstream_addresses
+                .iter_mut()
+                .find(|upstream| upstream.addr == upstream_ip)
+            {
+                Some(upstream) => upstream,
+                None => continue,
+            };
+            if is_healthy && upstream.is_dead {
+                if state.max_revivals_per_health_check > 0
+                    && revivals >= state.max_revivals_per_health_check
+                {
+                    log::info!(
+                        "Upstream {} is healthy again, but deferring its revival to a later health \
+                        check",
+                        upstream_ip
+                    );
                     continue;
                 }
-            };
-            let _ = request::write_to_stream(&request, &mut upstream).await;
-            let response = response::read_from_stream(&mut upstream, &request.method()).await;
-            if response.is_ok() && response.unwrap().status() == http::StatusCode::OK {
-                w_upstream_addresses[idx].is_dead = false;
-            } else {
-                w_upstream_addresses[idx].is_dead = true;
+                log::info!("Upstream {} is healthy again", upstream_ip);
+                revivals += 1;
             }
+            changed |= upstream.is_dead == is_healthy;
+            upstream.set_dead(!is_healthy);
+        }
+        if changed {
+            rebuild_hash_ring(state, &w_upstream_addresses);
+        }
+    }
+}
+
+/// How long to wait before health checking the given upstream again
+fn health_check_delay(state: &ProxyState, upstream: &UpstreamState) -> Duration {
+    let pool = state.router.pool(upstream.pool.as_deref());
+    let interval = upstream
+        .health_check_interval
+        .or_else(|| pool.and_then(|pool| pool.health_check_interval))
+        .unwrap_or_else(|| Duration::from_secs(state.active_health_check_interval as u64));
+    if state.health_check_jitter > 0.0 {
+        let jitter =
+            rand::thread_rng().gen_range(-state.health_check_jitter, state.health_check_jitter);
+        interval.mul_f64(1.0 + jitter)
+    } else {
+        interval
+    }
+}
+
+/// Sends an active health check request to the given upstream, and returns true if it answered
+/// in time with a healthy response
+async fn probe_upstream(state: &ProxyState, upstream: &UpstreamState) -> bool {
+    if state.mode == Mode::Udp {
+        // There's no telling whether a UDP upstream is up short of speaking its protocol, so
+        // upstreams that errored out of a session (see udp::serve) just get another chance
+        return true;
+    }
+    if state.mode == Mode::Tcp {
+        // We don't know what protocol the upstream speaks, so all we can check is that it's
+        // accepting connections
+        let connect = upstream.connect(&state.upstream_connector, None);
+        return matches!(
+            tokio::time::timeout(state.health_check_timeout, connect).await,
+            Ok(Ok(_))
+        );
+    }
+    let request = health_check_request(state, upstream);
+    let h2c = upstream_protocol(state, upstream.pool.as_deref()) == UpstreamProtocol::H2c;
+    let probe = async {
+        let conn = upstream.connect(&state.upstream_connector, None).await?;
+        if h2c {
+            return http2::probe_h2c(conn, &request, &upstream.addr)
+                .await
+                .map_err(std::io::Error::other);
+        }
+        let mut conn = BufReader::new(conn);
+        request::write_to_stream(&request, &mut conn).await?;
+        response::read_from_stream(&mut conn, request.method())
+            .await
+            .map_err(|error| std::io::Error::other(error.to_string()))
+    };
+    match tokio::time::timeout(state.health_check_timeout, probe).await {
+        Ok(Ok(response)) => is_healthy_response(state, &response),
+        Ok(Err(error)) => {
+            log::debug!("Health check of {} failed: {}", upstream.addr, error);
+            false
+        }
+        Err(_) => {
+            log::debug!("Health check of {} timed out", upstream.addr);
+            false
         }
     }
 }
 
-async fn rate_limit_client(client_ip: &String, state: &Arc<ProxyState>) -> Result<(), ()> {
+/// Builds the request that an active health check sends to the given upstream
+fn health_check_request(state: &ProxyState, upstream: &UpstreamState) -> http::Request<Vec<u8>> {
+    let body = state
+        .health_check_request_body
+        .clone()
+        .unwrap_or_default()
+        .into_bytes();
+    let path = state
+        .router
+        .pool(upstream.pool.as_deref())
+        .and_then(|pool| pool.health_check_path.as_ref())
+        .unwrap_or(&state.active_health_check_path);
+    let mut request = http::Request::builder()
+        .method(state.health_check_method.clone())
+        .uri(path)
+        .header("Host", upstream.configured_host_port());
+    if !body.is_empty() {
+        request = request.header("Content-Length", body.len());
+    }
+    let mut request = request.body(body).unwrap();
+    for (name, value) in &state.health_check_headers {
+        request.headers_mut().insert(name, value.clone());
+    }
+    request
+}
+
+/// Returns true if an upstream's answer to an active health check means it's healthy
+fn is_healthy_response(state: &ProxyState, response: &http::Response<Vec<u8>>) -> bool {
+    state.health_check_status.contains(response.status())
+        && match &state.health_check_body {
+            Some(expected) => String::from_utf8_lossy(response.body()).contains(expected.as_str()),
+            None => true,
+        }
+}
+
+/// Counts a request against its client's rate limit (see --rate-limit-algorithm) and any route
+/// limits it matches, unless the client is on the allowlist. Returns the response to refuse the
+/// request with if the client is on the denylist or over any of those limits.
+async fn rate_limit_client(
+    client_ip: IpAddr,
+    request: &http::Request<Vec<u8>>,
+    state: &Arc<ProxyState>,
+) -> Result<(), http::Response<Vec<u8>>> {
+    match state.client_lists.read().await.check(client_ip) {
+        rate_limit::Listed::Denied => {
+            log::debug!("Refusing request from denied client {}", client_ip);
+            return Err(response::make_http_error(http::StatusCode::FORBIDDEN));
+        }
+        rate_limit::Listed::Allowed => return Ok(()),
+        rate_limit::Listed::Unlisted => {}
+    }
+    let client = rate_limit_key(client_ip, request, state);
     let now = Instant::now();
-    let one_minute = Duration::from_secs(60);
-    let mut w_client_addresses = state.client_addresses.write().await;
-    let rpm = w_client_addresses
-        .entry(client_ip.to_string())
-        .or_insert(UpstreamRpm {
-            count: 0,
-            instant: now,
-        });
-    rpm.count += 1;
-    if rpm.count > state.max_requests_per_minute
-        && Instant::now().duration_since(rpm.instant) < one_minute
+    // Every matching limit counts the request, even once one has refused it, so that each of them
+    // sees all of the client's requests. The client is told to wait until all of them would let
+    // it through.
+    let mut retry_after = None;
+    if let Some(limit) = &state.rate_limit {
+        if let Err(wait) = state.rate_limit_store.take(&client, limit, now).await {
+            retry_after = retry_after.max(Some(wait));
+        }
+    }
+    let path = path::normalize(request.uri().path());
+    for route in &state.route_limits {
+        if route.rule.matches(request.method(), &path) {
+            if let Err(wait) = route.clients.take(&client, &route.limit, now).await {
+                retry_after = retry_after.max(Some(wait));
+            }
+        }
+    }
+    match retry_after {
+        None => Ok(()),
+        Some(wait) => Err(response::make_rate_limited_response(
+            wait,
+            state.rate_limit_json_errors,
+        )),
+    }
+}
+
+/// Identifies the client a request counts against for rate limiting: by its --rate-limit-header,
+/// if it has one, or else by its IP. Header values are hashed, so that secrets like API keys
+/// aren't kept around in the rate limit tables (or logged when evicted from them).
+fn rate_limit_key(
+    client_ip: IpAddr,
+    request: &http::Request<Vec<u8>>,
+    state: &ProxyState,
+) -> String {
+    match state
+        .rate_limit_header
+        .as_ref()
+        .and_then(|name| request.headers().get(name))
     {
-        return Err(());
-    } else if Instant::now().duration_since(rpm.instant) >= one_minute {
-        rpm.instant = now;
-        rpm.count = 1;
+        Some(value) => format!("header:{:016x}", hash_ring::hash(&value.as_bytes())),
+        None => client_ip.to_string(),
     }
-    Ok(())
 }
 
-/// TODO: Avoid overutilisation of the client_addresses hashmap
-async fn clear_rate_limit(_state: &Arc<ProxyState>) {}
+/// Every so often, forgets the clients that rate limiting no longer needs to remember (see
+/// rate_limit::RateLimiterStore::remove_idle), so that the tables don't grow forever
+async fn clear_rate_limit(state: &Arc<ProxyState>) {
+    loop {
+        delay_for(rate_limit::CLEANUP_INTERVAL).await;
+        let now = Instant::now();
+        let mut removed = 0;
+        if let Some(limit) = &state.rate_limit {
+            removed += state.rate_limit_store.remove_idle(limit, now).await;
+        }
+        for route in &state.route_limits {
+            removed += route.clients.remove_idle(&route.limit, now).await;
+        }
+        if removed > 0 {
+            log::debug!("Forgot {} idle clients from the rate limit tables", removed);
+        }
+    }
+}
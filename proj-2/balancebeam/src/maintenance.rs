@@ -0,0 +1,129 @@
+use crate::cidr::Cidr;
+use crate::error_page::Page;
+use crate::response;
+use parking_lot::RwLock;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Maintenance mode, for planned upstream downtime: while it's on, requests get a 503 (with
+/// --maintenance-page as its body, if given) instead of being forwarded, except from the clients in
+/// --maintenance-allow, who can still check on things. It's turned on with --maintenance or from
+/// the admin API, which can also narrow it down to some paths.
+#[derive(Debug)]
+pub struct Maintenance {
+    on: AtomicBool,
+    /// Path prefixes that are under maintenance (matching whole segments), or empty for all paths
+    paths: RwLock<Vec<String>>,
+    /// Clients that are let through anyway
+    allow: Vec<Cidr>,
+    page: Option<Page>,
+    /// Seconds to tell clients to wait before trying again, if we have a guess
+    retry_after: Option<u64>,
+}
+
+impl Maintenance {
+    pub fn new(
+        on: bool,
+        paths: Vec<String>,
+        allow: Vec<Cidr>,
+        page: Option<&str>,
+        retry_after: Option<u64>,
+    ) -> Result<Maintenance, String> {
+        Ok(Maintenance {
+            on: AtomicBool::new(on),
+            paths: RwLock::new(normalize_paths(paths)?),
+            allow,
+            page: page.map(Page::load).transpose()?,
+            retry_after,
+        })
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on.load(Ordering::SeqCst)
+    }
+
+    /// The path prefixes under maintenance (empty meaning all of them)
+    pub fn paths(&self) -> Vec<String> {
+        self.paths.read().clone()
+    }
+
+    /// Turns maintenance mode on or off. If paths are given, they replace the ones under
+    /// maintenance.
+    pub fn set(&self, on: bool, paths: Option<Vec<String>>) -> Result<(), String> {
+        if let Some(paths) = paths {
+            *self.paths.write() = normalize_paths(paths)?;
+        }
+        if self.on.swap(on, Ordering::SeqCst) != on {
+            match on {
+                true => log::info!("Entering maintenance mode"),
+                false => log::info!("Leaving maintenance mode"),
+            }
+        }
+        Ok(())
+    }
+
+    /// If the request falls under maintenance, returns the 503 to send instead of forwarding it
+    pub fn check(
+        &self,
+        client_ip: IpAddr,
+        request_id: Option<&str>,
+        request: &http::Request<Vec<u8>>,
+    ) -> Option<http::Response<Vec<u8>>> {
+        if !self.is_on() || self.allow.iter().any(|cidr| cidr.contains(client_ip)) {
+            return None;
+        }
+        let path = request.uri().path();
+        let paths = self.paths.read();
+        if !paths.is_empty() && !paths.iter().any(|prefix| covers(prefix, path)) {
+            return None;
+        }
+        log::debug!(
+            "Refusing {} {}: down for maintenance",
+            request.method(),
+            path
+        );
+        let mut response =
+            response::make_unavailable_response("The service is down for maintenance.");
+        if let Some(page) = &self.page {
+            // Our own page isn't an error for --error-page to replace
+            response.extensions_mut().remove::<response::Generated>();
+            page.render(request_id, &mut response);
+        }
+        if let Some(retry_after) = self.retry_after {
+            response.headers_mut().insert(
+                http::header::RETRY_AFTER,
+                http::HeaderValue::from(retry_after),
+            );
+        }
+        Some(response)
+    }
+}
+
+/// Checks that prefixes look like paths, and drops their trailing slashes
+fn normalize_paths(paths: Vec<String>) -> Result<Vec<String>, String> {
+    paths
+        .into_iter()
+        .map(|path| {
+            let path = path.trim();
+            if !path.starts_with('/') {
+                return Err(format!(
+                    "maintenance path prefix \"{}\" should start with /",
+                    path
+                ));
+            }
+            Ok(match path.trim_end_matches('/') {
+                "" => "/".to_string(),
+                path => path.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Whether a path is under a prefix, counting whole segments only (so /api covers /api/users but
+/// not /apiary)
+fn covers(prefix: &str, path: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'),
+        None => false,
+    }
+}
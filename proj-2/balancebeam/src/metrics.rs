@@ -0,0 +1,443 @@
+use crate::{ProxyState, UpstreamState};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use tokio::time::{Duration, Instant};
+
+/// Upper bounds (in seconds) of the latency histograms' buckets
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// How far back an upstream's recent statistics (see RecentStats) look
+const RECENT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Most samples of each kind kept for an upstream's recent statistics, so that a busy upstream's
+/// don't take up too much memory. Past this, the statistics cover less than RECENT_WINDOW.
+const MAX_RECENT_SAMPLES: usize = 1000;
+
+/// Counts of how long things took, in Prometheus histogram form
+#[derive(Debug, Default)]
+pub struct Histogram {
+    /// How many observations fell in each bucket (not counting those in earlier buckets). Those
+    /// over the last bound are only in count.
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|&bound| seconds <= bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Writes the histogram's samples, with labels (e.g. `upstream="..."`) added to each
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, separator, bound, cumulative
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, separator, count
+        );
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, count);
+    }
+}
+
+/// Counters for the whole proxy, kept in ProxyState and served by the admin API's /metrics
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Responses sent to clients, by status class (1xx through 5xx)
+    responses: [AtomicU64; 5],
+    /// Requests we read that never got a response (because the client or upstream hung up partway)
+    unanswered: AtomicU64,
+    /// How long requests took, from reading their heads to sending the last of their responses
+    latency: Histogram,
+    /// Client connections currently open
+    client_connections: AtomicUsize,
+    /// Client connections turned away because --max-connections were already open
+    rejected_connections: AtomicU64,
+}
+
+impl Metrics {
+    /// Records a request we're done with. status is None if the client got no response.
+    pub fn record_request(&self, status: Option<u16>, latency: Duration) {
+        match status {
+            Some(status @ 100..=599) => {
+                self.responses[usize::from(status / 100 - 1)].fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {
+                self.unanswered.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.latency.observe(latency);
+    }
+
+    /// The number of requests we've finished with, answered or not
+    pub fn total_requests(&self) -> u64 {
+        let answered: u64 = self
+            .responses
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .sum();
+        answered + self.unanswered.load(Ordering::Relaxed)
+    }
+
+    pub fn client_connections(&self) -> usize {
+        self.client_connections.load(Ordering::SeqCst)
+    }
+
+    pub fn record_rejected_connection(&self) {
+        self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts a client connection towards Metrics::client_connections for as long as it's alive
+pub struct OpenConnection<'a> {
+    metrics: &'a Metrics,
+}
+
+impl OpenConnection<'_> {
+    pub fn new(metrics: &Metrics) -> OpenConnection<'_> {
+        metrics.client_connections.fetch_add(1, Ordering::SeqCst);
+        OpenConnection { metrics }
+    }
+}
+
+impl Drop for OpenConnection<'_> {
+    fn drop(&mut self) {
+        self.metrics
+            .client_connections
+            .fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Counters for a single upstream, shared by its UpstreamState and the ActiveConnections to it
+#[derive(Debug, Default)]
+pub struct UpstreamMetrics {
+    /// Requests sent to the upstream
+    requests: AtomicU64,
+    /// Those that failed: the upstream couldn't be reached, didn't answer, or answered with a 5xx
+    errors: AtomicU64,
+    /// How long the upstream took to start answering
+    latency: Histogram,
+    /// The last RECENT_WINDOW's worth of the above, for recent_stats
+    recent: Mutex<RecentSamples>,
+}
+
+impl UpstreamMetrics {
+    pub fn record_outcome(&self, success: bool) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if !success {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        push_sample(&mut self.recent.lock().outcomes, success);
+    }
+
+    pub fn record_response_time(&self, response_time: Duration) {
+        self.latency.observe(response_time);
+        push_sample(&mut self.recent.lock().response_times, response_time);
+    }
+
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// Summarizes the requests this upstream finished in the last RECENT_WINDOW
+    pub fn recent_stats(&self) -> RecentStats {
+        let now = Instant::now();
+        let mut recent = self.recent.lock();
+        prune_samples(&mut recent.outcomes, now);
+        prune_samples(&mut recent.response_times, now);
+
+        let requests = recent.outcomes.len();
+        let errors = recent
+            .outcomes
+            .iter()
+            .filter(|(_, success)| !success)
+            .count();
+        let mut response_times: Vec<Duration> = recent
+            .response_times
+            .iter()
+            .map(|&(_, response_time)| response_time)
+            .collect();
+        response_times.sort_unstable();
+        let percentile = |p: f64| {
+            // Nearest-rank: the smallest sample that at least p of the samples are no bigger than
+            let rank = (p * response_times.len() as f64).ceil() as usize;
+            response_times
+                .get(rank.max(1) - 1)
+                .map(|response_time| response_time.as_secs_f64() * 1000.0)
+        };
+        RecentStats {
+            requests,
+            error_rate: Some(errors as f64 / requests as f64).filter(|_| requests > 0),
+            p50_ms: percentile(0.5),
+            p95_ms: percentile(0.95),
+            p99_ms: percentile(0.99),
+        }
+    }
+}
+
+/// Timestamped samples, oldest first
+#[derive(Debug, Default)]
+struct RecentSamples {
+    /// Whether each request succeeded
+    outcomes: VecDeque<(Instant, bool)>,
+    /// How long the upstream took to start answering each request
+    response_times: VecDeque<(Instant, Duration)>,
+}
+
+fn push_sample<T>(samples: &mut VecDeque<(Instant, T)>, sample: T) {
+    let now = Instant::now();
+    prune_samples(samples, now);
+    if samples.len() == MAX_RECENT_SAMPLES {
+        samples.pop_front();
+    }
+    samples.push_back((now, sample));
+}
+
+/// Drops samples older than RECENT_WINDOW
+fn prune_samples<T>(samples: &mut VecDeque<(Instant, T)>, now: Instant) {
+    while let Some(&(taken, _)) = samples.front() {
+        if now.duration_since(taken) <= RECENT_WINDOW {
+            break;
+        }
+        samples.pop_front();
+    }
+}
+
+/// How an upstream has been doing over the last RECENT_WINDOW, so that a degrading upstream can
+/// be spotted before it starts failing health checks
+#[derive(Debug, Serialize)]
+pub struct RecentStats {
+    /// Requests finished
+    pub requests: usize,
+    /// The fraction of those that failed (see UpstreamMetrics::errors), or None if there were none
+    pub error_rate: Option<f64>,
+    /// Percentiles of how long the upstream took to start answering, in milliseconds
+    pub p50_ms: Option<f64>,
+    pub p95_ms: Option<f64>,
+    pub p99_ms: Option<f64>,
+}
+
+/// Writes the HELP and TYPE lines that start a metric
+fn describe(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escapes a label value for the Prometheus text format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Renders all our metrics in the Prometheus text exposition format
+pub fn render(state: &ProxyState, upstreams: &[UpstreamState]) -> String {
+    let metrics = &state.metrics;
+    let mut out = String::new();
+
+    describe(
+        &mut out,
+        "balancebeam_responses_total",
+        "counter",
+        "Responses sent to clients, by status class",
+    );
+    for (class, count) in metrics.responses.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "balancebeam_responses_total{{class=\"{}xx\"}} {}",
+            class + 1,
+            count.load(Ordering::Relaxed)
+        );
+    }
+    describe(
+        &mut out,
+        "balancebeam_unanswered_requests_total",
+        "counter",
+        "Requests that got no response",
+    );
+    let _ = writeln!(
+        out,
+        "balancebeam_unanswered_requests_total {}",
+        metrics.unanswered.load(Ordering::Relaxed)
+    );
+    describe(
+        &mut out,
+        "balancebeam_request_duration_seconds",
+        "histogram",
+        "Time from reading a request to finishing its response",
+    );
+    metrics
+        .latency
+        .render(&mut out, "balancebeam_request_duration_seconds", "");
+    describe(
+        &mut out,
+        "balancebeam_client_connections",
+        "gauge",
+        "Client connections currently open",
+    );
+    let _ = writeln!(
+        out,
+        "balancebeam_client_connections {}",
+        metrics.client_connections()
+    );
+    describe(
+        &mut out,
+        "balancebeam_rejected_connections_total",
+        "counter",
+        "Client connections turned away because --max-connections were open",
+    );
+    let _ = writeln!(
+        out,
+        "balancebeam_rejected_connections_total {}",
+        metrics.rejected_connections.load(Ordering::Relaxed)
+    );
+    if let Some(cache) = &state.cache {
+        let (entries, size) = cache.usage();
+        describe(
+            &mut out,
+            "balancebeam_cache_hits_total",
+            "counter",
+            "Requests answered from the response cache",
+        );
+        let _ = writeln!(out, "balancebeam_cache_hits_total {}", cache.hits());
+        describe(
+            &mut out,
+            "balancebeam_cache_misses_total",
+            "counter",
+            "Cacheable requests that had to go to an upstream",
+        );
+        let _ = writeln!(out, "balancebeam_cache_misses_total {}", cache.misses());
+        describe(
+            &mut out,
+            "balancebeam_cache_entries",
+            "gauge",
+            "Responses in the cache",
+        );
+        let _ = writeln!(out, "balancebeam_cache_entries {}", entries);
+        describe(
+            &mut out,
+            "balancebeam_cache_size_bytes",
+            "gauge",
+            "Bytes of responses in the cache",
+        );
+        let _ = writeln!(out, "balancebeam_cache_size_bytes {}", size);
+    }
+    describe(
+        &mut out,
+        "balancebeam_in_flight_requests",
+        "gauge",
+        "Requests currently being handled",
+    );
+    let _ = writeln!(
+        out,
+        "balancebeam_in_flight_requests {}",
+        state.in_flight_requests.load(Ordering::SeqCst)
+    );
+
+    let labels: Vec<String> = upstreams
+        .iter()
+        .map(|upstream| format!("upstream=\"{}\"", escape_label(&upstream.addr)))
+        .collect();
+    describe(
+        &mut out,
+        "balancebeam_upstream_up",
+        "gauge",
+        "Whether the upstream is considered alive",
+    );
+    for (upstream, labels) in upstreams.iter().zip(&labels) {
+        let _ = writeln!(
+            out,
+            "balancebeam_upstream_up{{{}}} {}",
+            labels,
+            u8::from(!upstream.is_dead)
+        );
+    }
+    describe(
+        &mut out,
+        "balancebeam_upstream_active_connections",
+        "gauge",
+        "Requests currently being proxied to the upstream",
+    );
+    for (upstream, labels) in upstreams.iter().zip(&labels) {
+        let _ = writeln!(
+            out,
+            "balancebeam_upstream_active_connections{{{}}} {}",
+            labels,
+            upstream.active_connections.load(Ordering::SeqCst)
+        );
+    }
+    describe(
+        &mut out,
+        "balancebeam_upstream_requests_total",
+        "counter",
+        "Requests sent to the upstream",
+    );
+    for (upstream, labels) in upstreams.iter().zip(&labels) {
+        let _ = writeln!(
+            out,
+            "balancebeam_upstream_requests_total{{{}}} {}",
+            labels,
+            upstream.metrics.requests()
+        );
+    }
+    describe(
+        &mut out,
+        "balancebeam_upstream_errors_total",
+        "counter",
+        "Requests to the upstream that failed or got a 5xx response",
+    );
+    for (upstream, labels) in upstreams.iter().zip(&labels) {
+        let _ = writeln!(
+            out,
+            "balancebeam_upstream_errors_total{{{}}} {}",
+            labels,
+            upstream.metrics.errors()
+        );
+    }
+    describe(
+        &mut out,
+        "balancebeam_upstream_response_seconds",
+        "histogram",
+        "Time the upstream took to start answering",
+    );
+    for (upstream, labels) in upstreams.iter().zip(&labels) {
+        upstream
+            .metrics
+            .latency
+            .render(&mut out, "balancebeam_upstream_response_seconds", labels);
+    }
+    out
+}
@@ -0,0 +1,57 @@
+/// Puts a path in the form upstreams usually see it in once they've cleaned it up, so that rules
+/// can't be sidestepped with paths like `//admin`, `/./admin`, or `/%61dmin`: repeated slashes are
+/// collapsed, `.` and `..` segments resolved, and percent-escapes of unreserved characters decoded.
+pub fn normalize(path: &str) -> String {
+    let decoded = decode_unreserved(path);
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let mut normalized = format!("/{}", segments.join("/"));
+    if !segments.is_empty() && (decoded.ends_with('/') || decoded.ends_with("/.")) {
+        normalized.push('/');
+    }
+    normalized
+}
+
+/// Decodes the percent-escapes in a path that stand for unreserved characters (letters, digits,
+/// and `-._~`), which mean the same thing escaped or not. Others (like `%2F`) are left alone.
+fn decode_unreserved(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = String::with_capacity(path.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = path
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .filter(|&byte| byte.is_ascii_alphanumeric() || b"-._~".contains(&byte));
+        match escaped {
+            Some(byte) => {
+                decoded.push(char::from(byte));
+                i += 3;
+            }
+            None => {
+                let c = path[i..].chars().next().unwrap();
+                decoded.push(c);
+                i += c.len_utf8();
+            }
+        }
+    }
+    decoded
+}
+
+/// Whether a path prefix covers a path, matching whole segments: /api covers /api and /api/users
+/// but not /apiary.
+pub fn covers(prefix: &str, path: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'),
+        None => false,
+    }
+}
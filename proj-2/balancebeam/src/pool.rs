@@ -0,0 +1,92 @@
+use crate::{body, UpstreamConn};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// An upstream connection that's waiting to be reused
+struct IdleConnection {
+    conn: UpstreamConn,
+    idle_since: Instant,
+}
+
+/// Keeps upstream connections open after the clients using them are done, so that later clients
+/// can reuse them instead of paying for a new TCP (and TLS) handshake
+pub struct Pool {
+    /// Most idle connections kept per upstream
+    max_idle: usize,
+    /// How long a connection may sit idle before it's closed
+    idle_timeout: Duration,
+    /// Idle connections by upstream address, oldest first
+    idle: Mutex<HashMap<String, Vec<IdleConnection>>>,
+}
+
+impl Pool {
+    pub fn new(max_idle: usize, idle_timeout: Duration) -> Pool {
+        Pool {
+            max_idle,
+            idle_timeout,
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes an idle connection to the upstream at addr, if there's one that's still usable
+    pub async fn take(&self, addr: &str) -> Option<UpstreamConn> {
+        loop {
+            // The most recently used connection is the least likely to have been closed
+            let idle = {
+                let mut idle = self.idle.lock();
+                let conns = idle.get_mut(addr)?;
+                let conn = conns.pop();
+                if conns.is_empty() {
+                    idle.remove(addr);
+                }
+                conn?
+            };
+            if idle.idle_since.elapsed() >= self.idle_timeout {
+                // Everything older than this has timed out too
+                self.idle.lock().remove(addr);
+                return None;
+            }
+            let mut conn = idle.conn;
+            if is_still_idle(&mut conn).await {
+                return Some(conn);
+            }
+            log::debug!("Idle connection to {} was closed; trying another", addr);
+        }
+    }
+
+    /// Keeps a connection to the upstream at addr for reuse. The connection must be between
+    /// requests: anything sent on it so far has been answered in full.
+    pub fn put(&self, addr: &str, conn: UpstreamConn) {
+        if self.max_idle == 0 {
+            return;
+        }
+        let mut idle = self.idle.lock();
+        // Close whatever has timed out, so that connections to upstreams that are no longer used
+        // don't linger
+        let idle_timeout = self.idle_timeout;
+        idle.retain(|_, conns| {
+            conns.retain(|idle| idle.idle_since.elapsed() < idle_timeout);
+            !conns.is_empty()
+        });
+        let conns = idle.entry(addr.to_string()).or_default();
+        if conns.len() == self.max_idle {
+            conns.remove(0);
+        }
+        conns.push(IdleConnection {
+            conn,
+            idle_since: Instant::now(),
+        });
+    }
+}
+
+/// Returns true if nothing has happened on an idle connection. An upstream shouldn't send anything
+/// while it's not answering a request, so if there's something to read, it's most likely hung up
+/// (or is confused), and the connection can't be reused.
+async fn is_still_idle(conn: &mut UpstreamConn) -> bool {
+    // Peeking doesn't take anything out of the stream, so giving up on it right away is harmless
+    let readable = body::peek(conn, |_| ());
+    tokio::time::timeout(Duration::from_secs(0), readable)
+        .await
+        .is_err()
+}
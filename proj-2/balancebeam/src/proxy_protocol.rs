@@ -0,0 +1,301 @@
+use crate::tls::ClientStream;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::time::{timeout, Duration};
+
+/// The PROXY protocol (https://www.haproxy.org/download/2.0/doc/proxy-protocol.txt) lets a proxy
+/// in front of us tell us where each connection really came from, and lets us tell upstreams the
+/// same. Every v2 header starts with this.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The longest a v1 header can be, including its CRLF
+const V1_MAX_LENGTH: usize = 107;
+
+/// How long a client has to send its header once it's connected
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Which version of the PROXY protocol to send an upstream
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Version {
+    /// The human-readable version (`PROXY TCP4 ...`)
+    V1,
+    /// The binary version
+    V2,
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" => Ok(Version::V1),
+            "v2" => Ok(Version::V2),
+            other => Err(format!(
+                "unknown PROXY protocol version \"{}\" (expected v1 or v2)",
+                other
+            )),
+        }
+    }
+}
+
+/// The two ends of a client's original connection, as a PROXY protocol header describes them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Origin {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+/// Reads the PROXY protocol header (either version) that a client must start its connection with
+/// on a listener that accepts them, and returns the connection's origin. Returns None for headers
+/// that don't name one (a v1 UNKNOWN, or a v2 LOCAL connection such as a health check), in which
+/// case the connection is taken to come from its actual peer. Reads nothing past the header.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<Origin>> {
+    timeout(HEADER_TIMEOUT, read_header_inner(stream))
+        .await
+        .map_err(|_| invalid("timed out waiting for a PROXY protocol header"))?
+}
+
+async fn read_header_inner<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<Origin>> {
+    // Both versions' headers are at least this long
+    let mut start = [0_u8; 12];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        let mut fixed = [0_u8; 4];
+        stream.read_exact(&mut fixed).await?;
+        let length = u16::from_be_bytes([fixed[2], fixed[3]]) as usize;
+        let mut addresses = vec![0_u8; length];
+        stream.read_exact(&mut addresses).await?;
+        return parse_v2(fixed[0], fixed[1], &addresses);
+    }
+    if !start.starts_with(b"PROXY ") {
+        return Err(invalid(
+            "connection didn't start with a PROXY protocol header",
+        ));
+    }
+    // v1 headers end with CRLF; read up to it a byte at a time, so as not to read past it
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LENGTH {
+            return Err(invalid("PROXY protocol v1 header is too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    parse_v1(&line[..line.len() - 2])
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Parses a v1 header (without its CRLF), e.g. `PROXY TCP4 203.0.113.7 10.0.0.1 51234 443`
+fn parse_v1(line: &[u8]) -> io::Result<Option<Origin>> {
+    let line =
+        std::str::from_utf8(line).map_err(|_| invalid("invalid PROXY protocol v1 header"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4", source, destination, source_port, destination_port]
+        | ["PROXY", "TCP6", source, destination, source_port, destination_port] => {
+            let address = |ip: &str, port: &str| -> Option<SocketAddr> {
+                Some(SocketAddr::new(ip.parse().ok()?, port.parse().ok()?))
+            };
+            match (
+                address(source, source_port),
+                address(destination, destination_port),
+            ) {
+                (Some(source), Some(destination)) => Ok(Some(Origin {
+                    source,
+                    destination,
+                })),
+                _ => Err(invalid("invalid address in PROXY protocol v1 header")),
+            }
+        }
+        _ => Err(invalid("invalid PROXY protocol v1 header")),
+    }
+}
+
+/// Parses the rest of a v2 header, given its version/command and family bytes and its address
+/// block
+fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> io::Result<Option<Origin>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL: the sender's own connection, e.g. a health check
+        0 => return Ok(None),
+        // PROXY
+        1 => {}
+        _ => return Err(invalid("unsupported PROXY protocol command")),
+    }
+    let port = |pos: usize| u16::from_be_bytes([addresses[pos], addresses[pos + 1]]);
+    // The high nibble is the address family, and the low one the transport (TCP or UDP)
+    match family >> 4 {
+        // IPv4
+        1 if addresses.len() >= 12 => {
+            let ip = |pos: usize| {
+                IpAddr::from([
+                    addresses[pos],
+                    addresses[pos + 1],
+                    addresses[pos + 2],
+                    addresses[pos + 3],
+                ])
+            };
+            Ok(Some(Origin {
+                source: SocketAddr::new(ip(0), port(8)),
+                destination: SocketAddr::new(ip(4), port(10)),
+            }))
+        }
+        // IPv6
+        2 if addresses.len() >= 36 => {
+            let ip = |pos: usize| {
+                let mut octets = [0_u8; 16];
+                octets.copy_from_slice(&addresses[pos..pos + 16]);
+                IpAddr::from(octets)
+            };
+            Ok(Some(Origin {
+                source: SocketAddr::new(ip(0), port(32)),
+                destination: SocketAddr::new(ip(16), port(34)),
+            }))
+        }
+        1 | 2 => Err(invalid("PROXY protocol v2 address block is too short")),
+        // Unspecified or Unix socket addresses: there's no IP to report
+        _ => Ok(None),
+    }
+}
+
+/// Encodes the header to start an upstream connection with. Without an origin (e.g. for a health
+/// check), the header says the connection is our own.
+pub fn encode_header(version: Version, origin: Option<&Origin>) -> Vec<u8> {
+    // Both ends must be in the same family, so if they aren't, describe both as IPv6
+    let origin = origin.map(|origin| match (origin.source, origin.destination) {
+        (SocketAddr::V4(_), SocketAddr::V4(_)) | (SocketAddr::V6(_), SocketAddr::V6(_)) => {
+            (origin.source, origin.destination)
+        }
+        _ => (to_ipv6(origin.source), to_ipv6(origin.destination)),
+    });
+    match version {
+        Version::V1 => match origin {
+            Some((source, destination)) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if source.is_ipv4() { "TCP4" } else { "TCP6" },
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes(),
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        Version::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            let (source, destination) = match origin {
+                Some(addresses) => addresses,
+                None => {
+                    // LOCAL, with no addresses
+                    header.extend_from_slice(&[0x20, 0x00, 0, 0]);
+                    return header;
+                }
+            };
+            let mut addresses = Vec::with_capacity(36);
+            let family = match (source.ip(), destination.ip()) {
+                (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+                    addresses.extend_from_slice(&source_ip.octets());
+                    addresses.extend_from_slice(&destination_ip.octets());
+                    // IPv4 over TCP
+                    0x11
+                }
+                (source_ip, destination_ip) => {
+                    addresses.extend_from_slice(&to_ipv6_octets(source_ip));
+                    addresses.extend_from_slice(&to_ipv6_octets(destination_ip));
+                    // IPv6 over TCP
+                    0x21
+                }
+            };
+            addresses.extend_from_slice(&source.port().to_be_bytes());
+            addresses.extend_from_slice(&destination.port().to_be_bytes());
+            // Version 2, PROXY command
+            header.push(0x21);
+            header.push(family);
+            header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+            header.extend_from_slice(&addresses);
+            header
+        }
+    }
+}
+
+fn to_ipv6(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(IpAddr::from(to_ipv6_octets(addr.ip())), addr.port())
+}
+
+fn to_ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+/// A client connection that may have arrived through a proxy that told us (with a PROXY protocol
+/// header) where it really came from. If it did, that's what peer_addr and local_addr report, so
+/// that logging, rate limiting, and the headers we send upstream all see the real client.
+pub struct Proxied<S> {
+    inner: S,
+    origin: Option<Origin>,
+}
+
+impl<S> Proxied<S> {
+    pub fn new(inner: S, origin: Option<Origin>) -> Proxied<S> {
+        Proxied { inner, origin }
+    }
+}
+
+impl<S: ClientStream> ClientStream for Proxied<S> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self.origin {
+            Some(origin) => Ok(origin.source),
+            None => self.inner.peer_addr(),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self.origin {
+            Some(origin) => Ok(origin.destination),
+            None => self.inner.local_addr(),
+        }
+    }
+
+    fn proto(&self) -> &'static str {
+        self.inner.proto()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Proxied<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Proxied<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
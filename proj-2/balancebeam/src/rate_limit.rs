@@ -0,0 +1,592 @@
+use crate::cidr::Cidr;
+use crate::path;
+use crate::redis;
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
+
+/// How long a sliding window covers
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// How often idle clients are cleared out of the ClientTable (see ClientTable::remove_idle)
+pub const CLEANUP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How clients' requests are counted against --max-requests-per-minute
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    /// See TokenBucket
+    TokenBucket,
+    /// See SlidingWindow
+    SlidingWindow,
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "token-bucket" => Ok(Algorithm::TokenBucket),
+            "sliding-window" => Ok(Algorithm::SlidingWindow),
+            other => Err(format!(
+                "unknown rate limiting algorithm \"{}\" (expected token-bucket or sliding-window)",
+                other
+            )),
+        }
+    }
+}
+
+/// How many requests a client may make
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    /// The sustained rate. Never 0 (a limit of 0 means no limit, which is no Limit at all).
+    pub per_minute: usize,
+    /// How many requests can be made at once, for TokenBucket
+    pub burst: usize,
+    pub algorithm: Algorithm,
+}
+
+impl Limit {
+    /// How long after its last request a client's counter is back to how a new client's would
+    /// start
+    fn idle_expiry(&self) -> Duration {
+        match self.algorithm {
+            Algorithm::TokenBucket => {
+                Duration::from_secs_f64(self.burst as f64 * 60.0 / self.per_minute as f64)
+            }
+            Algorithm::SlidingWindow => WINDOW * 2,
+        }
+    }
+}
+
+/// A limit on requests to some paths (and/or with some method), counted separately from and on
+/// top of the limit on all of a client's requests, so that expensive endpoints (e.g. /login) can be
+/// held to less. Written on the command line as `[METHOD] [PATH PREFIX]=REQUESTS PER MINUTE`, e.g.
+/// `POST /login=10`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteRule {
+    /// Only requests with this method count, if given
+    pub method: Option<http::Method>,
+    /// Only requests for paths under this count (matching whole segments, as for --route)
+    pub path_prefix: String,
+    pub per_minute: usize,
+    /// Defaults to per_minute (see --rate-limit-burst)
+    pub burst: Option<usize>,
+}
+
+impl RouteRule {
+    /// Returns true if requests with the given method and path count against this rule. The path
+    /// should have been through path::normalize, so that e.g. `//login` counts against `/login`.
+    pub fn matches(&self, method: &http::Method, path: &str) -> bool {
+        self.method.as_ref().is_none_or(|rule| rule == method)
+            && path::covers(&self.path_prefix, path)
+    }
+
+    /// The Limit for this rule, counted with the given algorithm
+    pub fn limit(&self, algorithm: Algorithm) -> Limit {
+        Limit {
+            per_minute: self.per_minute,
+            burst: self.burst.unwrap_or(self.per_minute),
+            algorithm,
+        }
+    }
+}
+
+impl FromStr for RouteRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (route, per_minute) = s.rsplit_once('=').ok_or_else(|| {
+            format!(
+                "invalid route rate limit \"{}\" (expected e.g. \"POST /login=10\")",
+                s
+            )
+        })?;
+        let per_minute = match per_minute.trim().parse() {
+            Ok(0) | Err(_) => {
+                return Err(format!(
+                    "invalid requests per minute \"{}\" in route rate limit \"{}\"",
+                    per_minute, s
+                ))
+            }
+            Ok(per_minute) => per_minute,
+        };
+        let mut method = None;
+        let mut path_prefix = None;
+        for part in route.split_whitespace() {
+            if part.starts_with('/') && path_prefix.is_none() {
+                path_prefix = Some(part.to_string());
+            } else if method.is_none() && path_prefix.is_none() {
+                method = Some(
+                    part.parse()
+                        .map_err(|_| format!("invalid method \"{}\" in \"{}\"", part, s))?,
+                );
+            } else {
+                return Err(format!(
+                    "unexpected \"{}\" in route rate limit \"{}\"",
+                    part, s
+                ));
+            }
+        }
+        if method.is_none() && path_prefix.is_none() {
+            return Err(format!(
+                "route rate limit \"{}\" needs a method, a path prefix, or both",
+                s
+            ));
+        }
+        Ok(RouteRule {
+            method,
+            path_prefix: path_prefix.unwrap_or_else(|| "/".to_string()),
+            per_minute,
+            burst: None,
+        })
+    }
+}
+
+/// A RouteRule in effect, with the clients whose requests it's counting
+#[derive(Debug)]
+pub struct RouteLimiter {
+    pub rule: RouteRule,
+    pub limit: Limit,
+    pub clients: Box<dyn RateLimiterStore>,
+}
+
+impl RouteLimiter {
+    pub fn new(
+        rule: RouteRule,
+        algorithm: Algorithm,
+        clients: Box<dyn RateLimiterStore>,
+    ) -> RouteLimiter {
+        RouteLimiter {
+            limit: rule.limit(algorithm),
+            rule,
+            clients,
+        }
+    }
+
+    /// What sets this rule's clients apart from other rules' in a shared store (see RedisStore)
+    pub fn key_prefix(rule: &RouteRule) -> String {
+        format!(
+            "route:{}:{}:",
+            rule.method.as_ref().map_or("*", |method| method.as_str()),
+            rule.path_prefix
+        )
+    }
+}
+
+/// Where clients' counters are kept: in memory (MemoryStore, the default), or in Redis
+/// (RedisStore), so that several balancebeams can share them
+#[async_trait]
+pub trait RateLimiterStore: std::fmt::Debug + Send + Sync {
+    /// Counts a request from client against limit. If the client is over it, the request should be
+    /// turned away, and this returns how long until the client can make another.
+    async fn take(&self, client: &str, limit: &Limit, now: Instant) -> Result<(), Duration>;
+
+    /// Forgets clients that no longer need remembering (see ClientTable::remove_idle). Returns how
+    /// many were removed.
+    async fn remove_idle(&self, limit: &Limit, now: Instant) -> usize;
+
+    /// The number of clients being tracked, if the store knows
+    fn tracked_clients(&self) -> Option<usize>;
+}
+
+/// Keeps counters in a ClientTable in our own memory
+#[derive(Debug)]
+pub struct MemoryStore {
+    table: Mutex<ClientTable>,
+}
+
+impl MemoryStore {
+    pub fn new(max_clients: usize) -> MemoryStore {
+        MemoryStore {
+            table: Mutex::new(ClientTable::new(max_clients)),
+        }
+    }
+}
+
+#[async_trait]
+impl RateLimiterStore for MemoryStore {
+    async fn take(&self, client: &str, limit: &Limit, now: Instant) -> Result<(), Duration> {
+        self.table.lock().take(client, limit, now)
+    }
+
+    async fn remove_idle(&self, limit: &Limit, now: Instant) -> usize {
+        self.table.lock().remove_idle(limit, now)
+    }
+
+    fn tracked_clients(&self) -> Option<usize> {
+        Some(self.table.lock().len())
+    }
+}
+
+/// Counts a request against a TokenBucket kept in a Redis hash (see TokenBucket::take), timed by
+/// Redis's clock so that balancebeams with different clocks agree. Returns 0 if the request is
+/// allowed, or else how many milliseconds until another will be.
+/// KEYS[1] is the client's key; ARGV is per_minute, burst, and how long to keep an idle bucket.
+const TOKEN_BUCKET_SCRIPT: &str = r#"
+local per_minute, burst = tonumber(ARGV[1]), tonumber(ARGV[2])
+-- Before Redis 5, a script can't write after reading the time without this
+redis.replicate_commands()
+local time = redis.call('TIME')
+local now = tonumber(time[1]) + tonumber(time[2]) / 1000000
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'updated')
+local tokens = tonumber(bucket[1]) or burst
+local updated = tonumber(bucket[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - updated) * per_minute / 60)
+local wait = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+else
+    wait = math.max(1, math.ceil((1 - tokens) * 60000 / per_minute))
+end
+redis.call('HMSET', KEYS[1], 'tokens', tostring(tokens), 'updated', tostring(now))
+redis.call('EXPIRE', KEYS[1], ARGV[3])
+return wait
+"#;
+
+/// Counts a request against a SlidingWindow kept as one Redis counter per minute (see
+/// SlidingWindow::take). Returns the same as TOKEN_BUCKET_SCRIPT. KEYS[1] is the client's key;
+/// ARGV is per_minute, and how long to keep a minute's counter.
+const SLIDING_WINDOW_SCRIPT: &str = r#"
+local per_minute = tonumber(ARGV[1])
+-- Before Redis 5, a script can't write after reading the time without this
+redis.replicate_commands()
+local time = redis.call('TIME')
+local now = tonumber(time[1]) + tonumber(time[2]) / 1000000
+local window = math.floor(now / 60)
+local current_key = KEYS[1] .. ':' .. window
+local previous = tonumber(redis.call('GET', KEYS[1] .. ':' .. (window - 1))) or 0
+local current = tonumber(redis.call('GET', current_key)) or 0
+local into_window = now - window * 60
+if previous * (1 - into_window / 60) + current + 1 > per_minute then
+    local wait
+    if current + 1 <= per_minute then
+        wait = 60 * (1 - (per_minute - current - 1) / previous) - into_window
+    else
+        wait = 60 - into_window + math.max(0, 60 * (1 - (per_minute - 1) / current))
+    end
+    return math.max(1, math.ceil(wait * 1000))
+end
+redis.call('INCR', current_key)
+redis.call('EXPIRE', current_key, ARGV[2])
+return 0
+"#;
+
+/// Keeps counters in Redis, so that every balancebeam using the same server shares them. Counters
+/// expire in Redis on their own once idle. If Redis can't be reached, requests are let through
+/// rather than turning everyone away.
+#[derive(Debug)]
+pub struct RedisStore {
+    client: Arc<redis::Client>,
+    /// Put in front of client keys, to keep them apart from other keys on the server
+    prefix: String,
+}
+
+impl RedisStore {
+    pub fn new(client: Arc<redis::Client>, prefix: String) -> RedisStore {
+        RedisStore {
+            client,
+            prefix: format!("balancebeam:rate-limit:{}", prefix),
+        }
+    }
+}
+
+#[async_trait]
+impl RateLimiterStore for RedisStore {
+    async fn take(&self, client: &str, limit: &Limit, _now: Instant) -> Result<(), Duration> {
+        let key = format!("{}{}", self.prefix, client);
+        let per_minute = limit.per_minute.to_string();
+        let burst = limit.burst.to_string();
+        let expiry = (limit.idle_expiry().as_secs() + 1).to_string();
+        let reply = match limit.algorithm {
+            Algorithm::TokenBucket => {
+                self.client
+                    .command(&[
+                        b"EVAL",
+                        TOKEN_BUCKET_SCRIPT.as_bytes(),
+                        b"1",
+                        key.as_bytes(),
+                        per_minute.as_bytes(),
+                        burst.as_bytes(),
+                        expiry.as_bytes(),
+                    ])
+                    .await
+            }
+            Algorithm::SlidingWindow => {
+                self.client
+                    .command(&[
+                        b"EVAL",
+                        SLIDING_WINDOW_SCRIPT.as_bytes(),
+                        b"1",
+                        key.as_bytes(),
+                        per_minute.as_bytes(),
+                        expiry.as_bytes(),
+                    ])
+                    .await
+            }
+        };
+        match reply {
+            Ok(redis::Reply::Integer(0)) => Ok(()),
+            Ok(redis::Reply::Integer(wait)) if wait > 0 => Err(Duration::from_millis(wait as u64)),
+            Ok(reply) => {
+                log::warn!(
+                    "Unexpected rate limit reply from Redis at {}: {:?}",
+                    self.client.authority(),
+                    reply
+                );
+                Ok(())
+            }
+            Err(err) => {
+                log::warn!(
+                    "Could not reach Redis at {} for rate limiting: {}",
+                    self.client.authority(),
+                    err
+                );
+                Ok(())
+            }
+        }
+    }
+
+    async fn remove_idle(&self, _limit: &Limit, _now: Instant) -> usize {
+        // Redis expires idle counters itself
+        0
+    }
+
+    fn tracked_clients(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// Clients that are never rate limited (e.g. health checkers), and clients that are always turned
+/// away. These are checked before a client's counter; a client in both lists is denied.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientLists {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+/// Which of the ClientLists a client is in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Listed {
+    Allowed,
+    Denied,
+    Unlisted,
+}
+
+impl ClientLists {
+    pub fn check(&self, ip: IpAddr) -> Listed {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            Listed::Denied
+        } else if self.allow.iter().any(|cidr| cidr.contains(ip)) {
+            Listed::Allowed
+        } else {
+            Listed::Unlisted
+        }
+    }
+}
+
+/// The clients being rate limited, with their counters. It holds at most max_clients; to make room
+/// past that, the client whose last request was longest ago is forgotten, so that requests from
+/// many different addresses (e.g. a scan) can't use up our memory.
+#[derive(Debug)]
+pub struct ClientTable {
+    clients: HashMap<String, Client>,
+    /// The clients' keys, by when they last made a request (oldest first)
+    by_last_request: BTreeMap<u64, String>,
+    /// Goes up with each request, to order by_last_request
+    next_sequence: u64,
+    max_clients: usize,
+}
+
+#[derive(Debug)]
+struct Client {
+    counter: Counter,
+    last_request: Instant,
+    /// last_request's key in by_last_request
+    sequence: u64,
+}
+
+impl ClientTable {
+    pub fn new(max_clients: usize) -> ClientTable {
+        ClientTable {
+            clients: HashMap::new(),
+            by_last_request: BTreeMap::new(),
+            next_sequence: 0,
+            max_clients: max_clients.max(1),
+        }
+    }
+
+    /// The number of clients being tracked
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Counts a request from client (see Counter::take)
+    pub fn take(&mut self, client: &str, limit: &Limit, now: Instant) -> Result<(), Duration> {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        if let Some(entry) = self.clients.get_mut(client) {
+            self.by_last_request.remove(&entry.sequence);
+            self.by_last_request.insert(sequence, client.to_string());
+            entry.sequence = sequence;
+            entry.last_request = now;
+            return entry.counter.take(limit, now);
+        }
+        if self.clients.len() >= self.max_clients {
+            if let Some((_, oldest)) = self.by_last_request.pop_first() {
+                log::debug!("Rate limit table is full; forgetting client {}", oldest);
+                self.clients.remove(&oldest);
+            }
+        }
+        let mut counter = Counter::new(limit, now);
+        let taken = counter.take(limit, now);
+        self.by_last_request.insert(sequence, client.to_string());
+        self.clients.insert(
+            client.to_string(),
+            Client {
+                counter,
+                last_request: now,
+                sequence,
+            },
+        );
+        taken
+    }
+
+    /// Forgets clients whose counters are back to how a new client's would start, since they'd be
+    /// made again just the same if those clients come back. Returns how many were removed.
+    pub fn remove_idle(&mut self, limit: &Limit, now: Instant) -> usize {
+        let expiry = limit.idle_expiry();
+        let mut removed = 0;
+        while let Some((_, client)) = self.by_last_request.first_key_value() {
+            if now.duration_since(self.clients[client].last_request) < expiry {
+                break;
+            }
+            let (_, client) = self.by_last_request.pop_first().unwrap();
+            self.clients.remove(&client);
+            removed += 1;
+        }
+        removed
+    }
+}
+
+/// One client's recent requests, as counted by whichever Algorithm is in use
+#[derive(Debug, Clone)]
+pub enum Counter {
+    TokenBucket(TokenBucket),
+    SlidingWindow(SlidingWindow),
+}
+
+impl Counter {
+    pub fn new(limit: &Limit, now: Instant) -> Counter {
+        match limit.algorithm {
+            Algorithm::TokenBucket => Counter::TokenBucket(TokenBucket::new(limit.burst, now)),
+            Algorithm::SlidingWindow => Counter::SlidingWindow(SlidingWindow::new(now)),
+        }
+    }
+
+    /// Counts a request, if the client is allowed another. If it isn't, the request should be
+    /// turned away, and this returns how long until it will be.
+    pub fn take(&mut self, limit: &Limit, now: Instant) -> Result<(), Duration> {
+        match self {
+            Counter::TokenBucket(bucket) => bucket.take(limit.per_minute, limit.burst, now),
+            Counter::SlidingWindow(window) => window.take(limit.per_minute, now),
+        }
+    }
+}
+
+/// A client's allowance of requests: it holds up to burst tokens, refills at the sustained rate,
+/// and each request takes a token. Unlike counting requests per fixed minute, this doesn't let a
+/// client get twice its limit through by sending a minute's worth on each side of the point where
+/// the count resets.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    tokens: f64,
+    /// When tokens was last brought up to date
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Makes a full bucket
+    pub fn new(burst: usize, now: Instant) -> TokenBucket {
+        TokenBucket {
+            tokens: burst as f64,
+            updated: now,
+        }
+    }
+
+    /// Takes a token for a request, refilling the bucket at per_minute tokens a minute (up to
+    /// burst) first. If there wasn't one, the request should be turned away, and this returns how
+    /// long until there will be.
+    pub fn take(&mut self, per_minute: usize, burst: usize, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_minute as f64 / 60.0).min(burst as f64);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) * 60.0 / per_minute as f64,
+            ))
+        }
+    }
+}
+
+/// Counts requests per minute, but rather than starting each minute from zero, assumes the
+/// previous minute's requests were spread evenly over it and counts the part of it that's still
+/// within the last minute. This keeps the limit smooth over time, without remembering every
+/// request. (Bursts aren't allowed past the limit, so --rate-limit-burst doesn't apply.)
+#[derive(Debug, Clone)]
+pub struct SlidingWindow {
+    /// When the current window started
+    window_start: Instant,
+    /// Requests allowed in the current window and the one before it
+    current: usize,
+    previous: usize,
+}
+
+impl SlidingWindow {
+    pub fn new(now: Instant) -> SlidingWindow {
+        SlidingWindow {
+            window_start: now,
+            current: 0,
+            previous: 0,
+        }
+    }
+
+    /// Counts a request, if there have been fewer than per_minute in the last minute. If not,
+    /// returns how long until there will have been.
+    pub fn take(&mut self, per_minute: usize, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed >= WINDOW * 2 {
+            self.window_start = now;
+            self.previous = 0;
+            self.current = 0;
+        } else if elapsed >= WINDOW {
+            self.window_start += WINDOW;
+            self.previous = self.current;
+            self.current = 0;
+        }
+        let window = WINDOW.as_secs_f64();
+        let into_window = now.duration_since(self.window_start).as_secs_f64();
+        let previous_weight = 1.0 - into_window / window;
+        let estimate = self.previous as f64 * previous_weight + self.current as f64;
+        if estimate + 1.0 <= per_minute as f64 {
+            self.current += 1;
+            return Ok(());
+        }
+        let (per_minute, current, previous) =
+            (per_minute as f64, self.current as f64, self.previous as f64);
+        // Either enough of the previous window has to slide out of the last minute, or, if the
+        // current window is already full, all of it does, and then enough of this one
+        let wait = if current + 1.0 <= per_minute {
+            window * (1.0 - (per_minute - current - 1.0) / previous) - into_window
+        } else {
+            window - into_window + (window * (1.0 - (per_minute - 1.0) / current)).max(0.0)
+        };
+        Err(Duration::from_secs_f64(wait.max(0.0)))
+    }
+}
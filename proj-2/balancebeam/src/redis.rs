@@ -0,0 +1,214 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::time::{timeout, Duration};
+
+/// How long Redis has to answer a command (including connecting, if we have to), so that a slow
+/// or missing server doesn't hold up the requests waiting on it
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Longest bulk string and array replies we'll read. We only ever get counters and short strings
+/// back, so anything bigger means the server (or whatever is answering in its place) is confused,
+/// and we shouldn't allocate for it.
+const MAX_BULK_LEN: i64 = 1 << 20;
+const MAX_ARRAY_LEN: i64 = 1024;
+
+/// A Redis server, given as `redis://[:password@]host[:port][/db]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct RedisAddress {
+    /// The host:port to connect to
+    authority: String,
+    password: Option<String>,
+    db: Option<u32>,
+}
+
+impl FromStr for RedisAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix("redis://")
+            .ok_or_else(|| format!("Redis address \"{}\" must start with redis://", s))?;
+        let (rest, db) = match rest.split_once('/') {
+            Some((rest, "")) => (rest, None),
+            Some((rest, db)) => (
+                rest,
+                Some(
+                    db.parse()
+                        .map_err(|_| format!("invalid Redis database \"{}\" in \"{}\"", db, s))?,
+                ),
+            ),
+            None => (rest, None),
+        };
+        let (password, authority) = match rest.rsplit_once('@') {
+            Some((userinfo, authority)) => {
+                // Redis before 6 has no users, so only the password part matters
+                let password = userinfo.split_once(':').map_or(userinfo, |(_, pass)| pass);
+                (Some(password.to_string()), authority)
+            }
+            None => (None, rest),
+        };
+        if authority.is_empty() {
+            return Err(format!("Redis address \"{}\" has no host", s));
+        }
+        let authority = if authority.contains(':') && !authority.ends_with(']') {
+            authority.to_string()
+        } else {
+            format!("{}:6379", authority)
+        };
+        Ok(RedisAddress {
+            authority,
+            password: password.filter(|password| !password.is_empty()),
+            db,
+        })
+    }
+}
+
+/// A reply to a command (see https://redis.io/docs/reference/protocol-spec/)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Status(String),
+    /// The command failed, though the connection is fine
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+/// A connection to a Redis server. It's made when first needed, and made again after anything goes
+/// wrong with it. Commands are sent one at a time.
+#[derive(Debug)]
+pub struct Client {
+    address: RedisAddress,
+    conn: Mutex<Option<BufReader<TcpStream>>>,
+}
+
+impl Client {
+    pub fn new(address: RedisAddress) -> Client {
+        Client {
+            address,
+            conn: Mutex::new(None),
+        }
+    }
+
+    /// The host:port of the server, for logging
+    pub fn authority(&self) -> &str {
+        &self.address.authority
+    }
+
+    /// Runs a command, given as its name and arguments. Returns an error if the server couldn't
+    /// be reached or didn't answer in time (an error from the command itself is a Reply::Error).
+    pub async fn command(&self, args: &[&[u8]]) -> Result<Reply, String> {
+        let mut conn = self.conn.lock().await;
+        let result = timeout(COMMAND_TIMEOUT, async {
+            if conn.is_none() {
+                *conn = Some(connect(&self.address).await?);
+            }
+            run(conn.as_mut().unwrap(), args).await
+        })
+        .await
+        .unwrap_or_else(|_| Err("timed out".to_string()));
+        if result.is_err() {
+            // We can't tell where the connection is up to, so start over with a new one
+            *conn = None;
+        }
+        result
+    }
+}
+
+/// Connects to the server, logging in and picking the database if the address says to
+async fn connect(address: &RedisAddress) -> Result<BufReader<TcpStream>, String> {
+    let mut conn = BufReader::new(
+        TcpStream::connect(&address.authority)
+            .await
+            .map_err(|err| err.to_string())?,
+    );
+    if let Some(password) = &address.password {
+        if let Reply::Error(err) = run(&mut conn, &[b"AUTH", password.as_bytes()]).await? {
+            return Err(format!("AUTH failed: {}", err));
+        }
+    }
+    if let Some(db) = address.db {
+        let db = db.to_string();
+        if let Reply::Error(err) = run(&mut conn, &[b"SELECT", db.as_bytes()]).await? {
+            return Err(format!("SELECT failed: {}", err));
+        }
+    }
+    Ok(conn)
+}
+
+/// Sends a command and reads its reply
+async fn run(conn: &mut BufReader<TcpStream>, args: &[&[u8]]) -> Result<Reply, String> {
+    let mut command = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        command.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        command.extend_from_slice(arg);
+        command.extend_from_slice(b"\r\n");
+    }
+    conn.get_mut()
+        .write_all(&command)
+        .await
+        .map_err(|err| err.to_string())?;
+    read_reply(conn).await
+}
+
+/// Reads a reply. (Arrays hold replies of their own, so this has to box itself to recurse.)
+fn read_reply(
+    conn: &mut BufReader<TcpStream>,
+) -> Pin<Box<dyn Future<Output = Result<Reply, String>> + Send + '_>> {
+    Box::pin(async move {
+        let mut line = String::new();
+        conn.read_line(&mut line)
+            .await
+            .map_err(|err| err.to_string())?;
+        let line = line
+            .strip_suffix("\r\n")
+            .ok_or_else(|| "connection closed".to_string())?;
+        if line.is_empty() {
+            return Err("empty reply".to_string());
+        }
+        let rest = line
+            .get(1..)
+            .ok_or_else(|| format!("unexpected reply \"{}\"", line))?;
+        let length = || {
+            rest.parse::<i64>()
+                .map_err(|_| format!("invalid length in reply \"{}\"", line))
+        };
+        match line.as_bytes()[0] {
+            b'+' => Ok(Reply::Status(rest.to_string())),
+            b'-' => Ok(Reply::Error(rest.to_string())),
+            b':' => Ok(Reply::Integer(length()?)),
+            b'$' => match length()? {
+                length if length < 0 => Ok(Reply::Bulk(None)),
+                length if length > MAX_BULK_LEN => {
+                    Err(format!("bulk reply of {} bytes is too long", length))
+                }
+                length => {
+                    let mut data = vec![0; length as usize + 2];
+                    conn.read_exact(&mut data)
+                        .await
+                        .map_err(|err| err.to_string())?;
+                    data.truncate(length as usize);
+                    Ok(Reply::Bulk(Some(data)))
+                }
+            },
+            b'*' => match length()? {
+                length if length < 0 => Ok(Reply::Array(None)),
+                length if length > MAX_ARRAY_LEN => {
+                    Err(format!("array reply of {} items is too long", length))
+                }
+                length => {
+                    let mut items = Vec::with_capacity(length as usize);
+                    for _ in 0..length {
+                        items.push(read_reply(conn).await?);
+                    }
+                    Ok(Reply::Array(Some(items)))
+                }
+            },
+            _ => Err(format!("unexpected reply \"{}\"", line)),
+        }
+    })
+}
@@ -1,6 +1,7 @@
+use crate::body::{self, Framing};
 use std::cmp::min;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use std::pin::Pin;
+use tokio::io::{AsyncBufRead, AsyncWrite, AsyncWriteExt};
 
 const MAX_HEADERS_SIZE: usize = 8000;
 const MAX_BODY_SIZE: usize = 10000000;
@@ -13,36 +14,118 @@ pub enum Error {
     IncompleteRequest(usize),
     /// Client sent an invalid HTTP request. httparse::Error contains more details
     MalformedRequest(httparse::Error),
-    /// The Content-Length header is present, but does not contain a valid numeric value
+    /// The Content-Length header is present, but does not contain a valid numeric value (or there
+    /// are several that don't agree)
     InvalidContentLength,
+    /// There's a Transfer-Encoding header, but chunked isn't its final coding, so there's no
+    /// telling where the body ends
+    InvalidTransferEncoding,
     /// The Content-Length header does not match the size of the request body that was sent
     ContentLengthMismatch,
     /// The request body is bigger than MAX_BODY_SIZE
     RequestBodyTooLarge,
-    /// Encountered an I/O error when reading/writing a TcpStream
-    ConnectionError(std::io::Error),
+    /// The request's headers are bigger, or more numerous, than HeaderLimits allows
+    HeadersTooLarge,
+    /// The request uses a method that normally carries a body (e.g. POST), but has neither a
+    /// Content-Length nor a Transfer-Encoding header, so we can't tell where its body ends
+    LengthRequired,
+    /// The body is chunked, but isn't valid chunked encoding (or the client hung up partway through
+    /// it)
+    InvalidChunkedBody,
+    /// Encountered an I/O error when reading/writing a stream
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IncompleteRequest(bytes_read) => write!(
+                f,
+                "client hung up after sending {} bytes of a request",
+                bytes_read
+            ),
+            Error::MalformedRequest(err) => write!(f, "malformed request: {}", err),
+            Error::InvalidContentLength => write!(f, "invalid Content-Length"),
+            Error::InvalidTransferEncoding => write!(f, "Transfer-Encoding doesn't end in chunked"),
+            Error::ContentLengthMismatch => write!(f, "body doesn't match its Content-Length"),
+            Error::RequestBodyTooLarge => write!(f, "request body is too large"),
+            Error::HeadersTooLarge => write!(f, "request headers are too large"),
+            Error::LengthRequired => write!(f, "request has a body but no length"),
+            Error::InvalidChunkedBody => write!(f, "invalid chunked body"),
+            Error::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// How big a request's headers may be. Requests over these limits are rejected before anything is
+/// sent upstream.
+#[derive(Debug, Clone, Copy)]
+pub struct HeaderLimits {
+    /// Longest the request line and headers may be, in bytes
+    pub max_size: usize,
+    /// Most headers a request may have
+    pub max_count: usize,
+}
+
+impl Default for HeaderLimits {
+    fn default() -> HeaderLimits {
+        HeaderLimits {
+            max_size: MAX_HEADERS_SIZE,
+            max_count: MAX_NUM_HEADERS,
+        }
+    }
+}
+
+impl HeaderLimits {
+    /// Returns true if a set of headers we didn't parse ourselves (e.g. from an HTTP/2 client) is
+    /// within these limits, counting each header as it would be sent over HTTP/1.1
+    pub fn allows(&self, headers: &http::HeaderMap) -> bool {
+        let size: usize = headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len() + 4)
+            .sum();
+        headers.len() <= self.max_count && size <= self.max_size
+    }
 }
 
 /// Extracts the Content-Length header value from the provided request. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
-/// Err(Error) if Content-Length is present but invalid.
-///
-/// You won't need to touch this function.
+/// Err(Error) if Content-Length is present but invalid. A request may repeat Content-Length (in
+/// several headers, or as a list), but only if every value is the same; otherwise an upstream
+/// could pick a different one than we did.
 fn get_content_length(request: &http::Request<Vec<u8>>) -> Result<Option<usize>, Error> {
-    // Look for content-length header
-    if let Some(header_value) = request.headers().get("content-length") {
-        // If it exists, parse it as a usize (or return InvalidContentLength if it can't be parsed as such)
-        Ok(Some(
-            header_value
-                .to_str()
-                .or(Err(Error::InvalidContentLength))?
+    let mut content_length = None;
+    for header_value in request.headers().get_all("content-length") {
+        for value in header_value
+            .to_str()
+            .or(Err(Error::InvalidContentLength))?
+            .split(',')
+        {
+            let value = value
+                .trim()
                 .parse::<usize>()
-                .or(Err(Error::InvalidContentLength))?,
-        ))
-    } else {
-        // If it doesn't exist, return None
-        Ok(None)
+                .or(Err(Error::InvalidContentLength))?;
+            if content_length.is_some_and(|length| length != value) {
+                return Err(Error::InvalidContentLength);
+            }
+            content_length = Some(value);
+        }
     }
+    Ok(content_length)
+}
+
+/// Returns true if requests with this method are expected to carry a body
+fn is_body_bearing_method(method: &http::Method) -> bool {
+    matches!(
+        *method,
+        http::Method::POST | http::Method::PUT | http::Method::PATCH
+    )
+}
+
+/// Returns true if the request has a header that says how long its body is
+fn has_framing_headers(request: &http::Request<Vec<u8>>) -> bool {
+    request.headers().contains_key("content-length")
+        || request.headers().contains_key("transfer-encoding")
 }
 
 /// This function appends to a header value (adding a new header if the header is not already
@@ -66,6 +149,65 @@ pub fn extend_header_value(
         .insert(name, http::HeaderValue::from_bytes(&new_value).unwrap());
 }
 
+/// Returns true if option (e.g. "close") is listed in these headers' Connection header
+pub fn has_connection_option(headers: &http::HeaderMap, option: &str) -> bool {
+    headers
+        .get_all("connection")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case(option))
+}
+
+/// Returns true if the client is asking to switch protocols (e.g. to WebSocket): it sent an
+/// Upgrade header, and listed "upgrade" in its Connection header
+pub fn is_upgrade_request(request: &http::Request<Vec<u8>>) -> bool {
+    request.headers().contains_key("upgrade") && has_connection_option(request.headers(), "upgrade")
+}
+
+/// Returns true if the client is waiting to hear that we want its body before sending it
+pub fn expects_continue(request: &http::Request<Vec<u8>>) -> bool {
+    request
+        .headers()
+        .get("expect")
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"100-continue"))
+}
+
+/// Returns the value of the named cookie, if the request sent one. Clients may split their
+/// cookies across several Cookie headers, so all of them are searched.
+pub fn get_cookie(request: &http::Request<Vec<u8>>, name: &str) -> Option<String> {
+    request
+        .headers()
+        .get_all("cookie")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| {
+            let mut parts = pair.trim().splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) if key == name => Some(value.to_string()),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+/// Longest X-Request-Id we'll take from a client rather than replacing with our own
+const MAX_REQUEST_ID_LENGTH: usize = 200;
+
+/// Returns the request's X-Request-Id, if it has one that's reasonable to pass along and put in
+/// our logs: not too long, and only printable ASCII without spaces
+pub fn get_request_id(headers: &http::HeaderMap) -> Option<&str> {
+    let value = headers.get("x-request-id")?.as_bytes();
+    if value.is_empty()
+        || value.len() > MAX_REQUEST_ID_LENGTH
+        || !value.iter().all(|byte| byte.is_ascii_graphic())
+    {
+        return None;
+    }
+    std::str::from_utf8(value).ok()
+}
+
 /// Attempts to parse the data in the supplied buffer as an HTTP request. Returns one of the
 /// following:
 ///
@@ -74,12 +216,17 @@ pub fn extend_header_value(
 /// * If there is data in the buffer that is definitely not a valid HTTP request, returns Err(Error)
 ///
 /// You won't need to touch this function.
-fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
-    let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
+#[allow(clippy::type_complexity)]
+fn parse_request(
+    buffer: &[u8],
+    max_headers: usize,
+) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
+    let mut headers = vec![httparse::EMPTY_HEADER; max_headers];
     let mut req = httparse::Request::new(&mut headers);
-    let res = req
-        .parse(buffer)
-        .or_else(|err| Err(Error::MalformedRequest(err)))?;
+    let res = req.parse(buffer).map_err(|err| match err {
+        httparse::Error::TooManyHeaders => Error::HeadersTooLarge,
+        err => Error::MalformedRequest(err),
+    })?;
 
     if let httparse::Status::Complete(len) = res {
         let mut request = http::Request::builder()
@@ -97,130 +244,158 @@ fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)
 }
 
 /// Reads an HTTP request from the provided stream, waiting until a complete set of headers is sent.
-/// This function only reads the request line and headers; the read_body function can subsequently
-/// be called in order to read the request body (for a POST request).
+/// This function only reads the request line and headers, and leaves the stream positioned at the
+/// start of the body (if any).
 ///
 /// Returns Ok(http::Request) if a valid request is received, or Error if not.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+async fn read_headers<S: AsyncBufRead + Unpin>(
+    stream: &mut S,
+    limits: &HeaderLimits,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Try reading the headers from the request. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a request, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP request
-    let mut request_buffer = [0_u8; MAX_HEADERS_SIZE];
-    let mut bytes_read = 0;
+    let mut request_buffer = Vec::new();
     loop {
-        // Read bytes from the connection into the buffer, starting at position bytes_read
-        let new_bytes = stream
-            .read(&mut request_buffer[bytes_read..])
-            .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+        // Look at whatever the client has sent so far, without taking it out of the stream yet
+        let already_read = request_buffer.len();
+        let new_bytes = body::peek(stream, |available| {
+            let new_bytes = min(available.len(), limits.max_size - already_read);
+            request_buffer.extend_from_slice(&available[..new_bytes]);
+            new_bytes
+        })
+        .await
+        .map_err(Error::Io)?;
         if new_bytes == 0 {
             // We didn't manage to read a complete request
-            return Err(Error::IncompleteRequest(bytes_read));
+            return Err(Error::IncompleteRequest(request_buffer.len()));
         }
-        bytes_read += new_bytes;
 
         // See if we've read a valid request so far
-        if let Some((mut request, headers_len)) = parse_request(&request_buffer[..bytes_read])? {
-            // We've read a complete set of headers. However, if this was a POST request, a request
-            // body might have been included as well, and we might have read part of the body out of
-            // the stream into header_buffer. We need to add those bytes to the Request body so that
-            // we don't lose them
-            request
-                .body_mut()
-                .extend_from_slice(&request_buffer[headers_len..bytes_read]);
-            return Ok(request);
+        match parse_request(&request_buffer, limits.max_count)? {
+            Some((request, headers_len)) => {
+                // We've read a complete set of headers. Only take the headers out of the stream;
+                // whatever follows them is the body, or the client's next request.
+                Pin::new(&mut *stream).consume(headers_len - already_read);
+                return Ok(request);
+            }
+            None if request_buffer.len() == limits.max_size => {
+                return Err(Error::HeadersTooLarge);
+            }
+            None => Pin::new(&mut *stream).consume(new_bytes),
         }
     }
 }
 
-/// This function reads the body for a request from the stream. The client only sends a body if the
-/// Content-Length header is present; this function reads that number of bytes from the stream. It
-/// returns Ok(()) if successful, or Err(Error) if Content-Length bytes couldn't be read.
+/// Reads a request's line and headers from the provided stream, and works out how its body is
+/// framed. The body itself is left in the stream, for the caller to read (or pass on) with
+/// body::Reader or body::copy.
 ///
-/// You will need to modify this function in Milestone 2.
-async fn read_body(
-    stream: &mut TcpStream,
-    request: &mut http::Request<Vec<u8>>,
-    content_length: usize,
-) -> Result<(), Error> {
-    // Keep reading data until we read the full body length, or until we hit an error.
-    while request.body().len() < content_length {
-        // Read up to 512 bytes at a time. (If the client only sent a small body, then only allocate
-        // space to read that body.)
-        let mut buffer = vec![0_u8; min(512, content_length)];
-        let bytes_read = stream
-            .read(&mut buffer)
-            .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
-
-        // Make sure the client is still sending us bytes
-        if bytes_read == 0 {
-            log::debug!(
-                "Client hung up after sending a body of length {}, even though it said the content \
-                length is {}",
-                request.body().len(),
-                content_length
-            );
-            return Err(Error::ContentLengthMismatch);
+/// If a POST/PUT/PATCH request has neither a Content-Length nor a Transfer-Encoding header, its body
+/// is treated as empty, unless require_length is set, in which case Error::LengthRequired is
+/// returned instead. Requests whose headers are over limits get Error::HeadersTooLarge.
+pub async fn read_head<S: AsyncBufRead + Unpin>(
+    stream: &mut S,
+    require_length: bool,
+    limits: &HeaderLimits,
+) -> Result<(http::Request<Vec<u8>>, Framing), Error> {
+    let mut request = read_headers(stream, limits).await?;
+    if require_length && is_body_bearing_method(request.method()) && !has_framing_headers(&request)
+    {
+        return Err(Error::LengthRequired);
+    }
+    // A chunked body ends with its last chunk, whatever Content-Length says. (A client sending
+    // both is out of line, and Content-Length must not be passed upstream in that case.) Any other
+    // final transfer coding leaves a request with no way to tell where its body ends, which
+    // upstreams could guess at differently than we would.
+    let framing = if request.headers().contains_key("transfer-encoding") {
+        if !body::is_chunked(request.headers()) {
+            return Err(Error::InvalidTransferEncoding);
         }
-
-        // Make sure the client didn't send us *too many* bytes
-        if request.body().len() + bytes_read > content_length {
-            log::debug!(
-                "Client sent more bytes than we expected based on the given content length!"
-            );
-            return Err(Error::ContentLengthMismatch);
+        request.headers_mut().remove("content-length");
+        Framing::Chunked
+    } else {
+        let content_length = get_content_length(&request)?;
+        // Pass on a single Content-Length, even if the client repeated it
+        if let Some(content_length) = content_length {
+            request
+                .headers_mut()
+                .insert("content-length", content_length.into());
         }
-
-        // Store the received bytes in the request body
-        request.body_mut().extend_from_slice(&buffer[..bytes_read]);
-    }
-    Ok(())
+        match content_length {
+            Some(0) | None => Framing::Empty,
+            Some(content_length) => Framing::Length(content_length as u64),
+        }
+    };
+    Ok((request, framing))
 }
 
-/// This function reads and returns an HTTP request from a stream, returning an Error if the client
-/// closes the connection prematurely or sends an invalid request.
+/// This function reads and returns an HTTP request (body and all) from a stream, returning an
+/// Error if the client closes the connection prematurely or sends an invalid request. Proxied
+/// requests are streamed instead (see read_head); this is for requests we handle ourselves.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
-    // Read headers
-    let mut request = read_headers(stream).await?;
-    // Read body if the client supplied the Content-Length header (which it does for POST requests)
-    if let Some(content_length) = get_content_length(&request)? {
-        if content_length > MAX_BODY_SIZE {
-            return Err(Error::RequestBodyTooLarge);
-        } else {
-            read_body(stream, &mut request, content_length).await?;
-        }
+pub async fn read_from_stream<S: AsyncBufRead + Unpin>(
+    stream: &mut S,
+    require_length: bool,
+    limits: &HeaderLimits,
+) -> Result<http::Request<Vec<u8>>, Error> {
+    let (mut request, framing) = read_head(stream, require_length, limits).await?;
+    if matches!(framing, Framing::Length(length) if length > MAX_BODY_SIZE as u64) {
+        return Err(Error::RequestBodyTooLarge);
     }
+    *request.body_mut() = body::read_to_end(stream, framing, MAX_BODY_SIZE)
+        .await
+        .map_err(|err| match err {
+            body::Error::BodyTooLarge => Error::RequestBodyTooLarge,
+            body::Error::IncompleteBody if framing != Framing::Chunked => {
+                Error::ContentLengthMismatch
+            }
+            body::Error::IncompleteBody | body::Error::MalformedChunkedBody => {
+                Error::InvalidChunkedBody
+            }
+            body::Error::ReadError(err) | body::Error::WriteError(err) => Error::Io(err),
+        })?;
     Ok(request)
 }
 
-/// This function serializes a request to bytes and writes those bytes to the provided stream.
-///
-/// You will need to modify this function in Milestone 2.
-pub async fn write_to_stream(
+/// Writes a request's line and headers to the provided stream. The body (if any) should be
+/// written right after, with body::Writer or body::copy.
+pub async fn write_head<S: AsyncWrite + Unpin>(
     request: &http::Request<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut S,
 ) -> Result<(), std::io::Error> {
     stream
-        .write(&format_request_line(request).into_bytes())
+        .write_all(&format_request_line(request).into_bytes())
         .await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    stream.write_all(b"\r\n").await?; // \r\n
     for (header_name, header_value) in request.headers() {
         stream
-            .write(&format!("{}: ", header_name).as_bytes())
+            .write_all(format!("{}: ", header_name).as_bytes())
             .await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?; // \r\n
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if request.body().len() > 0 {
-        stream.write(request.body()).await?;
-    }
-    Ok(())
+    stream.write_all(b"\r\n").await
+}
+
+/// This function serializes a request (including the body it holds) to bytes and writes those
+/// bytes to the provided stream.
+///
+/// You will need to modify this function in Milestone 2.
+pub async fn write_to_stream<S: AsyncWrite + Unpin>(
+    request: &http::Request<Vec<u8>>,
+    stream: &mut S,
+) -> Result<(), std::io::Error> {
+    write_head(request, stream).await?;
+    let framing = if body::is_chunked(request.headers()) {
+        Framing::Chunked
+    } else {
+        Framing::Length(request.body().len() as u64)
+    };
+    body::write_all(stream, request.body(), framing).await
 }
 
 pub fn format_request_line(request: &http::Request<Vec<u8>>) -> String {
@@ -1,5 +1,8 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use crate::body::{self, Framing};
+use std::cmp::min;
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncWrite, AsyncWriteExt};
 
 const MAX_HEADERS_SIZE: usize = 8000;
 const MAX_BODY_SIZE: usize = 10000000;
@@ -17,8 +20,25 @@ pub enum Error {
     ContentLengthMismatch,
     /// The request body is bigger than MAX_BODY_SIZE
     ResponseBodyTooLarge,
-    /// Encountered an I/O error when reading/writing a TcpStream
-    ConnectionError(std::io::Error),
+    /// The body is chunked, but isn't valid chunked encoding (or the server hung up partway through
+    /// it)
+    InvalidChunkedBody,
+    /// Encountered an I/O error when reading/writing a stream
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IncompleteResponse => write!(f, "upstream hung up before sending a response"),
+            Error::MalformedResponse(err) => write!(f, "malformed response: {}", err),
+            Error::InvalidContentLength => write!(f, "invalid Content-Length"),
+            Error::ContentLengthMismatch => write!(f, "body doesn't match its Content-Length"),
+            Error::ResponseBodyTooLarge => write!(f, "response body is too large"),
+            Error::InvalidChunkedBody => write!(f, "invalid chunked body"),
+            Error::Io(err) => write!(f, "{}", err),
+        }
+    }
 }
 
 /// Extracts the Content-Length header value from the provided response. Returns Ok(Some(usize)) if
@@ -52,12 +72,11 @@ fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<usize
 ///   Err(Error)
 ///
 /// You won't need to touch this function.
+#[allow(clippy::type_complexity)]
 fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usize)>, Error> {
     let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
     let mut resp = httparse::Response::new(&mut headers);
-    let res = resp
-        .parse(buffer)
-        .or_else(|err| Err(Error::MalformedResponse(err)))?;
+    let res = resp.parse(buffer).map_err(Error::MalformedResponse)?;
 
     if let httparse::Status::Complete(len) = res {
         let mut response = http::Response::builder()
@@ -74,135 +93,142 @@ fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usiz
 }
 
 /// Reads an HTTP response from the provided stream, waiting until a complete set of headers is
-/// sent. This function only reads the response line and headers; the read_body function can
-/// subsequently be called in order to read the response body.
+/// sent. This function only reads the response line and headers, and leaves the stream positioned
+/// at the start of the body (if any).
 ///
 /// Returns Ok(http::Response) if a valid response is received, or Error if not.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>, Error> {
+async fn read_headers<S: AsyncBufRead + Unpin>(
+    stream: &mut S,
+) -> Result<http::Response<Vec<u8>>, Error> {
     // Try reading the headers from the response. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a response, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP response
-    let mut response_buffer = [0_u8; MAX_HEADERS_SIZE];
-    let mut bytes_read = 0;
+    let mut response_buffer = Vec::new();
     loop {
-        // Read bytes from the connection into the buffer, starting at position bytes_read
-        let new_bytes = stream
-            .read(&mut response_buffer[bytes_read..])
-            .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+        // Look at whatever the server has sent so far, without taking it out of the stream yet
+        let already_read = response_buffer.len();
+        let new_bytes = body::peek(stream, |available| {
+            let new_bytes = min(available.len(), MAX_HEADERS_SIZE - already_read);
+            response_buffer.extend_from_slice(&available[..new_bytes]);
+            new_bytes
+        })
+        .await
+        .map_err(Error::Io)?;
         if new_bytes == 0 {
             // We didn't manage to read a complete response
             return Err(Error::IncompleteResponse);
         }
-        bytes_read += new_bytes;
 
         // See if we've read a valid response so far
-        if let Some((mut response, headers_len)) = parse_response(&response_buffer[..bytes_read])? {
-            // We've read a complete set of headers. We may have also read the first part of the
-            // response body; take whatever is left over in the response buffer and save that as
-            // the start of the response body.
-            response
-                .body_mut()
-                .extend_from_slice(&response_buffer[headers_len..bytes_read]);
-            return Ok(response);
-        }
-    }
-}
-
-/// This function reads the body for a response from the stream. If the Content-Length header is
-/// present, it reads that many bytes; otherwise, it reads bytes until the connection is closed.
-///
-/// You will need to modify this function in Milestone 2.
-async fn read_body(
-    stream: &mut TcpStream,
-    response: &mut http::Response<Vec<u8>>,
-) -> Result<(), Error> {
-    // The response may or may not supply a Content-Length header. If it provides the header, then
-    // we want to read that number of bytes; if it does not, we want to keep reading bytes until
-    // the connection is closed.
-    let content_length = get_content_length(response)?;
-
-    while content_length.is_none() || response.body().len() < content_length.unwrap() {
-        let mut buffer = [0_u8; 512];
-        let bytes_read = stream
-            .read(&mut buffer)
-            .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
-        if bytes_read == 0 {
-            // The server has hung up!
-            if content_length.is_none() {
-                // We've reached the end of the response
-                break;
-            } else {
-                // Content-Length was set, but the server hung up before we managed to read that
-                // number of bytes
-                return Err(Error::ContentLengthMismatch);
+        match parse_response(&response_buffer)? {
+            Some((response, headers_len)) => {
+                // We've read a complete set of headers. Only take the headers out of the stream,
+                // and leave the body behind.
+                Pin::new(&mut *stream).consume(headers_len - already_read);
+                return Ok(response);
             }
+            None if response_buffer.len() == MAX_HEADERS_SIZE => {
+                return Err(Error::MalformedResponse(httparse::Error::TooManyHeaders));
+            }
+            None => Pin::new(&mut *stream).consume(new_bytes),
         }
-
-        // Make sure the server doesn't send more bytes than it promised to send
-        if content_length.is_some() && response.body().len() + bytes_read > content_length.unwrap()
-        {
-            return Err(Error::ContentLengthMismatch);
-        }
-
-        // Make sure server doesn't send more bytes than we allow
-        if response.body().len() + bytes_read > MAX_BODY_SIZE {
-            return Err(Error::ResponseBodyTooLarge);
-        }
-
-        // Append received bytes to the response body
-        response.body_mut().extend_from_slice(&buffer[..bytes_read]);
     }
-    Ok(())
 }
 
-/// This function reads and returns an HTTP response from a stream, returning an Error if the server
-/// closes the connection prematurely or sends an invalid response.
-///
-/// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(
-    stream: &mut TcpStream,
+/// Reads a response's status line and headers from the provided stream, and works out how its body
+/// is framed. The body itself is left in the stream, for the caller to read (or pass on) with
+/// body::Reader or body::copy.
+pub async fn read_head<S: AsyncBufRead + Unpin>(
+    stream: &mut S,
     request_method: &http::Method,
-) -> Result<http::Response<Vec<u8>>, Error> {
+) -> Result<(http::Response<Vec<u8>>, Framing), Error> {
     let mut response = read_headers(stream).await?;
     // A response may have a body as long as it is not responding to a HEAD request and as long as
     // the response status code is not 1xx, 204 (no content), or 304 (not modified).
-    if !(request_method == http::Method::HEAD
+    let framing = if request_method == http::Method::HEAD
         || response.status().as_u16() < 200
         || response.status() == http::StatusCode::NO_CONTENT
-        || response.status() == http::StatusCode::NOT_MODIFIED)
+        || response.status() == http::StatusCode::NOT_MODIFIED
     {
-        read_body(stream, &mut response).await?;
-    }
-    Ok(response)
+        Framing::Empty
+    } else if body::is_chunked(response.headers()) {
+        // The body ends with its last chunk, so we don't have to wait for the server to hang up
+        response.headers_mut().remove("content-length");
+        Framing::Chunked
+    } else {
+        // If the response doesn't say how long its body is, the body is everything the server
+        // sends until it closes the connection
+        match get_content_length(&response)? {
+            Some(0) => Framing::Empty,
+            Some(content_length) => Framing::Length(content_length as u64),
+            None => Framing::UntilClose,
+        }
+    };
+    Ok((response, framing))
 }
 
-/// This function serializes a response to bytes and writes those bytes to the provided stream.
+/// This function reads and returns an HTTP response (body and all) from a stream, returning an
+/// Error if the server closes the connection prematurely or sends an invalid response. Proxied
+/// responses are streamed instead (see read_head).
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn write_to_stream(
+pub async fn read_from_stream<S: AsyncBufRead + Unpin>(
+    stream: &mut S,
+    request_method: &http::Method,
+) -> Result<http::Response<Vec<u8>>, Error> {
+    let (mut response, framing) = read_head(stream, request_method).await?;
+    *response.body_mut() = body::read_to_end(stream, framing, MAX_BODY_SIZE)
+        .await
+        .map_err(|err| match err {
+            body::Error::BodyTooLarge => Error::ResponseBodyTooLarge,
+            body::Error::IncompleteBody if framing != Framing::Chunked => {
+                Error::ContentLengthMismatch
+            }
+            body::Error::IncompleteBody | body::Error::MalformedChunkedBody => {
+                Error::InvalidChunkedBody
+            }
+            body::Error::ReadError(err) | body::Error::WriteError(err) => Error::Io(err),
+        })?;
+    Ok(response)
+}
+
+/// Writes a response's status line and headers to the provided stream. The body (if any) should be
+/// written right after, with body::Writer or body::copy.
+pub async fn write_head<S: AsyncWrite + Unpin>(
     response: &http::Response<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut S,
 ) -> Result<(), std::io::Error> {
     stream
-        .write(&format_response_line(response).into_bytes())
+        .write_all(&format_response_line(response).into_bytes())
         .await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    stream.write_all(b"\r\n").await?; // \r\n
     for (header_name, header_value) in response.headers() {
         stream
-            .write(&format!("{}: ", header_name).as_bytes())
+            .write_all(format!("{}: ", header_name).as_bytes())
             .await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
-    }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if response.body().len() > 0 {
-        stream.write(response.body()).await?;
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?; // \r\n
     }
-    Ok(())
+    stream.write_all(b"\r\n").await
+}
+
+/// This function serializes a response (including the body it holds) to bytes and writes those
+/// bytes to the provided stream.
+///
+/// You will need to modify this function in Milestone 2.
+pub async fn write_to_stream<S: AsyncWrite + Unpin>(
+    response: &http::Response<Vec<u8>>,
+    stream: &mut S,
+) -> Result<(), std::io::Error> {
+    write_head(response, stream).await?;
+    let framing = if body::is_chunked(response.headers()) {
+        Framing::Chunked
+    } else {
+        Framing::Length(response.body().len() as u64)
+    };
+    body::write_all(stream, response.body(), framing).await
 }
 
 pub fn format_response_line(response: &http::Response<Vec<u8>>) -> String {
@@ -214,6 +240,11 @@ pub fn format_response_line(response: &http::Response<Vec<u8>>) -> String {
     )
 }
 
+/// Set on the error responses we make ourselves (rather than pass on from an upstream), so that
+/// --error-page can replace their bodies just before they're sent
+#[derive(Debug, Clone, Copy)]
+pub struct Generated;
+
 /// This is a helper function that creates an http::Response containing an HTTP error that can be
 /// sent to a client.
 pub fn make_http_error(status: http::StatusCode) -> http::Response<Vec<u8>> {
@@ -228,6 +259,50 @@ pub fn make_http_error(status: http::StatusCode) -> http::Response<Vec<u8>> {
         .header("Content-Type", "text/plain")
         .header("Content-Length", body.len().to_string())
         .version(http::Version::HTTP_11)
+        .extension(Generated)
+        .body(body)
+        .unwrap()
+}
+
+/// Makes a 503 Service Unavailable response explaining why the request couldn't be served
+pub fn make_unavailable_response(message: &str) -> http::Response<Vec<u8>> {
+    let body = format!("HTTP 503 Service Unavailable\n\n{}\n", message).into_bytes();
+    http::Response::builder()
+        .status(http::StatusCode::SERVICE_UNAVAILABLE)
+        .header("Content-Type", "text/plain")
+        .header("Content-Length", body.len().to_string())
+        .version(http::Version::HTTP_11)
+        .extension(Generated)
         .body(body)
         .unwrap()
 }
+
+/// Makes a 429 Too Many Requests response for a rate limited client, telling it (in Retry-After,
+/// rounded up to whole seconds) when to try again. The body is JSON if json is set.
+pub fn make_rate_limited_response(retry_after: Duration, json: bool) -> http::Response<Vec<u8>> {
+    let retry_after = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    let retry_after = retry_after.max(1);
+    let mut response = if json {
+        let body = serde_json::json!({
+            "error": "Too Many Requests",
+            "retry_after": retry_after,
+        })
+        .to_string()
+        .into_bytes();
+        http::Response::builder()
+            .status(http::StatusCode::TOO_MANY_REQUESTS)
+            .header("Content-Type", "application/json")
+            .header("Content-Length", body.len().to_string())
+            .version(http::Version::HTTP_11)
+            .extension(Generated)
+            .body(body)
+            .unwrap()
+    } else {
+        make_http_error(http::StatusCode::TOO_MANY_REQUESTS)
+    };
+    response.headers_mut().insert(
+        http::header::RETRY_AFTER,
+        http::HeaderValue::from(retry_after),
+    );
+    response
+}
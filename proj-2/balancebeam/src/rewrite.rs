@@ -0,0 +1,199 @@
+use std::str::FromStr;
+
+/// Headers that say where a message's body ends. Rules can't touch these, since we've already
+/// worked out the framing by the time they run.
+const FRAMING_HEADERS: [&str; 2] = ["content-length", "transfer-encoding"];
+
+#[derive(Debug, Clone)]
+enum HeaderAction {
+    /// Replace any values the header has with this one
+    Set(http::HeaderValue),
+    /// Add this value alongside any the header already has
+    Add(http::HeaderValue),
+    Remove,
+}
+
+/// A change to make to the headers of every request sent upstream (--request-header) or every
+/// response passed back to a client (--response-header). Written as `set <Name>: <value>`,
+/// `add <Name>: <value>`, or `remove <Name>`, e.g. `remove Server` or
+/// `set Strict-Transport-Security: max-age=63072000`.
+#[derive(Debug, Clone)]
+pub struct HeaderRule {
+    name: http::header::HeaderName,
+    action: HeaderAction,
+}
+
+impl HeaderRule {
+    pub fn apply(&self, headers: &mut http::HeaderMap) {
+        match &self.action {
+            HeaderAction::Set(value) => {
+                headers.insert(&self.name, value.clone());
+            }
+            HeaderAction::Add(value) => {
+                headers.append(&self.name, value.clone());
+            }
+            HeaderAction::Remove => {
+                headers.remove(&self.name);
+            }
+        }
+    }
+}
+
+impl FromStr for HeaderRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (verb, header) = s.trim().split_once(char::is_whitespace).ok_or_else(|| {
+            format!(
+                "invalid header rule \"{}\" (expected e.g. \"set X-Env: prod\" or \"remove \
+                 Server\")",
+                s
+            )
+        })?;
+        let verb = verb.to_ascii_lowercase();
+        let (name, action) = match (verb.as_str(), header.split_once(':')) {
+            ("set", Some((name, value))) | ("add", Some((name, value))) => {
+                let (name, value) = crate::parse_header(name, value)?;
+                let action = match verb.as_str() {
+                    "set" => HeaderAction::Set(value),
+                    _ => HeaderAction::Add(value),
+                };
+                (name, action)
+            }
+            ("remove", None) => {
+                let name = http::header::HeaderName::from_bytes(header.trim().as_bytes())
+                    .map_err(|_| format!("invalid header name \"{}\"", header.trim()))?;
+                (name, HeaderAction::Remove)
+            }
+            ("set", None) | ("add", None) => {
+                return Err(format!(
+                    "header rule \"{}\" needs a value (e.g. \"{} X-Env: prod\")",
+                    s, verb
+                ))
+            }
+            _ => {
+                return Err(format!(
+                    "unknown header rule action \"{}\" in \"{}\" (expected set, add, or remove)",
+                    verb, s
+                ))
+            }
+        };
+        if FRAMING_HEADERS.contains(&name.as_str()) {
+            return Err(format!("header rules can't change {}", name));
+        }
+        Ok(HeaderRule { name, action })
+    }
+}
+
+/// Applies rules to headers in order, so later rules see the changes earlier ones made
+pub fn apply_header_rules(rules: &[HeaderRule], headers: &mut http::HeaderMap) {
+    for rule in rules {
+        rule.apply(headers);
+    }
+}
+
+/// A change to make to the path of every request sent upstream (--rewrite-path), for upstreams that
+/// don't know how their URLs look from outside. Written as `strip <prefix>`, which takes a prefix
+/// off the paths under it (whole segments only, so `strip /api` turns `/api/users` into `/users`
+/// but leaves `/apiary` alone), or `replace <regex> <replacement>`, which replaces the first match
+/// of regex, with `$1` etc. in replacement standing for its groups (e.g.
+/// `replace ^/v1/(.*)$ /api/$1`). The query string is left as it is.
+#[derive(Debug, Clone)]
+pub enum PathRewrite {
+    StripPrefix(String),
+    Replace(regex::Regex, String),
+}
+
+impl PathRewrite {
+    /// The rewritten path, or None if the rule doesn't change it
+    fn apply(&self, path: &str) -> Option<String> {
+        let rewritten = match self {
+            PathRewrite::StripPrefix(prefix) => {
+                let rest = path.strip_prefix(prefix.as_str())?;
+                if !rest.is_empty() && !rest.starts_with('/') {
+                    return None;
+                }
+                rest.to_string()
+            }
+            PathRewrite::Replace(regex, replacement) => {
+                match regex.replace(path, replacement.as_str()) {
+                    std::borrow::Cow::Borrowed(_) => return None,
+                    std::borrow::Cow::Owned(rewritten) => rewritten,
+                }
+            }
+        };
+        match rewritten.starts_with('/') {
+            true => Some(rewritten),
+            false => Some(format!("/{}", rewritten)),
+        }
+    }
+}
+
+impl FromStr for PathRewrite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        match (words.next(), words.next(), words.next(), words.next()) {
+            (Some("strip"), Some(prefix), None, None) => {
+                let prefix = prefix.trim_end_matches('/');
+                if !prefix.starts_with('/') {
+                    return Err(format!(
+                        "prefix to strip \"{}\" should start with / (and not be just /)",
+                        prefix
+                    ));
+                }
+                Ok(PathRewrite::StripPrefix(prefix.to_string()))
+            }
+            (Some("replace"), Some(pattern), Some(replacement), None) => {
+                let regex = regex::Regex::new(pattern)
+                    .map_err(|err| format!("invalid path regex \"{}\": {}", pattern, err))?;
+                Ok(PathRewrite::Replace(regex, replacement.to_string()))
+            }
+            _ => Err(format!(
+                "invalid path rewrite \"{}\" (expected e.g. \"strip /api\" or \"replace ^/v1/(.*)$ \
+                 /api/$1\")",
+                s
+            )),
+        }
+    }
+}
+
+/// Set on a request by rewrite_path if it changed the URL, so that routes still match the URL the
+/// client asked for
+#[derive(Debug, Clone)]
+pub struct OriginalUri(pub http::Uri);
+
+/// Applies rules to a request's path in order, so later rules see the changes earlier ones made
+pub fn rewrite_path(rules: &[PathRewrite], request: &mut http::Request<Vec<u8>>) {
+    let original = request.uri().path();
+    let mut path = original.to_string();
+    for rule in rules {
+        if let Some(rewritten) = rule.apply(&path) {
+            path = rewritten;
+        }
+    }
+    if path == original {
+        return;
+    }
+    let uri = request.uri().clone();
+    let mut parts = uri.clone().into_parts();
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path,
+    };
+    parts.path_and_query = match path_and_query.parse() {
+        Ok(path_and_query) => Some(path_and_query),
+        Err(_) => {
+            log::warn!(
+                "Not rewriting {} to {}, which isn't a valid path",
+                uri,
+                path_and_query
+            );
+            return;
+        }
+    };
+    *request.uri_mut() = http::Uri::from_parts(parts).unwrap();
+    log::debug!("Rewrote {} to {}", uri, request.uri());
+    request.extensions_mut().insert(OriginalUri(uri));
+}
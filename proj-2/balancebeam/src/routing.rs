@@ -0,0 +1,362 @@
+use crate::hash_ring;
+use crate::rewrite;
+use crate::{LoadBalancingStrategy, UpstreamProtocol};
+use rand::Rng;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::AtomicUsize;
+use tokio::time::Duration;
+
+/// A named group of upstreams (those given with `pool=<name>`) that routes send requests to. Each
+/// pool can have its own load balancing strategy and health checks; whatever it leaves out comes
+/// from the global settings. Written on the command line as
+/// `<name>[,strategy=<strategy>][,health_path=<path>][,health_interval=<seconds>]
+/// [,protocol=<protocol>]`.
+#[derive(Debug)]
+pub struct Pool {
+    pub name: String,
+    pub strategy: Option<LoadBalancingStrategy>,
+    /// Path to send active health checks to, instead of --active-health-check-path
+    pub health_check_path: Option<String>,
+    /// How often to health check the pool's upstreams, instead of --active-health-check-interval
+    /// (an upstream's own health_interval still wins)
+    pub health_check_interval: Option<Duration>,
+    /// What to speak to the pool's upstreams, instead of --upstream-protocol
+    pub protocol: Option<UpstreamProtocol>,
+    /// Number of round-robin selections made from this pool so far
+    pub round_robin_counter: AtomicUsize,
+}
+
+impl Pool {
+    pub fn new(name: String) -> Pool {
+        Pool {
+            name,
+            strategy: None,
+            health_check_path: None,
+            health_check_interval: None,
+            protocol: None,
+            round_robin_counter: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl FromStr for Pool {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let name = parts.next().unwrap_or("").trim();
+        if name.is_empty() {
+            return Err("pool name is empty".to_string());
+        }
+        let mut pool = Pool::new(name.to_string());
+        for option in parts {
+            match option.split_once('=') {
+                Some(("strategy", value)) => pool.strategy = Some(value.parse()?),
+                Some(("protocol", value)) => pool.protocol = Some(value.parse()?),
+                Some(("health_path", value)) => {
+                    if !value.starts_with('/') {
+                        return Err(format!(
+                            "health_path \"{}\" for pool {} should start with /",
+                            value, name
+                        ));
+                    }
+                    pool.health_check_path = Some(value.to_string())
+                }
+                Some(("health_interval", value)) => {
+                    pool.health_check_interval = match value.parse::<u64>() {
+                        Ok(interval) if interval > 0 => Some(Duration::from_secs(interval)),
+                        _ => {
+                            return Err(format!(
+                                "invalid health_interval \"{}\" for pool {}",
+                                value, name
+                            ))
+                        }
+                    }
+                }
+                _ => return Err(format!("unknown pool option \"{}\"", option)),
+            }
+        }
+        Ok(pool)
+    }
+}
+
+/// Sends requests for paths under path_prefix (and, if host is given, for that host) to the named
+/// pool. Written on the command line as `[<host>]<path prefix>=<pool>`, e.g. `/api=api`,
+/// `shop.example.com=shop`, or `*.example.com/static=cdn`. A prefix matches whole path segments,
+/// so `/api` (or `/api/*`) covers `/api` and `/api/users` but not `/apiary`. A host starting with
+/// `*.` matches any subdomain of the rest (but not the domain itself).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    /// Lowercase, without a port
+    pub host: Option<String>,
+    pub path_prefix: String,
+    pub pool: String,
+}
+
+impl Route {
+    pub fn new(host: Option<&str>, path_prefix: &str, pool: &str) -> Result<Route, String> {
+        let host = host
+            .map(|host| host.trim().trim_end_matches('.').to_ascii_lowercase())
+            .filter(|host| !host.is_empty());
+        if let Some(host) = &host {
+            let name = host.strip_prefix("*.").unwrap_or(host);
+            if name.is_empty() || name.contains(['*', '/', ':']) {
+                return Err(format!("invalid route host \"{}\"", host));
+            }
+        }
+        let path_prefix = path_prefix.trim();
+        let path_prefix = match path_prefix.strip_suffix("/*") {
+            Some("") => "/",
+            Some(prefix) => prefix,
+            None => path_prefix.trim_end_matches('*'),
+        };
+        if !path_prefix.starts_with('/') {
+            return Err(format!(
+                "route path prefix \"{}\" should start with /",
+                path_prefix
+            ));
+        }
+        if pool.trim().is_empty() {
+            return Err(format!("route for {} has no pool", path_prefix));
+        }
+        Ok(Route {
+            host,
+            path_prefix: path_prefix.to_string(),
+            pool: pool.trim().to_string(),
+        })
+    }
+
+    /// If the route matches a request for host and path, how specifically: routes for an exact
+    /// host beat wildcard ones (longer wildcards first), which beat routes for any host, and after
+    /// that the longest path prefix wins
+    fn specificity(&self, host: &str, path: &str) -> Option<(u8, usize, usize)> {
+        let host_rank = match self.host.as_deref() {
+            None => (0, 0),
+            Some(route_host) => match route_host.strip_prefix("*.") {
+                Some(domain) => {
+                    let subdomain = host.strip_suffix(domain)?;
+                    if subdomain.len() < 2 || !subdomain.ends_with('.') {
+                        return None;
+                    }
+                    (1, domain.len())
+                }
+                None if route_host == host => (2, 0),
+                None => return None,
+            },
+        };
+        let rest = path.strip_prefix(&self.path_prefix)?;
+        if !rest.is_empty() && !rest.starts_with('/') && !self.path_prefix.ends_with('/') {
+            return None;
+        }
+        Some((host_rank.0, host_rank.1, self.path_prefix.len()))
+    }
+
+    /// How the route is written on the command line, for messages
+    fn describe(&self) -> String {
+        format!("{}{}", self.host.as_deref().unwrap_or(""), self.path_prefix)
+    }
+}
+
+impl FromStr for Route {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (target, pool) = s.rsplit_once('=').ok_or_else(|| {
+            format!(
+                "invalid route \"{}\" (expected e.g. \"/api=api\" or \"api.example.com=api\")",
+                s
+            )
+        })?;
+        let target = target.trim();
+        match target.find('/') {
+            Some(0) => Route::new(None, target, pool),
+            Some(slash) => Route::new(Some(&target[..slash]), &target[slash..], pool),
+            None => Route::new(Some(target), "/", pool),
+        }
+    }
+}
+
+/// Sends a share of the requests that no route matches to a canary pool (--canary-pool), so that a
+/// new version of the upstreams can be tried out on live traffic
+#[derive(Debug)]
+pub struct Canary {
+    pub pool: String,
+    /// Percentage of requests to send to the pool
+    pub percent: f64,
+    /// Whether to pick by client IP, so that a client sees the same version every time, rather
+    /// than request by request
+    pub sticky: bool,
+}
+
+/// Set on a request by Router::assign_canary if it was picked to go to the canary pool
+#[derive(Debug, Clone)]
+struct ToCanary;
+
+/// Copies a sample of requests to a pool whose responses are thrown away (--mirror-pool), so that
+/// a new version of the upstreams can be tried out on live traffic without clients noticing
+#[derive(Debug)]
+pub struct Mirror {
+    pub pool: String,
+    /// Percentage of requests to copy
+    pub percent: f64,
+}
+
+/// Decides which pool each request goes to
+#[derive(Debug)]
+pub struct Router {
+    routes: Vec<Route>,
+    pools: Vec<Pool>,
+    canary: Option<Canary>,
+    mirror: Option<Mirror>,
+}
+
+impl Router {
+    /// Checks that the pools have different names, and that every route (and the canary and mirror,
+    /// if any) goes to one of them or to a pool that some upstream says it's in (upstream_pools).
+    /// Pools that only upstreams mention use the global settings.
+    pub fn new(
+        routes: Vec<Route>,
+        pools: Vec<Pool>,
+        canary: Option<Canary>,
+        mirror: Option<Mirror>,
+        upstream_pools: &[&str],
+    ) -> Result<Router, String> {
+        for (i, pool) in pools.iter().enumerate() {
+            if pools[..i].iter().any(|other| other.name == pool.name) {
+                return Err(format!("pool {} is defined more than once", pool.name));
+            }
+        }
+        let known = |name: &str| {
+            pools.iter().any(|pool| pool.name == name) || upstream_pools.contains(&name)
+        };
+        for route in &routes {
+            if !known(&route.pool) {
+                return Err(format!(
+                    "route for {} goes to pool {}, which isn't defined and has no upstreams",
+                    route.describe(),
+                    route.pool
+                ));
+            }
+        }
+        if let Some(canary) = &canary {
+            if !known(&canary.pool) {
+                return Err(format!(
+                    "canary pool {} isn't defined and has no upstreams",
+                    canary.pool
+                ));
+            }
+            if !(0.0..=100.0).contains(&canary.percent) {
+                return Err(format!(
+                    "canary percentage {} should be between 0 and 100",
+                    canary.percent
+                ));
+            }
+        }
+        if let Some(mirror) = &mirror {
+            if !known(&mirror.pool) {
+                return Err(format!(
+                    "mirror pool {} isn't defined and has no upstreams",
+                    mirror.pool
+                ));
+            }
+            if !(0.0..=100.0).contains(&mirror.percent) {
+                return Err(format!(
+                    "mirror percentage {} should be between 0 and 100",
+                    mirror.percent
+                ));
+            }
+        }
+        Ok(Router {
+            routes,
+            pools,
+            canary,
+            mirror,
+        })
+    }
+
+    /// Decides whether a request from client_ip goes to the canary pool, should no route match it
+    pub fn assign_canary(&self, client_ip: IpAddr, request: &mut http::Request<Vec<u8>>) {
+        let canary = match &self.canary {
+            Some(canary) => canary,
+            None => return,
+        };
+        let roll = if canary.sticky {
+            (hash_ring::hash(&client_ip) % 10000) as f64 / 100.0
+        } else {
+            rand::thread_rng().gen_range(0.0, 100.0)
+        };
+        if roll < canary.percent {
+            request.extensions_mut().insert(ToCanary);
+        }
+    }
+
+    /// The pool to send a copy of a request to, if there's a mirror and the request is picked for
+    /// its sample
+    pub fn pick_mirror(&self) -> Option<&str> {
+        let mirror = self.mirror.as_ref()?;
+        match rand::thread_rng().gen_range(0.0, 100.0) < mirror.percent {
+            true => Some(&mirror.pool),
+            false => None,
+        }
+    }
+
+    /// Whether pool is the canary pool
+    pub fn is_canary(&self, pool: Option<&str>) -> bool {
+        match (&self.canary, pool) {
+            (Some(canary), Some(pool)) => canary.pool == pool,
+            _ => false,
+        }
+    }
+
+    /// The pool a request goes to: that of the most specific route matching its Host and path (see
+    /// Route::specificity), or None (for the upstreams that aren't in a pool) if no route matches
+    /// and assign_canary didn't pick it for the canary. If --rewrite-path changed the path, routes
+    /// match the one the client asked for.
+    pub fn route(&self, request: &http::Request<Vec<u8>>) -> Option<&str> {
+        self.match_route(request).or_else(|| {
+            request.extensions().get::<ToCanary>()?;
+            Some(self.canary.as_ref()?.pool.as_str())
+        })
+    }
+
+    fn match_route(&self, request: &http::Request<Vec<u8>>) -> Option<&str> {
+        if self.routes.is_empty() {
+            return None;
+        }
+        let host = request_host(request);
+        let path = match request.extensions().get::<rewrite::OriginalUri>() {
+            Some(rewrite::OriginalUri(original)) => original.path(),
+            None => request.uri().path(),
+        };
+        self.routes
+            .iter()
+            .filter_map(|route| Some((route.specificity(&host, path)?, route)))
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, route)| route.pool.as_str())
+    }
+
+    /// The settings for the named pool, if it was defined with --pool
+    pub fn pool(&self, name: Option<&str>) -> Option<&Pool> {
+        let name = name?;
+        self.pools.iter().find(|pool| pool.name == name)
+    }
+}
+
+/// The host a request is for, from its Host header (or its URL, if it was sent in absolute form),
+/// lowercased and without the port
+fn request_host(request: &http::Request<Vec<u8>>) -> String {
+    let host = request
+        .headers()
+        .get(http::header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| request.uri().host())
+        .unwrap_or("")
+        .trim();
+    let name = match host.strip_prefix('[') {
+        // An IPv6 literal, e.g. [::1]:8080
+        Some(rest) => rest.split(']').next().unwrap_or(""),
+        None => host.split(':').next().unwrap_or(""),
+    };
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
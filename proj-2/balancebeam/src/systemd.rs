@@ -0,0 +1,66 @@
+use std::os::unix::io::RawFd;
+
+/// systemd passes sockets starting at this fd (SD_LISTEN_FDS_START)
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Names a socket can be given (with FileDescriptorName= in the .socket unit) to say which
+/// listener it's for
+const KNOWN_NAMES: [&str; 2] = ["proxy", "admin"];
+
+/// Listening sockets passed to us by systemd socket activation (see sd_listen_fds(3)). This lets
+/// systemd bind privileged ports for us, and start us on the first connection.
+pub struct ActivatedSockets {
+    /// (fd, name) pairs, in the order systemd passed them
+    fds: Vec<(RawFd, String)>,
+}
+
+impl ActivatedSockets {
+    /// Collects the sockets systemd passed us, if any. The LISTEN_* variables are cleared
+    /// afterwards so that they aren't passed on to any process we start.
+    pub fn from_env() -> ActivatedSockets {
+        let pid = std::env::var("LISTEN_PID").ok();
+        let count = std::env::var("LISTEN_FDS").ok();
+        let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+        for var in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            std::env::remove_var(var);
+        }
+
+        // The variables are only meant for us if LISTEN_PID is our pid
+        let count = match (pid, count) {
+            (Some(pid), Some(count)) if pid == std::process::id().to_string() => {
+                count.parse().unwrap_or(0)
+            }
+            _ => 0,
+        };
+        let mut names = names.split(':');
+        let fds = (LISTEN_FDS_START..LISTEN_FDS_START + count)
+            .map(|fd| {
+                // Like sd_listen_fds, make sure these don't leak into processes we spawn
+                unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+                (fd, names.next().unwrap_or("").to_string())
+            })
+            .collect();
+        ActivatedSockets { fds }
+    }
+
+    /// Takes every socket named after the given listener ("proxy" or "admin"). If none of the
+    /// sockets are named after a listener, they are handed out in order instead: the first is the
+    /// proxy listener, and the second (if any) is the admin listener.
+    pub fn take(&mut self, name: &str) -> Vec<RawFd> {
+        let any_named = self
+            .fds
+            .iter()
+            .any(|(_, fd_name)| KNOWN_NAMES.contains(&fd_name.as_str()));
+        if !any_named {
+            return if self.fds.is_empty() {
+                Vec::new()
+            } else {
+                vec![self.fds.remove(0).0]
+            };
+        }
+        let (taken, rest): (Vec<_>, Vec<_>) =
+            self.fds.drain(..).partition(|(_, fd_name)| fd_name == name);
+        self.fds = rest;
+        taken.into_iter().map(|(fd, _)| fd).collect()
+    }
+}
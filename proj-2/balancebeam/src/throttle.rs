@@ -0,0 +1,71 @@
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Weak};
+use tokio::time::{delay_until, Duration, Instant};
+
+/// How far ahead of its rate a client may get, so that small responses aren't held up at all
+const BURST: Duration = Duration::from_secs(1);
+
+/// Paces the response bytes sent to one client IP to --client-bandwidth bytes a second, across all
+/// of its connections
+#[derive(Debug)]
+pub struct Pacer {
+    bytes_per_second: f64,
+    /// When everything sent so far will have been paid for at our rate. Sending may run up to BURST
+    /// ahead of now; past that, senders wait.
+    paid_until: Mutex<Instant>,
+}
+
+impl Pacer {
+    fn new(bytes_per_second: u64) -> Pacer {
+        Pacer {
+            bytes_per_second: bytes_per_second as f64,
+            paid_until: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits until bytes more may be sent. Concurrent senders each take their turn, so a client
+    /// with several downloads at once gets the same total rate as one with a single download.
+    pub async fn take(&self, bytes: usize) {
+        let now = Instant::now();
+        let send_at = {
+            let mut paid_until = self.paid_until.lock();
+            *paid_until = (*paid_until).max(now)
+                + Duration::from_secs_f64(bytes as f64 / self.bytes_per_second);
+            (*paid_until).checked_sub(BURST).unwrap_or(now)
+        };
+        if send_at > now {
+            delay_until(send_at).await;
+        }
+    }
+}
+
+/// The pacers for each client IP that has a connection open
+#[derive(Debug)]
+pub struct Throttles {
+    bytes_per_second: u64,
+    pacers: Mutex<HashMap<IpAddr, Weak<Pacer>>>,
+}
+
+impl Throttles {
+    pub fn new(bytes_per_second: u64) -> Throttles {
+        Throttles {
+            bytes_per_second,
+            pacers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the pacer for a client IP, shared with any other connections it has open. It's
+    /// forgotten once none of them are using it.
+    pub fn pacer(&self, ip: IpAddr) -> Arc<Pacer> {
+        let mut pacers = self.pacers.lock();
+        if let Some(pacer) = pacers.get(&ip).and_then(Weak::upgrade) {
+            return pacer;
+        }
+        pacers.retain(|_, pacer| pacer.strong_count() > 0);
+        let pacer = Arc::new(Pacer::new(self.bytes_per_second));
+        pacers.insert(ip, Arc::downgrade(&pacer));
+        pacer
+    }
+}
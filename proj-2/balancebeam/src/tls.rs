@@ -0,0 +1,361 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    AllowAnyAuthenticatedClient, Certificate, ClientConfig, NoClientAuth, PrivateKey,
+    RootCertStore, ServerCertVerified, ServerCertVerifier, ServerConfig, Session, TLSError,
+};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::{client, TlsAcceptor, TlsConnector};
+
+/// Prefix for upstream addresses that we should speak TLS to, e.g. `tls://10.0.0.1:443`
+const UPSTREAM_TLS_SCHEME: &str = "tls://";
+/// Prefix for upstreams listening on a Unix domain socket, e.g. `unix:///run/app.sock`
+const UPSTREAM_UNIX_SCHEME: &str = "unix://";
+
+/// Returns the socket path, if addr is a `unix://` upstream address
+pub fn unix_socket_path(addr: &str) -> Option<&str> {
+    addr.strip_prefix(UPSTREAM_UNIX_SCHEME)
+}
+
+/// A connection from a client: plain TCP, or TLS over TCP. handle_connection works with either.
+pub trait ClientStream: AsyncRead + AsyncWrite + Unpin + Send {
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+    /// The scheme the client connected with, for the Forwarded header
+    fn proto(&self) -> &'static str;
+}
+
+impl ClientStream for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+
+    fn proto(&self) -> &'static str {
+        "http"
+    }
+}
+
+impl ClientStream for TlsStream<TcpStream> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().0.peer_addr()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().0.local_addr()
+    }
+
+    fn proto(&self) -> &'static str {
+        "https"
+    }
+}
+
+/// A client connection that we read requests from through a buffer
+impl<S: ClientStream> ClientStream for tokio::io::BufReader<S> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().peer_addr()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().local_addr()
+    }
+
+    fn proto(&self) -> &'static str {
+        self.get_ref().proto()
+    }
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>, String> {
+    let file = File::open(path).map_err(|err| format!("Could not open {}: {}", path, err))?;
+    match pemfile::certs(&mut BufReader::new(file)) {
+        Ok(certs) if !certs.is_empty() => Ok(certs),
+        _ => Err(format!("No PEM certificates found in {}", path)),
+    }
+}
+
+fn load_private_key(path: &str) -> Result<PrivateKey, String> {
+    let read_keys = |parse: fn(&mut dyn io::BufRead) -> Result<Vec<PrivateKey>, ()>| {
+        let file = File::open(path).map_err(|err| format!("Could not open {}: {}", path, err))?;
+        Ok::<_, String>(parse(&mut BufReader::new(file)).unwrap_or_default())
+    };
+    // Keys may be in either PKCS #8 ("BEGIN PRIVATE KEY") or PKCS #1 ("BEGIN RSA PRIVATE KEY")
+    // format
+    let mut keys = read_keys(pemfile::pkcs8_private_keys)?;
+    if keys.is_empty() {
+        keys = read_keys(pemfile::rsa_private_keys)?;
+    }
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| format!("No PEM private key found in {}", path))
+}
+
+/// Builds the TLS acceptor used to terminate client connections, from a PEM certificate chain
+/// and private key. If client_ca_path is given, clients must present a certificate signed by one
+/// of the CAs in that PEM bundle, or the handshake fails (before we read any requests). If http2 is
+/// set, clients may choose HTTP/2 through ALPN (see negotiated_http2).
+pub fn make_acceptor(
+    cert_path: &str,
+    key_path: &str,
+    client_ca_path: Option<&str>,
+    http2: bool,
+) -> Result<TlsAcceptor, String> {
+    let mut config = match client_ca_path {
+        Some(client_ca_path) => {
+            let mut client_cas = RootCertStore::empty();
+            for cert in load_certs(client_ca_path)? {
+                client_cas
+                    .add(&cert)
+                    .map_err(|err| format!("Invalid CA in {}: {}", client_ca_path, err))?;
+            }
+            ServerConfig::new(AllowAnyAuthenticatedClient::new(client_cas))
+        }
+        None => ServerConfig::new(NoClientAuth::new()),
+    };
+    config
+        .set_single_cert(load_certs(cert_path)?, load_private_key(key_path)?)
+        .map_err(|err| format!("Invalid certificate or key: {}", err))?;
+    if http2 {
+        config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    }
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Returns true if the client chose HTTP/2 during the handshake
+pub fn negotiated_http2(stream: &TlsStream<TcpStream>) -> bool {
+    stream.get_ref().1.get_alpn_protocol() == Some(&b"h2"[..])
+}
+
+/// How to speak TLS to an upstream
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpstreamTls {
+    /// Name to send in SNI, and to check the upstream's certificate against
+    pub server_name: String,
+    /// If false, the upstream's certificate is accepted no matter what. This keeps the traffic
+    /// private from passive eavesdroppers, but not from anyone who can impersonate the upstream.
+    pub verify: bool,
+}
+
+/// Splits an upstream address into the host:port to connect to and, for `tls://` addresses, the
+/// default TLS settings: SNI with the address's host name, and certificate verification on
+pub fn parse_upstream_address(addr: &str) -> (&str, Option<UpstreamTls>) {
+    match addr.strip_prefix(UPSTREAM_TLS_SCHEME) {
+        Some(host_port) => {
+            let host = match host_port.rsplit_once(':') {
+                Some((host, _port)) => host,
+                None => host_port,
+            };
+            let tls = UpstreamTls {
+                server_name: host
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .to_string(),
+                verify: true,
+            };
+            (host_port, Some(tls))
+        }
+        None => (addr, None),
+    }
+}
+
+impl UpstreamTls {
+    /// Returns an error if server_name can't be sent in SNI. (Only DNS names can, so upstreams
+    /// given by IP address need an explicit name.)
+    pub fn check(&self) -> Result<(), String> {
+        DNSNameRef::try_from_ascii_str(&self.server_name)
+            .map(|_| ())
+            .map_err(|_| {
+                format!(
+                    "\"{}\" is not a valid TLS server name (set one with sni=)",
+                    self.server_name
+                )
+            })
+    }
+}
+
+/// Accepts whatever certificate the upstream presents, for upstreams with verification turned off
+struct NoServerVerification;
+
+impl ServerCertVerifier for NoServerVerification {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        _presented_certs: &[Certificate],
+        _dns_name: DNSNameRef<'_>,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Opens connections to upstreams, over TLS for those that want it
+pub struct UpstreamConnector {
+    verifying: TlsConnector,
+    non_verifying: TlsConnector,
+}
+
+impl UpstreamConnector {
+    /// Upstream certificates are verified against the CAs in the PEM bundle at ca_path, or if
+    /// that's not given, against the usual set of public CAs
+    pub fn new(ca_path: Option<&str>) -> Result<UpstreamConnector, String> {
+        let mut verifying = ClientConfig::new();
+        match ca_path {
+            Some(ca_path) => {
+                for cert in load_certs(ca_path)? {
+                    verifying
+                        .root_store
+                        .add(&cert)
+                        .map_err(|err| format!("Invalid CA in {}: {}", ca_path, err))?;
+                }
+            }
+            None => verifying
+                .root_store
+                .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+        }
+        let mut non_verifying = ClientConfig::new();
+        non_verifying
+            .dangerous()
+            .set_certificate_verifier(Arc::new(NoServerVerification));
+        Ok(UpstreamConnector {
+            verifying: TlsConnector::from(Arc::new(verifying)),
+            non_verifying: TlsConnector::from(Arc::new(non_verifying)),
+        })
+    }
+
+    /// Connects to host_port (or to a Unix socket, for `unix://` addresses), sends proxy_header
+    /// if given (see proxy_protocol::encode_header), and does a TLS handshake if tls is given
+    pub async fn connect(
+        &self,
+        host_port: &str,
+        tls: Option<&UpstreamTls>,
+        proxy_header: Option<&[u8]>,
+    ) -> io::Result<UpstreamStream> {
+        if let Some(path) = unix_socket_path(host_port) {
+            let mut stream = UnixStream::connect(path).await?;
+            if let Some(proxy_header) = proxy_header {
+                stream.write_all(proxy_header).await?;
+            }
+            return Ok(UpstreamStream::Unix(stream));
+        }
+        let mut stream = TcpStream::connect(host_port).await?;
+        // Requests are written a piece at a time, so on a reused connection, Nagle's algorithm
+        // would hold each one up waiting for the upstream to acknowledge the previous piece
+        stream.set_nodelay(true)?;
+        // The header comes before anything else, TLS handshake included
+        if let Some(proxy_header) = proxy_header {
+            stream.write_all(proxy_header).await?;
+        }
+        let tls = match tls {
+            Some(tls) => tls,
+            None => return Ok(UpstreamStream::Plain(stream)),
+        };
+        let server_name = DNSNameRef::try_from_ascii_str(&tls.server_name)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid TLS server name"))?;
+        let connector = if tls.verify {
+            &self.verifying
+        } else {
+            &self.non_verifying
+        };
+        Ok(UpstreamStream::Tls(Box::new(
+            connector.connect(server_name, stream).await?,
+        )))
+    }
+}
+
+/// A connection to an upstream: plain TCP, TLS over TCP, or a Unix domain socket
+pub enum UpstreamStream {
+    Plain(TcpStream),
+    Tls(Box<client::TlsStream<TcpStream>>),
+    Unix(UnixStream),
+}
+
+impl UpstreamStream {
+    fn tcp_peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            UpstreamStream::Plain(stream) => stream.peer_addr().ok(),
+            UpstreamStream::Tls(stream) => stream.get_ref().0.peer_addr().ok(),
+            UpstreamStream::Unix(_) => None,
+        }
+    }
+
+    fn unix_peer_path(&self) -> String {
+        match self {
+            UpstreamStream::Unix(stream) => stream
+                .peer_addr()
+                .ok()
+                .and_then(|addr| addr.as_pathname().map(|path| path.display().to_string()))
+                .unwrap_or_else(|| "<unix socket>".to_string()),
+            _ => "<unknown>".to_string(),
+        }
+    }
+
+    /// The upstream's IP address and port (or socket path), for logging
+    pub fn peer_name(&self) -> String {
+        match self.tcp_peer_addr() {
+            Some(addr) => addr.to_string(),
+            None => self.unix_peer_path(),
+        }
+    }
+
+    /// The upstream's IP address (or socket path), for logging
+    pub fn peer_host(&self) -> String {
+        match self.tcp_peer_addr() {
+            Some(addr) => addr.ip().to_string(),
+            None => self.unix_peer_path(),
+        }
+    }
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,378 @@
+use crate::{request, response};
+use rand::Rng;
+use serde::Serialize;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::BufReader;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::{timeout, Duration, Instant};
+
+/// Most finished spans waiting to be exported. Past this, new spans are dropped rather than let a
+/// slow (or missing) collector use up our memory.
+const MAX_QUEUED_SPANS: usize = 2048;
+
+/// Most spans sent in one export request
+const MAX_BATCH_SIZE: usize = 512;
+
+/// Longest a finished span waits before being exported, if a batch doesn't fill up sooner
+const BATCH_DELAY: Duration = Duration::from_secs(5);
+
+/// How long the collector has to accept a batch
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where to send spans: an OTLP/HTTP collector, given as `http://host:port` (optionally followed by
+/// a path prefix). Spans are posted as JSON to `<endpoint>/v1/traces`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OtlpEndpoint {
+    /// The host:port to connect to
+    authority: String,
+    /// The path to post to
+    path: String,
+}
+
+impl FromStr for OtlpEndpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("OTLP endpoint \"{}\" must start with http://", s))?;
+        let (authority, prefix) = match rest.find('/') {
+            Some(idx) => rest.split_at(idx),
+            None => (rest, ""),
+        };
+        if authority.is_empty() {
+            return Err(format!("OTLP endpoint \"{}\" has no host", s));
+        }
+        let authority = if authority.contains(':') && !authority.ends_with(']') {
+            authority.to_string()
+        } else {
+            format!("{}:80", authority)
+        };
+        Ok(OtlpEndpoint {
+            authority,
+            path: format!("{}/v1/traces", prefix.trim_end_matches('/')),
+        })
+    }
+}
+
+/// Identifies a span within a trace, as carried in a W3C traceparent header
+/// (`00-<trace id>-<span id>-<flags>`; see https://www.w3.org/TR/trace-context/)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpanContext {
+    pub trace_id: u128,
+    pub span_id: u64,
+    /// Whether whoever started the trace wants it recorded. We don't record spans of traces that
+    /// aren't sampled, but still pass their context on.
+    pub sampled: bool,
+}
+
+impl SpanContext {
+    /// The context for a span that handles a request with the given headers: part of the trace
+    /// named by its traceparent header, or the start of a new (sampled) trace if it doesn't have a
+    /// valid one. Returns the new context and the ID of its parent span, if it has one.
+    pub fn for_request(headers: &http::HeaderMap) -> (SpanContext, Option<u64>) {
+        let span_id = new_span_id();
+        match headers
+            .get("traceparent")
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_traceparent)
+        {
+            Some(parent) => (SpanContext { span_id, ..parent }, Some(parent.span_id)),
+            None => (
+                SpanContext {
+                    trace_id: rand::thread_rng().gen_range(1, u128::MAX),
+                    span_id,
+                    sampled: true,
+                },
+                None,
+            ),
+        }
+    }
+
+    /// Formats this context as a traceparent header value, naming this span as the parent of
+    /// whatever gets the header
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id,
+            self.span_id,
+            u8::from(self.sampled)
+        )
+    }
+}
+
+/// Parses a traceparent header value. Later versions of the format may add fields, but must start
+/// with the same ones, so those are read the same way (and anything after them ignored).
+fn parse_traceparent(value: &str) -> Option<SpanContext> {
+    let value = value.trim();
+    let mut fields = value.split('-');
+    let version = fields.next()?;
+    let trace_id = fields.next()?;
+    let span_id = fields.next()?;
+    let flags = fields.next()?;
+    let is_hex = |field: &str, len: usize| {
+        field.len() == len
+            && field
+                .bytes()
+                .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+    };
+    if !is_hex(version, 2)
+        || version == "ff"
+        || !is_hex(trace_id, 32)
+        || !is_hex(span_id, 16)
+        || !is_hex(flags, 2)
+        || (version == "00" && fields.next().is_some())
+    {
+        return None;
+    }
+    let trace_id = u128::from_str_radix(trace_id, 16).ok()?;
+    let span_id = u64::from_str_radix(span_id, 16).ok()?;
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    // All-zero IDs are invalid
+    if trace_id == 0 || span_id == 0 {
+        return None;
+    }
+    Some(SpanContext {
+        trace_id,
+        span_id,
+        sampled: flags & 1 == 1,
+    })
+}
+
+fn new_span_id() -> u64 {
+    rand::thread_rng().gen_range(1, u64::MAX)
+}
+
+/// What a span stands for, as OTLP numbers it
+#[derive(Debug, Clone, Copy)]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+}
+
+/// An attribute value
+#[derive(Debug, Clone, Serialize)]
+pub enum Value {
+    #[serde(rename = "stringValue")]
+    String(String),
+    #[serde(rename = "intValue")]
+    Int(i64),
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Value {
+        Value::String(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Value {
+        Value::String(value)
+    }
+}
+
+impl From<u16> for Value {
+    fn from(value: u16) -> Value {
+        Value::Int(i64::from(value))
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Attribute {
+    key: &'static str,
+    value: Value,
+}
+
+/// A finished span, as sent to the collector
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SpanData {
+    trace_id: String,
+    span_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent_span_id: Option<String>,
+    name: String,
+    kind: u8,
+    start_time_unix_nano: String,
+    end_time_unix_nano: String,
+    attributes: Vec<Attribute>,
+    status: Status,
+}
+
+#[derive(Debug, Serialize)]
+struct Status {
+    /// 0 (unset) or 2 (error)
+    code: u8,
+}
+
+/// Sends spans to an OTLP collector. Cloning one gives another handle to the same exporter.
+#[derive(Debug, Clone)]
+pub struct Tracer {
+    spans: mpsc::Sender<SpanData>,
+}
+
+impl Tracer {
+    /// Starts exporting spans to endpoint in the background
+    pub fn start(endpoint: OtlpEndpoint) -> Tracer {
+        let (sender, receiver) = mpsc::channel(MAX_QUEUED_SPANS);
+        tokio::spawn(export(endpoint, receiver));
+        Tracer { spans: sender }
+    }
+}
+
+/// A span being recorded. It's sent to the collector when it's dropped, so a span whose work is
+/// cancelled partway (e.g. the losing side of a hedged request) still ends up in the trace.
+///
+/// Spans are started whether or not tracing is on, so that callers don't have to check; without a
+/// tracer (or a sampled context to put the span in), nothing is recorded.
+pub struct Span {
+    tracer: Option<Tracer>,
+    context: SpanContext,
+    parent_span_id: Option<u64>,
+    name: String,
+    kind: SpanKind,
+    start: SystemTime,
+    attributes: Vec<Attribute>,
+    error: bool,
+}
+
+impl Span {
+    /// Starts a span for work done as part of the span with context parent
+    pub fn child(tracer: Option<&Tracer>, parent: Option<&SpanContext>, name: &str) -> Span {
+        let context = parent.map(|parent| SpanContext {
+            span_id: new_span_id(),
+            ..*parent
+        });
+        let parent_span_id = parent.map(|parent| parent.span_id);
+        Span::new(tracer, context, parent_span_id, name, SpanKind::Internal)
+    }
+
+    /// Starts a span with the given context (e.g. one made by SpanContext::for_request)
+    pub fn new(
+        tracer: Option<&Tracer>,
+        context: Option<SpanContext>,
+        parent_span_id: Option<u64>,
+        name: &str,
+        kind: SpanKind,
+    ) -> Span {
+        let tracer = tracer.filter(|_| context.is_some_and(|context| context.sampled));
+        Span {
+            tracer: tracer.cloned(),
+            context: context.unwrap_or(SpanContext {
+                trace_id: 0,
+                span_id: 0,
+                sampled: false,
+            }),
+            parent_span_id,
+            name: name.to_string(),
+            kind,
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+            error: false,
+        }
+    }
+
+    pub fn set_attribute(&mut self, key: &'static str, value: impl Into<Value>) {
+        if self.tracer.is_some() {
+            self.attributes.push(Attribute {
+                key,
+                value: value.into(),
+            });
+        }
+    }
+
+    /// Marks the span as failed
+    pub fn set_error(&mut self) {
+        self.error = true;
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let mut tracer = match self.tracer.take() {
+            Some(tracer) => tracer,
+            None => return,
+        };
+        let nanos = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos()
+                .to_string()
+        };
+        let span = SpanData {
+            trace_id: format!("{:032x}", self.context.trace_id),
+            span_id: format!("{:016x}", self.context.span_id),
+            parent_span_id: self.parent_span_id.map(|id| format!("{:016x}", id)),
+            name: std::mem::take(&mut self.name),
+            kind: self.kind as u8,
+            start_time_unix_nano: nanos(self.start),
+            end_time_unix_nano: nanos(SystemTime::now()),
+            attributes: std::mem::take(&mut self.attributes),
+            status: Status {
+                code: if self.error { 2 } else { 0 },
+            },
+        };
+        if tracer.spans.try_send(span).is_err() {
+            log::debug!("Span queue is full; dropping a span");
+        }
+    }
+}
+
+/// Sends spans to the collector in batches, until every Tracer is gone
+async fn export(endpoint: OtlpEndpoint, mut spans: mpsc::Receiver<SpanData>) {
+    while let Some(first) = spans.recv().await {
+        let mut batch = vec![first];
+        let deadline = Instant::now() + BATCH_DELAY;
+        while batch.len() < MAX_BATCH_SIZE {
+            match tokio::time::timeout_at(deadline, spans.recv()).await {
+                Ok(Some(span)) => batch.push(span),
+                Ok(None) | Err(_) => break,
+            }
+        }
+        if let Err(err) = send_batch(&endpoint, batch).await {
+            log::warn!("Could not export spans to {}: {}", endpoint.authority, err);
+        }
+    }
+}
+
+/// Posts spans to the collector as an OTLP ExportTraceServiceRequest, in JSON
+async fn send_batch(endpoint: &OtlpEndpoint, spans: Vec<SpanData>) -> Result<(), String> {
+    let body = serde_json::json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{"key": "service.name", "value": {"stringValue": "balancebeam"}}]
+            },
+            "scopeSpans": [{
+                "scope": {"name": "balancebeam", "version": env!("CARGO_PKG_VERSION")},
+                "spans": spans,
+            }]
+        }]
+    });
+    let body = serde_json::to_vec(&body).unwrap();
+    let request = http::Request::builder()
+        .method(http::Method::POST)
+        .uri(endpoint.path.as_str())
+        .header("Host", endpoint.authority.as_str())
+        .header("Content-Type", "application/json")
+        .header("Content-Length", body.len().to_string())
+        .header("Connection", "close")
+        .body(body)
+        .unwrap();
+    let send = async {
+        let mut conn = BufReader::new(TcpStream::connect(&endpoint.authority).await?);
+        request::write_to_stream(&request, &mut conn).await?;
+        response::read_from_stream(&mut conn, request.method())
+            .await
+            .map_err(|err| std::io::Error::other(err.to_string()))
+    };
+    let response = timeout(EXPORT_TIMEOUT, send)
+        .await
+        .map_err(|_| "timed out".to_string())?
+        .map_err(|err| err.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("collector answered {}", response.status()));
+    }
+    Ok(())
+}
@@ -0,0 +1,214 @@
+use crate::{record_failure, select_upstream, tls, ActiveConnection, ProxyState};
+use crate::{RequestSlot, UpstreamSelection, UpstreamState};
+use rand::SeedableRng;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::udp::SendHalf;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::{delay_for, Instant};
+
+/// The largest payload a UDP datagram can carry
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// Binds a UDP socket to each of addrs
+pub async fn bind(addrs: &[String]) -> Result<Vec<UdpSocket>, String> {
+    let mut sockets = Vec::with_capacity(addrs.len());
+    for addr in addrs {
+        let socket = UdpSocket::bind(addr)
+            .await
+            .map_err(|err| format!("Could not bind to {}: {}", addr, err))?;
+        sockets.push(socket);
+    }
+    Ok(sockets)
+}
+
+/// A client's session: datagrams from the client are passed to the task relaying them to its
+/// upstream through sender. id tells this session apart from later ones for the same client.
+struct Session {
+    id: u64,
+    sender: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+/// Relays datagrams arriving on socket to upstreams (for --mode udp). Each client (by IP and port)
+/// gets a session pinned to one upstream, from its own socket, so that the upstream's replies can
+/// be told apart and sent back to the right client. A session ends once neither side has sent
+/// anything for --udp-session-timeout, and the client's next datagram starts a new one (which may
+/// go to a different upstream).
+pub async fn serve(socket: UdpSocket, state: Arc<ProxyState>) {
+    let (mut recv_half, send_half) = socket.split();
+    let send_half = Arc::new(Mutex::new(send_half));
+    let mut sessions: HashMap<SocketAddr, Session> = HashMap::new();
+    let mut next_id: u64 = 0;
+    // Session tasks report here when they end, so that the client's entry can be removed
+    let (ended_sender, mut ended_receiver) = mpsc::unbounded_channel::<(SocketAddr, u64)>();
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        let (len, client) = tokio::select! {
+            received = recv_half.recv_from(&mut buf) => match received {
+                Ok(received) => received,
+                Err(err) => {
+                    log::warn!("Error receiving UDP datagram: {}", err);
+                    continue;
+                }
+            },
+            Some((client, id)) = ended_receiver.recv() => {
+                // The client may already have started a new session
+                if sessions.get(&client).map(|session| session.id) == Some(id) {
+                    sessions.remove(&client);
+                }
+                continue;
+            }
+        };
+        let mut datagram = buf[..len].to_vec();
+        if let Some(session) = sessions.get(&client) {
+            match session.sender.send(datagram) {
+                Ok(()) => continue,
+                // The session ended just now; start another
+                Err(mpsc::error::SendError(unsent)) => datagram = unsent,
+            }
+        }
+
+        if !state.client_access.read().await.permits(client.ip()) {
+            log::debug!("Dropping datagram from denied client {}", client);
+            continue;
+        }
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let _ = sender.send(datagram);
+        next_id += 1;
+        sessions.insert(
+            client,
+            Session {
+                id: next_id,
+                sender,
+            },
+        );
+        let state = Arc::clone(&state);
+        let send_half = Arc::clone(&send_half);
+        let ended_sender = ended_sender.clone();
+        let id = next_id;
+        tokio::spawn(async move {
+            run_session(&state, client, receiver, send_half).await;
+            let _ = ended_sender.send((client, id));
+        });
+    }
+}
+
+/// Picks an upstream for a new session and connects a socket to it. Upstreams that can't be
+/// reached this way (TLS and Unix socket upstreams) are never picked.
+async fn connect_session(
+    state: &ProxyState,
+    client: SocketAddr,
+) -> Result<(UdpSocket, UpstreamState, UpstreamSelection), std::io::Error> {
+    let mut rng = rand::rngs::StdRng::from_entropy();
+    let eligible = |upstream: &UpstreamState| {
+        upstream.takes_requests(Instant::now())
+            && upstream.pool.is_none()
+            && upstream.tls.is_none()
+            && tls::unix_socket_path(&upstream.addr).is_none()
+    };
+    let mut selection = UpstreamSelection::new(
+        state.strategy.name(),
+        &state.upstream_addresses.read().await,
+        eligible,
+    );
+    loop {
+        let upstream = match select_upstream(
+            state,
+            client.ip(),
+            None,
+            None,
+            &eligible,
+            &mut selection,
+            &mut rng,
+        )
+        .await
+        {
+            Some(upstream) => upstream,
+            None => return Err(std::io::Error::other("No more upstreams to send to")),
+        };
+        let connect = async {
+            let upstream_addr = tokio::net::lookup_host(upstream.host_port())
+                .await?
+                .next()
+                .ok_or_else(|| std::io::Error::other("no addresses"))?;
+            let bind_addr = if upstream_addr.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            };
+            let socket = UdpSocket::bind(bind_addr).await?;
+            socket.connect(upstream_addr).await?;
+            Ok::<_, std::io::Error>(socket)
+        };
+        match connect.await {
+            Ok(socket) => return Ok((socket, upstream, selection)),
+            Err(err) => {
+                log::error!("Failed to reach upstream {}: {}", upstream.addr, err);
+                record_failure(state, &upstream.addr, &upstream.passive_health).await;
+                selection.failed.push(upstream.addr);
+            }
+        }
+    }
+}
+
+/// Relays datagrams between a client and its upstream until the session times out or the upstream
+/// refuses them
+async fn run_session(
+    state: &ProxyState,
+    client: SocketAddr,
+    mut datagrams: mpsc::UnboundedReceiver<Vec<u8>>,
+    client_socket: Arc<Mutex<SendHalf>>,
+) {
+    let (upstream_socket, upstream, selection) = match connect_session(state, client).await {
+        Ok(connected) => connected,
+        Err(err) => {
+            log::warn!("Dropping UDP datagrams from {}: {}", client, err);
+            return;
+        }
+    };
+    log::info!(
+        "{} -> {}: UDP session ({})",
+        client,
+        upstream.addr,
+        selection
+    );
+    let slot = RequestSlot::take(&upstream, &state.request_queue);
+    let _active_connection = ActiveConnection::new(&upstream, slot, &state.breaker_settings);
+    let (mut upstream_recv, mut upstream_send) = upstream_socket.split();
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        // A refused datagram shows up as an error on the upstream socket (the kernel reports the
+        // ICMP port unreachable it got back), on whichever call comes next
+        let result = tokio::select! {
+            datagram = datagrams.recv() => match datagram {
+                Some(datagram) => upstream_send.send(&datagram).await.map(|_| ()),
+                None => return,
+            },
+            received = upstream_recv.recv(&mut buf) => match received {
+                Ok(len) => {
+                    if let Err(err) = client_socket.lock().await.send_to(&buf[..len], &client).await {
+                        log::warn!("Could not send UDP datagram to {}: {}", client, err);
+                    }
+                    Ok(())
+                }
+                Err(err) => Err(err),
+            },
+            _ = delay_for(state.udp_session_timeout) => {
+                log::debug!("UDP session for {} timed out", client);
+                return;
+            }
+        };
+        if let Err(err) = result {
+            log::warn!(
+                "Ending UDP session for {} with upstream {}: {}",
+                client,
+                upstream.addr,
+                err
+            );
+            record_failure(state, &upstream.addr, &upstream.passive_health).await;
+            return;
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+use tokio::net::TcpListener;
+
+/// Environment variables a new balancebeam process finds its predecessor's listening sockets in
+/// (as comma-separated fd numbers)
+pub const LISTENER_FDS_VAR: &str = "BALANCEBEAM_LISTENER_FDS";
+pub const ADMIN_LISTENER_FDS_VAR: &str = "BALANCEBEAM_ADMIN_LISTENER_FDS";
+/// Environment variable a new balancebeam process finds the pipe it tells its predecessor it's
+/// ready on (as an fd number)
+const READY_FD_VAR: &str = "BALANCEBEAM_READY_FD";
+
+/// Returns true if our predecessor handed us sockets in fd_var
+pub fn has_inherited(fd_var: &str) -> bool {
+    std::env::var_os(fd_var).is_some()
+}
+
+/// Returns listeners for addrs. If we were started by upgrade (see spawn_successor), these are the
+/// sockets our predecessor was listening on, named by the environment variable fd_var. Otherwise,
+/// they're the sockets systemd activated us with (activated_fds), if any, or else newly-bound
+/// ones.
+pub async fn bind_or_inherit(
+    addrs: &[String],
+    fd_var: &str,
+    activated_fds: Vec<RawFd>,
+) -> Result<Vec<TcpListener>, String> {
+    let fds = match std::env::var(fd_var) {
+        Ok(fds) => {
+            // Don't pass the variable on to any process we might start later; it gets its own
+            std::env::remove_var(fd_var);
+            let fds = fds
+                .split(',')
+                .map(|fd| fd.parse())
+                .collect::<Result<Vec<RawFd>, _>>()
+                .map_err(|_| format!("bad {}: {}", fd_var, fds))?;
+            log::info!(
+                "Taking over listening sockets (fds {:?}) from our predecessor",
+                fds
+            );
+            fds
+        }
+        Err(_) if !activated_fds.is_empty() => {
+            log::info!(
+                "Using listening sockets (fds {:?}) from systemd",
+                activated_fds
+            );
+            activated_fds
+        }
+        Err(_) => {
+            let mut listeners = Vec::with_capacity(addrs.len());
+            for addr in addrs {
+                let listener = TcpListener::bind(addr)
+                    .await
+                    .map_err(|err| format!("Could not bind to {}: {}", addr, err))?;
+                listeners.push(listener);
+            }
+            return Ok(listeners);
+        }
+    };
+    fds.into_iter()
+        .map(|fd| {
+            // Safety: whoever gave us this fd gave it to us for exactly this purpose, and nothing
+            // else in this process uses it
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            listener
+                .set_nonblocking(true)
+                .and_then(|()| TcpListener::from_std(listener))
+                .map_err(|err| format!("Could not use listening socket (fd {}): {}", fd, err))
+        })
+        .collect()
+}
+
+fn format_fds(fds: &[RawFd]) -> String {
+    let fds: Vec<String> = fds.iter().map(|fd| fd.to_string()).collect();
+    fds.join(",")
+}
+
+/// If we were started by upgrade, tells our predecessor that we're accepting connections, so that
+/// it can stop
+pub fn notify_predecessor() {
+    let fd = match std::env::var(READY_FD_VAR) {
+        Ok(fd) => fd,
+        Err(_) => return,
+    };
+    std::env::remove_var(READY_FD_VAR);
+    let fd: RawFd = match fd.parse() {
+        Ok(fd) => fd,
+        Err(_) => {
+            log::warn!("bad {}: {}", READY_FD_VAR, fd);
+            return;
+        }
+    };
+    // Safety: our predecessor gave us this fd for exactly this purpose, and nothing else in this
+    // process uses it
+    let mut pipe = unsafe { File::from_raw_fd(fd) };
+    if let Err(err) = pipe.write_all(b"1") {
+        log::warn!("Could not tell our predecessor we're ready: {}", err);
+    }
+}
+
+/// A new balancebeam process started by spawn_successor
+pub struct Successor {
+    child: Child,
+    /// Read end of the pipe the new process writes to once it's accepting connections
+    ready: File,
+}
+
+impl Successor {
+    pub fn pid(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// Blocks until the new process says it's accepting connections. If it exits first, returns
+    /// an error saying how.
+    pub fn wait_ready(mut self) -> io::Result<()> {
+        match self.ready.read_exact(&mut [0; 1]) {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                let status = self.child.wait()?;
+                Err(io::Error::other(format!(
+                    "it exited ({}) before it was ready",
+                    status
+                )))
+            }
+            result => result,
+        }
+    }
+}
+
+/// Starts a new balancebeam process (from whatever binary is now installed at our path), with the
+/// same arguments as this one, that inherits our listening sockets. Connections keep being
+/// accepted throughout, since both processes accept from the same sockets until this one exits,
+/// which it should only do once the new one is ready (see Successor::wait_ready).
+pub fn spawn_successor(
+    listeners: &[TcpListener],
+    admin_listener: Option<RawFd>,
+) -> io::Result<Successor> {
+    let mut inherited_fds: Vec<RawFd> = listeners.iter().map(|l| l.as_raw_fd()).collect();
+    // Not current_exe(): on Linux, that names the old binary (which is gone, if it was replaced)
+    let mut args = std::env::args_os();
+    let program = args
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no argv[0]"))?;
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .env(LISTENER_FDS_VAR, format_fds(&inherited_fds));
+    if let Some(fd) = admin_listener {
+        inherited_fds.push(fd);
+        command.env(ADMIN_LISTENER_FDS_VAR, fd.to_string());
+    }
+    let mut pipe_fds = [0; 2];
+    // Safety: pipe_fds has room for the two fds pipe2 returns
+    if unsafe { libc::pipe2(pipe_fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safety: pipe2 just opened these, and nothing else owns them
+    let (ready, ready_writer) = unsafe {
+        (
+            File::from_raw_fd(pipe_fds[0]),
+            File::from_raw_fd(pipe_fds[1]),
+        )
+    };
+    inherited_fds.push(ready_writer.as_raw_fd());
+    command.env(READY_FD_VAR, ready_writer.as_raw_fd().to_string());
+    // Rust opens sockets with close-on-exec set, so clear it on the ones the new process needs.
+    // Safety: fcntl is async-signal-safe, so it's fine to call between fork and exec.
+    unsafe {
+        command.pre_exec(move || {
+            for &fd in &inherited_fds {
+                if libc::fcntl(fd, libc::F_SETFD, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let child = command.spawn()?;
+    // The new process has its own copy of the write end; ours has to be closed for wait_ready to
+    // see it exit
+    drop(ready_writer);
+    Ok(Successor { child, ready })
+}
@@ -1,12 +1,20 @@
 mod common;
 
-use common::{init_logging, BalanceBeam, EchoServer, Server};
+use common::{init_logging, random_local_address, BalanceBeam, EchoServer, Server};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
 
 async fn setup() -> (BalanceBeam, EchoServer) {
+    setup_with_args(&[]).await
+}
+
+async fn setup_with_args(extra_args: &[&str]) -> (BalanceBeam, EchoServer) {
     init_logging();
     let upstream = EchoServer::new().await;
-    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], None, None, extra_args).await;
     (balancebeam, upstream)
 }
 
@@ -99,3 +107,1791 @@ async fn test_multiple_requests_per_connection() {
 
     log::info!("All done :)");
 }
+
+/// Make sure that in the default (legacy) mode, the upstream hears how the client reached us
+/// through X-Forwarded-Proto/-Host/-Port, replacing whatever the client claimed
+#[tokio::test]
+async fn test_x_forwarded_headers() {
+    let (balancebeam, upstream) = setup().await;
+    let port = balancebeam.address.rsplit_once(':').unwrap().1;
+
+    let client = reqwest::Client::new();
+    let response_text = client
+        .get(&format!("http://{}/first_url", balancebeam.address))
+        .header("x-forwarded-proto", "https")
+        .header("x-forwarded-port", "443")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .expect("Balancebeam replied with a malformed response");
+    assert!(response_text.contains("x-forwarded-proto: http\n"));
+    assert!(response_text.contains(&format!("x-forwarded-host: {}\n", balancebeam.address)));
+    assert!(response_text.contains(&format!("x-forwarded-port: {}\n", port)));
+    assert!(!response_text.contains("x-forwarded-proto: https"));
+    assert!(!response_text.contains("x-forwarded-port: 443\n"));
+    assert!(!response_text.contains("forwarded: for="));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+
+    log::info!("All done :)");
+}
+
+/// Every request should reach the upstream with an X-Request-Id, and the client should get the same
+/// ID back. One the client already sent should be kept.
+#[tokio::test]
+async fn test_request_id() {
+    let (balancebeam, upstream) = setup().await;
+    let client = reqwest::Client::new();
+
+    log::info!("Sending a request without an ID");
+    let response = client
+        .get(&format!("http://{}/first_url", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let request_id = response.headers()["x-request-id"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert_eq!(request_id.len(), 36);
+    let response_text = response.text().await.unwrap();
+    assert!(response_text.contains(&format!("x-request-id: {}\n", request_id)));
+
+    log::info!("Sending another, which should get a different ID");
+    let response_text = balancebeam
+        .get("/second_url")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("x-request-id: "));
+    assert!(!response_text.contains(&request_id));
+
+    log::info!("Sending a request with an ID of its own");
+    let response = client
+        .get(&format!("http://{}/third_url", balancebeam.address))
+        .header("x-request-id", "client-chosen-id")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.headers()["x-request-id"], "client-chosen-id");
+    let response_text = response.text().await.unwrap();
+    assert!(response_text.contains("x-request-id: client-chosen-id\n"));
+    assert!(balancebeam
+        .output()
+        .iter()
+        .any(|line| line.contains("[client-chosen-id] -> ")));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 3);
+
+    log::info!("All done :)");
+}
+
+/// With --access-log, each request should get a line in combined log format, and SIGUSR1 should
+/// make balancebeam start a new file once the old one has been moved aside
+#[tokio::test]
+async fn test_access_log() {
+    let log_path = std::env::temp_dir().join(format!(
+        "balancebeam-test-{}.log",
+        random_local_address().rsplit_once(':').unwrap().1
+    ));
+    let rotated_path = log_path.with_extension("log.1");
+    let (balancebeam, upstream) =
+        setup_with_args(&["--access-log", log_path.to_str().unwrap()]).await;
+
+    log::info!("Sending a request");
+    reqwest::Client::new()
+        .get(&format!("http://{}/logged?a=1", balancebeam.address))
+        .header("referer", "http://example.com/")
+        .header("user-agent", "balancebeam \"tests\"")
+        .header("x-request-id", "logged-request")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    tokio::time::delay_for(tokio::time::Duration::from_millis(200)).await;
+    let contents = std::fs::read_to_string(&log_path).unwrap();
+    let lines: Vec<&str> = contents.lines().collect();
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].starts_with("127.0.0.1 - - ["));
+    assert!(lines[0].contains("] \"GET /logged?a=1 HTTP/1.1\" 200 "));
+    assert!(lines[0].contains("\"http://example.com/\" \"balancebeam \\\"tests\\\"\" "));
+    assert!(lines[0].ends_with(&format!(" {} logged-request", upstream.address)));
+
+    log::info!("Rotating the log");
+    std::fs::rename(&log_path, &rotated_path).unwrap();
+    balancebeam.send_signal(nix::sys::signal::Signal::SIGUSR1);
+    tokio::time::delay_for(tokio::time::Duration::from_millis(500)).await;
+    balancebeam
+        .get("/after-rotation")
+        .await
+        .expect("Error sending request to balancebeam");
+    tokio::time::delay_for(tokio::time::Duration::from_millis(200)).await;
+    let contents = std::fs::read_to_string(&log_path).unwrap();
+    assert_eq!(contents.lines().count(), 1);
+    assert!(contents.contains("\"GET /after-rotation HTTP/1.1\" 200 "));
+    let rotated_contents = std::fs::read_to_string(&rotated_path).unwrap();
+    assert_eq!(rotated_contents.lines().count(), 1);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 2);
+    std::fs::remove_file(&log_path).unwrap();
+    std::fs::remove_file(&rotated_path).unwrap();
+
+    log::info!("All done :)");
+}
+
+/// With --log-format json, every line balancebeam logs should be a JSON object, and each request
+/// should be logged as a single event
+#[tokio::test]
+async fn test_json_logging() {
+    let (balancebeam, upstream) = setup_with_args(&["--log-format", "json"]).await;
+
+    balancebeam
+        .get("/json?a=1")
+        .await
+        .expect("Error sending request to balancebeam");
+    tokio::time::delay_for(tokio::time::Duration::from_millis(200)).await;
+    let lines: Vec<serde_json::Value> = balancebeam
+        .output()
+        .iter()
+        .map(|line| serde_json::from_str(line).expect("Log line isn't JSON"))
+        .collect();
+    assert!(lines.iter().all(|line| line["timestamp"].is_string()));
+    let events: Vec<&serde_json::Value> = lines
+        .iter()
+        .filter(|line| line["target"] == "balancebeam::request")
+        .collect();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0]["level"], "INFO");
+    assert_eq!(events[0]["client_ip"], "127.0.0.1");
+    assert_eq!(events[0]["upstream"], upstream.address.as_str());
+    assert_eq!(events[0]["method"], "GET");
+    assert_eq!(events[0]["path"], "/json?a=1");
+    assert_eq!(events[0]["status"], 200);
+    assert!(events[0]["latency_ms"].is_number());
+    assert_eq!(events[0]["request_id"].as_str().unwrap().len(), 36);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+
+    log::info!("All done :)");
+}
+
+/// With --otlp-endpoint, a request should get a span in its caller's trace (with children for the
+/// work done upstream), and the upstream should get a traceparent naming that span
+#[tokio::test]
+async fn test_tracing() {
+    init_logging();
+    let collector_address = random_local_address();
+    let exported = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let bind_addr = collector_address.parse().unwrap();
+    let collector_exported = Arc::clone(&exported);
+    tokio::spawn(async move {
+        let service = hyper::service::make_service_fn(move |_| {
+            let exported = Arc::clone(&collector_exported);
+            async move {
+                Ok::<_, std::convert::Infallible>(hyper::service::service_fn(
+                    move |req: hyper::Request<hyper::Body>| {
+                        let exported = Arc::clone(&exported);
+                        async move {
+                            assert_eq!(req.uri().path(), "/v1/traces");
+                            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                            exported.lock().unwrap().push(body);
+                            Ok::<_, std::convert::Infallible>(hyper::Response::new(
+                                hyper::Body::from("{}"),
+                            ))
+                        }
+                    },
+                ))
+            }
+        });
+        let _ = hyper::Server::bind(&bind_addr).serve(service).await;
+    });
+    let endpoint = format!("http://{}", collector_address);
+    let (balancebeam, upstream) = setup_with_args(&["--otlp-endpoint", &endpoint]).await;
+
+    let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+    let caller_span_id = "00f067aa0ba902b7";
+    let response_text = reqwest::Client::new()
+        .get(&format!("http://{}/traced", balancebeam.address))
+        .header(
+            "traceparent",
+            format!("00-{}-{}-01", trace_id, caller_span_id),
+        )
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .unwrap();
+    let traceparent = response_text
+        .lines()
+        .find_map(|line| line.strip_prefix("traceparent: "))
+        .expect("Upstream didn't get a traceparent header");
+    let fields: Vec<&str> = traceparent.split('-').collect();
+    assert_eq!(fields.len(), 4);
+    assert_eq!(fields[1], trace_id);
+    assert_ne!(fields[2], caller_span_id);
+    assert_eq!(fields[3], "01");
+    let request_span_id = fields[2];
+
+    log::info!("Waiting for spans to be exported");
+    let mut spans = Vec::new();
+    for _ in 0..100 {
+        spans = exported
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|export| {
+                export["resourceSpans"][0]["scopeSpans"][0]["spans"]
+                    .as_array()
+                    .cloned()
+                    .unwrap_or_default()
+            })
+            .collect();
+        if spans.len() >= 4 {
+            break;
+        }
+        tokio::time::delay_for(tokio::time::Duration::from_millis(100)).await;
+    }
+    log::info!("Spans: {:?}", spans);
+    assert!(spans.iter().all(|span| span["traceId"] == trace_id));
+    let request_span = spans
+        .iter()
+        .find(|span| span["spanId"] == request_span_id)
+        .expect("Request span wasn't exported");
+    assert_eq!(request_span["parentSpanId"], caller_span_id);
+    assert_eq!(request_span["name"], "GET");
+    assert_eq!(request_span["kind"], 2);
+    for name in &["connect", "send request", "read response"] {
+        let child = spans
+            .iter()
+            .find(|span| span["name"] == *name)
+            .unwrap_or_else(|| panic!("No {} span was exported", name));
+        assert_eq!(child["parentSpanId"], request_span_id);
+    }
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+
+    log::info!("All done :)");
+}
+
+/// Make sure that in rfc7239 mode, the upstream gets a well-formed Forwarded header (instead of
+/// X-Forwarded-For), and that we append to a Forwarded chain the client already sent.
+#[tokio::test]
+async fn test_rfc7239_forwarded_header() {
+    let (balancebeam, upstream) = setup_with_args(&["--forwarded-header-style", "rfc7239"]).await;
+    let expected_element = format!(
+        "for=127.0.0.1;proto=http;by=\"{}\";host=\"{}\"",
+        balancebeam.address, balancebeam.address
+    );
+
+    log::info!("Sending a request without a Forwarded header");
+    let response_text = balancebeam
+        .get("/first_url")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains(&format!("forwarded: {}\n", expected_element)));
+    assert!(!response_text.contains("x-forwarded-for"));
+
+    log::info!("Sending a request that already went through another proxy");
+    let client = reqwest::Client::new();
+    let response_text = client
+        .get(&format!("http://{}/second_url", balancebeam.address))
+        .header("forwarded", "for=192.0.2.43")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .expect("Balancebeam replied with a malformed response");
+    assert!(response_text.contains(&format!(
+        "forwarded: for=192.0.2.43, {}\n",
+        expected_element
+    )));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 2);
+
+    log::info!("All done :)");
+}
+
+/// Sends a POST request with neither Content-Length nor Transfer-Encoding (which reqwest won't do)
+/// and returns whatever balancebeam sends back before closing the connection
+async fn post_without_framing_headers(balancebeam: &BalanceBeam) -> String {
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(b"POST /unframed HTTP/1.1\r\nx-sent-by: balancebeam-tests\r\n\r\n")
+        .await
+        .expect("Could not send request to balancebeam");
+    // Hang up our side so that balancebeam closes the connection once it has replied
+    stream
+        .shutdown(std::net::Shutdown::Write)
+        .expect("Could not shut down connection");
+    let mut response = Vec::new();
+    stream
+        .read_to_end(&mut response)
+        .await
+        .expect("Error reading response from balancebeam");
+    String::from_utf8_lossy(&response).to_string()
+}
+
+/// By default, a POST without framing headers is treated as having an empty body and forwarded.
+#[tokio::test]
+async fn test_unframed_post_forwarded_with_empty_body() {
+    let (balancebeam, upstream) = setup().await;
+
+    log::info!("Sending a POST request without framing headers");
+    let response_text = post_without_framing_headers(&balancebeam).await;
+    assert!(response_text.starts_with("HTTP/1.1 200"));
+    assert!(response_text.contains("POST /unframed HTTP/1.1"));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+
+    log::info!("All done :)");
+}
+
+/// With --require-content-length, a POST without framing headers gets a 411 and is never
+/// forwarded upstream.
+#[tokio::test]
+async fn test_unframed_post_length_required() {
+    let (balancebeam, upstream) = setup_with_args(&["--require-content-length"]).await;
+
+    log::info!("Sending a POST request without framing headers");
+    let response_text = post_without_framing_headers(&balancebeam).await;
+    assert!(response_text.starts_with("HTTP/1.1 411"));
+
+    log::info!("Making sure requests with framing headers still work");
+    let response_text = balancebeam
+        .post("/framed", "Hello world!")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("POST /framed HTTP/1.1"));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+
+    log::info!("All done :)");
+}
+
+/// balancebeam should accept connections on every --bind address, and proxy them all the same
+#[tokio::test]
+async fn test_multiple_bind_addresses() {
+    let second_address = random_local_address();
+    let (balancebeam, upstream) = setup_with_args(&["--bind", &second_address]).await;
+
+    for address in [&balancebeam.address, &second_address].iter() {
+        log::info!("Sending a request to {}", address);
+        let response_text = reqwest::Client::new()
+            .get(&format!("http://{}/via/{}", address, address))
+            .header("x-sent-by", "balancebeam-tests")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .unwrap();
+        assert!(response_text.contains(&format!("GET /via/{} HTTP/1.1", address)));
+        assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+    }
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 2);
+
+    log::info!("All done :)");
+}
+
+/// A chunked upload should be decoded (so that it doesn't get mistaken for the start of the next
+/// request on the connection) and passed upstream still chunked
+#[tokio::test]
+async fn test_chunked_request() {
+    let (balancebeam, upstream) = setup().await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(
+            b"POST /chunked HTTP/1.1\r\nx-sent-by: balancebeam-tests\r\n\
+              Transfer-Encoding: chunked\r\n\r\n\
+              5\r\nHello\r\n7;some-extension\r\n world!\r\n0\r\n\r\n",
+        )
+        .await
+        .expect("Could not send request to balancebeam");
+    let response_text = read_until_contains(&mut stream, "Hello world!").await;
+    assert!(response_text.contains("POST /chunked HTTP/1.1"));
+    assert!(response_text.contains("transfer-encoding: chunked"));
+
+    log::info!("Making sure the connection can still be used");
+    stream
+        .write_all(b"GET /after HTTP/1.1\r\nx-sent-by: balancebeam-tests\r\n\r\n")
+        .await
+        .expect("Could not send request to balancebeam");
+    // Hang up our side so that balancebeam closes the connection once it has replied
+    stream
+        .shutdown(std::net::Shutdown::Write)
+        .expect("Could not shut down connection");
+    let mut response = Vec::new();
+    stream
+        .read_to_end(&mut response)
+        .await
+        .expect("Error reading response from balancebeam");
+    assert!(String::from_utf8_lossy(&response).contains("GET /after HTTP/1.1"));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 2);
+
+    log::info!("All done :)");
+}
+
+/// Requests whose framing headers an upstream could read differently than we do (so that part of
+/// the body would be taken for the next request on a shared upstream connection) should get a 400,
+/// and the connection closed, without anything being forwarded
+#[tokio::test]
+async fn test_ambiguous_framing() {
+    let (balancebeam, upstream) = setup().await;
+
+    let smuggling_attempts: [&[u8]; 3] = [
+        b"POST /te HTTP/1.1\r\nContent-Length: 5\r\n\
+          Transfer-Encoding: chunked, identity\r\n\r\nhello",
+        b"POST /cl HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 30\r\n\r\nhello",
+        b"POST /cl HTTP/1.1\r\nContent-Length: 5, 30\r\n\r\nhello",
+    ];
+    for request in smuggling_attempts.iter() {
+        let mut stream = TcpStream::connect(&balancebeam.address)
+            .await
+            .expect("Could not connect to balancebeam");
+        stream
+            .write_all(request)
+            .await
+            .expect("Could not send request to balancebeam");
+        // balancebeam should hang up after replying, without our having to
+        let mut response = Vec::new();
+        stream
+            .read_to_end(&mut response)
+            .await
+            .expect("Error reading response from balancebeam");
+        assert!(String::from_utf8_lossy(&response).starts_with("HTTP/1.1 400"));
+    }
+
+    log::info!("Making sure Content-Length is dropped when the body is chunked");
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(
+            b"POST /both HTTP/1.1\r\nContent-Length: 30\r\nContent-Length: 30\r\n\
+              Transfer-Encoding: chunked\r\n\r\n5\r\nHello\r\n0\r\n\r\n",
+        )
+        .await
+        .expect("Could not send request to balancebeam");
+    let response_text = read_until_contains(&mut stream, "Hello").await;
+    assert!(response_text.contains("POST /both HTTP/1.1"));
+    assert!(!response_text.contains("content-length: 30"));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+
+    log::info!("All done :)");
+}
+
+/// Bodies are passed through rather than held in memory, so there's no limit on how big they can be
+#[tokio::test]
+async fn test_large_body() {
+    let (balancebeam, upstream) = setup().await;
+
+    // Bigger than the 10MB requests and responses were once capped at
+    let body = "0123456789abcdef".repeat(1 << 20);
+    let response_text = balancebeam
+        .post("/large", &body)
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("POST /large HTTP/1.1"));
+    assert!(response_text.ends_with(&format!("\n\n{}", body)));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+
+    log::info!("All done :)");
+}
+
+/// Bodies over --max-body-size should get a 413, whether the client says how big they are up front
+/// or not
+#[tokio::test]
+async fn test_max_body_size() {
+    let (balancebeam, upstream) = setup_with_args(&["--max-body-size", "1000"]).await;
+
+    log::info!("Sending a body that fits");
+    let response_text = balancebeam
+        .post("/small", &"a".repeat(1000))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("POST /small HTTP/1.1"));
+
+    log::info!("Sending a body whose Content-Length is over the limit");
+    let response_text = balancebeam
+        .post("/large", &"a".repeat(1001))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("413"));
+
+    log::info!("Sending a chunked body that goes over the limit");
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let request = format!(
+        "POST /chunked HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n800\r\n{}\r\n",
+        "a".repeat(0x800)
+    );
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .expect("Could not send request to balancebeam");
+    let mut response = Vec::new();
+    stream
+        .read_to_end(&mut response)
+        .await
+        .expect("Error reading response from balancebeam");
+    assert!(String::from_utf8_lossy(&response).starts_with("HTTP/1.1 413"));
+
+    // The oversized Content-Length request never makes it upstream
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert!(num_requests_received <= 2);
+
+    log::info!("All done :)");
+}
+
+/// Requests with too many headers, or headers that are too long, should get a 431 and never make it
+/// upstream
+#[tokio::test]
+async fn test_header_limits() {
+    let (balancebeam, upstream) =
+        setup_with_args(&["--max-headers", "8", "--max-header-size", "500"]).await;
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/headers", balancebeam.address);
+
+    log::info!("Sending a request within the limits");
+    let response = client
+        .get(&url)
+        .header("x-extra", "a".repeat(100))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+
+    log::info!("Sending a request with too many headers");
+    let mut request = client.get(&url);
+    for i in 0..8 {
+        request = request.header(format!("x-extra-{}", i).as_str(), "a");
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 431);
+
+    log::info!("Sending a request with headers that are too long");
+    let response = client
+        .get(&url)
+        .header("x-extra", "a".repeat(500))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 431);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+
+    log::info!("All done :)");
+}
+
+/// Reads from stream until what's been read contains text, and returns everything read
+async fn read_until_contains(stream: &mut TcpStream, text: &str) -> String {
+    let mut response = Vec::new();
+    while !String::from_utf8_lossy(&response).contains(text) {
+        let mut buffer = [0_u8; 512];
+        let n = stream
+            .read(&mut buffer)
+            .await
+            .expect("Error reading response from balancebeam");
+        assert!(n > 0, "balancebeam hung up before sending {:?}", text);
+        response.extend_from_slice(&buffer[..n]);
+    }
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+/// A client that sends Expect: 100-continue should be told to go ahead before it sends its body
+#[tokio::test]
+async fn test_expect_continue() {
+    let (balancebeam, upstream) = setup().await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(
+            b"POST /expect HTTP/1.1\r\nx-sent-by: balancebeam-tests\r\n\
+              Expect: 100-continue\r\nContent-Length: 12\r\n\r\n",
+        )
+        .await
+        .expect("Could not send request to balancebeam");
+    let interim = tokio::time::timeout(
+        tokio::time::Duration::from_secs(5),
+        read_until_contains(&mut stream, "\r\n\r\n"),
+    )
+    .await
+    .expect("Timed out waiting for 100 Continue");
+    assert!(interim.starts_with("HTTP/1.1 100 Continue"));
+
+    stream
+        .write_all(b"Hello world!")
+        .await
+        .expect("Could not send request body to balancebeam");
+    let response = read_until_contains(&mut stream, "Hello world!").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("expect: 100-continue"));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+
+    log::info!("All done :)");
+}
+
+/// If the upstream answers an Expect: 100-continue request with a final response, the client
+/// should get that response without sending its body
+#[tokio::test]
+async fn test_expect_continue_declined() {
+    init_logging();
+    let upstream_address = random_local_address();
+    let mut listener = tokio::net::TcpListener::bind(&upstream_address)
+        .await
+        .unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buffer = [0_u8; 512];
+        while !request.ends_with(b"\r\n\r\n") {
+            match stream.read(&mut buffer).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => request.extend_from_slice(&buffer[..n]),
+            }
+        }
+        let _ = stream
+            .write_all(b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n")
+            .await;
+        // Keep the connection open, as an upstream waiting to see what the client does next would
+        while let Ok(n) = stream.read(&mut buffer).await {
+            if n == 0 {
+                break;
+            }
+        }
+    });
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(b"POST /expect HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 12\r\n\r\n")
+        .await
+        .expect("Could not send request to balancebeam");
+    // balancebeam hangs up after the response, since we might still send the body
+    let mut response = Vec::new();
+    tokio::time::timeout(
+        tokio::time::Duration::from_secs(5),
+        stream.read_to_end(&mut response),
+    )
+    .await
+    .expect("Timed out waiting for a response")
+    .expect("Error reading response from balancebeam");
+    let response = String::from_utf8_lossy(&response);
+    assert!(response.starts_with("HTTP/1.1 401 Unauthorized"));
+    assert!(!response.contains("100 Continue"));
+
+    log::info!("All done :)");
+}
+
+/// Trailers on chunked requests and responses should be passed on, not dropped
+#[tokio::test]
+async fn test_trailers() {
+    init_logging();
+    // This upstream sends back the raw request it received as a chunked body, followed by a
+    // trailer of its own
+    let upstream_address = random_local_address();
+    let mut listener = tokio::net::TcpListener::bind(&upstream_address)
+        .await
+        .unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        while !request.ends_with(b"x-client-trailer: yes\r\n\r\n") {
+            let mut buffer = [0_u8; 512];
+            match stream.read(&mut buffer).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => request.extend_from_slice(&buffer[..n]),
+            }
+        }
+        let head = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\
+                     Trailer: x-upstream-trailer\r\n\r\n";
+        let response = [
+            &head[..],
+            format!("{:x}\r\n", request.len()).as_bytes(),
+            &request,
+            b"\r\n0\r\nx-upstream-trailer: yes\r\n\r\n",
+        ]
+        .concat();
+        let _ = stream.write_all(&response).await;
+        let _ = stream.read(&mut [0_u8; 512]).await;
+    });
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(
+            b"POST /trailers HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\
+              Trailer: x-client-trailer\r\n\r\n\
+              5\r\nHello\r\n0\r\nx-client-trailer: yes\r\n\r\n",
+        )
+        .await
+        .expect("Could not send request to balancebeam");
+    let response = tokio::time::timeout(
+        tokio::time::Duration::from_secs(5),
+        read_until_contains(&mut stream, "x-upstream-trailer: yes\r\n\r\n"),
+    )
+    .await
+    .expect("Timed out waiting for the response's trailers");
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("\r\n0\r\nx-client-trailer: yes\r\n\r\n"));
+
+    log::info!("All done :)");
+}
+
+/// Starts an upstream that answers every request with a chunked response, and keeps connections
+/// open afterwards
+async fn start_chunked_upstream() -> String {
+    let address = random_local_address();
+    let mut listener = tokio::net::TcpListener::bind(&address).await.unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buffer = [0_u8; 512];
+                loop {
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&buffer[..n]),
+                    }
+                    // Requests from balancebeam here have no body, so each one ends with its head
+                    while let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        request.drain(..end + 4);
+                        stream
+                            .write_all(
+                                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                                  5\r\nHello\r\n7\r\n world!\r\n0\r\n\r\n",
+                            )
+                            .await
+                            .unwrap();
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// A chunked response ends with its last chunk, so balancebeam should pass it on without waiting
+/// for the upstream to close the connection
+#[tokio::test]
+async fn test_chunked_response() {
+    init_logging();
+    let upstream_address = start_chunked_upstream().await;
+    let balancebeam = BalanceBeam::new(&[&upstream_address], Some(3600), None).await;
+
+    let client = reqwest::Client::new();
+    for i in 0..3 {
+        let request = client
+            .get(&format!("http://{}/request-{}", balancebeam.address, i))
+            .send();
+        let response = tokio::time::timeout(tokio::time::Duration::from_secs(5), request)
+            .await
+            .expect("Timed out waiting for a chunked response")
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.text().await.unwrap(), "Hello world!");
+    }
+
+    log::info!("All done :)");
+}
+
+/// Starts an upstream that answers every request with a short response and keeps connections open
+/// afterwards. Returns its address and a count of the connections it has accepted.
+async fn start_counting_upstream() -> (String, Arc<AtomicUsize>) {
+    let address = random_local_address();
+    let mut listener = tokio::net::TcpListener::bind(&address).await.unwrap();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&connections);
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buffer = [0_u8; 512];
+                loop {
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&buffer[..n]),
+                    }
+                    while let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        request.drain(..end + 4);
+                        stream
+                            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nHello")
+                            .await
+                            .unwrap();
+                    }
+                }
+            });
+        }
+    });
+    (address, connections)
+}
+
+/// Clients that come one after another should share an upstream connection, unless
+/// --upstream-max-idle turns pooling off
+#[tokio::test]
+async fn test_upstream_connection_reuse() {
+    init_logging();
+    for (max_idle, expected_connections) in [("8", 1), ("0", 3)] {
+        let (upstream_address, connections) = start_counting_upstream().await;
+        let balancebeam = BalanceBeam::new_with_args(
+            &[&upstream_address],
+            None,
+            None,
+            &["--upstream-max-idle", max_idle],
+        )
+        .await;
+        for _ in 0..3 {
+            let response_text = balancebeam
+                .get("/")
+                .await
+                .expect("Error sending request to balancebeam");
+            assert_eq!(response_text, "Hello");
+            // Give balancebeam a moment to notice that the client hung up
+            tokio::time::delay_for(tokio::time::Duration::from_millis(100)).await;
+        }
+        assert_eq!(
+            connections.load(Ordering::SeqCst),
+            expected_connections,
+            "Unexpected number of upstream connections with --upstream-max-idle {}",
+            max_idle
+        );
+    }
+
+    log::info!("All done :)");
+}
+
+/// Requests should be forwarded to upstreams listening on a Unix domain socket. The upstream
+/// answers each request with its request line.
+#[tokio::test]
+async fn test_unix_socket_upstream() {
+    init_logging();
+    let socket_path = std::env::temp_dir().join(format!(
+        "balancebeam-test-{}.sock",
+        random_local_address().rsplit_once(':').unwrap().1
+    ));
+    let _ = std::fs::remove_file(&socket_path);
+    let mut listener = tokio::net::UnixListener::bind(&socket_path).unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buffer = [0_u8; 512];
+                loop {
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&buffer[..n]),
+                    }
+                    while let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        let head = String::from_utf8_lossy(&request[..end]).into_owned();
+                        request.drain(..end + 4);
+                        let request_line = head.lines().next().unwrap_or_default();
+                        let response = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                            request_line.len(),
+                            request_line
+                        );
+                        stream.write_all(response.as_bytes()).await.unwrap();
+                    }
+                }
+            });
+        }
+    });
+
+    let upstream = format!("unix://{}", socket_path.display());
+    let balancebeam = BalanceBeam::new(&[&upstream], None, None).await;
+    for i in 0..3 {
+        let path = format!("/over-unix-socket-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response_text, format!("GET {} HTTP/1.1", path));
+    }
+    std::fs::remove_file(&socket_path).unwrap();
+
+    log::info!("All done :)");
+}
+
+/// With --mode tcp, bytes should be passed through to the upstream and back untouched, even if
+/// they aren't HTTP. The upstream echoes back whatever it's sent.
+#[tokio::test]
+async fn test_tcp_mode() {
+    init_logging();
+    let upstream_address = random_local_address();
+    let mut listener = tokio::net::TcpListener::bind(&upstream_address)
+        .await
+        .unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut read, mut write) = stream.split();
+                let _ = tokio::io::copy(&mut read, &mut write).await;
+            });
+        }
+    });
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream_address], None, None, &["--mode", "tcp"]).await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    for message in ["PING\r\n", "*1\r\n$4\r\nPING\r\n"] {
+        client.write_all(message.as_bytes()).await.unwrap();
+        let mut echoed = vec![0_u8; message.len()];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&echoed), message);
+    }
+    client.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut rest = Vec::new();
+    client.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+
+    log::info!("All done :)");
+}
+
+/// Connects to address, sends prefix followed by a GET request, and returns everything sent back
+/// before the connection closes
+async fn get_with_prefix(address: &str, prefix: &[u8]) -> String {
+    let mut stream = TcpStream::connect(address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let request = b"GET /proxied HTTP/1.1\r\nx-sent-by: balancebeam-tests\r\n\r\n";
+    stream
+        .write_all(&[prefix, &request[..]].concat())
+        .await
+        .expect("Could not send request to balancebeam");
+    // Hang up our side so that balancebeam closes the connection once it has replied
+    stream
+        .shutdown(std::net::Shutdown::Write)
+        .expect("Could not shut down connection");
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response).await;
+    String::from_utf8_lossy(&response).to_string()
+}
+
+/// On a listener marked proxy_protocol, the client's address should be taken from the PROXY
+/// protocol header (of either version), and connections without one should be turned away
+#[tokio::test]
+async fn test_accept_proxy_protocol() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let proxied_address = random_local_address();
+    let proxied_bind = format!("{},proxy_protocol", proxied_address);
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], None, None, &["--bind", &proxied_bind])
+            .await;
+
+    log::info!("Sending a v1 header");
+    let response_text = get_with_prefix(
+        &proxied_address,
+        b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 443\r\n",
+    )
+    .await;
+    assert!(response_text.contains("GET /proxied HTTP/1.1"));
+    assert!(response_text.contains("x-forwarded-for: 203.0.113.7"));
+
+    log::info!("Sending a v2 header");
+    let mut v2_header = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+    v2_header.extend_from_slice(&[198, 51, 100, 9, 10, 0, 0, 1]);
+    v2_header.extend_from_slice(&51234_u16.to_be_bytes());
+    v2_header.extend_from_slice(&443_u16.to_be_bytes());
+    let response_text = get_with_prefix(&proxied_address, &v2_header).await;
+    assert!(response_text.contains("x-forwarded-for: 198.51.100.9"));
+
+    log::info!("Sending no header");
+    let response_text = get_with_prefix(&proxied_address, b"").await;
+    assert!(response_text.is_empty());
+
+    log::info!("Making sure the plain listener doesn't expect a header");
+    let response_text = get_with_prefix(&balancebeam.address, b"").await;
+    assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+    assert_eq!(Box::new(upstream).stop().await, 3);
+
+    log::info!("All done :)");
+}
+
+/// An upstream marked proxy_protocol=v1 should get a PROXY protocol header naming the client at
+/// the start of each connection. The upstream answers each request with the header it got.
+#[tokio::test]
+async fn test_send_proxy_protocol() {
+    init_logging();
+    let upstream_address = random_local_address();
+    let mut listener = tokio::net::TcpListener::bind(&upstream_address)
+        .await
+        .unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut stream = tokio::io::BufReader::new(stream);
+                let mut header = String::new();
+                stream.read_line(&mut header).await.unwrap();
+                // Skip the request's head, then answer with the header
+                loop {
+                    let mut line = String::new();
+                    if stream.read_line(&mut line).await.unwrap() == 0 || line == "\r\n" {
+                        break;
+                    }
+                }
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                    header.len(),
+                    header
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+    let upstream = format!("{},proxy_protocol=v1", upstream_address);
+    let balancebeam = BalanceBeam::new(&[&upstream], None, None).await;
+
+    for _ in 0..2 {
+        let response_text = balancebeam
+            .get("/")
+            .await
+            .expect("Error sending request to balancebeam");
+        let balancebeam_port = balancebeam.address.rsplit_once(':').unwrap().1;
+        assert!(response_text.starts_with("PROXY TCP4 127.0.0.1 127.0.0.1 "));
+        assert!(response_text.ends_with(&format!(" {}\r\n", balancebeam_port)));
+    }
+
+    log::info!("All done :)");
+}
+
+/// Once --max-connections are open, a new connection should wait up to --connection-queue-timeout
+/// for one to close, and be served if one does, or else be turned away with a 503
+#[tokio::test]
+async fn test_max_connections() {
+    let (balancebeam, upstream) = setup_with_args(&[
+        "--max-connections",
+        "1",
+        "--connection-queue-timeout",
+        "500",
+    ])
+    .await;
+    let request = b"GET /slots HTTP/1.1\r\nx-sent-by: balancebeam-tests\r\n\r\n";
+
+    log::info!("Taking the only connection slot");
+    let mut first = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    first.write_all(request).await.unwrap();
+    read_until_contains(&mut first, "GET /slots HTTP/1.1").await;
+
+    log::info!("A second connection should be turned away once its wait is up");
+    let mut second = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let response = read_until_contains(&mut second, "\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+
+    log::info!("A third connection should get the slot when the first closes");
+    let mut third = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    tokio::time::delay_for(tokio::time::Duration::from_millis(100)).await;
+    drop(first);
+    third.write_all(request).await.unwrap();
+    read_until_contains(&mut third, "GET /slots HTTP/1.1").await;
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// An upstream that takes a request but never answers should get the client a 504 once
+/// --upstream-response-timeout passes, rather than leaving it waiting forever
+#[tokio::test]
+async fn test_upstream_response_timeout() {
+    init_logging();
+    let upstream_address = random_local_address();
+    let mut listener = tokio::net::TcpListener::bind(&upstream_address)
+        .await
+        .unwrap();
+    tokio::spawn(async move {
+        let mut hung = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            hung.push(stream);
+        }
+    });
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        None,
+        None,
+        &["--upstream-response-timeout", "1"],
+    )
+    .await;
+
+    let started = std::time::Instant::now();
+    let response = reqwest::Client::new()
+        .get(&format!("http://{}/hung", balancebeam.address))
+        .timeout(std::time::Duration::from_secs(5))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 504);
+    assert!(
+        started.elapsed() < std::time::Duration::from_secs(3),
+        "The 504 took {:?}",
+        started.elapsed()
+    );
+
+    log::info!("All done :)");
+}
+
+/// Connections that sit idle, or trickle in their headers, for longer than --client-idle-timeout
+/// should be closed
+#[tokio::test]
+async fn test_client_idle_timeout() {
+    let (balancebeam, _upstream) = setup_with_args(&["--client-idle-timeout", "1"]).await;
+
+    let mut idle = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let mut slow = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    slow.write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\n")
+        .await
+        .expect("Could not send request to balancebeam");
+    for stream in [&mut idle, &mut slow].iter_mut() {
+        let mut leftover = Vec::new();
+        tokio::time::timeout(
+            tokio::time::Duration::from_secs(3),
+            stream.read_to_end(&mut leftover),
+        )
+        .await
+        .expect("balancebeam didn't close the connection")
+        .expect("Error reading from balancebeam");
+        assert!(leftover.is_empty());
+    }
+
+    log::info!("All done :)");
+}
+
+/// Response bodies should be sent no faster than --client-bandwidth, once the client has used up its
+/// burst
+#[tokio::test]
+async fn test_client_bandwidth() {
+    let (balancebeam, _upstream) = setup_with_args(&["--client-bandwidth", "200000"]).await;
+
+    // The echoed response is a little over 600KB, 400KB of which has to wait its turn
+    let body = "0123456789abcdef".repeat(600000 / 16);
+    let started = std::time::Instant::now();
+    let response_text = balancebeam
+        .post("/download", &body)
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.ends_with(&format!("\n\n{}", body)));
+    assert!(
+        started.elapsed() >= std::time::Duration::from_millis(1800),
+        "The response took only {:?}",
+        started.elapsed()
+    );
+
+    log::info!("All done :)");
+}
+
+/// With --cache-size, repeated GET requests should be answered from the cache without reaching the
+/// upstream, while other requests still go through
+#[tokio::test]
+async fn test_response_cache() {
+    let (balancebeam, upstream) = setup_with_args(&["--cache-size", "1000000"]).await;
+
+    let url = format!("http://{}/cached", balancebeam.address);
+    let first = reqwest::get(&url)
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(first.headers().get("age").is_none());
+    let first = first.text().await.unwrap();
+    let second = reqwest::get(&url)
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(second.headers().get("age").unwrap(), "0");
+    assert_eq!(second.text().await.unwrap(), first);
+
+    // A different URL, or a method that isn't cached, still reaches the upstream
+    balancebeam
+        .get("/uncached")
+        .await
+        .expect("Error sending request to balancebeam");
+    balancebeam
+        .post("/cached", "body")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(Box::new(upstream).stop().await, 3);
+
+    log::info!("All done :)");
+}
+
+/// With --compress-gzip and --compress-deflate, text bodies should be compressed for clients that
+/// accept it, and left alone for clients that don't, or when they're too small to bother
+#[tokio::test]
+async fn test_response_compression() {
+    init_logging();
+    let upstream_address = random_local_address();
+    let mut listener = tokio::net::TcpListener::bind(&upstream_address)
+        .await
+        .unwrap();
+    let text = "balancebeam ".repeat(500);
+    let served_text = text.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let text = served_text.clone();
+            tokio::spawn(async move {
+                let mut buffer = [0_u8; 4096];
+                loop {
+                    let mut request = Vec::new();
+                    while !request.ends_with(b"\r\n\r\n") {
+                        match stream.read(&mut buffer).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buffer[..n]),
+                        }
+                    }
+                    let body = match request.starts_with(b"GET /tiny") {
+                        true => "tiny",
+                        false => &text,
+                    };
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\n\
+                         ETag: \"v1\"\r\nContent-Length: {}\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    if stream.write_all(response.as_bytes()).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        None,
+        None,
+        &["--compress-gzip", "--compress-deflate"],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let get = |path: &str, accept_encoding: Option<&str>| {
+        let mut request = client.get(&format!("http://{}{}", balancebeam.address, path));
+        if let Some(accept_encoding) = accept_encoding {
+            request = request.header("Accept-Encoding", accept_encoding);
+        }
+        async move {
+            let response = request
+                .send()
+                .await
+                .expect("Error sending request to balancebeam");
+            let header = |name| {
+                response
+                    .headers()
+                    .get(name)
+                    .map(|value: &reqwest::header::HeaderValue| value.to_str().unwrap().to_string())
+            };
+            let headers = (header("content-encoding"), header("vary"), header("etag"));
+            (headers, response.bytes().await.unwrap().to_vec())
+        }
+    };
+
+    // gzip ends with the length of the uncompressed body
+    let ((encoding, vary, etag), body) = get("/", Some("gzip, deflate")).await;
+    assert_eq!(encoding.as_deref(), Some("gzip"));
+    assert_eq!(vary.as_deref(), Some("Accept-Encoding"));
+    assert_eq!(etag.as_deref(), Some("W/\"v1\""));
+    assert_eq!(&body[..2], &[0x1f, 0x8b]);
+    assert!(body.len() < text.len() / 10);
+    assert_eq!(
+        &body[body.len() - 4..],
+        &(text.len() as u32).to_le_bytes()[..]
+    );
+
+    let ((encoding, _, _), body) = get("/", Some("gzip;q=0.5, deflate")).await;
+    assert_eq!(encoding.as_deref(), Some("deflate"));
+    assert_eq!(body[0], 0x78);
+    assert!(body.len() < text.len() / 10);
+
+    // Clients that don't ask for compression, and small bodies, get the body as it is
+    let ((encoding, _, etag), body) = get("/", None).await;
+    assert_eq!(encoding, None);
+    assert_eq!(etag.as_deref(), Some("\"v1\""));
+    assert_eq!(body, text.as_bytes());
+    let ((encoding, _, _), body) = get("/", Some("br, gzip;q=0")).await;
+    assert_eq!(encoding, None);
+    assert_eq!(body, text.as_bytes());
+    let ((encoding, _, _), body) = get("/tiny", Some("gzip")).await;
+    assert_eq!(encoding, None);
+    assert_eq!(body, b"tiny");
+
+    log::info!("All done :)");
+}
+
+/// --request-header and --response-header rules should set, add, and remove headers on the way
+/// to and from the upstream
+#[tokio::test]
+async fn test_header_rules() {
+    let (balancebeam, upstream) = setup_with_args(&[
+        "--request-header",
+        "remove X-Debug",
+        "--request-header",
+        "set X-Env: prod",
+        "--response-header",
+        "remove Date",
+        "--response-header",
+        "set Strict-Transport-Security: max-age=63072000",
+        "--response-header",
+        "add X-Served-By: balancebeam",
+    ])
+    .await;
+
+    let response = reqwest::Client::new()
+        .get(&format!("http://{}/headers", balancebeam.address))
+        .header("X-Debug", "secret")
+        .header("X-Env", "dev")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response.headers().get("date").is_none());
+    assert_eq!(
+        response.headers().get("strict-transport-security").unwrap(),
+        "max-age=63072000"
+    );
+    assert_eq!(
+        response.headers().get("x-served-by").unwrap(),
+        "balancebeam"
+    );
+    let echoed = response.text().await.unwrap().to_lowercase();
+    assert!(!echoed.contains("x-debug"));
+    assert!(echoed.contains("x-env: prod\n"));
+    assert!(!echoed.contains("x-env: dev"));
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// With --cors-origin, preflights from allowed origins should be answered without reaching the
+/// upstream, and responses to allowed origins should carry Access-Control-* headers
+#[tokio::test]
+async fn test_cors() {
+    let (balancebeam, upstream) = setup_with_args(&[
+        "--cors-origin",
+        "https://app.example.com",
+        "--cors-origin",
+        "https://*.example.org",
+        "--cors-credentials",
+    ])
+    .await;
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/api", balancebeam.address);
+
+    let preflight = client
+        .request(reqwest::Method::OPTIONS, &url)
+        .header("Origin", "https://app.example.com")
+        .header("Access-Control-Request-Method", "PUT")
+        .header("Access-Control-Request-Headers", "content-type")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(preflight.status(), reqwest::StatusCode::NO_CONTENT);
+    let headers = preflight.headers();
+    assert_eq!(
+        headers.get("access-control-allow-origin").unwrap(),
+        "https://app.example.com"
+    );
+    assert_eq!(
+        headers.get("access-control-allow-credentials").unwrap(),
+        "true"
+    );
+    assert!(headers
+        .get("access-control-allow-methods")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .contains("PUT"));
+    assert_eq!(
+        headers.get("access-control-allow-headers").unwrap(),
+        "content-type"
+    );
+
+    let refused = client
+        .request(reqwest::Method::OPTIONS, &url)
+        .header("Origin", "https://evil.example.net")
+        .header("Access-Control-Request-Method", "PUT")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(refused.status(), reqwest::StatusCode::FORBIDDEN);
+
+    let response = client
+        .get(&url)
+        .header("Origin", "https://shop.example.org")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(
+        response
+            .headers()
+            .get("access-control-allow-origin")
+            .unwrap(),
+        "https://shop.example.org"
+    );
+    assert_eq!(response.headers().get("vary").unwrap(), "Origin");
+    let response = client
+        .get(&url)
+        .header("Origin", "https://example.org")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response
+        .headers()
+        .get("access-control-allow-origin")
+        .is_none());
+
+    // Only the two simple requests reached the upstream
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
+
+/// --method-rule should refuse methods a deny rule covers with a 403, and methods the most
+/// specific allow rule doesn't list with a 405, without bothering the upstream
+#[tokio::test]
+async fn test_method_rules() {
+    let (balancebeam, upstream) = setup_with_args(&[
+        "--method-rule",
+        "deny TRACE",
+        "--method-rule",
+        "allow GET,HEAD /public",
+        "--method-rule",
+        "allow GET,POST /public/forms",
+    ])
+    .await;
+    let client = reqwest::Client::new();
+    let send = |method: reqwest::Method, path: &str| {
+        client
+            .request(method, &format!("http://{}{}", balancebeam.address, path))
+            .send()
+    };
+
+    let response = send(reqwest::Method::TRACE, "/anything").await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::FORBIDDEN);
+    let response = send(reqwest::Method::POST, "/public/page").await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(response.headers().get("allow").unwrap(), "GET, HEAD");
+    let response = send(reqwest::Method::DELETE, "/public").await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::METHOD_NOT_ALLOWED);
+    // Nor can the rules be dodged with paths an upstream would clean up into a covered one
+    for path in &["//public/x", "/%70ublic/x", "/private/%2E%2E/public"] {
+        let response = send(reqwest::Method::DELETE, path).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    for (method, path) in &[
+        (reqwest::Method::GET, "/public/page"),
+        (reqwest::Method::POST, "/public/forms/signup"),
+        (reqwest::Method::POST, "/publicity"),
+        (reqwest::Method::DELETE, "/private"),
+    ] {
+        let response = send(method.clone(), path).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+    }
+    assert_eq!(Box::new(upstream).stop().await, 4);
+
+    log::info!("All done :)");
+}
+
+/// Writes a file to a fresh path in the temp directory and returns its path
+fn write_temp_file(name: &str, contents: &str) -> String {
+    let path = std::env::temp_dir().join(format!(
+        "balancebeam-test-{}-{}",
+        rand::random::<u64>(),
+        name
+    ));
+    std::fs::write(&path, contents).expect("Could not write temp file");
+    path.to_str().unwrap().to_string()
+}
+
+/// Basic --auth rules should let in users with the right password (from MD5 and SHA-1 htpasswd
+/// hashes) and tell the upstream who they are, and answer anyone else with a 401
+#[tokio::test]
+async fn test_basic_auth() {
+    let htpasswd = write_temp_file(
+        "htpasswd",
+        "# made with htpasswd -m and -s\n\
+         alice:$apr1$xyzsalt1$mdDPSoZhouTDI1GHRla8E1\n\
+         bob:{SHA}87u9ZqY9S/F0eUBXjsPQEDUw4h0=\n",
+    );
+    let (balancebeam, upstream) = setup_with_args(&[
+        "--auth",
+        "basic /admin",
+        "--auth",
+        "none /admin/ping",
+        "--basic-auth-file",
+        &htpasswd,
+    ])
+    .await;
+    let client = reqwest::Client::new();
+    let url = |path| format!("http://{}{}", balancebeam.address, path);
+
+    for (user, password) in &[("alice", "secret"), ("bob", "hunter2")] {
+        let response = client
+            .get(&url("/admin/users"))
+            .basic_auth(user, Some(password))
+            .header("X-Authenticated-User", "mallory")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let echoed = response.text().await.unwrap();
+        assert!(echoed.contains(&format!("x-authenticated-user: {}\n", user)));
+        assert!(!echoed.contains("mallory"));
+    }
+    for (user, password) in &[("alice", Some("hunter2")), ("carol", Some("secret"))] {
+        let response = client
+            .get(&url("/admin/users"))
+            .basic_auth(user, password.as_ref())
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+    }
+    let response = client.get(&url("/admin")).send().await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        response.headers().get("www-authenticate").unwrap(),
+        "Basic realm=\"balancebeam\""
+    );
+    // Nor can the rules be dodged with paths an upstream would clean up into a protected one
+    for path in &["//admin", "/%61dmin/users", "/admin/ping/%2E%2E/users"] {
+        let response = client.get(&url(path)).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+    }
+
+    // Paths no rule (or a none rule) covers don't need credentials, but clients still can't say
+    // who they are themselves
+    for path in &["/", "/administrator", "/admin/ping"] {
+        let response = client
+            .get(&url(path))
+            .header("X-Authenticated-User", "mallory")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert!(!response.text().await.unwrap().contains("mallory"));
+    }
+    assert_eq!(Box::new(upstream).stop().await, 5);
+    std::fs::remove_file(&htpasswd).unwrap();
+
+    log::info!("All done :)");
+}
+
+/// Builds a JWT from its claims, signed by sign
+fn make_jwt(alg: &str, claims: &str, sign: impl Fn(&[u8]) -> Vec<u8>) -> String {
+    let encode = |bytes: &[u8]| base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+    let signed = format!(
+        "{}.{}",
+        encode(format!(r#"{{"alg":"{}","typ":"JWT"}}"#, alg).as_bytes()),
+        encode(claims.as_bytes())
+    );
+    let signature = sign(signed.as_bytes());
+    format!("{}.{}", signed, encode(&signature))
+}
+
+/// JWT --auth rules should accept bearer tokens signed with --jwt-secret or a key in --jwt-jwks
+/// whose claims check out, and answer anything else with a 401
+#[tokio::test]
+async fn test_jwt_auth() {
+    use ring::signature::KeyPair;
+
+    let rng = ring::rand::SystemRandom::new();
+    let algorithm = &ring::signature::ECDSA_P256_SHA256_FIXED_SIGNING;
+    let pkcs8 = ring::signature::EcdsaKeyPair::generate_pkcs8(algorithm, &rng).unwrap();
+    let key_pair = ring::signature::EcdsaKeyPair::from_pkcs8(algorithm, pkcs8.as_ref()).unwrap();
+    let point = key_pair.public_key().as_ref();
+    let jwks = write_temp_file(
+        "jwks.json",
+        &format!(
+            r#"{{"keys": [{{"kty": "EC", "crv": "P-256", "kid": "test", "x": "{}", "y": "{}"}}]}}"#,
+            base64::encode_config(&point[1..33], base64::URL_SAFE_NO_PAD),
+            base64::encode_config(&point[33..], base64::URL_SAFE_NO_PAD)
+        ),
+    );
+    let (balancebeam, upstream) = setup_with_args(&[
+        "--auth",
+        "jwt /api",
+        "--jwt-secret",
+        "s3cret",
+        "--jwt-jwks",
+        &jwks,
+        "--jwt-audience",
+        "api",
+    ])
+    .await;
+    let hs256 = |secret: &str, claims: &str| {
+        let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret.as_bytes());
+        make_jwt("HS256", claims, |signed| {
+            ring::hmac::sign(&key, signed).as_ref().to_vec()
+        })
+    };
+    let es256 = |claims: &str| {
+        make_jwt("ES256", claims, |signed| {
+            key_pair.sign(&rng, signed).unwrap().as_ref().to_vec()
+        })
+    };
+    let client = reqwest::Client::new();
+    let get = |token: Option<String>| {
+        let mut request = client.get(&format!("http://{}/api/orders", balancebeam.address));
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        async move {
+            let response = request
+                .send()
+                .await
+                .expect("Error sending request to balancebeam");
+            (response.status().as_u16(), response.text().await.unwrap())
+        }
+    };
+
+    let (status, echoed) = get(Some(hs256("s3cret", r#"{"sub":"alice","aud":"api"}"#))).await;
+    assert_eq!(status, 200);
+    assert!(echoed.contains("x-authenticated-user: alice\n"));
+    let (status, echoed) = get(Some(es256(
+        r#"{"sub":"bob","aud":["web","api"],"exp":4102444800}"#,
+    )))
+    .await;
+    assert_eq!(status, 200);
+    assert!(echoed.contains("x-authenticated-user: bob\n"));
+
+    // Bad signatures, expired tokens, tokens for someone else, and no token at all are refused
+    for token in [
+        Some(hs256("wrong", r#"{"sub":"alice","aud":"api"}"#)),
+        Some(hs256(
+            "s3cret",
+            r#"{"sub":"alice","aud":"api","exp":1000000000}"#,
+        )),
+        Some(hs256("s3cret", r#"{"sub":"alice","aud":"web"}"#)),
+        Some(make_jwt("none", r#"{"sub":"alice","aud":"api"}"#, |_| {
+            Vec::new()
+        })),
+        Some("not.a.token".to_string()),
+        None,
+    ] {
+        assert_eq!(get(token).await.0, 401);
+    }
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    std::fs::remove_file(&jwks).unwrap();
+
+    log::info!("All done :)");
+}
+
+/// --error-page files should replace the bodies of the errors balancebeam makes itself, with the
+/// request's ID filled in, but leave what the upstream says alone
+#[tokio::test]
+async fn test_error_pages() {
+    let bad_gateway = write_temp_file(
+        "502.html",
+        "<h1>{status} {reason}</h1><p>Quote {request_id} when you call us</p>\n",
+    );
+    let client_error = write_temp_file(
+        "4xx.json",
+        "{\"status\": {status}, \"error\": \"{reason}\", \"request_id\": \"{request_id}\"}",
+    );
+    let error_page = |status, path| format!("{}={}", status, path);
+    let (balancebeam, upstream) = setup_with_args(&[
+        "--error-page",
+        &error_page("502", &bad_gateway),
+        "--error-page",
+        &error_page("4xx", &client_error),
+        "--method-rule",
+        "deny DELETE",
+    ])
+    .await;
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/orders/1", balancebeam.address);
+
+    let response = client
+        .get(&url)
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    assert!(response.text().await.unwrap().contains("GET /orders/1"));
+
+    let response = client
+        .delete(&url)
+        .header("X-Request-Id", "req-42")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status(), reqwest::StatusCode::FORBIDDEN);
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "application/json"
+    );
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(
+        body,
+        serde_json::json!({"status": 403, "error": "Forbidden", "request_id": "req-42"})
+    );
+
+    // With the upstream gone, we have to answer with a 502 ourselves
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    let response = client
+        .get(&url)
+        .header("X-Request-Id", "<b>")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status(), reqwest::StatusCode::BAD_GATEWAY);
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "text/html; charset=utf-8"
+    );
+    assert_eq!(
+        response.text().await.unwrap(),
+        "<h1>502 Bad Gateway</h1><p>Quote &lt;b&gt; when you call us</p>\n"
+    );
+    std::fs::remove_file(&bad_gateway).unwrap();
+    std::fs::remove_file(&client_error).unwrap();
+
+    log::info!("All done :)");
+}
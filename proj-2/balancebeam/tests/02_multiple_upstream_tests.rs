@@ -1,13 +1,36 @@
 mod common;
 
-use common::{init_logging, skip_time, BalanceBeam, EchoServer, ErrorServer, Server};
+use common::{
+    init_logging, random_local_address, skip_time, BalanceBeam, EchoServer, ErrorServer, Server,
+};
 
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
 use tokio::time::{delay_for, Duration};
 
 async fn setup_with_params(
     n_upstreams: usize,
     active_health_check_interval: Option<usize>,
     max_requests_per_minute: Option<usize>,
+) -> (BalanceBeam, Vec<Box<dyn Server>>) {
+    setup_with_args(
+        n_upstreams,
+        active_health_check_interval,
+        max_requests_per_minute,
+        &[],
+    )
+    .await
+}
+
+async fn setup_with_args(
+    n_upstreams: usize,
+    active_health_check_interval: Option<usize>,
+    max_requests_per_minute: Option<usize>,
+    extra_args: &[&str],
 ) -> (BalanceBeam, Vec<Box<dyn Server>>) {
     init_logging();
     let mut upstreams: Vec<Box<dyn Server>> = Vec::new();
@@ -22,10 +45,11 @@ async fn setup_with_params(
         .iter()
         .map(|addr| addr.as_str())
         .collect();
-    let balancebeam = BalanceBeam::new(
+    let balancebeam = BalanceBeam::new_with_args(
         &upstream_addresses,
         active_health_check_interval,
         max_requests_per_minute,
+        extra_args,
     )
     .await;
     (balancebeam, upstreams)
@@ -120,6 +144,208 @@ async fn try_failover(balancebeam: &BalanceBeam, upstreams: &mut Vec<Box<dyn Ser
     }
 }
 
+/// Starts an upstream that reads the start of each request and then hangs up without answering.
+/// Returns its address and a count of the connections it has accepted.
+async fn start_hang_up_upstream() -> (String, Arc<AtomicUsize>) {
+    let address = random_local_address();
+    let mut listener = tokio::net::TcpListener::bind(&address).await.unwrap();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&connections);
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            let mut buffer = [0_u8; 512];
+            let _ = stream.read(&mut buffer).await;
+        }
+    });
+    (address, connections)
+}
+
+/// An idempotent request whose upstream fails partway through should be retried on another
+/// upstream, rather than the client getting a 502
+#[tokio::test]
+async fn test_retry_on_another_upstream() {
+    init_logging();
+    let n_requests = 4;
+    let (hang_up_address, _) = start_hang_up_upstream().await;
+    let echo = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&hang_up_address, &echo.address],
+        Some(3600),
+        None,
+        &["--strategy", "round-robin"],
+    )
+    .await;
+
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    assert_eq!(Box::new(echo).stop().await, n_requests);
+    assert!(balancebeam
+        .output()
+        .iter()
+        .any(|line| line.contains("retrying GET /request-0")));
+
+    log::info!("All done :)");
+}
+
+/// With --passive-failure-threshold, an upstream should only be marked dead once that many of its
+/// requests have failed in a row
+#[tokio::test]
+async fn test_passive_failure_threshold() {
+    init_logging();
+    let n_requests = 6;
+    let (hang_up_address, hang_up_connections) = start_hang_up_upstream().await;
+    let echo = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&hang_up_address, &echo.address],
+        Some(3600),
+        None,
+        &[
+            "--strategy",
+            "round-robin",
+            "--passive-failure-threshold",
+            "3",
+        ],
+    )
+    .await;
+
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    // Every request to the failing upstream is retried on the other one, and round-robin keeps
+    // coming back to the failing upstream until its third failure
+    assert_eq!(hang_up_connections.load(Ordering::SeqCst), 3);
+    assert_eq!(Box::new(echo).stop().await, n_requests);
+
+    log::info!("All done :)");
+}
+
+/// With --hedge-after, a request that a slow upstream sits on should be sent to another upstream
+/// too, and the client should get the faster answer
+#[tokio::test]
+async fn test_hedged_requests() {
+    init_logging();
+    let slow = EchoServer::new_with_delay(Duration::from_secs(3)).await;
+    let fast = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&slow.address, &fast.address],
+        Some(3600),
+        None,
+        &["--strategy", "round-robin", "--hedge-after", "200"],
+    )
+    .await;
+
+    // Round-robin sends the first request to the slow upstream
+    let started = tokio::time::Instant::now();
+    let response_text = balancebeam
+        .get("/hedged")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /hedged HTTP/1.1"));
+    assert!(
+        started.elapsed() < Duration::from_secs(2),
+        "Request wasn't hedged: it took {:?}",
+        started.elapsed()
+    );
+    assert!(balancebeam
+        .output()
+        .iter()
+        .any(|line| line.contains("hedging GET /hedged")));
+
+    assert_eq!(Box::new(slow).stop().await, 1);
+    assert_eq!(Box::new(fast).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// With --breaker-failures, an upstream that keeps answering with errors should stop getting
+/// requests, even though it's up and no active health check has run
+#[tokio::test]
+async fn test_circuit_breaker() {
+    init_logging();
+    let n_requests = 10;
+    let failing = ErrorServer::new().await;
+    let healthy = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&failing.address, &healthy.address],
+        Some(3600),
+        None,
+        &["--strategy", "round-robin", "--breaker-failures", "2"],
+    )
+    .await;
+
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    // Round-robin alternates until the failing upstream's second error trips its breaker
+    assert_eq!(Box::new(failing).stop().await, 2);
+    assert_eq!(Box::new(healthy).stop().await, n_requests - 2);
+    assert!(balancebeam
+        .output()
+        .iter()
+        .any(|line| line.contains("tripped")));
+
+    log::info!("All done :)");
+}
+
+/// With --adaptive-weights, upstreams that fail or answer slowly should get far fewer requests than
+/// a healthy one, but still a few, so that we'd notice if they recovered
+#[tokio::test]
+async fn test_adaptive_weights() {
+    init_logging();
+    let n_requests = 150;
+    let healthy = EchoServer::new().await;
+    let failing = ErrorServer::new().await;
+    let slow = EchoServer::new_with_delay(Duration::from_millis(200)).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&healthy.address, &failing.address, &slow.address],
+        Some(3600),
+        None,
+        &["--strategy", "round-robin", "--adaptive-weights"],
+    )
+    .await;
+
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let failing_requests = Box::new(failing).stop().await;
+    let slow_requests = Box::new(slow).stop().await;
+    let healthy_requests = Box::new(healthy).stop().await;
+    log::info!(
+        "Healthy upstream got {} requests, failing one {}, slow one {}",
+        healthy_requests,
+        failing_requests,
+        slow_requests
+    );
+    assert!(healthy_requests > n_requests * 2 / 3);
+    assert!(failing_requests > 0 && failing_requests < n_requests / 5);
+    assert!(slow_requests > 0 && slow_requests < n_requests / 5);
+
+    log::info!("All done :)");
+}
+
 /// Make sure passive health checks work. Send a few requests, then kill one of the upstreams and
 /// make sure requests continue to work
 #[tokio::test]
@@ -189,6 +415,306 @@ async fn test_active_health_checks_check_http_status() {
     }
 }
 
+/// Make sure active health checks give up on an upstream that doesn't answer within
+/// --health-check-timeout, rather than waiting on it forever:
+///
+/// * Start one fast upstream and one that takes a few seconds to answer anything
+/// * Wait for a few rounds of health checks
+/// * Send some requests. They should all be answered quickly by the fast upstream
+#[tokio::test]
+async fn test_active_health_check_timeout() {
+    init_logging();
+    let fast = EchoServer::new().await;
+    let slow = EchoServer::new_with_delay(Duration::from_secs(3)).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&fast.address, &slow.address],
+        Some(1),
+        None,
+        &["--health-check-timeout", "1"],
+    )
+    .await;
+
+    log::info!("Waiting for health checks to give up on the slow upstream...");
+    delay_for(Duration::from_secs(3)).await;
+
+    let n_requests = 6;
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = tokio::time::timeout(Duration::from_secs(2), balancebeam.get(&path))
+            .await
+            .expect("Request was sent to the upstream that fails its health checks")
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    assert!(Box::new(fast).stop().await >= n_requests);
+    Box::new(slow).stop().await;
+}
+
+/// An upstream at its max_requests should be passed over until one of its requests finishes, even
+/// though it's healthy and would otherwise get nearly all of the traffic
+#[tokio::test]
+async fn test_upstream_max_requests() {
+    init_logging();
+    let small = EchoServer::new_with_delay(Duration::from_secs(1)).await;
+    let large = EchoServer::new().await;
+    let small_arg = format!("{},weight=1000,max_requests=1", small.address);
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&small_arg, &large.address], Some(3600), None, &[]).await;
+
+    let n_requests = 4;
+    let requests: Vec<_> = (0..n_requests)
+        .map(|i| {
+            let url = format!("http://{}/request-{}", balancebeam.address, i);
+            tokio::spawn(async move {
+                reqwest::Client::new()
+                    .get(&url)
+                    .header("x-sent-by", "balancebeam-tests")
+                    .send()
+                    .await
+                    .expect("Error sending request to balancebeam")
+                    .status()
+                    .as_u16()
+            })
+        })
+        .collect();
+    for request in requests {
+        assert_eq!(request.await.unwrap(), 200);
+    }
+
+    let small_requests = Box::new(small).stop().await;
+    assert!(
+        small_requests <= 1,
+        "The upstream with max_requests=1 got {} requests at once",
+        small_requests
+    );
+    assert_eq!(Box::new(large).stop().await, n_requests - small_requests);
+}
+
+/// Make sure that once every upstream is at its max_requests, requests wait in the queue for one to
+/// have room, and those that don't fit in the queue get a 503 saying why
+#[tokio::test]
+async fn test_request_queue() {
+    init_logging();
+    let upstream = EchoServer::new_with_delay(Duration::from_secs(1)).await;
+    let upstream_arg = format!("{},max_requests=1", upstream.address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_arg],
+        Some(3600),
+        None,
+        &["--request-queue-size", "1"],
+    )
+    .await;
+
+    let send = |i: usize| {
+        let url = format!("http://{}/request-{}", balancebeam.address, i);
+        tokio::spawn(async move {
+            let response = reqwest::Client::new()
+                .get(&url)
+                .header("x-sent-by", "balancebeam-tests")
+                .send()
+                .await
+                .expect("Error sending request to balancebeam");
+            let status = response.status().as_u16();
+            (status, response.text().await.unwrap())
+        })
+    };
+    // The first request takes the upstream's only slot and the second waits in the queue, leaving
+    // no room for the third
+    let first = send(0);
+    delay_for(Duration::from_millis(200)).await;
+    let queued = send(1);
+    delay_for(Duration::from_millis(200)).await;
+    let (status, body) = send(2).await.unwrap();
+    assert_eq!(status, 503);
+    assert!(
+        body.contains("already waiting"),
+        "The 503 should explain that the queue is full, but its body was {:?}",
+        body
+    );
+    assert_eq!(first.await.unwrap().0, 200);
+    assert_eq!(queued.await.unwrap().0, 200);
+    assert_eq!(Box::new(upstream).stop().await, 2);
+}
+
+/// Make sure an upstream's health_interval option overrides --active-health-check-interval, with
+/// --health-check-jitter varying it a little. No requests are sent through balancebeam, so the
+/// upstreams' request counts are all health checks.
+#[tokio::test]
+async fn test_per_upstream_health_check_interval() {
+    init_logging();
+    let frequent = EchoServer::new().await;
+    let infrequent = EchoServer::new().await;
+    let frequent_arg = format!("{},health_interval=1", frequent.address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&frequent_arg, &infrequent.address],
+        Some(3600),
+        None,
+        &["--health-check-jitter", "20"],
+    )
+    .await;
+    delay_for(Duration::from_millis(3500)).await;
+    drop(balancebeam);
+
+    let frequent_checks = Box::new(frequent).stop().await;
+    assert!(
+        (2..=4).contains(&frequent_checks),
+        "Expected about 3 health checks of the upstream checked every second, but got {}",
+        frequent_checks
+    );
+    assert_eq!(
+        Box::new(infrequent).stop().await,
+        0,
+        "The upstream using the global interval shouldn't have been checked yet"
+    );
+}
+
+/// Make sure a slow health check doesn't hold up proxying to the other upstreams:
+///
+/// * Start one fast upstream and one that never answers in time
+/// * Once the slow upstream has been marked dead, keep sending requests while further health
+///   checks wait on it
+/// * Every request should be answered quickly
+#[tokio::test]
+async fn test_health_checks_do_not_block_requests() {
+    init_logging();
+    let fast = EchoServer::new().await;
+    let slow = EchoServer::new_with_delay(Duration::from_secs(30)).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&fast.address, &slow.address],
+        Some(1),
+        None,
+        &["--health-check-timeout", "1"],
+    )
+    .await;
+
+    log::info!("Waiting for health checks to give up on the slow upstream...");
+    delay_for(Duration::from_millis(2500)).await;
+
+    let started = tokio::time::Instant::now();
+    let mut i = 0;
+    while started.elapsed() < Duration::from_secs(3) {
+        let path = format!("/request-{}", i);
+        let response_text =
+            tokio::time::timeout(Duration::from_millis(500), balancebeam.get(&path))
+                .await
+                .expect("Request was held up while a health check was running")
+                .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        i += 1;
+    }
+    Box::new(fast).stop().await;
+}
+
+/// Make sure --health-check-status and --health-check-body decide which answers count as healthy:
+/// an upstream that returns errors is kept when 500 is an acceptable status, and an upstream whose
+/// answer doesn't contain the expected text is marked dead
+#[tokio::test]
+async fn test_active_health_check_expectations() {
+    init_logging();
+    let echo = EchoServer::new().await;
+    let error = ErrorServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&echo.address, &error.address],
+        Some(1),
+        None,
+        &[
+            "--strategy",
+            "round-robin",
+            "--health-check-status",
+            "200-299,500",
+        ],
+    )
+    .await;
+    delay_for(Duration::from_secs(2)).await;
+    let n_requests = 10;
+    for i in 0..n_requests {
+        let _ = balancebeam.get(&format!("/request-{}", i)).await;
+    }
+    drop(balancebeam);
+    let error_count = Box::new(error).stop().await;
+    assert!(
+        error_count >= n_requests / 2,
+        "Upstream returning an acceptable status was marked dead"
+    );
+
+    let error = ErrorServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&echo.address, &error.address],
+        Some(1),
+        None,
+        &[
+            "--health-check-status",
+            "200-299,500",
+            "--health-check-body",
+            "GET / HTTP/1.1",
+        ],
+    )
+    .await;
+    delay_for(Duration::from_secs(2)).await;
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(
+            response_text.contains(&format!("GET {} HTTP/1.1", path)),
+            "Request was sent to an upstream whose health check answer didn't match"
+        );
+    }
+    Box::new(echo).stop().await;
+    Box::new(error).stop().await;
+}
+
+/// Make sure active health check requests are sent with --health-check-method,
+/// --health-check-header and --health-check-request-body. The echo server answers with the request
+/// it got, so --health-check-body can check what was sent:
+///
+/// * An upstream is kept while the probe carries everything expected of it
+/// * An upstream is marked dead when the probe is missing the expected header
+#[tokio::test]
+async fn test_custom_health_check_request() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        Some(1),
+        None,
+        &[
+            "--health-check-method",
+            "POST",
+            "--health-check-header",
+            "X-Probe-Token: secret",
+            "--health-check-request-body",
+            "ping",
+            "--health-check-body",
+            "x-probe-token: secret",
+        ],
+    )
+    .await;
+    delay_for(Duration::from_secs(2)).await;
+    balancebeam
+        .get("/after-health-checks")
+        .await
+        .expect("Upstream was marked dead even though the health check request matched");
+    drop(balancebeam);
+
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        Some(1),
+        None,
+        &["--health-check-body", "x-probe-token: secret"],
+    )
+    .await;
+    delay_for(Duration::from_secs(2)).await;
+    let response_text = balancebeam.get("/after-health-checks").await;
+    assert!(
+        response_text.is_err() || !response_text.unwrap().contains("GET /after-health-checks"),
+        "Upstream should have been marked dead, since the health check request had no token"
+    );
+    Box::new(upstream).stop().await;
+}
+
 /// Make sure active health checks restore upstreams that were previously failed but are now
 /// working again:
 ///
@@ -239,6 +765,158 @@ async fn test_active_health_checks_restore_failed_upstream() {
     log::info!("All done :)");
 }
 
+/// Make sure backup upstreams only get requests once every primary upstream is dead:
+///
+/// * Send some requests. They should all go to the primary upstreams
+/// * Kill the primary upstreams
+/// * Send some more requests. They should all succeed, thanks to the backup
+#[tokio::test]
+async fn test_backup_tier() {
+    init_logging();
+    let primaries = vec![EchoServer::new().await, EchoServer::new().await];
+    let backup = EchoServer::new().await;
+    let backup_arg = format!("{},tier=backup", backup.address);
+    let balancebeam = BalanceBeam::new(
+        &[&primaries[0].address, &primaries[1].address, &backup_arg],
+        None,
+        None,
+    )
+    .await;
+
+    let n_requests = 10;
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    log::info!("Killing the primary upstreams");
+    let mut primary_count = 0;
+    for primary in primaries {
+        primary_count += Box::new(primary).stop().await;
+    }
+    assert_eq!(primary_count, n_requests);
+
+    for i in 0..n_requests {
+        let path = format!("/after-failover-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam. Backup failover may not be working");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    assert_eq!(
+        Box::new(backup).stop().await,
+        n_requests,
+        "The backup should only have gotten requests once the primaries were dead"
+    );
+}
+
+/// Make sure an upstream that comes back is ramped up over --slow-start rather than immediately
+/// getting its full share of requests:
+///
+/// * Kill one of two upstreams, then bring it back
+/// * Right after the active health check revives it, send a batch of requests round-robin
+/// * The revived upstream should get far fewer than half of them
+#[tokio::test]
+async fn test_slow_start() {
+    let (balancebeam, mut upstreams) = setup_with_args(
+        2,
+        Some(1),
+        None,
+        &["--strategy", "round-robin", "--slow-start", "60"],
+    )
+    .await;
+    let failed_ip = upstreams[upstreams.len() - 1].address();
+    try_failover(&balancebeam, &mut upstreams).await;
+
+    log::info!("Re-starting the \"failed\" upstream server...");
+    upstreams.push(Box::new(EchoServer::new_at_address(failed_ip).await));
+    delay_for(Duration::from_millis(1500)).await;
+
+    let n_requests = 40;
+    for i in 0..n_requests {
+        let path = format!("/after-restore-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    // The restarted server also counts health checks, of which there are at most a couple
+    let revived_count = upstreams.pop().unwrap().stop().await;
+    assert!(
+        revived_count < n_requests / 4,
+        "Revived upstream got {} of {} requests, even though it should still be ramping up",
+        revived_count,
+        n_requests
+    );
+    upstreams.pop().unwrap().stop().await;
+}
+
+/// Sends a GET request claiming (in X-Forwarded-For) to have come through the given chain of
+/// addresses, and returns the response status
+async fn get_forwarded_for(balancebeam: &BalanceBeam, forwarded_for: &str) -> u16 {
+    reqwest::Client::new()
+        .get(&format!("http://{}/forwarded", balancebeam.address))
+        .header("x-sent-by", "balancebeam-tests")
+        .header("x-forwarded-for", forwarded_for)
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// When requests come from a trusted proxy, the client should be identified (here, for rate
+/// limiting and logging) by the first untrusted address in X-Forwarded-For, counting from the
+/// right
+#[tokio::test]
+async fn test_trusted_proxies() {
+    init_logging();
+    let rate_limit_threshold = 2;
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        Some(rate_limit_threshold),
+        &[
+            "--trusted-proxies",
+            "127.0.0.1",
+            "--trusted-proxies",
+            "10.0.0.0/8",
+        ],
+    )
+    .await;
+
+    log::info!("Using up the first client's requests, through another trusted proxy");
+    for _ in 0..rate_limit_threshold {
+        assert_eq!(
+            get_forwarded_for(&balancebeam, "198.51.100.1, 203.0.113.7, 10.1.2.3").await,
+            200
+        );
+    }
+    assert_eq!(get_forwarded_for(&balancebeam, "203.0.113.7").await, 429);
+
+    log::info!("Making sure a different client isn't limited");
+    assert_eq!(get_forwarded_for(&balancebeam, "203.0.113.8").await, 200);
+    assert!(balancebeam
+        .output()
+        .iter()
+        .any(|line| line.contains("203.0.113.7 [")));
+    assert!(!balancebeam
+        .output()
+        .iter()
+        .any(|line| line.contains("198.51.100.1 [")));
+    assert_eq!(Box::new(upstream).stop().await, rate_limit_threshold + 1);
+
+    log::info!("All done :)");
+}
+
 /// Enable rate limiting and ensure that requests fail after sending more than the threshold
 #[tokio::test]
 async fn test_simple_rate_limiting() {
@@ -353,3 +1031,1501 @@ async fn test_advance_rate_limiting() {
 
     log::info!("All done :)");
 }
+
+/// A client that has used up its burst should get requests through again at the sustained rate,
+/// rather than having to wait for a whole minute to pass
+#[tokio::test]
+async fn test_rate_limit_burst() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        Some(60),
+        &["--rate-limit-burst", "2"],
+    )
+    .await;
+    let get = || async {
+        reqwest::Client::new()
+            .get(&format!("http://{}/", balancebeam.address))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .status()
+            .as_u16()
+    };
+
+    log::info!("Using up the burst");
+    assert_eq!(get().await, 200);
+    assert_eq!(get().await, 200);
+    assert_eq!(get().await, 429);
+
+    log::info!("Waiting for a token to come back (60 a minute is one a second)");
+    delay_for(Duration::from_millis(1100)).await;
+    assert_eq!(get().await, 200);
+    assert_eq!(get().await, 429);
+    assert_eq!(Box::new(upstream).stop().await, 3);
+
+    log::info!("All done :)");
+}
+
+/// With the sliding-window algorithm, a client that has used up its limit shouldn't get any more
+/// requests through until its earlier ones start to age out of the window
+#[tokio::test]
+async fn test_sliding_window_rate_limiting() {
+    init_logging();
+    let rate_limit_threshold = 60;
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        Some(rate_limit_threshold),
+        &["--rate-limit-algorithm", "sliding-window"],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let get = || async {
+        client
+            .get(&format!("http://{}/", balancebeam.address))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .status()
+            .as_u16()
+    };
+
+    log::info!("Using up the limit");
+    for _ in 0..rate_limit_threshold {
+        assert_eq!(get().await, 200);
+    }
+    assert_eq!(get().await, 429);
+
+    log::info!("Making sure the limit holds a second later (a token bucket would have refilled)");
+    delay_for(Duration::from_millis(1100)).await;
+    assert_eq!(get().await, 429);
+    assert_eq!(Box::new(upstream).stop().await, rate_limit_threshold);
+
+    log::info!("All done :)");
+}
+
+/// Once the rate limit table is full, the client whose last request was longest ago should be
+/// forgotten to make room for a new one
+#[tokio::test]
+async fn test_rate_limit_table_eviction() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        Some(1),
+        &[
+            "--trusted-proxies",
+            "127.0.0.1",
+            "--rate-limit-max-clients",
+            "2",
+        ],
+    )
+    .await;
+
+    log::info!("Using up the first client's allowance");
+    assert_eq!(get_forwarded_for(&balancebeam, "203.0.113.1").await, 200);
+    assert_eq!(get_forwarded_for(&balancebeam, "203.0.113.1").await, 429);
+
+    log::info!("Filling the table with other clients");
+    assert_eq!(get_forwarded_for(&balancebeam, "203.0.113.2").await, 200);
+    assert_eq!(get_forwarded_for(&balancebeam, "203.0.113.2").await, 429);
+    assert_eq!(get_forwarded_for(&balancebeam, "203.0.113.3").await, 200);
+
+    log::info!("The first client should have been forgotten, and the third remembered");
+    assert_eq!(get_forwarded_for(&balancebeam, "203.0.113.1").await, 200);
+    assert_eq!(get_forwarded_for(&balancebeam, "203.0.113.3").await, 429);
+    assert_eq!(Box::new(upstream).stop().await, 4);
+
+    log::info!("All done :)");
+}
+
+/// Route rate limits should hold matching requests to their own limit, counted separately from
+/// (and on top of) the limit on all of a client's requests
+#[tokio::test]
+async fn test_route_rate_limits() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        Some(5),
+        &["--route-rate-limit", "POST /login=2"],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let send = |method: reqwest::Method, path: &str| {
+        client
+            .request(method, &format!("http://{}{}", balancebeam.address, path))
+            .header("x-sent-by", "balancebeam-tests")
+            .send()
+    };
+    let status = |response: Result<reqwest::Response, reqwest::Error>| {
+        response
+            .expect("Error sending request to balancebeam")
+            .status()
+            .as_u16()
+    };
+
+    log::info!("Using up the /login allowance");
+    for _ in 0..2 {
+        assert_eq!(status(send(reqwest::Method::POST, "/login").await), 200);
+    }
+    assert_eq!(status(send(reqwest::Method::POST, "/login").await), 429);
+
+    log::info!("Other methods and paths should only be held to the overall limit");
+    assert_eq!(status(send(reqwest::Method::GET, "/login").await), 200);
+    assert_eq!(status(send(reqwest::Method::POST, "/other").await), 200);
+    // Every request so far, including the refused one, counted against the overall limit
+    assert_eq!(status(send(reqwest::Method::GET, "/other").await), 429);
+    assert_eq!(Box::new(upstream).stop().await, 4);
+
+    log::info!("All done :)");
+}
+
+/// Route rate limits should match whole path segments, and count requests for paths an upstream
+/// would clean up into a matching one
+#[tokio::test]
+async fn test_route_rate_limit_paths() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--route-rate-limit", "POST /login=3"],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let post = |path: &str| {
+        client
+            .post(&format!("http://{}{}", balancebeam.address, path))
+            .send()
+    };
+    let status = |response: Result<reqwest::Response, reqwest::Error>| {
+        response
+            .expect("Error sending request to balancebeam")
+            .status()
+            .as_u16()
+    };
+
+    log::info!("Using up the /login allowance with disguised paths");
+    for path in &["//login", "/%6cogin", "/login/./"] {
+        assert_eq!(status(post(path).await), 200);
+    }
+    assert_eq!(status(post("/login").await), 429);
+
+    log::info!("Paths that only start with the same characters shouldn't count");
+    assert_eq!(status(post("/loginfoo").await), 200);
+    assert_eq!(Box::new(upstream).stop().await, 4);
+
+    log::info!("All done :)");
+}
+
+/// With --rate-limit-header, clients sharing an address should be limited separately by that
+/// header's value, and requests without it by their address
+#[tokio::test]
+async fn test_rate_limit_header() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        Some(1),
+        &["--rate-limit-header", "X-Api-Key"],
+    )
+    .await;
+    let get = |api_key: Option<&str>| {
+        let mut request = reqwest::Client::new()
+            .get(&format!("http://{}/", balancebeam.address))
+            .header("x-sent-by", "balancebeam-tests");
+        if let Some(api_key) = api_key {
+            request = request.header("x-api-key", api_key);
+        }
+        async move {
+            request
+                .send()
+                .await
+                .expect("Error sending request to balancebeam")
+                .status()
+                .as_u16()
+        }
+    };
+
+    log::info!("Each key should get its own limit");
+    assert_eq!(get(Some("first")).await, 200);
+    assert_eq!(get(Some("first")).await, 429);
+    assert_eq!(get(Some("second")).await, 200);
+    assert_eq!(get(Some("second")).await, 429);
+
+    log::info!("Requests without a key should be limited by address");
+    assert_eq!(get(None).await, 200);
+    assert_eq!(get(None).await, 429);
+    assert_eq!(Box::new(upstream).stop().await, 3);
+
+    log::info!("All done :)");
+}
+
+/// Reads one command (an array of bulk strings) sent to a fake Redis server
+async fn read_redis_command(conn: &mut tokio::io::BufReader<TcpStream>) -> Option<Vec<String>> {
+    use tokio::io::AsyncBufReadExt;
+    let mut line = String::new();
+    conn.read_line(&mut line).await.ok()?;
+    let count: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+    let mut args = Vec::new();
+    for _ in 0..count {
+        line.clear();
+        conn.read_line(&mut line).await.ok()?;
+        let length: usize = line.trim_end().strip_prefix('$')?.parse().ok()?;
+        let mut arg = vec![0; length + 2];
+        conn.read_exact(&mut arg).await.ok()?;
+        arg.truncate(length);
+        args.push(String::from_utf8(arg).ok()?);
+    }
+    Some(args)
+}
+
+/// With --rate-limit-redis, balancebeams should share their counters. This runs two of them
+/// against a fake Redis server that allows each key per_minute EVALs (standing in for the real
+/// scripts), and checks that a client's requests through both count against one limit.
+#[tokio::test]
+async fn test_redis_rate_limiting() {
+    init_logging();
+    let redis_address = random_local_address();
+    let mut listener = tokio::net::TcpListener::bind(&redis_address).await.unwrap();
+    let counts: Arc<Mutex<std::collections::HashMap<String, usize>>> = Arc::default();
+    let server_counts = Arc::clone(&counts);
+    tokio::spawn(async move {
+        loop {
+            let (conn, _) = listener.accept().await.unwrap();
+            let counts = Arc::clone(&server_counts);
+            tokio::spawn(async move {
+                let mut conn = tokio::io::BufReader::new(conn);
+                while let Some(args) = read_redis_command(&mut conn).await {
+                    let reply = if args[0] == "EVAL" {
+                        let (key, per_minute) = (&args[3], args[4].parse::<usize>().unwrap());
+                        let mut counts = counts.lock().unwrap();
+                        let count = counts.entry(key.clone()).or_insert(0);
+                        *count += 1;
+                        // 0 lets the request through; otherwise, it's the milliseconds to wait
+                        format!(":{}\r\n", if *count <= per_minute { 0 } else { 30000 })
+                    } else {
+                        "+OK\r\n".to_string()
+                    };
+                    conn.get_mut().write_all(reply.as_bytes()).await.unwrap();
+                }
+            });
+        }
+    });
+    let upstream = EchoServer::new().await;
+    let redis_url = format!("redis://{}", redis_address);
+    let args = ["--rate-limit-redis", redis_url.as_str()];
+    let first = BalanceBeam::new_with_args(&[&upstream.address], None, Some(2), &args).await;
+    let second = BalanceBeam::new_with_args(&[&upstream.address], None, Some(2), &args).await;
+    let status = |balancebeam: &BalanceBeam| {
+        let request = reqwest::Client::new()
+            .get(&format!("http://{}/", balancebeam.address))
+            .header("x-sent-by", "balancebeam-tests");
+        async move {
+            request
+                .send()
+                .await
+                .expect("Error sending request to balancebeam")
+                .status()
+                .as_u16()
+        }
+    };
+
+    assert_eq!(status(&first).await, 200);
+    assert_eq!(status(&second).await, 200);
+    assert_eq!(status(&first).await, 429);
+    assert_eq!(status(&second).await, 429);
+    assert_eq!(
+        counts
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<String>>(),
+        vec!["balancebeam:rate-limit:127.0.0.1".to_string()]
+    );
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
+
+/// Reads a response with a Content-Length body from a raw connection, returning its head (status
+/// line and headers) and body
+async fn read_raw_response(conn: &mut tokio::io::BufReader<TcpStream>) -> (String, String) {
+    use tokio::io::AsyncBufReadExt;
+    let mut head = String::new();
+    loop {
+        let length = head.len();
+        conn.read_line(&mut head).await.unwrap();
+        if head.len() == length {
+            panic!("Connection closed before the end of the response");
+        }
+        if head.ends_with("\r\n\r\n") {
+            break;
+        }
+    }
+    let content_length: usize = head
+        .lines()
+        .find_map(|line| {
+            line.to_lowercase()
+                .strip_prefix("content-length: ")
+                .map(str::to_string)
+        })
+        .expect("Response has no Content-Length")
+        .parse()
+        .unwrap();
+    let mut body = vec![0; content_length];
+    conn.read_exact(&mut body).await.unwrap();
+    (head, String::from_utf8(body).unwrap())
+}
+
+/// A rate limited request should get a 429 with a Retry-After header (and, with
+/// --rate-limit-json-errors, a JSON body), and the client's connection should stay open for its
+/// next request, with the refused request's body skipped
+#[tokio::test]
+async fn test_rate_limit_response() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        Some(1),
+        &["--rate-limit-json-errors"],
+    )
+    .await;
+    let mut conn =
+        tokio::io::BufReader::new(TcpStream::connect(&balancebeam.address).await.unwrap());
+    let requests = format!(
+        "GET /first HTTP/1.1\r\nHost: {0}\r\n\r\n\
+         POST /refused HTTP/1.1\r\nHost: {0}\r\nContent-Length: 11\r\n\r\nhello world\
+         GET /next HTTP/1.1\r\nHost: {0}\r\n\r\n",
+        balancebeam.address
+    );
+    // The requests are sent all at once, so that balancebeam has to skip the refused request's
+    // body to find the one after it
+    conn.get_mut().write_all(requests.as_bytes()).await.unwrap();
+    let (head, _) = read_raw_response(&mut conn).await;
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+
+    let (head, body) = read_raw_response(&mut conn).await;
+    assert!(head.starts_with("HTTP/1.1 429"), "{}", head);
+    let retry_after: u64 = head
+        .lines()
+        .find_map(|line| {
+            line.to_lowercase()
+                .strip_prefix("retry-after: ")
+                .map(str::to_string)
+        })
+        .expect("429 response has no Retry-After header")
+        .parse()
+        .unwrap();
+    // Refilling the single token takes a minute
+    assert!((59..=60).contains(&retry_after), "{}", retry_after);
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["retry_after"], serde_json::json!(retry_after));
+
+    log::info!("The connection should still be usable");
+    let (head, _) = read_raw_response(&mut conn).await;
+    assert!(head.starts_with("HTTP/1.1 429"), "{}", head);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// Make sure that when many upstreams recover at the same time, the active health checks bring
+/// them back in batches of --max-revivals-per-health-check rather than all at once:
+///
+/// * Point balancebeam at upstreams that aren't running yet, so they all get marked dead
+/// * Start all of the upstreams
+/// * After one round of health checks, only the first batch should be receiving traffic
+/// * After another round, everything should be receiving traffic
+#[tokio::test]
+async fn test_staggered_revivals() {
+    init_logging();
+    let n_upstreams = 4;
+    let upstream_addresses: Vec<String> =
+        (0..n_upstreams).map(|_| random_local_address()).collect();
+    let upstream_addresses: Vec<&str> = upstream_addresses
+        .iter()
+        .map(|addr| addr.as_str())
+        .collect();
+    let balancebeam = BalanceBeam::new_with_args(
+        &upstream_addresses,
+        Some(3),
+        None,
+        &["--max-revivals-per-health-check", "2"],
+    )
+    .await;
+
+    log::info!("Sending a request while no upstreams are up, so that they all get marked dead");
+    // balancebeam picks an upstream per connection, so don't let the client reuse connections
+    let client = reqwest::Client::builder()
+        .pool_max_idle_per_host(0)
+        .build()
+        .unwrap();
+    let response = client
+        .get(&format!("http://{}/before-start", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+
+    log::info!("Starting all of the upstreams at once");
+    let mut upstreams: Vec<Box<dyn Server>> = Vec::new();
+    for address in &upstream_addresses {
+        upstreams.push(Box::new(
+            EchoServer::new_at_address(address.to_string()).await,
+        ));
+    }
+
+    log::info!("Waiting for one round of health checks...");
+    delay_for(Duration::from_secs(3)).await;
+    // (Health checks hit every upstream too, so only look at requests sent during this batch)
+    let counts_before: Vec<usize> = upstreams
+        .iter()
+        .map(|upstream| upstream.requests_received())
+        .collect();
+    for i in 0..20 {
+        let path = format!("/first-batch-{}", i);
+        let response_text = client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .expect("Balancebeam replied with a malformed response");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    let revived_count = upstreams
+        .iter()
+        .zip(counts_before.iter())
+        .filter(|(upstream, before)| upstream.requests_received() > **before)
+        .count();
+    assert_eq!(
+        revived_count, 2,
+        "Expected exactly one batch of upstreams to be revived after one round of health checks"
+    );
+
+    log::info!("Waiting for another round of health checks...");
+    delay_for(Duration::from_secs(3)).await;
+    // (Health checks hit every upstream too, so only look at requests sent during this batch)
+    let counts_before: Vec<usize> = upstreams
+        .iter()
+        .map(|upstream| upstream.requests_received())
+        .collect();
+    for i in 0..60 {
+        let path = format!("/second-batch-{}", i);
+        let response_text = client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .expect("Balancebeam replied with a malformed response");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    let revived_count = upstreams
+        .iter()
+        .zip(counts_before.iter())
+        .filter(|(upstream, before)| upstream.requests_received() > **before)
+        .count();
+    assert_eq!(
+        revived_count, n_upstreams,
+        "Expected all upstreams to be revived after two rounds of health checks"
+    );
+
+    while let Some(upstream) = upstreams.pop() {
+        upstream.stop().await;
+    }
+
+    log::info!("All done :)");
+}
+
+/// Make sure balancebeam logs why it picked each upstream: the algorithm, the candidates it chose
+/// between, and any upstreams it had to give up on along the way.
+#[tokio::test]
+async fn test_upstream_selection_logging() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    // Nothing is listening here, so balancebeam will fail over the first time it picks it
+    let dead_address = random_local_address();
+    let balancebeam = BalanceBeam::new(&[&upstream.address, &dead_address], None, None).await;
+
+    for i in 0..20 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let selections: Vec<String> = balancebeam
+        .output()
+        .into_iter()
+        .filter(|line| line.contains("Selected upstream"))
+        .collect();
+    assert_eq!(
+        selections.len(),
+        20,
+        "Expected one selection log per connection"
+    );
+    for selection in &selections {
+        assert!(selection.contains(&format!("Selected upstream {} ", upstream.address)));
+        assert!(selection.contains("strategy=random"));
+    }
+    assert!(
+        selections
+            .iter()
+            .any(|selection| selection.contains(&format!(
+                "candidates=[{}(active=0 weight=1), {}(active=0 weight=1)] failed=[{}]",
+                upstream.address, dead_address, dead_address
+            ))),
+        "Expected a selection that failed over from the dead upstream"
+    );
+    assert!(
+        selections.last().unwrap().contains(&format!(
+            "candidates=[{}(active=0 weight=1)] failed=[]",
+            upstream.address
+        )),
+        "The dead upstream should no longer be a candidate"
+    );
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// With --strategy round-robin, connections should be spread across upstreams exactly evenly.
+#[tokio::test]
+async fn test_round_robin_distribution() {
+    let n_upstreams = 3;
+    let n_requests = 30;
+    // Use a long health check interval so that health check requests don't skew the counts
+    let (balancebeam, mut upstreams) = setup_with_args(
+        n_upstreams,
+        Some(3600),
+        None,
+        &["--strategy", "round-robin"],
+    )
+    .await;
+
+    // balancebeam.get uses a new connection for every request
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.insert(0, upstream.stop().await);
+    }
+    log::info!(
+        "Number of requests received by each upstream: {:?}",
+        request_counters
+    );
+    assert_eq!(
+        request_counters,
+        vec![n_requests / n_upstreams; n_upstreams]
+    );
+    assert!(balancebeam
+        .output()
+        .iter()
+        .any(|line| line.contains("strategy=round-robin")));
+
+    log::info!("All done :)");
+}
+
+/// Requests sent over a single keep-alive connection should each pick their own upstream, rather
+/// than all going wherever the first one did.
+#[tokio::test]
+async fn test_per_request_selection() {
+    let n_upstreams = 3;
+    let n_requests = 30;
+    let (balancebeam, mut upstreams) = setup_with_args(
+        n_upstreams,
+        Some(3600),
+        None,
+        &["--strategy", "round-robin"],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .unwrap();
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.insert(0, upstream.stop().await);
+    }
+    log::info!(
+        "Number of requests received by each upstream: {:?}",
+        request_counters
+    );
+    assert_eq!(
+        request_counters,
+        vec![n_requests / n_upstreams; n_upstreams]
+    );
+
+    log::info!("All done :)");
+}
+
+/// Sends a request whose body never arrives, so that it keeps an upstream busy until the returned
+/// connection is dropped
+async fn start_unfinished_request(balancebeam: &BalanceBeam) -> TcpStream {
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(b"POST /busy HTTP/1.1\r\nHost: balancebeam\r\nContent-Length: 1000\r\n\r\n")
+        .await
+        .unwrap();
+    // Give balancebeam a moment to pick an upstream and pass the request on
+    delay_for(Duration::from_millis(100)).await;
+    stream
+}
+
+/// With --strategy least-connections, an upstream that is busy with a long-running request
+/// shouldn't be given any more requests while another upstream sits idle, and the selection log
+/// should show the in-flight counts that decided it.
+#[tokio::test]
+async fn test_least_connections() {
+    let n_requests = 10;
+    let (balancebeam, mut upstreams) =
+        setup_with_args(2, Some(3600), None, &["--strategy", "least-connections"]).await;
+
+    let busy_client = start_unfinished_request(&balancebeam).await;
+
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        // Give balancebeam a moment to notice that the connection was closed
+        delay_for(Duration::from_millis(50)).await;
+    }
+    drop(busy_client);
+
+    let selections: Vec<String> = balancebeam
+        .output()
+        .into_iter()
+        .filter(|line| line.contains("Selected upstream"))
+        .collect();
+    assert_eq!(selections.len(), n_requests + 1);
+    let picked_first = format!("Selected upstream {} ", upstreams[0].address());
+    let (busy, idle) = match selections[0].contains(&picked_first) {
+        true => (upstreams[0].address(), upstreams[1].address()),
+        false => (upstreams[1].address(), upstreams[0].address()),
+    };
+    for selection in &selections[1..] {
+        assert!(selection.contains(&format!("Selected upstream {} ", idle)));
+        assert!(selection.contains(&format!("{}(active=1 weight=1)", busy)));
+        assert!(selection.contains(&format!("{}(active=0 weight=1)", idle)));
+        assert!(selection.contains("strategy=least-connections"));
+    }
+
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.push(upstream.stop().await);
+    }
+    request_counters.sort_unstable();
+    log::info!(
+        "Number of requests received by each upstream: {:?}",
+        request_counters
+    );
+    assert_eq!(request_counters, vec![1, n_requests]);
+
+    log::info!("All done :)");
+}
+
+/// Upstreams given a weight should receive a proportional share of randomly-selected connections.
+#[tokio::test]
+async fn test_weighted_selection() {
+    init_logging();
+    let n_requests = 100;
+    let heavy = EchoServer::new().await;
+    let light = EchoServer::new().await;
+    let heavy_arg = format!("{},weight=3", heavy.address);
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&heavy_arg, &light.address], Some(3600), None, &[]).await;
+
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let heavy_count = Box::new(heavy).stop().await;
+    let light_count = Box::new(light).stop().await;
+    log::info!(
+        "Weight 3 upstream got {} requests, weight 1 upstream got {}",
+        heavy_count,
+        light_count
+    );
+    assert_eq!(heavy_count + light_count, n_requests);
+    // Expect 75, but leave plenty of room for randomness
+    assert!(
+        (55..=92).contains(&heavy_count),
+        "Weighted upstream got {} of {} requests",
+        heavy_count,
+        n_requests
+    );
+
+    log::info!("All done :)");
+}
+
+/// With --strategy p2c and only two upstreams, both are always sampled, so the busy upstream
+/// should never be chosen over the idle one.
+#[tokio::test]
+async fn test_power_of_two_choices() {
+    let n_requests = 10;
+    let (balancebeam, mut upstreams) =
+        setup_with_args(2, Some(3600), None, &["--strategy", "p2c"]).await;
+
+    let busy_client = start_unfinished_request(&balancebeam).await;
+
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        // Give balancebeam a moment to notice that the connection was closed
+        delay_for(Duration::from_millis(50)).await;
+    }
+    drop(busy_client);
+
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.push(upstream.stop().await);
+    }
+    request_counters.sort_unstable();
+    assert_eq!(request_counters, vec![1, n_requests]);
+    assert!(balancebeam
+        .output()
+        .iter()
+        .any(|line| line.contains("strategy=p2c")));
+
+    log::info!("All done :)");
+}
+
+/// With --strategy least-latency, a slow upstream should get far fewer connections than a fast one.
+#[tokio::test]
+async fn test_least_latency() {
+    init_logging();
+    let n_requests = 40;
+    let fast = EchoServer::new().await;
+    let slow = EchoServer::new_with_delay(Duration::from_millis(100)).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&fast.address, &slow.address],
+        Some(3600),
+        None,
+        &["--strategy", "least-latency"],
+    )
+    .await;
+
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let fast_count = Box::new(fast).stop().await;
+    let slow_count = Box::new(slow).stop().await;
+    log::info!(
+        "Fast upstream got {} requests, slow upstream got {}",
+        fast_count,
+        slow_count
+    );
+    assert_eq!(fast_count + slow_count, n_requests);
+    assert!(
+        fast_count > 3 * slow_count,
+        "Slow upstream got {} of {} requests",
+        slow_count,
+        n_requests
+    );
+
+    log::info!("All done :)");
+}
+
+/// With --strategy ip-hash, every connection from the same client should go to the same upstream,
+/// and once that upstream dies, to the same replacement.
+#[tokio::test]
+async fn test_ip_hash_affinity() {
+    let n_upstreams = 3;
+    let n_requests = 10;
+    let (balancebeam, mut upstreams) =
+        setup_with_args(n_upstreams, Some(3600), None, &["--strategy", "ip-hash"]).await;
+
+    async fn send_requests(balancebeam: &BalanceBeam, n_requests: usize) {
+        for i in 0..n_requests {
+            let path = format!("/request-{}", i);
+            let response_text = balancebeam
+                .get(&path)
+                .await
+                .expect("Error sending request to balancebeam");
+            assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        }
+    }
+
+    send_requests(&balancebeam, n_requests).await;
+    let counts: Vec<usize> = upstreams
+        .iter()
+        .map(|upstream| upstream.requests_received())
+        .collect();
+    log::info!("Number of requests received by each upstream: {:?}", counts);
+    assert_eq!(
+        counts.iter().filter(|&&count| count == n_requests).count(),
+        1
+    );
+    assert_eq!(counts.iter().sum::<usize>(), n_requests);
+
+    log::info!("Killing the pinned upstream");
+    let pinned = counts
+        .iter()
+        .position(|&count| count == n_requests)
+        .unwrap();
+    upstreams.remove(pinned).stop().await;
+
+    send_requests(&balancebeam, n_requests).await;
+    let counts: Vec<usize> = upstreams
+        .iter()
+        .map(|upstream| upstream.requests_received())
+        .collect();
+    log::info!("Number of requests received by the survivors: {:?}", counts);
+    assert_eq!(
+        counts.iter().filter(|&&count| count == n_requests).count(),
+        1
+    );
+    assert_eq!(counts.iter().sum::<usize>(), n_requests);
+
+    log::info!("All done :)");
+}
+
+/// With --sticky-sessions, a client that sends back its bb-upstream cookie should keep going to the
+/// same upstream (even over new connections) until that upstream dies, and then get a new cookie.
+#[tokio::test]
+async fn test_sticky_sessions() {
+    let n_upstreams = 3;
+    let n_requests = 10;
+    let (balancebeam, mut upstreams) =
+        setup_with_args(n_upstreams, Some(3600), None, &["--sticky-sessions"]).await;
+
+    /// Sends a request on a new connection, returning the cookie balancebeam set, if any
+    async fn get_with_cookie(
+        balancebeam: &BalanceBeam,
+        path: &str,
+        cookie: Option<&str>,
+    ) -> Option<String> {
+        let mut request = reqwest::Client::new()
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .header("x-sent-by", "balancebeam-tests");
+        if let Some(cookie) = cookie {
+            request = request.header("cookie", format!("session=abc; {}", cookie));
+        }
+        let response = request
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let set_cookie = response.headers().get("set-cookie").map(|value| {
+            value
+                .to_str()
+                .unwrap()
+                .split(';')
+                .next()
+                .unwrap()
+                .to_string()
+        });
+        assert!(response
+            .text()
+            .await
+            .unwrap()
+            .contains(&format!("GET {} HTTP/1.1", path)));
+        set_cookie
+    }
+
+    async fn send_requests(balancebeam: &BalanceBeam, n_requests: usize, cookie: &str) {
+        for i in 0..n_requests {
+            let path = format!("/request-{}", i);
+            assert_eq!(
+                get_with_cookie(balancebeam, &path, Some(cookie)).await,
+                None
+            );
+        }
+    }
+
+    let cookie = get_with_cookie(&balancebeam, "/first", None)
+        .await
+        .expect("balancebeam didn't set a sticky-session cookie");
+    assert!(cookie.starts_with("bb-upstream="));
+    send_requests(&balancebeam, n_requests, &cookie).await;
+    let counts: Vec<usize> = upstreams
+        .iter()
+        .map(|upstream| upstream.requests_received())
+        .collect();
+    log::info!("Number of requests received by each upstream: {:?}", counts);
+    assert_eq!(
+        counts
+            .iter()
+            .filter(|&&count| count == n_requests + 1)
+            .count(),
+        1
+    );
+    assert_eq!(counts.iter().sum::<usize>(), n_requests + 1);
+
+    log::info!("Killing the pinned upstream");
+    let pinned = counts
+        .iter()
+        .position(|&count| count == n_requests + 1)
+        .unwrap();
+    upstreams.remove(pinned).stop().await;
+
+    let new_cookie = get_with_cookie(&balancebeam, "/failover", Some(&cookie))
+        .await
+        .expect("balancebeam didn't re-pin after the pinned upstream died");
+    assert_ne!(new_cookie, cookie);
+    send_requests(&balancebeam, n_requests, &new_cookie).await;
+    let counts: Vec<usize> = upstreams
+        .iter()
+        .map(|upstream| upstream.requests_received())
+        .collect();
+    log::info!("Number of requests received by the survivors: {:?}", counts);
+    assert_eq!(
+        counts
+            .iter()
+            .filter(|&&count| count == n_requests + 1)
+            .count(),
+        1
+    );
+    assert_eq!(counts.iter().sum::<usize>(), n_requests + 1);
+
+    log::info!("All done :)");
+}
+
+/// Starts a UDP upstream that answers each datagram with its name followed by the datagram.
+/// Returns its address and the set of source addresses it has heard from.
+async fn start_udp_upstream(name: &'static str) -> (String, Arc<Mutex<HashSet<SocketAddr>>>) {
+    let address = random_local_address();
+    let mut socket = UdpSocket::bind(&address).await.unwrap();
+    let peers = Arc::new(Mutex::new(HashSet::new()));
+    let seen = Arc::clone(&peers);
+    tokio::spawn(async move {
+        let mut buffer = [0_u8; 512];
+        loop {
+            let (len, peer) = socket.recv_from(&mut buffer).await.unwrap();
+            seen.lock().unwrap().insert(peer);
+            let reply = [name.as_bytes(), b":", &buffer[..len]].concat();
+            let _ = socket.send_to(&reply, &peer).await;
+        }
+    });
+    (address, peers)
+}
+
+/// Sends message to balancebeam over client, and returns the name of the upstream that echoed it
+async fn exchange(client: &mut UdpSocket, message: String) -> String {
+    client.send(message.as_bytes()).await.unwrap();
+    let mut buffer = [0_u8; 512];
+    let len = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buffer))
+        .await
+        .expect("No reply from balancebeam")
+        .unwrap();
+    let reply = String::from_utf8_lossy(&buffer[..len]).into_owned();
+    let (upstream, echoed) = reply.split_once(':').unwrap();
+    assert_eq!(echoed, message);
+    upstream.to_string()
+}
+
+/// With --mode udp, each client should keep talking to the same upstream for the length of its
+/// session, and should get a new session once the old one has been idle too long
+#[tokio::test]
+async fn test_udp_mode() {
+    init_logging();
+    let (first_address, first_peers) = start_udp_upstream("first").await;
+    let (second_address, second_peers) = start_udp_upstream("second").await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&first_address, &second_address],
+        None,
+        None,
+        &[
+            "--mode",
+            "udp",
+            "--strategy",
+            "round-robin",
+            "--udp-session-timeout",
+            "1",
+        ],
+    )
+    .await;
+
+    let mut clients = Vec::new();
+    for _ in 0..4 {
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(&balancebeam.address).await.unwrap();
+        clients.push(client);
+    }
+    let mut upstreams_used = Vec::new();
+    for (i, client) in clients.iter_mut().enumerate() {
+        let upstream = exchange(client, format!("client {} message 0", i)).await;
+        for j in 1..3 {
+            let message = format!("client {} message {}", i, j);
+            assert_eq!(exchange(client, message).await, upstream);
+        }
+        upstreams_used.push(upstream);
+    }
+    log::info!("Upstream used by each client: {:?}", upstreams_used);
+    assert!(upstreams_used.iter().any(|upstream| upstream == "first"));
+    assert!(upstreams_used.iter().any(|upstream| upstream == "second"));
+    let sessions = |peers: &Arc<Mutex<HashSet<SocketAddr>>>| peers.lock().unwrap().len();
+    assert_eq!(sessions(&first_peers) + sessions(&second_peers), 4);
+
+    log::info!("Letting the sessions expire");
+    delay_for(Duration::from_secs(2)).await;
+    exchange(&mut clients[0], "after expiry".to_string()).await;
+    assert_eq!(sessions(&first_peers) + sessions(&second_peers), 5);
+
+    log::info!("All done :)");
+}
+
+/// With --route, requests under a path prefix should only go to the upstreams in that route's pool
+/// (balanced with the pool's own strategy), and everything else to the upstreams not in a pool
+#[tokio::test]
+async fn test_path_routing() {
+    init_logging();
+    let web = EchoServer::new().await;
+    let api = vec![EchoServer::new().await, EchoServer::new().await];
+    let api_args: Vec<String> = api
+        .iter()
+        .map(|upstream| format!("{},pool=api", upstream.address))
+        .collect();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&web.address, &api_args[0], &api_args[1]],
+        None,
+        None,
+        &[
+            "--pool",
+            "api,strategy=round-robin",
+            "--route",
+            "/api/*=api",
+        ],
+    )
+    .await;
+
+    for path in &["/api", "/api/users", "/api/users/1", "/api/orders"] {
+        let response_text = balancebeam
+            .get(path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    // A prefix only matches whole path segments
+    for path in &["/", "/apiary", "/static/api"] {
+        balancebeam
+            .get(path)
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    assert_eq!(Box::new(web).stop().await, 3);
+    for upstream in api {
+        assert_eq!(
+            Box::new(upstream).stop().await,
+            2,
+            "The api pool's requests should be shared round-robin"
+        );
+    }
+
+    log::info!("All done :)");
+}
+
+/// Routes for a host should send its requests to their pool, with exact hosts winning over
+/// wildcards, and wildcards over routes for any host
+#[tokio::test]
+async fn test_host_routing() {
+    init_logging();
+    let default = EchoServer::new().await;
+    let shop = EchoServer::new().await;
+    let tenants = EchoServer::new().await;
+    let static_files = EchoServer::new().await;
+    let shop_arg = format!("{},pool=shop", shop.address);
+    let tenants_arg = format!("{},pool=tenants", tenants.address);
+    let static_arg = format!("{},pool=static", static_files.address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&default.address, &shop_arg, &tenants_arg, &static_arg],
+        None,
+        None,
+        &[
+            "--route",
+            "shop.example.com=shop",
+            "--route",
+            "*.example.com=tenants",
+            "--route",
+            "/static=static",
+        ],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    for (host, path) in &[
+        ("shop.example.com", "/"),
+        ("SHOP.example.com:8080", "/static/logo.png"),
+        ("a.example.com", "/"),
+        ("b.c.example.com", "/static/logo.png"),
+        ("example.com", "/"),
+        ("other.org", "/"),
+        ("other.org", "/static/logo.png"),
+    ] {
+        let response_text = client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .header("Host", *host)
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .unwrap();
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    assert_eq!(Box::new(shop).stop().await, 2);
+    assert_eq!(Box::new(tenants).stop().await, 2);
+    assert_eq!(Box::new(default).stop().await, 2);
+    assert_eq!(Box::new(static_files).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// --rewrite-path should change the path sent upstream (keeping the query string), while routes
+/// still match the path the client asked for
+#[tokio::test]
+async fn test_path_rewriting() {
+    init_logging();
+    let web = EchoServer::new().await;
+    let api = EchoServer::new().await;
+    let api_arg = format!("{},pool=api", api.address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&web.address, &api_arg],
+        None,
+        None,
+        &[
+            "--route",
+            "/api=api",
+            "--rewrite-path",
+            "strip /api",
+            "--rewrite-path",
+            "replace ^/v1/(.*)$ /v2/$1",
+        ],
+    )
+    .await;
+
+    for (path, upstream_path) in &[
+        ("/api/users?page=2", "/users?page=2"),
+        ("/api", "/"),
+        ("/api/v1/orders", "/v2/orders"),
+        ("/v1/about", "/v2/about"),
+        ("/apiary", "/apiary"),
+    ] {
+        let response_text = balancebeam
+            .get(path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(
+            response_text.starts_with(&format!("GET {} HTTP/1.1", upstream_path)),
+            "{} should reach the upstream as {}, got {}",
+            path,
+            upstream_path,
+            response_text
+        );
+    }
+
+    assert_eq!(Box::new(api).stop().await, 3);
+    assert_eq!(Box::new(web).stop().await, 2);
+
+    log::info!("All done :)");
+}
+
+/// --canary-percent of requests should go to the canary pool, every request from a client should
+/// go the same way with --canary-sticky, and requests should fall back to the other upstreams if
+/// the canary is down
+#[tokio::test]
+async fn test_canary_split() {
+    init_logging();
+    let stable = EchoServer::new().await;
+    let canary = EchoServer::new().await;
+    let canary_arg = format!("{},pool=canary", canary.address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&stable.address, &canary_arg],
+        Some(3600),
+        None,
+        &["--canary-pool", "canary", "--canary-percent", "25"],
+    )
+    .await;
+    for _ in 0..200 {
+        balancebeam
+            .get("/")
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    let to_canary = canary.requests_received();
+    assert!(
+        (20..=90).contains(&to_canary),
+        "About a quarter of 200 requests should go to the canary, not {}",
+        to_canary
+    );
+    assert_eq!(stable.requests_received(), 200 - to_canary);
+    drop(balancebeam);
+
+    // All of our requests come from the same IP, so they should all go the same way
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&stable.address, &canary_arg],
+        Some(3600),
+        None,
+        &[
+            "--canary-pool",
+            "canary",
+            "--canary-percent",
+            "50",
+            "--canary-sticky",
+        ],
+    )
+    .await;
+    for _ in 0..20 {
+        balancebeam
+            .get("/")
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    let to_canary = canary.requests_received() - to_canary;
+    assert!(
+        to_canary == 0 || to_canary == 20,
+        "A sticky canary split sent {} of a client's 20 requests to the canary",
+        to_canary
+    );
+    drop(balancebeam);
+
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&stable.address, &canary_arg],
+        Some(3600),
+        None,
+        &["--canary-pool", "canary", "--canary-percent", "100"],
+    )
+    .await;
+    let before = stable.requests_received();
+    Box::new(canary).stop().await;
+    balancebeam
+        .get("/")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(Box::new(stable).stop().await, before + 1);
+
+    log::info!("All done :)");
+}
+
+/// --mirror-pool should get a copy of each request without a body, while clients only hear from
+/// the other upstreams
+#[tokio::test]
+async fn test_request_mirroring() {
+    init_logging();
+    let primary = EchoServer::new().await;
+    let shadow = EchoServer::new().await;
+    let shadow_arg = format!("{},pool=shadow", shadow.address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&primary.address, &shadow_arg],
+        Some(3600),
+        None,
+        &["--mirror-pool", "shadow"],
+    )
+    .await;
+
+    for i in 0..10 {
+        let response_text = balancebeam
+            .get(&format!("/mirrored/{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.starts_with(&format!("GET /mirrored/{} HTTP/1.1", i)));
+    }
+    balancebeam
+        .post("/not-mirrored", "body")
+        .await
+        .expect("Error sending request to balancebeam");
+    // The copies are sent in the background
+    delay_for(Duration::from_millis(500)).await;
+
+    assert_eq!(Box::new(primary).stop().await, 11);
+    assert_eq!(Box::new(shadow).stop().await, 10);
+
+    log::info!("All done :)");
+}
+
+/// A response body of one chunk followed by trailers, the shape of a unary gRPC response
+struct BodyWithTrailers {
+    data: Option<hyper::body::Bytes>,
+    trailers: Option<hyper::HeaderMap>,
+}
+
+impl hyper::body::HttpBody for BodyWithTrailers {
+    type Data = hyper::body::Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        mut self: std::pin::Pin<&mut Self>,
+        _: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Result<Self::Data, Self::Error>>> {
+        std::task::Poll::Ready(self.data.take().map(Ok))
+    }
+
+    fn poll_trailers(
+        mut self: std::pin::Pin<&mut Self>,
+        _: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<Option<hyper::HeaderMap>, Self::Error>> {
+        std::task::Poll::Ready(Ok(self.trailers.take()))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.data.is_none() && self.trailers.is_none()
+    }
+}
+
+/// Starts an upstream that, like a gRPC server, only speaks cleartext HTTP/2 (with prior
+/// knowledge). It answers each request with its name, the request's path, version, and body, and
+/// a grpc-status trailer. Returns its address and a count of the requests it has served.
+async fn start_h2c_upstream(name: &'static str) -> (String, Arc<AtomicUsize>) {
+    let address = random_local_address();
+    let count = Arc::new(AtomicUsize::new(0));
+    let server_count = count.clone();
+    let make_service = hyper::service::make_service_fn(move |_| {
+        let count = server_count.clone();
+        async move {
+            Ok::<_, hyper::Error>(hyper::service::service_fn(
+                move |request: hyper::Request<hyper::Body>| {
+                    count.fetch_add(1, Ordering::SeqCst);
+                    async move {
+                        let path = request.uri().path().to_string();
+                        let version = request.version();
+                        let body = hyper::body::to_bytes(request.into_body()).await?;
+                        let text = format!(
+                            "{} {} {:?} {}",
+                            name,
+                            path,
+                            version,
+                            String::from_utf8_lossy(&body)
+                        );
+                        let mut trailers = hyper::HeaderMap::new();
+                        trailers.insert("grpc-status", "0".parse().unwrap());
+                        let response_body = BodyWithTrailers {
+                            data: Some(text.into()),
+                            trailers: Some(trailers),
+                        };
+                        Ok::<_, hyper::Error>(
+                            hyper::Response::builder()
+                                .header("content-type", "application/grpc")
+                                .body(response_body)
+                                .unwrap(),
+                        )
+                    }
+                },
+            ))
+        }
+    });
+    let server = hyper::Server::bind(&address.parse().unwrap())
+        .http2_only(true)
+        .serve(make_service);
+    tokio::spawn(server);
+    (address, count)
+}
+
+#[tokio::test]
+async fn test_h2c_upstreams() {
+    use hyper::body::HttpBody;
+
+    init_logging();
+    let (address_a, count_a) = start_h2c_upstream("a").await;
+    let (address_b, count_b) = start_h2c_upstream("b").await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&address_a, &address_b],
+        Some(3600),
+        None,
+        &[
+            "--http2",
+            "--upstream-protocol",
+            "h2c",
+            "--strategy",
+            "round-robin",
+        ],
+    )
+    .await;
+
+    // The client multiplexes all its calls over one connection, but each call should be balanced
+    // on its own
+    let client = hyper::Client::builder()
+        .http2_only(true)
+        .build_http::<hyper::Body>();
+    let mut served_by = HashSet::new();
+    for i in 0..6 {
+        let request = hyper::Request::post(format!(
+            "http://{}/helloworld.Greeter/SayHello",
+            balancebeam.address
+        ))
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .body(hyper::Body::from(format!("call {}", i)))
+        .unwrap();
+        let response = client.request(request).await.unwrap();
+        assert_eq!(response.status(), hyper::StatusCode::OK);
+        assert_eq!(response.version(), hyper::Version::HTTP_2);
+        assert_eq!(response.headers()["content-type"], "application/grpc");
+        let mut body = response.into_body();
+        let mut data = Vec::new();
+        while let Some(chunk) = body.data().await {
+            data.extend_from_slice(&chunk.unwrap());
+        }
+        let trailers = body.trailers().await.unwrap().expect("no trailers");
+        assert_eq!(trailers["grpc-status"], "0");
+        let text = String::from_utf8(data).unwrap();
+        let (name, rest) = text.split_once(' ').unwrap();
+        assert_eq!(
+            rest,
+            format!("/helloworld.Greeter/SayHello HTTP/2.0 call {}", i)
+        );
+        served_by.insert(name.to_string());
+    }
+    assert_eq!(served_by.len(), 2);
+    assert_eq!(count_a.load(Ordering::SeqCst), 3);
+    assert_eq!(count_b.load(Ordering::SeqCst), 3);
+
+    // An HTTP/1.1 client can't be passed through to an HTTP/2-only upstream
+    let response = reqwest::get(&format!("http://{}/", balancebeam.address))
+        .await
+        .unwrap();
+    assert_eq!(
+        response.status(),
+        reqwest::StatusCode::HTTP_VERSION_NOT_SUPPORTED
+    );
+}
@@ -0,0 +1,808 @@
+mod common;
+
+use common::{init_logging, random_local_address, BalanceBeam, EchoServer, ErrorServer, Server};
+
+async fn setup() -> (BalanceBeam, EchoServer, String) {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = random_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--admin-bind", &admin_address],
+    )
+    .await;
+    (balancebeam, upstream, admin_address)
+}
+
+async fn admin_request(
+    method: reqwest::Method,
+    admin_address: &str,
+    path: &str,
+) -> reqwest::StatusCode {
+    reqwest::Client::new()
+        .request(method, &format!("http://{}{}", admin_address, path))
+        .send()
+        .await
+        .expect("Error sending request to the admin API")
+        .status()
+}
+
+/// Draining should fail the readiness check, but keep serving existing connections and keep the
+/// process running.
+#[tokio::test]
+async fn test_drain() {
+    let (balancebeam, upstream, admin_address) = setup().await;
+
+    // Open a keep-alive connection before draining, so we can make sure it keeps working
+    let client = reqwest::Client::new();
+    let get = |path: &str| {
+        client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .header("x-sent-by", "balancebeam-tests")
+            .send()
+    };
+    let response = get("/before_drain")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    response.text().await.unwrap();
+
+    log::info!("Checking readiness before draining");
+    assert_eq!(
+        admin_request(reqwest::Method::GET, &admin_address, "/ready").await,
+        reqwest::StatusCode::OK
+    );
+
+    log::info!("Draining");
+    assert_eq!(
+        admin_request(reqwest::Method::POST, &admin_address, "/drain").await,
+        reqwest::StatusCode::OK
+    );
+    assert_eq!(
+        admin_request(reqwest::Method::GET, &admin_address, "/ready").await,
+        reqwest::StatusCode::SERVICE_UNAVAILABLE
+    );
+
+    log::info!("Making sure the existing connection still works");
+    let response_text = get("/after_drain")
+        .await
+        .expect("Error sending request to balancebeam after draining")
+        .text()
+        .await
+        .unwrap();
+    assert!(response_text.contains("GET /after_drain HTTP/1.1"));
+
+    log::info!("Making sure balancebeam is still running");
+    let response_text = balancebeam
+        .get("/new_connection")
+        .await
+        .expect("Balancebeam stopped after draining");
+    assert!(response_text.contains("GET /new_connection HTTP/1.1"));
+    assert_eq!(
+        admin_request(reqwest::Method::GET, &admin_address, "/ready").await,
+        reqwest::StatusCode::SERVICE_UNAVAILABLE
+    );
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 3);
+
+    log::info!("All done :)");
+}
+
+/// Admin requests shouldn't be forwarded, and unknown endpoints should 404.
+#[tokio::test]
+async fn test_admin_routing() {
+    let (_balancebeam, upstream, admin_address) = setup().await;
+
+    assert_eq!(
+        admin_request(reqwest::Method::GET, &admin_address, "/nonexistent").await,
+        reqwest::StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        admin_request(reqwest::Method::GET, &admin_address, "/drain").await,
+        reqwest::StatusCode::METHOD_NOT_ALLOWED
+    );
+    assert_eq!(
+        admin_request(reqwest::Method::GET, &admin_address, "/ready").await,
+        reqwest::StatusCode::OK
+    );
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 0);
+
+    log::info!("All done :)");
+}
+
+/// /metrics should count proxied requests, overall and for each upstream, in Prometheus format
+#[tokio::test]
+async fn test_metrics() {
+    let (balancebeam, upstream, admin_address) = setup().await;
+    let n_requests = 3;
+
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    // Requests are counted once balancebeam is done with them, which can be just after we've read
+    // the response
+    tokio::time::delay_for(std::time::Duration::from_millis(100)).await;
+
+    log::info!("Fetching metrics");
+    let response = reqwest::get(&format!("http://{}/metrics", admin_address))
+        .await
+        .expect("Error sending request to the admin API");
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    assert!(response.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain"));
+    let metrics = response.text().await.unwrap();
+    log::info!("Metrics:\n{}", metrics);
+    let expected = [
+        "# TYPE balancebeam_responses_total counter".to_string(),
+        format!(
+            "balancebeam_responses_total{{class=\"2xx\"}} {}",
+            n_requests
+        ),
+        "balancebeam_responses_total{class=\"5xx\"} 0".to_string(),
+        format!("balancebeam_request_duration_seconds_count {}", n_requests),
+        format!(
+            "balancebeam_request_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            n_requests
+        ),
+        format!(
+            "balancebeam_upstream_requests_total{{upstream=\"{}\"}} {}",
+            upstream.address, n_requests
+        ),
+        format!(
+            "balancebeam_upstream_errors_total{{upstream=\"{}\"}} 0",
+            upstream.address
+        ),
+        format!(
+            "balancebeam_upstream_up{{upstream=\"{}\"}} 1",
+            upstream.address
+        ),
+    ];
+    for line in &expected {
+        assert!(
+            metrics.lines().any(|metric| metric == line),
+            "Metrics are missing \"{}\"",
+            line
+        );
+    }
+    assert_eq!(
+        admin_request(reqwest::Method::POST, &admin_address, "/metrics").await,
+        reqwest::StatusCode::METHOD_NOT_ALLOWED
+    );
+
+    Box::new(upstream).stop().await;
+
+    log::info!("All done :)");
+}
+
+/// The status page should summarize the proxy and its upstreams, as HTML or JSON
+#[tokio::test]
+async fn test_status_page() {
+    init_logging();
+    let n_requests = 3;
+    let upstream = EchoServer::new().await;
+    let admin_address = random_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        Some(100),
+        &["--admin-bind", &admin_address],
+    )
+    .await;
+    for i in 0..n_requests {
+        balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    tokio::time::delay_for(std::time::Duration::from_millis(100)).await;
+    let status_url = format!("http://{}/__balancebeam/status", admin_address);
+
+    log::info!("Fetching the status page as JSON");
+    for request in [
+        reqwest::Client::new().get(&format!("{}?format=json", status_url)),
+        reqwest::Client::new()
+            .get(&status_url)
+            .header("Accept", "application/json"),
+    ] {
+        let response = request
+            .send()
+            .await
+            .expect("Error sending request to the admin API");
+        assert_eq!(response.headers()["content-type"], "application/json");
+        let status: serde_json::Value =
+            serde_json::from_str(&response.text().await.unwrap()).expect("Status isn't JSON");
+        assert!(status["uptime_seconds"].is_u64());
+        assert_eq!(status["total_requests"], serde_json::json!(n_requests));
+        assert_eq!(status["rate_limit_table_size"], serde_json::json!(1));
+        assert_eq!(status["draining"], serde_json::json!(false));
+        assert_eq!(
+            status["upstreams"][0]["address"],
+            serde_json::json!(upstream.address)
+        );
+        assert_eq!(status["upstreams"][0]["alive"], serde_json::json!(true));
+        assert_eq!(
+            status["upstreams"][0]["requests"],
+            serde_json::json!(n_requests)
+        );
+        assert_eq!(status["upstreams"][0]["errors"], serde_json::json!(0));
+    }
+
+    log::info!("Fetching the status page as HTML");
+    let response = reqwest::get(&status_url)
+        .await
+        .expect("Error sending request to the admin API");
+    assert!(response.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/html"));
+    let page = response.text().await.unwrap();
+    assert!(page.contains(&format!("<td>{}</td><td>alive</td>", upstream.address)));
+    assert!(page.contains(&format!(
+        "<tr><th>Total requests</th><td>{}</td></tr>",
+        n_requests
+    )));
+
+    Box::new(upstream).stop().await;
+
+    log::info!("All done :)");
+}
+
+/// The upstream listing should include each upstream's recent error rate and latency percentiles
+#[tokio::test]
+async fn test_upstream_stats() {
+    init_logging();
+    let n_requests = 4;
+    let slow = EchoServer::new_with_delay(std::time::Duration::from_millis(50)).await;
+    let failing = ErrorServer::new().await;
+    let admin_address = random_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&slow.address, &failing.address],
+        Some(3600),
+        None,
+        &["--admin-bind", &admin_address],
+    )
+    .await;
+    let send_requests = || async {
+        for i in 0..n_requests {
+            balancebeam
+                .get(&format!("/request-{}", i))
+                .await
+                .expect("Error sending request to balancebeam");
+        }
+    };
+
+    log::info!("Sending requests to each upstream in turn");
+    for (alive, dead) in [
+        (&slow.address, &failing.address),
+        (&failing.address, &slow.address),
+    ] {
+        for (addr, action) in [(alive, "alive"), (dead, "dead")] {
+            assert_eq!(
+                admin_request(
+                    reqwest::Method::POST,
+                    &admin_address,
+                    &format!("/upstreams/{}/{}", addr, action)
+                )
+                .await,
+                reqwest::StatusCode::OK
+            );
+        }
+        send_requests().await;
+    }
+    assert_eq!(slow.requests_received(), n_requests);
+
+    let listing = reqwest::get(&format!("http://{}/upstreams", admin_address))
+        .await
+        .expect("Error sending request to the admin API")
+        .text()
+        .await
+        .unwrap();
+    log::info!("Upstreams: {}", listing);
+    let upstreams: serde_json::Value =
+        serde_json::from_str(&listing).expect("Upstream listing isn't valid JSON");
+    let recent = |addr: &str| {
+        upstreams
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|upstream| upstream["address"] == serde_json::json!(addr))
+            .expect("Upstream missing from listing")["recent"]
+            .clone()
+    };
+
+    let slow_stats = recent(&slow.address);
+    assert_eq!(slow_stats["requests"], serde_json::json!(n_requests));
+    assert_eq!(slow_stats["error_rate"], serde_json::json!(0.0));
+    let p50 = slow_stats["p50_ms"].as_f64().expect("p50_ms missing");
+    let p99 = slow_stats["p99_ms"].as_f64().expect("p99_ms missing");
+    assert!(p50 >= 50.0, "p50 of {}ms is faster than the upstream", p50);
+    assert!(p99 >= p50);
+
+    let failing_stats = recent(&failing.address);
+    assert_eq!(failing_stats["requests"], serde_json::json!(n_requests));
+    assert_eq!(failing_stats["error_rate"], serde_json::json!(1.0));
+
+    Box::new(slow).stop().await;
+    Box::new(failing).stop().await;
+
+    log::info!("All done :)");
+}
+
+/// Upstreams added, removed, and marked dead/alive through the admin API should take effect for
+/// the next connection
+#[tokio::test]
+async fn test_upstream_management() {
+    init_logging();
+    let n_requests = 5;
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let admin_address = random_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&first.address],
+        Some(3600),
+        None,
+        &["--admin-bind", &admin_address],
+    )
+    .await;
+    let upstreams_path = |suffix: &str| format!("/upstreams/{}", suffix);
+    let send_requests = || async {
+        for i in 0..n_requests {
+            let path = format!("/request-{}", i);
+            let response_text = balancebeam
+                .get(&path)
+                .await
+                .expect("Error sending request to balancebeam");
+            assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        }
+    };
+
+    log::info!("Adding the second upstream");
+    assert_eq!(
+        admin_request(
+            reqwest::Method::POST,
+            &admin_address,
+            &upstreams_path(&second.address)
+        )
+        .await,
+        reqwest::StatusCode::CREATED
+    );
+    assert_eq!(
+        admin_request(
+            reqwest::Method::POST,
+            &admin_address,
+            &upstreams_path(&second.address)
+        )
+        .await,
+        reqwest::StatusCode::CONFLICT
+    );
+
+    log::info!("Marking the first upstream dead");
+    assert_eq!(
+        admin_request(
+            reqwest::Method::POST,
+            &admin_address,
+            &upstreams_path(&format!("{}/dead", first.address))
+        )
+        .await,
+        reqwest::StatusCode::OK
+    );
+    send_requests().await;
+    assert_eq!(first.requests_received(), 0);
+    assert_eq!(second.requests_received(), n_requests);
+
+    log::info!("Reviving the first upstream and removing the second");
+    assert_eq!(
+        admin_request(
+            reqwest::Method::POST,
+            &admin_address,
+            &upstreams_path(&format!("{}/alive", first.address))
+        )
+        .await,
+        reqwest::StatusCode::OK
+    );
+    assert_eq!(
+        admin_request(
+            reqwest::Method::DELETE,
+            &admin_address,
+            &upstreams_path(&second.address)
+        )
+        .await,
+        reqwest::StatusCode::OK
+    );
+    assert_eq!(
+        admin_request(
+            reqwest::Method::DELETE,
+            &admin_address,
+            &upstreams_path(&second.address)
+        )
+        .await,
+        reqwest::StatusCode::NOT_FOUND
+    );
+    send_requests().await;
+    assert_eq!(first.requests_received(), n_requests);
+    assert_eq!(second.requests_received(), n_requests);
+
+    log::info!("Listing upstreams");
+    let listing = reqwest::get(&format!("http://{}/upstreams", admin_address))
+        .await
+        .expect("Error sending request to the admin API")
+        .text()
+        .await
+        .unwrap();
+    let upstreams: serde_json::Value =
+        serde_json::from_str(&listing).expect("Upstream listing isn't valid JSON");
+    // (active_connections isn't checked, since our last connection may not have been torn down yet)
+    assert_eq!(
+        upstreams.as_array().map(|upstreams| upstreams.len()),
+        Some(1)
+    );
+    assert_eq!(upstreams[0]["address"], serde_json::json!(first.address));
+    assert_eq!(upstreams[0]["alive"], serde_json::json!(true));
+    assert_eq!(upstreams[0]["weight"], serde_json::json!(1));
+    assert_eq!(upstreams[0]["tier"], serde_json::json!("primary"));
+
+    Box::new(first).stop().await;
+    Box::new(second).stop().await;
+
+    log::info!("All done :)");
+}
+
+/// A drained upstream should get no new requests, not even from clients whose earlier requests it
+/// served, and those clients shouldn't be cut off
+#[tokio::test]
+async fn test_upstream_drain() {
+    init_logging();
+    let n_requests = 5;
+    let draining = EchoServer::new().await;
+    let other = EchoServer::new().await;
+    let admin_address = random_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&draining.address],
+        Some(3600),
+        None,
+        &["--admin-bind", &admin_address],
+    )
+    .await;
+
+    // Open a keep-alive connection while the first upstream is the only one
+    let client = reqwest::Client::new();
+    let get = |path: &str| {
+        client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .header("x-sent-by", "balancebeam-tests")
+            .send()
+    };
+    get("/before_drain")
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .unwrap();
+
+    log::info!("Adding a second upstream and draining the first");
+    assert_eq!(
+        admin_request(
+            reqwest::Method::POST,
+            &admin_address,
+            &format!("/upstreams/{}", other.address)
+        )
+        .await,
+        reqwest::StatusCode::CREATED
+    );
+    assert_eq!(
+        admin_request(
+            reqwest::Method::POST,
+            &admin_address,
+            &format!("/upstreams/{}/drain", draining.address)
+        )
+        .await,
+        reqwest::StatusCode::OK
+    );
+
+    log::info!("Making sure the existing connection still works, through the other upstream");
+    let response_text = get("/after_drain")
+        .await
+        .expect("Existing connection was dropped by draining")
+        .text()
+        .await
+        .unwrap();
+    assert!(response_text.contains("GET /after_drain HTTP/1.1"));
+
+    log::info!("Making sure new connections skip the draining upstream too");
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    assert_eq!(draining.requests_received(), 1);
+    assert_eq!(other.requests_received(), n_requests + 1);
+
+    log::info!("Undraining and draining the other upstream");
+    for (address, action) in [(&draining.address, "undrain"), (&other.address, "drain")].iter() {
+        assert_eq!(
+            admin_request(
+                reqwest::Method::POST,
+                &admin_address,
+                &format!("/upstreams/{}/{}", address, action)
+            )
+            .await,
+            reqwest::StatusCode::OK
+        );
+    }
+    balancebeam
+        .get("/undrained")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(draining.requests_received(), 2);
+
+    Box::new(draining).stop().await;
+    Box::new(other).stop().await;
+
+    log::info!("All done :)");
+}
+
+/// An upstream given by DNS name should be listed under the addresses it resolves to, and requests
+/// should reach it
+#[tokio::test]
+async fn test_dns_upstream() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let port = upstream.address.rsplit_once(':').unwrap().1;
+    let name = format!("localhost:{}", port);
+    let admin_address = random_local_address();
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&name], None, None, &["--admin-bind", &admin_address]).await;
+
+    let response_text = balancebeam
+        .get("/by-name")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /by-name HTTP/1.1"));
+
+    let listing = reqwest::get(&format!("http://{}/upstreams", admin_address))
+        .await
+        .expect("Error sending request to the admin API")
+        .text()
+        .await
+        .unwrap();
+    let upstreams: serde_json::Value =
+        serde_json::from_str(&listing).expect("Upstream listing isn't valid JSON");
+    let upstreams = upstreams.as_array().unwrap();
+    // localhost may resolve to ::1 as well as 127.0.0.1
+    assert!(upstreams
+        .iter()
+        .any(|listed| listed["address"] == serde_json::json!(format!("127.0.0.1:{}", port))));
+    assert!(upstreams
+        .iter()
+        .all(|listed| listed["resolved_from"] == serde_json::json!(name)));
+
+    Box::new(upstream).stop().await;
+}
+
+/// Cached responses should follow the upstream's Cache-Control and ETag headers: no-store responses
+/// aren't kept, stale ones are revalidated with If-None-Match, clients that already have a
+/// response get a 304, and the admin API can purge them
+#[tokio::test]
+async fn test_cache_control_and_purge() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    init_logging();
+    let upstream_address = random_local_address();
+    let mut listener = tokio::net::TcpListener::bind(&upstream_address)
+        .await
+        .unwrap();
+    // Counts full responses and 304s sent
+    let counts = Arc::new([AtomicUsize::new(0), AtomicUsize::new(0)]);
+    let server_counts = Arc::clone(&counts);
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let counts = Arc::clone(&server_counts);
+            tokio::spawn(async move {
+                let mut buffer = [0_u8; 4096];
+                loop {
+                    let mut request = Vec::new();
+                    while !request.ends_with(b"\r\n\r\n") {
+                        match stream.read(&mut buffer).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buffer[..n]),
+                        }
+                    }
+                    let request = String::from_utf8_lossy(&request).to_lowercase();
+                    let response: &[u8] = if request.contains("if-none-match: \"v1\"") {
+                        counts[1].fetch_add(1, Ordering::SeqCst);
+                        b"HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nCache-Control: max-age=1\r\n\r\n"
+                    } else if request.starts_with("get /nostore") {
+                        counts[0].fetch_add(1, Ordering::SeqCst);
+                        b"HTTP/1.1 200 OK\r\nCache-Control: no-store\r\nContent-Length: 5\r\n\r\nhello"
+                    } else {
+                        counts[0].fetch_add(1, Ordering::SeqCst);
+                        b"HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nCache-Control: max-age=1\r\nContent-Length: 5\r\n\r\nhello"
+                    };
+                    if stream.write_all(response).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    let admin_address = random_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        None,
+        None,
+        &["--admin-bind", &admin_address, "--cache-size", "100000"],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let get = |path: &str, etag: Option<&str>| {
+        let mut request = client.get(&format!("http://{}{}", balancebeam.address, path));
+        if let Some(etag) = etag {
+            request = request.header("If-None-Match", etag);
+        }
+        async move {
+            let response = request
+                .send()
+                .await
+                .expect("Error sending request to balancebeam");
+            (response.status().as_u16(), response.text().await.unwrap())
+        }
+    };
+    let sent = |counts: &[AtomicUsize; 2]| {
+        (
+            counts[0].load(Ordering::SeqCst),
+            counts[1].load(Ordering::SeqCst),
+        )
+    };
+
+    log::info!("Checking that no-store responses aren't cached");
+    get("/nostore", None).await;
+    get("/nostore", None).await;
+    assert_eq!(sent(&counts), (2, 0));
+
+    log::info!("Checking that fresh responses are served from the cache");
+    assert_eq!(get("/page", None).await, (200, "hello".to_string()));
+    assert_eq!(get("/page", None).await, (200, "hello".to_string()));
+    assert_eq!(get("/page", Some("\"v1\"")).await.0, 304);
+    assert_eq!(sent(&counts), (3, 0));
+
+    log::info!("Checking that stale responses are revalidated");
+    tokio::time::delay_for(tokio::time::Duration::from_millis(1100)).await;
+    assert_eq!(get("/page", None).await, (200, "hello".to_string()));
+    assert_eq!(get("/page", None).await, (200, "hello".to_string()));
+    assert_eq!(sent(&counts), (3, 1));
+
+    log::info!("Checking that purged responses are fetched again");
+    let purged = client
+        .post(&format!(
+            "http://{}/cache/purge?prefix=%2Fpa",
+            admin_address
+        ))
+        .send()
+        .await
+        .expect("Error sending request to the admin API")
+        .text()
+        .await
+        .unwrap();
+    assert_eq!(purged, "{\"purged\":1}");
+    assert_eq!(get("/page", None).await, (200, "hello".to_string()));
+    assert_eq!(sent(&counts), (4, 1));
+
+    log::info!("All done :)");
+}
+
+/// Maintenance mode should answer requests with the --maintenance-page 503, but still forward
+/// those from --maintenance-allow clients, and can be limited to some paths from the admin API
+#[tokio::test]
+async fn test_maintenance_mode() {
+    init_logging();
+    let page = std::env::temp_dir().join(format!(
+        "balancebeam-test-{}-maintenance.html",
+        rand::random::<u64>()
+    ));
+    std::fs::write(&page, "<p>Back soon! ({status}, {request_id})</p>").unwrap();
+    let upstream = EchoServer::new().await;
+    let admin_address = random_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        Some(3600),
+        &[
+            "--admin-bind",
+            &admin_address,
+            "--maintenance",
+            "--maintenance-page",
+            page.to_str().unwrap(),
+            "--maintenance-allow",
+            "10.0.0.0/8",
+            "--maintenance-retry-after",
+            "120",
+            // So that the tests can say which client they are in X-Forwarded-For
+            "--trusted-proxies",
+            "127.0.0.1/32",
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let get = |path: &str, from: &str| {
+        let request = client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .header("X-Forwarded-For", from)
+            .header("X-Request-Id", "req-7");
+        async move {
+            request
+                .send()
+                .await
+                .expect("Error sending request to balancebeam")
+        }
+    };
+    let admin = |method: reqwest::Method, path: &str| {
+        let request = client.request(method, &format!("http://{}{}", admin_address, path));
+        async move {
+            let response = request
+                .send()
+                .await
+                .expect("Error sending request to the admin API");
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+            serde_json::from_str::<serde_json::Value>(&response.text().await.unwrap()).unwrap()
+        }
+    };
+
+    let response = get("/orders", "192.0.2.1").await;
+    assert_eq!(response.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers().get("retry-after").unwrap(), "120");
+    assert_eq!(
+        response.text().await.unwrap(),
+        "<p>Back soon! (503, req-7)</p>"
+    );
+    let response = get("/orders", "10.1.2.3").await;
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+    log::info!("Narrowing maintenance down to /api");
+    assert_eq!(
+        admin(reqwest::Method::POST, "/maintenance/on?path=/api").await,
+        serde_json::json!({"on": true, "paths": ["/api"]})
+    );
+    assert_eq!(
+        get("/api/orders", "192.0.2.1").await.status(),
+        reqwest::StatusCode::SERVICE_UNAVAILABLE
+    );
+    assert_eq!(
+        get("/apiary", "192.0.2.1").await.status(),
+        reqwest::StatusCode::OK
+    );
+
+    log::info!("Turning maintenance mode off");
+    assert_eq!(
+        admin(reqwest::Method::POST, "/maintenance/off").await,
+        serde_json::json!({"on": false, "paths": ["/api"]})
+    );
+    assert_eq!(
+        admin(reqwest::Method::GET, "/maintenance").await["on"],
+        false
+    );
+    assert_eq!(
+        get("/api/orders", "192.0.2.1").await.status(),
+        reqwest::StatusCode::OK
+    );
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    std::fs::remove_file(&page).unwrap();
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,387 @@
+mod common;
+
+use common::{init_logging, random_local_address, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+
+/// Writes a config file to a fresh path in the temp directory and returns its path
+fn write_config(contents: &str) -> String {
+    let path = std::env::temp_dir().join(format!(
+        "balancebeam-test-{}.toml",
+        rand::thread_rng().gen::<u64>()
+    ));
+    std::fs::write(&path, contents).expect("Could not write config file");
+    path.to_str().unwrap().to_string()
+}
+
+/// Upstreams, strategy, and health check settings should all be read from the config file
+#[tokio::test]
+async fn test_config_file() {
+    init_logging();
+    let n_requests = 10;
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let config_path = write_config(&format!(
+        r#"
+strategy = "round-robin"
+
+[listener]
+forwarded_header_style = "both"
+
+[[upstream]]
+address = "{}"
+
+[[upstream]]
+address = "{}"
+weight = 2
+
+[health_check]
+interval = 3600
+"#,
+        first.address, second.address
+    ));
+    let balancebeam =
+        BalanceBeam::new_with_args(&[], None, None, &["--config", &config_path]).await;
+
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+        assert!(response_text.contains("forwarded: for=127.0.0.1"));
+    }
+
+    // Round-robin ignores weights, so the requests should be split evenly
+    assert_eq!(Box::new(first).stop().await, n_requests / 2);
+    assert_eq!(Box::new(second).stop().await, n_requests / 2);
+    std::fs::remove_file(&config_path).unwrap();
+
+    log::info!("All done :)");
+}
+
+/// The active health check request can be described in the config file, including headers that
+/// the upstream requires
+#[tokio::test]
+async fn test_health_check_request_config() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let config_path = write_config(&format!(
+        r#"
+[[upstream]]
+address = "{}"
+
+[health_check]
+interval = 1
+method = "POST"
+headers = {{ X-Probe-Token = "secret" }}
+request_body = "ping"
+expected_body = "POST / HTTP/1.1"
+"#,
+        upstream.address
+    ));
+    let balancebeam =
+        BalanceBeam::new_with_args(&[], None, None, &["--config", &config_path]).await;
+    tokio::time::delay_for(tokio::time::Duration::from_secs(2)).await;
+
+    let response_text = balancebeam
+        .get("/after-health-checks")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(
+        response_text.contains("GET /after-health-checks HTTP/1.1"),
+        "Upstream was marked dead even though it answered the configured health check"
+    );
+    Box::new(upstream).stop().await;
+    std::fs::remove_file(&config_path).unwrap();
+}
+
+/// Flags given on the command line should win over the config file. (The test harness always
+/// passes --bind, so the file's bind address is overridden as well.)
+#[tokio::test]
+async fn test_command_line_overrides_config() {
+    init_logging();
+    let n_requests = 10;
+    let from_file = EchoServer::new().await;
+    let from_command_line = EchoServer::new().await;
+    let config_path = write_config(&format!(
+        r#"
+[listener]
+bind = "{}"
+
+[[upstream]]
+address = "{}"
+
+[health_check]
+interval = 3600
+"#,
+        random_local_address(),
+        from_file.address
+    ));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&from_command_line.address],
+        None,
+        None,
+        &["--config", &config_path],
+    )
+    .await;
+
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    assert_eq!(Box::new(from_file).stop().await, 0);
+    assert_eq!(Box::new(from_command_line).stop().await, n_requests);
+    std::fs::remove_file(&config_path).unwrap();
+
+    log::info!("All done :)");
+}
+
+/// On SIGHUP, balancebeam should pick up upstreams added to and removed from the config file,
+/// without cutting off clients whose earlier requests went to a removed upstream
+#[tokio::test]
+async fn test_sighup_reload() {
+    init_logging();
+    let n_requests = 5;
+    let old = EchoServer::new().await;
+    let new = EchoServer::new().await;
+    let upstream_config = |address: &str| {
+        format!(
+            "[[upstream]]\naddress = \"{}\"\n\n[health_check]\ninterval = 3600\n",
+            address
+        )
+    };
+    let config_path = write_config(&upstream_config(&old.address));
+    let balancebeam =
+        BalanceBeam::new_with_args(&[], None, None, &["--config", &config_path]).await;
+
+    // Open a keep-alive connection, and send a request to the old upstream over it, before
+    // reloading
+    let client = reqwest::Client::new();
+    let get = |path: &str| {
+        client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .header("x-sent-by", "balancebeam-tests")
+            .send()
+    };
+    get("/before_reload")
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .unwrap();
+
+    log::info!("Replacing the upstream and sending SIGHUP");
+    std::fs::write(&config_path, upstream_config(&new.address)).unwrap();
+    balancebeam.send_signal(nix::sys::signal::Signal::SIGHUP);
+    tokio::time::delay_for(tokio::time::Duration::from_millis(500)).await;
+
+    log::info!("Making sure the existing connection still works, through the new upstream");
+    let response_text = get("/after_reload")
+        .await
+        .expect("Existing connection was dropped by the reload")
+        .text()
+        .await
+        .unwrap();
+    assert!(response_text.contains("GET /after_reload HTTP/1.1"));
+
+    log::info!("Making sure new connections go to the new upstream");
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    assert_eq!(Box::new(old).stop().await, 1);
+    assert_eq!(Box::new(new).stop().await, n_requests + 1);
+    std::fs::remove_file(&config_path).unwrap();
+
+    log::info!("All done :)");
+}
+
+/// On SIGUSR1, balancebeam should start draining the upstreams the config file marks with
+/// `drain = true`
+#[tokio::test]
+async fn test_sigusr1_drain() {
+    init_logging();
+    let n_requests = 5;
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let config = |drain_first: bool| {
+        format!(
+            r#"
+[[upstream]]
+address = "{}"
+drain = {}
+
+[[upstream]]
+address = "{}"
+
+[health_check]
+interval = 3600
+"#,
+            first.address, drain_first, second.address
+        )
+    };
+    let config_path = write_config(&config(false));
+    let balancebeam =
+        BalanceBeam::new_with_args(&[], None, None, &["--config", &config_path]).await;
+
+    log::info!("Marking the first upstream as draining and sending SIGUSR1");
+    std::fs::write(&config_path, config(true)).unwrap();
+    balancebeam.send_signal(nix::sys::signal::Signal::SIGUSR1);
+    tokio::time::delay_for(tokio::time::Duration::from_millis(500)).await;
+
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    assert_eq!(Box::new(first).stop().await, 0);
+    assert_eq!(Box::new(second).stop().await, n_requests);
+    std::fs::remove_file(&config_path).unwrap();
+
+    log::info!("All done :)");
+}
+
+/// Sends a request as if through a trusted proxy for the client at forwarded_for, returning the
+/// response's status
+async fn get_forwarded_for(balancebeam: &BalanceBeam, forwarded_for: &str) -> u16 {
+    reqwest::Client::new()
+        .get(&format!("http://{}/forwarded", balancebeam.address))
+        .header("x-sent-by", "balancebeam-tests")
+        .header("x-forwarded-for", forwarded_for)
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// Clients on the rate limit allowlist should never be limited, clients on the denylist should
+/// always be refused, and both lists should be picked up again on SIGHUP
+#[tokio::test]
+async fn test_rate_limit_lists() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let config = |allow: &str, deny: &str| {
+        format!(
+            r#"
+[listener]
+trusted_proxies = "127.0.0.1"
+
+[[upstream]]
+address = "{}"
+
+[health_check]
+interval = 3600
+
+[rate_limit]
+max_requests_per_minute = 1
+allow = ["{}"]
+deny = ["{}"]
+"#,
+            upstream.address, allow, deny
+        )
+    };
+    let config_path = write_config(&config("203.0.113.0/24", "198.51.100.1"));
+    let balancebeam =
+        BalanceBeam::new_with_args(&[], None, None, &["--config", &config_path]).await;
+
+    log::info!("Checking that allowed clients aren't limited");
+    for _ in 0..3 {
+        assert_eq!(get_forwarded_for(&balancebeam, "203.0.113.7").await, 200);
+    }
+    log::info!("Checking that denied clients are refused");
+    assert_eq!(get_forwarded_for(&balancebeam, "198.51.100.1").await, 403);
+    log::info!("Checking that other clients are still limited");
+    assert_eq!(get_forwarded_for(&balancebeam, "192.0.2.1").await, 200);
+    assert_eq!(get_forwarded_for(&balancebeam, "192.0.2.1").await, 429);
+
+    log::info!("Swapping the lists and sending SIGHUP");
+    std::fs::write(&config_path, config("198.51.100.1", "203.0.113.0/24")).unwrap();
+    balancebeam.send_signal(nix::sys::signal::Signal::SIGHUP);
+    tokio::time::delay_for(tokio::time::Duration::from_millis(500)).await;
+
+    assert_eq!(get_forwarded_for(&balancebeam, "203.0.113.7").await, 403);
+    for _ in 0..3 {
+        assert_eq!(get_forwarded_for(&balancebeam, "198.51.100.1").await, 200);
+    }
+    assert_eq!(Box::new(upstream).stop().await, 7);
+    std::fs::remove_file(&config_path).unwrap();
+
+    log::info!("All done :)");
+}
+
+/// Connections from clients that [client_access] doesn't let in should be refused before any
+/// request reaches an upstream, with the lists picked up again on SIGHUP
+#[tokio::test]
+async fn test_client_access_lists() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let config = |allow: &str, deny: &str, action: &str| {
+        format!(
+            r#"
+[[upstream]]
+address = "{}"
+
+[health_check]
+interval = 3600
+
+[client_access]
+allow = [{}]
+deny = [{}]
+action = "{}"
+"#,
+            upstream.address, allow, deny, action
+        )
+    };
+    let config_path = write_config(&config("", r#""127.0.0.0/8""#, "forbidden"));
+    let balancebeam =
+        BalanceBeam::new_with_args(&[], None, None, &["--config", &config_path]).await;
+    let url = format!("http://{}/", balancebeam.address);
+
+    log::info!("Checking that a denied client gets a 403");
+    let response = reqwest::get(&url)
+        .await
+        .expect("Expected a 403, not an error");
+    assert_eq!(response.status().as_u16(), 403);
+
+    log::info!("Letting us in and sending SIGHUP");
+    std::fs::write(
+        &config_path,
+        config(r#""127.0.0.1""#, r#""192.0.2.0/24""#, "forbidden"),
+    )
+    .unwrap();
+    balancebeam.send_signal(nix::sys::signal::Signal::SIGHUP);
+    tokio::time::delay_for(tokio::time::Duration::from_millis(500)).await;
+    let response = reqwest::get(&url).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    drop(balancebeam);
+
+    log::info!("Checking that an allowlist that leaves us out gets us dropped");
+    std::fs::write(&config_path, config(r#""10.0.0.0/8""#, "", "drop")).unwrap();
+    let balancebeam =
+        BalanceBeam::new_with_args(&[], None, None, &["--config", &config_path]).await;
+    assert!(reqwest::get(&format!("http://{}/", balancebeam.address))
+        .await
+        .is_err());
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    std::fs::remove_file(&config_path).unwrap();
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,145 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use nix::sys::signal::Signal;
+use tokio::time::{delay_for, timeout, Duration};
+
+async fn setup(response_delay: Duration, shutdown_timeout: u64) -> (BalanceBeam, EchoServer) {
+    init_logging();
+    let upstream = EchoServer::new_with_delay(response_delay).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        Some(3600),
+        None,
+        &["--shutdown-timeout", &shutdown_timeout.to_string()],
+    )
+    .await;
+    (balancebeam, upstream)
+}
+
+/// Sends a request from a separate task, so that it can be in flight while the test does other
+/// things
+fn spawn_request(
+    balancebeam: &BalanceBeam,
+    path: &str,
+) -> tokio::task::JoinHandle<Result<String, reqwest::Error>> {
+    let url = format!("http://{}{}", balancebeam.address, path);
+    tokio::spawn(async move {
+        reqwest::Client::new()
+            .get(&url)
+            .header("x-sent-by", "balancebeam-tests")
+            .send()
+            .await?
+            .text()
+            .await
+    })
+}
+
+/// On SIGTERM, balancebeam should stop accepting connections, finish the requests it's already
+/// working on, and then exit cleanly
+#[tokio::test]
+async fn test_graceful_shutdown() {
+    let (mut balancebeam, upstream) = setup(Duration::from_secs(2), 30).await;
+
+    let in_flight = spawn_request(&balancebeam, "/in_flight");
+    delay_for(Duration::from_millis(500)).await;
+    log::info!("Sending SIGTERM");
+    balancebeam.send_signal(Signal::SIGTERM);
+    delay_for(Duration::from_millis(200)).await;
+
+    log::info!("Making sure new connections are refused");
+    assert!(tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .is_err());
+
+    log::info!("Making sure the in-flight request finishes");
+    let response_text = in_flight
+        .await
+        .unwrap()
+        .expect("In-flight request failed during shutdown");
+    assert!(response_text.contains("GET /in_flight HTTP/1.1"));
+
+    let status = timeout(Duration::from_secs(5), balancebeam.wait())
+        .await
+        .expect("balancebeam didn't exit after finishing its requests");
+    assert!(status.success());
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// If in-flight requests take longer than --shutdown-timeout, balancebeam should give up on them
+/// and exit anyway
+#[tokio::test]
+async fn test_shutdown_timeout() {
+    let (mut balancebeam, upstream) = setup(Duration::from_secs(10), 1).await;
+
+    let in_flight = spawn_request(&balancebeam, "/too_slow");
+    delay_for(Duration::from_millis(500)).await;
+    log::info!("Sending SIGTERM");
+    balancebeam.send_signal(Signal::SIGTERM);
+
+    let status = timeout(Duration::from_secs(5), balancebeam.wait())
+        .await
+        .expect("balancebeam didn't exit after its shutdown timeout");
+    assert!(!status.success());
+    assert!(in_flight.await.unwrap().is_err());
+
+    Box::new(upstream).stop().await;
+
+    log::info!("All done :)");
+}
+
+/// On SIGUSR2, balancebeam should hand its listening socket to a new process and exit once that's
+/// ready, without any requests failing in between
+#[tokio::test]
+async fn test_binary_upgrade() {
+    let n_requests = 10;
+    let (mut balancebeam, upstream) = setup(Duration::from_secs(0), 30).await;
+
+    let in_flight = spawn_request(&balancebeam, "/before_upgrade");
+    log::info!("Sending SIGUSR2");
+    balancebeam.send_signal(Signal::SIGUSR2);
+    let status = timeout(Duration::from_secs(5), balancebeam.wait())
+        .await
+        .expect("Old balancebeam process didn't exit after upgrading");
+    assert!(status.success());
+    assert!(in_flight
+        .await
+        .unwrap()
+        .expect("Request failed during upgrade")
+        .contains("GET /before_upgrade HTTP/1.1"));
+
+    log::info!("Making sure the new process is serving requests");
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to the upgraded balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    assert_eq!(upstream.requests_received(), n_requests + 1);
+    // The old process should only have exited once the new one said it was ready
+    assert!(balancebeam
+        .output()
+        .iter()
+        .any(|line| line.contains("is ready, handing over")));
+
+    // The new process isn't our child, so it won't be killed when balancebeam is dropped
+    let pid: i32 = balancebeam
+        .output()
+        .iter()
+        .find_map(|line| {
+            line.split("Started new balancebeam process ")
+                .nth(1)
+                .map(|rest| rest.split(',').next().unwrap().parse().unwrap())
+        })
+        .expect("Couldn't find the new process's pid in balancebeam's output");
+    nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid), Signal::SIGTERM).unwrap();
+
+    Box::new(upstream).stop().await;
+
+    log::info!("All done :)");
+}
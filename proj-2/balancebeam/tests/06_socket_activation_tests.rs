@@ -0,0 +1,39 @@
+mod common;
+
+use common::{init_logging, random_local_address, BalanceBeam, EchoServer, Server};
+
+/// When systemd hands balancebeam a listening socket, balancebeam should serve on it instead of
+/// binding --bind itself
+#[tokio::test]
+async fn test_socket_activation() {
+    init_logging();
+    let n_requests = 5;
+    let upstream = EchoServer::new().await;
+    let listener = std::net::TcpListener::bind(random_local_address()).unwrap();
+    let ignored_bind = random_local_address();
+    let balancebeam = BalanceBeam::new_socket_activated(
+        listener,
+        &[&upstream.address],
+        &[
+            "--bind",
+            &ignored_bind,
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to socket-activated balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    assert!(tokio::net::TcpStream::connect(&ignored_bind).await.is_err());
+
+    assert_eq!(Box::new(upstream).stop().await, n_requests);
+
+    log::info!("All done :)");
+}
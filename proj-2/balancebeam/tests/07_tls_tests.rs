@@ -0,0 +1,311 @@
+mod common;
+
+use common::{init_logging, random_local_address, BalanceBeam, EchoServer, Server};
+use hyper::service::service_fn;
+use hyper::{Body, Response};
+use rand::Rng;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio_rustls::rustls::{internal::pemfile, NoClientAuth, ServerConfig};
+
+/// Writes contents to a fresh path in the temp directory and returns the path
+fn write_temp_file(suffix: &str, contents: &str) -> String {
+    let path = std::env::temp_dir().join(format!(
+        "balancebeam-test-{}-{}",
+        rand::thread_rng().gen::<u64>(),
+        suffix
+    ));
+    std::fs::write(&path, contents).expect("Could not write temp file");
+    path.to_str().unwrap().to_string()
+}
+
+/// Generates a self-signed certificate, returning the paths of the certificate and key files
+fn make_certificate() -> (String, String) {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    (
+        write_temp_file("cert.pem", &cert.serialize_pem().unwrap()),
+        write_temp_file("key.pem", &cert.serialize_private_key_pem()),
+    )
+}
+
+/// Generates a CA, returning it along with the path of its certificate file
+fn make_ca() -> (rcgen::Certificate, String) {
+    let mut params = rcgen::CertificateParams::new(Vec::new());
+    params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    let ca = rcgen::Certificate::from_params(params).unwrap();
+    let ca_path = write_temp_file("ca.pem", &ca.serialize_pem().unwrap());
+    (ca, ca_path)
+}
+
+/// Generates a certificate for localhost signed by ca, returning the certificate and key (as PEM)
+fn make_signed_certificate(ca: &rcgen::Certificate) -> (String, String) {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    (
+        cert.serialize_pem_with_signer(ca).unwrap(),
+        cert.serialize_private_key_pem(),
+    )
+}
+
+/// Starts an HTTPS server that replies to each request with its request line and the name the
+/// client sent in SNI. Returns its address, and a count of the requests it has served.
+async fn start_tls_upstream(cert_pem: &str, key_pem: &str) -> (String, Arc<AtomicUsize>) {
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    let certs = pemfile::certs(&mut cert_pem.as_bytes()).unwrap();
+    let key = pemfile::pkcs8_private_keys(&mut key_pem.as_bytes())
+        .unwrap()
+        .remove(0);
+    config.set_single_cert(certs, key).unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+
+    let address = random_local_address();
+    let mut listener = tokio::net::TcpListener::bind(&address).await.unwrap();
+    let requests_received = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&requests_received);
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            let counter = Arc::clone(&counter);
+            tokio::spawn(async move {
+                // Handshakes fail on purpose in some tests
+                let stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(_) => return,
+                };
+                let sni = stream.get_ref().1.get_sni_hostname().map(str::to_string);
+                let service = service_fn(move |req| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let text = format!("{} {}\nsni: {:?}\n", req.method(), req.uri(), sni);
+                    async move { Ok::<_, hyper::Error>(Response::new(Body::from(text))) }
+                });
+                let _ = hyper::server::conn::Http::new()
+                    .serve_connection(stream, service)
+                    .await;
+            });
+        }
+    });
+    (address, requests_received)
+}
+
+/// Generates a client certificate signed by ca, returning the certificate and key as one PEM
+/// bundle
+fn make_client_identity(ca: &rcgen::Certificate) -> String {
+    let cert = rcgen::generate_simple_self_signed(vec!["client".to_string()]).unwrap();
+    cert.serialize_pem_with_signer(ca).unwrap() + &cert.serialize_private_key_pem()
+}
+
+async fn setup_with_args(extra_args: &[&str]) -> (BalanceBeam, EchoServer) {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (cert_path, key_path) = make_certificate();
+    let mut args = vec![
+        "--tls-cert",
+        &cert_path,
+        "--tls-key",
+        &key_path,
+        "--forwarded-header-style",
+        "both",
+    ];
+    args.extend_from_slice(extra_args);
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], Some(3600), None, &args).await;
+    (balancebeam, upstream)
+}
+
+async fn setup() -> (BalanceBeam, EchoServer) {
+    setup_with_args(&[]).await
+}
+
+fn https_client() -> reqwest::Client {
+    // The certificate is self-signed (and for localhost rather than 127.0.0.1)
+    reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap()
+}
+
+/// Builds an HTTPS client that presents identity (a PEM certificate and key), if given. Client
+/// certificates need reqwest's rustls backend, which can only connect by hostname, so use
+/// localhost rather than 127.0.0.1 in URLs for this client.
+fn https_client_with_identity(identity: Option<String>) -> reqwest::Client {
+    let mut builder = reqwest::Client::builder()
+        .use_rustls_tls()
+        .danger_accept_invalid_certs(true);
+    if let Some(identity) = identity {
+        builder = builder.identity(
+            reqwest::Identity::from_pem(identity.as_bytes())
+                .expect("Could not load client identity"),
+        );
+    }
+    builder.build().unwrap()
+}
+
+/// balancebeam should accept HTTPS from clients, and forward the requests as plain HTTP
+#[tokio::test]
+async fn test_tls_termination() {
+    let n_requests = 3;
+    let (balancebeam, upstream) = setup().await;
+
+    // Send several requests on one connection, to make sure keep-alive works over TLS too
+    let client = https_client();
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = client
+            .get(&format!("https://{}{}", balancebeam.address, path))
+            .header("x-sent-by", "balancebeam-tests")
+            .send()
+            .await
+            .expect("Error sending HTTPS request to balancebeam")
+            .text()
+            .await
+            .unwrap();
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+        assert!(response_text.contains("proto=https"));
+    }
+
+    log::info!("Making sure plain HTTP is refused");
+    assert!(balancebeam.get("/plain").await.is_err());
+
+    assert_eq!(Box::new(upstream).stop().await, n_requests);
+
+    log::info!("All done :)");
+}
+
+/// With --tls-client-ca, only clients presenting a certificate signed by that CA should get
+/// through
+#[tokio::test]
+async fn test_tls_client_auth() {
+    let (ca, ca_path) = make_ca();
+    let (balancebeam, upstream) = setup_with_args(&["--tls-client-ca", &ca_path]).await;
+    let port = balancebeam.address.rsplit(':').next().unwrap();
+    let url = format!("https://localhost:{}/authenticated", port);
+
+    log::info!("Making sure clients without a certificate are refused");
+    let client = https_client_with_identity(None);
+    assert!(client.get(&url).send().await.is_err());
+
+    log::info!("Making sure clients with a certificate from some other CA are refused");
+    let (other_ca, _) = make_ca();
+    let client = https_client_with_identity(Some(make_client_identity(&other_ca)));
+    assert!(client.get(&url).send().await.is_err());
+
+    log::info!("Making sure clients with a trusted certificate get through");
+    let client = https_client_with_identity(Some(make_client_identity(&ca)));
+    let response_text = client
+        .get(&url)
+        .header("x-sent-by", "balancebeam-tests")
+        .send()
+        .await
+        .expect("Error sending HTTPS request to balancebeam")
+        .text()
+        .await
+        .unwrap();
+    assert!(response_text.contains("GET /authenticated HTTP/1.1"));
+
+    // None of the refused connections should have reached the upstream
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// Upstreams given as tls://host:port should be spoken to over TLS, with the configured SNI name,
+/// and with their certificates verified against --upstream-tls-ca
+#[tokio::test]
+async fn test_tls_upstream() {
+    init_logging();
+    let n_requests = 3;
+    let (ca, ca_path) = make_ca();
+    let (cert_pem, key_pem) = make_signed_certificate(&ca);
+    let (upstream_address, requests_received) = start_tls_upstream(&cert_pem, &key_pem).await;
+    let upstream = format!("tls://{},sni=localhost", upstream_address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        Some(3600),
+        None,
+        &["--upstream-tls-ca", &ca_path],
+    )
+    .await;
+
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {}", path)));
+        assert!(response_text.contains("sni: Some(\"localhost\")"));
+    }
+    assert_eq!(requests_received.load(Ordering::SeqCst), n_requests);
+
+    log::info!("All done :)");
+}
+
+/// An upstream whose certificate can't be verified should be treated like one that's down, unless
+/// verification is turned off for it
+#[tokio::test]
+async fn test_tls_upstream_verification() {
+    init_logging();
+    let (ca, _ca_path) = make_ca();
+    let (cert_pem, key_pem) = make_signed_certificate(&ca);
+    let (upstream_address, requests_received) = start_tls_upstream(&cert_pem, &key_pem).await;
+
+    log::info!("Making sure a certificate from an unknown CA is rejected");
+    let upstream = format!("tls://{},sni=localhost", upstream_address);
+    let balancebeam = BalanceBeam::new(&[&upstream], Some(3600), None).await;
+    let response = reqwest::get(&format!("http://{}/verified", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status(), reqwest::StatusCode::BAD_GATEWAY);
+    assert_eq!(requests_received.load(Ordering::SeqCst), 0);
+
+    log::info!("Making sure verify=false accepts it anyway");
+    let upstream = format!("tls://{},sni=localhost,verify=false", upstream_address);
+    let balancebeam = BalanceBeam::new(&[&upstream], Some(3600), None).await;
+    let response_text = balancebeam
+        .get("/unverified")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /unverified"));
+    assert_eq!(requests_received.load(Ordering::SeqCst), 1);
+
+    log::info!("All done :)");
+}
+
+/// With --http2, clients that negotiate HTTP/2 should be able to send several requests at once
+/// over one connection, and the requests should still reach the upstream as HTTP/1.1
+#[tokio::test]
+async fn test_http2() {
+    let n_requests = 5;
+    let (balancebeam, upstream) = setup_with_args(&["--http2"]).await;
+    let port = balancebeam.address.rsplit(':').next().unwrap();
+
+    let client = https_client_with_identity(None);
+    let mut requests = Vec::new();
+    for i in 0..n_requests {
+        let client = client.clone();
+        let url = format!("https://localhost:{}/request-{}", port, i);
+        requests.push(tokio::spawn(async move {
+            client
+                .post(&url)
+                .header("x-sent-by", "balancebeam-tests")
+                .body(format!("body-{}", i))
+                .send()
+                .await
+                .expect("Error sending HTTP/2 request to balancebeam")
+        }));
+    }
+    for (i, request) in requests.into_iter().enumerate() {
+        let response = request.await.unwrap();
+        assert_eq!(response.version(), reqwest::Version::HTTP_2);
+        let response_text = response.text().await.unwrap();
+        assert!(response_text.contains(&format!("POST /request-{} HTTP/1.1", i)));
+        assert!(response_text.contains(&format!("host: localhost:{}", port)));
+        assert!(response_text.contains("proto=https"));
+        assert!(response_text.ends_with(&format!("body-{}", i)));
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, n_requests);
+
+    log::info!("All done :)");
+}
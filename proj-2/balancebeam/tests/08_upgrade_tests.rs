@@ -0,0 +1,122 @@
+mod common;
+
+use common::{init_logging, random_local_address, BalanceBeam, EchoServer, Server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Reads from stream until the end of an HTTP message head, and returns the head
+async fn read_head(stream: &mut TcpStream) -> String {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0_u8; 1];
+        if stream.read(&mut byte).await.unwrap() == 0 {
+            break;
+        }
+        head.push(byte[0]);
+    }
+    String::from_utf8(head).unwrap()
+}
+
+/// Starts an upstream that accepts one WebSocket upgrade, and then echoes back whatever it's sent
+/// (uppercased, so the test can tell it apart from balancebeam echoing). Returns its address, and
+/// a handle that resolves to the upgrade request it received.
+async fn start_websocket_upstream() -> (String, tokio::task::JoinHandle<String>) {
+    let address = random_local_address();
+    let mut listener = TcpListener::bind(&address).await.unwrap();
+    let handle = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let request = read_head(&mut stream).await;
+        stream
+            .write_all(
+                b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                  Connection: Upgrade\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut buffer = [0_u8; 512];
+        loop {
+            let n = stream.read(&mut buffer).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            stream
+                .write_all(&buffer[..n].to_ascii_uppercase())
+                .await
+                .unwrap();
+        }
+        request
+    });
+    (address, handle)
+}
+
+/// Once an upstream accepts a WebSocket upgrade, balancebeam should pass bytes back and forth
+/// between it and the client untouched
+#[tokio::test]
+async fn test_websocket_upgrade() {
+    init_logging();
+    let (upstream_address, upstream) = start_websocket_upstream().await;
+    let balancebeam = BalanceBeam::new(&[&upstream_address], Some(3600), None).await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client
+        .write_all(
+            b"GET /chat HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade\r\n\
+              Upgrade: websocket\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let response = read_head(&mut client).await;
+    assert!(response.starts_with("HTTP/1.1 101"), "{}", response);
+    assert!(response.to_lowercase().contains("upgrade: websocket"));
+
+    log::info!("Sending data through the tunnel");
+    for message in &["hello", "world"] {
+        client.write_all(message.as_bytes()).await.unwrap();
+        let mut echoed = vec![0_u8; message.len()];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(echoed, message.to_uppercase().as_bytes());
+    }
+
+    log::info!("Closing the client side, which should close the upstream side too");
+    client.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut rest = Vec::new();
+    client.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+    let request = upstream.await.unwrap().to_lowercase();
+    assert!(request.contains("get /chat http/1.1"));
+    assert!(request.contains("upgrade: websocket"));
+
+    log::info!("All done :)");
+}
+
+/// If the upstream answers an upgrade request with anything other than 101, the connection should
+/// carry on as plain HTTP
+#[tokio::test]
+async fn test_upgrade_declined() {
+    init_logging();
+    let n_requests = 3;
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], Some(3600), None).await;
+
+    let client = reqwest::Client::new();
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .header("connection", "upgrade")
+            .header("upgrade", "websocket")
+            .header("x-sent-by", "balancebeam-tests")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .unwrap();
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        assert!(response_text.contains("upgrade: websocket"));
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, n_requests);
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,136 @@
+mod common;
+
+use common::{init_logging, random_local_address, BalanceBeam, EchoServer, Server};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Response};
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+use tokio::time::{delay_for, Duration};
+
+/// An SRV record's (priority, weight, port). The target is always 127.0.0.1.
+type SrvRecord = (u16, u16, u16);
+
+/// Starts a DNS server that answers every query with the given SRV records, and returns its
+/// address. The records can be changed while it's running.
+async fn start_dns_server(records: Arc<Mutex<Vec<SrvRecord>>>) -> String {
+    let address = random_local_address();
+    let mut socket = UdpSocket::bind(&address)
+        .await
+        .expect("Could not bind DNS server");
+    tokio::spawn(async move {
+        let mut query = vec![0; 512];
+        loop {
+            let (len, client) = match socket.recv_from(&mut query).await {
+                Ok(received) => received,
+                Err(_) => return,
+            };
+            let records = records.lock().unwrap().clone();
+            // Header: the query's ID, then "response, recursion available, no error", one question,
+            // and an answer per record
+            let mut answer = query[..2].to_vec();
+            answer.extend_from_slice(&[0x81, 0x80, 0, 1]);
+            answer.extend_from_slice(&(records.len() as u16).to_be_bytes());
+            answer.extend_from_slice(&[0, 0, 0, 0]);
+            // Echo the question back
+            answer.extend_from_slice(&query[12..len]);
+            for (priority, weight, port) in records {
+                // Name: a pointer to the question's name. Type SRV, class IN, TTL 30
+                answer.extend_from_slice(&[0xc0, 12, 0, 33, 0, 1, 0, 0, 0, 30]);
+                let target: &[u8] = b"\x03127\x010\x010\x011\x00";
+                answer.extend_from_slice(&((6 + target.len()) as u16).to_be_bytes());
+                answer.extend_from_slice(&priority.to_be_bytes());
+                answer.extend_from_slice(&weight.to_be_bytes());
+                answer.extend_from_slice(&port.to_be_bytes());
+                answer.extend_from_slice(target);
+            }
+            let _ = socket.send_to(&answer, &client).await;
+        }
+    });
+    address
+}
+
+fn port_of(server: &EchoServer) -> u16 {
+    server.address.rsplit_once(':').unwrap().1.parse().unwrap()
+}
+
+async fn send_requests(balancebeam: &BalanceBeam, n_requests: usize) {
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+}
+
+/// Upstreams should be found through an SRV record, with only the lowest-priority targets getting
+/// requests, and the upstream list should follow the record when it changes
+#[tokio::test]
+async fn test_srv_discovery() {
+    init_logging();
+    let n_requests = 6;
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let records = Arc::new(Mutex::new(vec![
+        (10, 1, port_of(&first)),
+        (20, 1, port_of(&second)),
+    ]));
+    let dns_server = start_dns_server(records.clone()).await;
+    let source = format!("srv:_http._tcp.api.test@{}", dns_server);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        None,
+        None,
+        &["--discover", &source, "--discovery-interval", "1"],
+    )
+    .await;
+
+    send_requests(&balancebeam, n_requests).await;
+    assert_eq!(first.requests_received(), n_requests);
+    assert_eq!(second.requests_received(), 0);
+
+    log::info!("Changing the SRV record so that only the second upstream is listed");
+    *records.lock().unwrap() = vec![(10, 1, port_of(&second))];
+    delay_for(Duration::from_secs(2)).await;
+    send_requests(&balancebeam, n_requests).await;
+    assert_eq!(Box::new(first).stop().await, n_requests);
+    assert_eq!(Box::new(second).stop().await, n_requests);
+}
+
+/// Upstreams should be found through a Consul agent's health API
+#[tokio::test]
+async fn test_consul_discovery() {
+    init_logging();
+    let n_requests = 6;
+    let upstream = EchoServer::new().await;
+    let catalog = format!(
+        r#"[{{"Node": {{"Address": "127.0.0.1"}}, "Service": {{"Address": "", "Port": {}}}}}]"#,
+        port_of(&upstream)
+    );
+    let agent_address = random_local_address();
+    let bind_addr = agent_address.parse().unwrap();
+    tokio::spawn(async move {
+        let service = make_service_fn(move |_| {
+            let catalog = catalog.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let response = if req.uri().path() == "/v1/health/service/api" {
+                        Response::new(Body::from(catalog.clone()))
+                    } else {
+                        Response::builder().status(404).body(Body::empty()).unwrap()
+                    };
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+        let _ = hyper::Server::bind(&bind_addr).serve(service).await;
+    });
+    delay_for(Duration::from_millis(100)).await;
+
+    let source = format!("consul:{}/api", agent_address);
+    let balancebeam = BalanceBeam::new_with_args(&[], None, None, &["--discover", &source]).await;
+    send_requests(&balancebeam, n_requests).await;
+    assert_eq!(Box::new(upstream).stop().await, n_requests);
+}
@@ -1,4 +1,6 @@
-use rand::Rng;
+use crate::common::random_local_address;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::{Child, Command};
@@ -8,6 +10,7 @@ pub struct BalanceBeam {
     #[allow(dead_code)]
     child: Child, // process is killed when dropped (Command::kill_on_drop)
     pub address: String,
+    output: Arc<Mutex<Vec<String>>>,
 }
 
 impl BalanceBeam {
@@ -19,13 +22,30 @@ impl BalanceBeam {
         path
     }
 
+    #[allow(dead_code)]
     pub async fn new(
         upstreams: &[&str],
         active_health_check_interval: Option<usize>,
         max_requests_per_minute: Option<usize>,
     ) -> BalanceBeam {
-        let mut rng = rand::thread_rng();
-        let address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
+        BalanceBeam::new_with_args(
+            upstreams,
+            active_health_check_interval,
+            max_requests_per_minute,
+            &[],
+        )
+        .await
+    }
+
+    /// Same as `new`, but passes any additional command-line arguments through to balancebeam
+    #[allow(dead_code)]
+    pub async fn new_with_args(
+        upstreams: &[&str],
+        active_health_check_interval: Option<usize>,
+        max_requests_per_minute: Option<usize>,
+        extra_args: &[&str],
+    ) -> BalanceBeam {
+        let address = random_local_address();
         let mut cmd = Command::new(BalanceBeam::target_bin_path());
         cmd.arg("--bind").arg(&address);
         for upstream in upstreams {
@@ -39,21 +59,63 @@ impl BalanceBeam {
             cmd.arg("--max-requests-per-minute")
                 .arg(max_requests_per_minute.to_string());
         }
+        cmd.args(extra_args);
+        BalanceBeam::start(cmd, address).await
+    }
+
+    /// Starts balancebeam the way systemd socket activation would: with listener already bound and
+    /// passed in as fd 3, and LISTEN_PID/LISTEN_FDS set to say so
+    #[allow(dead_code)]
+    pub async fn new_socket_activated(
+        listener: std::net::TcpListener,
+        upstreams: &[&str],
+        extra_args: &[&str],
+    ) -> BalanceBeam {
+        let address = listener.local_addr().unwrap().to_string();
+        // LISTEN_PID has to be balancebeam's pid, which we don't know until we've forked, so have
+        // a shell set it and then exec balancebeam in its place
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c")
+            .arg("LISTEN_PID=$$ LISTEN_FDS=1 exec \"$0\" \"$@\"")
+            .arg(BalanceBeam::target_bin_path());
+        for upstream in upstreams {
+            cmd.arg("--upstream").arg(upstream);
+        }
+        cmd.args(extra_args);
+        let listener_fd = listener.as_raw_fd();
+        // Safety: dup2 is async-signal-safe, so it's fine to call between fork and exec
+        unsafe {
+            cmd.pre_exec(move || {
+                nix::unistd::dup2(listener_fd, 3)
+                    .map(|_| ())
+                    .map_err(std::io::Error::other)
+            });
+        }
+        let balancebeam = BalanceBeam::start(cmd, address).await;
+        drop(listener);
+        balancebeam
+    }
+
+    async fn start(mut cmd: Command, address: String) -> BalanceBeam {
         cmd.kill_on_drop(true);
         cmd.stdout(std::process::Stdio::piped());
         cmd.stderr(std::process::Stdio::piped());
-        let mut child = cmd.spawn().expect(&format!(
-            "Could not execute balancebeam binary {}",
-            BalanceBeam::target_bin_path().to_str().unwrap()
-        ));
+        let mut child = cmd.spawn().unwrap_or_else(|_| {
+            panic!(
+                "Could not execute balancebeam binary {}",
+                BalanceBeam::target_bin_path().to_str().unwrap()
+            )
+        });
 
         // Print output from the child. We want to intercept and log this output (instead of letting
         // the child inherit stderr and print directly to the terminal) so that the output can be
         // suppressed if the test passes and displayed if it fails.
+        let output = Arc::new(Mutex::new(Vec::new()));
         let stdout = child
             .stdout
             .take()
             .expect("Child process somehow missing stdout pipe!");
+        let stdout_output = output.clone();
         tokio::spawn(async move {
             let mut stdout_reader = BufReader::new(stdout).lines();
             while let Some(line) = stdout_reader
@@ -62,12 +124,14 @@ impl BalanceBeam {
                 .expect("I/O error reading from child stdout")
             {
                 println!("Balancebeam output: {}", line);
+                stdout_output.lock().unwrap().push(line);
             }
         });
         let stderr = child
             .stderr
             .take()
             .expect("Child process somehow missing stderr pipe!");
+        let stderr_output = output.clone();
         tokio::spawn(async move {
             let mut stderr_reader = BufReader::new(stderr).lines();
             while let Some(line) = stderr_reader
@@ -76,12 +140,38 @@ impl BalanceBeam {
                 .expect("I/O error reading from child stderr")
             {
                 println!("Balancebeam output: {}", line);
+                stderr_output.lock().unwrap().push(line);
             }
         });
 
         // Hack: wait for executable to start running
         delay_for(Duration::from_secs(1)).await;
-        BalanceBeam { child, address }
+        BalanceBeam {
+            child,
+            address,
+            output,
+        }
+    }
+
+    /// Returns every line balancebeam has printed so far (stdout and stderr interleaved)
+    #[allow(dead_code)]
+    pub fn output(&self) -> Vec<String> {
+        self.output.lock().unwrap().clone()
+    }
+
+    /// Waits for the balancebeam process to exit, returning its exit status
+    #[allow(dead_code)]
+    pub async fn wait(&mut self) -> std::process::ExitStatus {
+        (&mut self.child)
+            .await
+            .expect("Error waiting for balancebeam to exit")
+    }
+
+    /// Sends a signal (e.g. SIGHUP) to the balancebeam process
+    #[allow(dead_code)]
+    pub fn send_signal(&self, signal: nix::sys::signal::Signal) {
+        let pid = nix::unistd::Pid::from_raw(self.child.id() as i32);
+        nix::sys::signal::kill(pid, signal).expect("Could not send signal to balancebeam");
     }
 
     #[allow(dead_code)]
@@ -1,14 +1,17 @@
+use crate::common::random_local_address;
 use crate::common::server::Server;
 use async_trait::async_trait;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response};
-use rand::Rng;
 use std::sync::{atomic, Arc};
 use tokio::sync::oneshot;
+use tokio::time::{delay_for, Duration};
 
 #[derive(Debug)]
 struct ServerState {
     pub requests_received: atomic::AtomicUsize,
+    /// How long to wait before responding to each request
+    pub response_delay: Duration,
 }
 
 async fn echo(
@@ -18,6 +21,9 @@ async fn echo(
     server_state
         .requests_received
         .fetch_add(1, atomic::Ordering::SeqCst);
+    if server_state.response_delay > Duration::from_secs(0) {
+        delay_for(server_state.response_delay).await;
+    }
     let mut req_text = format!("{} {} {:?}\n", req.method(), req.uri(), req.version());
     for (header_name, header_value) in req.headers() {
         req_text += &format!(
@@ -40,12 +46,23 @@ pub struct EchoServer {
 }
 
 impl EchoServer {
+    #[allow(dead_code)]
     pub async fn new() -> EchoServer {
-        let mut rng = rand::thread_rng();
-        EchoServer::new_at_address(format!("127.0.0.1:{}", rng.gen_range(1024, 65535))).await
+        EchoServer::new_at_address(random_local_address()).await
+    }
+
+    /// Creates an EchoServer that takes response_delay to respond to each request
+    #[allow(dead_code)]
+    pub async fn new_with_delay(response_delay: Duration) -> EchoServer {
+        EchoServer::start(random_local_address(), response_delay).await
     }
 
+    #[allow(dead_code)]
     pub async fn new_at_address(bind_addr_string: String) -> EchoServer {
+        EchoServer::start(bind_addr_string, Duration::from_secs(0)).await
+    }
+
+    async fn start(bind_addr_string: String, response_delay: Duration) -> EchoServer {
         let bind_addr = bind_addr_string.parse().unwrap();
         // Create a one-shot channel that can be used to tell the server to shut down
         let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
@@ -53,6 +70,7 @@ impl EchoServer {
         // Start a separate server task
         let server_state = Arc::new(ServerState {
             requests_received: atomic::AtomicUsize::new(0),
+            response_delay,
         });
         let server_task_state = server_state.clone();
         let server_task = tokio::spawn(async move {
@@ -101,4 +119,8 @@ impl Server for EchoServer {
     fn address(&self) -> String {
         self.address.clone()
     }
+
+    fn requests_received(&self) -> usize {
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
 }
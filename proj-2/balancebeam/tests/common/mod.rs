@@ -3,11 +3,13 @@ mod echo_server;
 mod error_server;
 mod server;
 
+use rand::Rng;
 use std::sync;
 use tokio::time::{advance, pause, resume, Duration};
 
 pub use balancebeam::BalanceBeam;
 pub use echo_server::EchoServer;
+#[allow(unused_imports)]
 pub use error_server::ErrorServer;
 pub use server::Server;
 
@@ -22,6 +24,13 @@ pub fn init_logging() {
     });
 }
 
+/// Picks a random localhost address to bind a test server to. Ports are chosen below Linux's
+/// ephemeral port range (32768+) so they don't collide with the source ports of client sockets.
+pub fn random_local_address() -> String {
+    format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024, 32768))
+}
+
+#[allow(dead_code)]
 pub async fn skip_time(duration: Duration) {
     pause();
     advance(duration).await;
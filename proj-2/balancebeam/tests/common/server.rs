@@ -3,5 +3,9 @@ use async_trait::async_trait;
 #[async_trait]
 pub trait Server {
     async fn stop(self: Box<Self>) -> usize;
+    #[allow(dead_code)]
     fn address(&self) -> String;
+    /// Number of requests received so far, without stopping the server
+    #[allow(dead_code)]
+    fn requests_received(&self) -> usize;
 }
@@ -15,87 +15,304 @@
 
 extern crate rand;
 use rand::Rng;
+use std::env;
 use std::fs;
 use std::io;
 use std::io::Write;
+use std::process;
 
 const NUM_INCORRECT_GUESSES: u32 = 5;
 const WORDS_PATH: &str = "words.txt";
 
-fn pick_a_random_word() -> String {
-    let file_string = fs::read_to_string(WORDS_PATH).expect("Unable to read file.");
-    let words: Vec<&str> = file_string.split('\n').collect();
-    String::from(words[rand::thread_rng().gen_range(0, words.len())].trim())
+/// The parts of the figure, in the order they're drawn. Each entry is (row, column, character),
+/// where rows and columns index into GALLOWS.
+const FIGURE_PARTS: [(usize, usize, char); 6] = [
+    (2, 2, 'O'),
+    (3, 2, '|'),
+    (3, 1, '/'),
+    (3, 3, '\\'),
+    (4, 1, '/'),
+    (4, 3, '\\'),
+];
+const GALLOWS: [&str; 6] = [
+    "  +---+", //
+    "  |   |", //
+    "      |", //
+    "      |", //
+    "      |", //
+    "=======",
+];
+
+/// Returns the ASCII-art hangman after num_incorrect wrong guesses out of max_incorrect allowed.
+/// The figure's parts are spread evenly over max_incorrect guesses, so the gallows is empty before
+/// any wrong guess and the figure is complete exactly when the player runs out of guesses.
+fn draw_hangman(num_incorrect: u32, max_incorrect: u32) -> String {
+    let num_parts = FIGURE_PARTS.len() as u32;
+    let max_incorrect = max_incorrect.max(1);
+    // Rounding down means the last part is only drawn on the final wrong guess
+    let num_drawn = num_incorrect.min(max_incorrect) * num_parts / max_incorrect;
+    let mut rows: Vec<Vec<char>> = GALLOWS.iter().map(|row| row.chars().collect()).collect();
+    for &(row, col, c) in FIGURE_PARTS.iter().take(num_drawn as usize) {
+        rows[row][col] = c;
+    }
+    rows.iter()
+        .map(|row| row.iter().collect::<String>())
+        .collect::<Vec<String>>()
+        .join("\n")
 }
 
-fn main() {
-    println!("Welcome to CS110L Hangman!");
-    let secret_word = pick_a_random_word();
+/// Parses the command line, returning the maximum number of incorrect guesses to allow
+fn parse_args() -> u32 {
+    let args: Vec<String> = env::args().collect();
+    match args.len() {
+        1 => NUM_INCORRECT_GUESSES,
+        3 if args[1] == "--max-guesses" => match args[2].parse::<u32>() {
+            Ok(max_guesses) if max_guesses > 0 => max_guesses,
+            _ => {
+                println!("--max-guesses must be a positive integer");
+                process::exit(1);
+            }
+        },
+        _ => {
+            println!("Usage: {} [--max-guesses N]", args[0]);
+            process::exit(1);
+        }
+    }
+}
+
+/// Picks a random word from the file at words_path. If previous is given (and the file has
+/// another word to offer), the new word is guaranteed to differ from it, so that playing again
+/// doesn't repeat the same word.
+fn pick_a_random_word(words_path: &str, previous: Option<&str>) -> String {
+    let file_string = fs::read_to_string(words_path).expect("Unable to read file.");
+    let words: Vec<&str> = file_string
+        .split('\n')
+        .map(|word| word.trim())
+        .filter(|word| !word.is_empty())
+        .collect();
+    let candidates: Vec<&str> = match previous {
+        Some(previous) if words.iter().any(|word| *word != previous) => words
+            .iter()
+            .filter(|word| **word != previous)
+            .cloned()
+            .collect(),
+        _ => words,
+    };
+    String::from(candidates[rand::thread_rng().gen_range(0, candidates.len())])
+}
+
+/// The outcome of guessing a single letter
+#[derive(Debug, PartialEq)]
+enum GuessResult {
+    AlreadyGuessed,
+    Correct,
+    Incorrect,
+}
+
+/// The state of a single round of hangman
+struct Game {
     // Note: given what you know about Rust so far, it's easier to pull characters out of a
     // vector than it is to pull them out of a string. You can get the ith character of
     // secret_word by doing secret_word_chars[i].
-    let secret_word_chars: Vec<char> = secret_word.chars().collect();
-    // Uncomment for debugging:
-    dbg!(secret_word);
-
-    // Your code here! :)
-
-    let mut i = 0;
-    let mut guessed_i: Vec<usize> = Vec::new();
-    let mut guessed_c: Vec<char> = Vec::new();
-
-    while i < NUM_INCORRECT_GUESSES && guessed_i.len() != secret_word_chars.len() {
-        print!("\nThe word so far is: ");
-        for x in 0..secret_word_chars.len() {
-            print!(
-                "{}",
-                if guessed_i.contains(&x) {
-                    secret_word_chars.get(x).unwrap()
+    secret_word_chars: Vec<char>,
+    guessed_i: Vec<usize>,
+    guessed_c: Vec<char>,
+    num_incorrect: u32,
+    max_incorrect: u32,
+}
+
+impl Game {
+    fn new(secret_word: &str, max_incorrect: u32) -> Game {
+        Game {
+            secret_word_chars: secret_word.chars().collect(),
+            guessed_i: Vec::new(),
+            guessed_c: Vec::new(),
+            num_incorrect: 0,
+            max_incorrect,
+        }
+    }
+
+    fn secret_word(&self) -> String {
+        self.secret_word_chars.iter().collect()
+    }
+
+    /// Returns the word with every letter that hasn't been guessed yet replaced by '_'
+    fn masked_word(&self) -> String {
+        (0..self.secret_word_chars.len())
+            .map(|x| {
+                if self.guessed_i.contains(&x) {
+                    self.secret_word_chars[x]
                 } else {
-                    &'_'
+                    '_'
                 }
-            );
-        }
+            })
+            .collect()
+    }
 
-        print!("\nYou have guessed the following letter:");
-        for x in &guessed_c {
-            print!("{}", *x);
-        }
-        println!("\nYou have {} guesses left.", NUM_INCORRECT_GUESSES - i);
-        io::stdout().flush().expect("Error flushing stdout.");
+    fn guesses_left(&self) -> u32 {
+        self.max_incorrect - self.num_incorrect
+    }
 
-        let mut guess = String::new();
-        print!("Please guess a letter: ");
-        io::stdout().flush().expect("Error flushing stdout.");
-        io::stdin()
-            .read_line(&mut guess)
-            .expect("Failed to read guess.");
-        let guess_c = guess.trim().chars().next().unwrap();
-        if !guessed_c.contains(&guess_c) {
-            guessed_c.push(guess_c);
-        } else {
-            println!("letter already guessed");
-            continue;
+    fn is_won(&self) -> bool {
+        self.guessed_i.len() == self.secret_word_chars.len()
+    }
+
+    fn is_lost(&self) -> bool {
+        self.num_incorrect == self.max_incorrect
+    }
+
+    fn guess(&mut self, guess_c: char) -> GuessResult {
+        if self.guessed_c.contains(&guess_c) {
+            return GuessResult::AlreadyGuessed;
         }
+        self.guessed_c.push(guess_c);
 
         let mut did_guess = false;
-        for x in 0..secret_word_chars.len() {
-            if *secret_word_chars.get(x).unwrap() == guess_c {
-                guessed_i.push(x);
+        for x in 0..self.secret_word_chars.len() {
+            if self.secret_word_chars[x] == guess_c {
+                self.guessed_i.push(x);
                 did_guess = true;
             }
         }
 
-        if !did_guess {
-            println!("Sorry, that letter is not in the word");
-            i += 1;
+        if did_guess {
+            GuessResult::Correct
+        } else {
+            self.num_incorrect += 1;
+            GuessResult::Incorrect
+        }
+    }
+}
+
+/// Reads a line from stdin, returning None if stdin has been closed
+fn read_line() -> Option<String> {
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line),
+    }
+}
+
+/// Plays a single round. Returns Some(true) if the player won, Some(false) if they lost, or None
+/// if they quit (by closing stdin) partway through.
+fn play(game: &mut Game) -> Option<bool> {
+    while !game.is_won() && !game.is_lost() {
+        println!("\n{}", draw_hangman(game.num_incorrect, game.max_incorrect));
+        println!("The word so far is: {}", game.masked_word());
+        print!("You have guessed the following letter:");
+        for x in &game.guessed_c {
+            print!("{}", *x);
+        }
+        println!("\nYou have {} guesses left.", game.guesses_left());
+        print!("Please guess a letter: ");
+        io::stdout().flush().expect("Error flushing stdout.");
+
+        let guess = read_line()?;
+        let guess_c = match guess.trim().chars().next() {
+            Some(c) => c,
+            None => continue,
+        };
+        match game.guess(guess_c) {
+            GuessResult::AlreadyGuessed => println!("letter already guessed"),
+            GuessResult::Incorrect => println!("Sorry, that letter is not in the word"),
+            GuessResult::Correct => {}
         }
     }
 
     io::stdout().flush().expect("Error flushing stdout.");
-    if guessed_i.len() == secret_word_chars.len() {
+    if game.is_won() {
         println!("Congratulations! You guessed the secret word!");
-    } else if i == NUM_INCORRECT_GUESSES {
+        Some(true)
+    } else {
+        println!("{}", draw_hangman(game.num_incorrect, game.max_incorrect));
         println!("Sorry! You ran out of guesses!");
+        println!("The word was: {}", game.secret_word());
+        Some(false)
+    }
+}
+
+fn main() {
+    let max_incorrect = parse_args();
+    println!("Welcome to CS110L Hangman!");
+    let mut wins = 0;
+    let mut losses = 0;
+    let mut secret_word = pick_a_random_word(WORDS_PATH, None);
+    loop {
+        // Uncomment for debugging:
+        // dbg!(&secret_word);
+        let mut game = Game::new(&secret_word, max_incorrect);
+        match play(&mut game) {
+            Some(true) => wins += 1,
+            Some(false) => losses += 1,
+            None => break,
+        }
+
+        print!("\nPlay again? (y/n) ");
+        io::stdout().flush().expect("Error flushing stdout.");
+        match read_line() {
+            Some(answer) if answer.trim().to_lowercase().starts_with('y') => {}
+            _ => break,
+        }
+        secret_word = pick_a_random_word(WORDS_PATH, Some(&secret_word));
+    }
+    println!(
+        "\nYou won {} and lost {}. Thanks for playing!",
+        wins, losses
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_loss_reveals_word() {
+        let mut game = Game::new("crawfish", NUM_INCORRECT_GUESSES);
+        for c in "zqxjv".chars() {
+            assert_eq!(game.guess(c), GuessResult::Incorrect);
+        }
+        assert!(game.is_lost());
+        assert!(!game.is_won());
+        assert_eq!(game.masked_word(), "________");
+        assert_eq!(game.secret_word(), "crawfish");
+    }
+
+    #[test]
+    fn test_guesses() {
+        let mut game = Game::new("shared", NUM_INCORRECT_GUESSES);
+        assert_eq!(game.guess('s'), GuessResult::Correct);
+        assert_eq!(game.guess('s'), GuessResult::AlreadyGuessed);
+        assert_eq!(game.guess('z'), GuessResult::Incorrect);
+        assert_eq!(game.masked_word(), "s_____");
+        assert_eq!(game.guesses_left(), NUM_INCORRECT_GUESSES - 1);
+        for c in "hared".chars() {
+            game.guess(c);
+        }
+        assert!(game.is_won());
+    }
+
+    #[test]
+    fn test_new_game_picks_fresh_word() {
+        let mut previous = pick_a_random_word(WORDS_PATH, None);
+        for _ in 0..20 {
+            let word = pick_a_random_word(WORDS_PATH, Some(&previous));
+            assert!(!word.is_empty());
+            assert_ne!(word, previous);
+            previous = word;
+        }
+    }
+
+    #[test]
+    fn test_draw_hangman() {
+        let empty = "  +---+\n  |   |\n      |\n      |\n      |\n=======";
+        let full = "  +---+\n  |   |\n  O   |\n /|\\  |\n / \\  |\n=======";
+        for &max in [1, NUM_INCORRECT_GUESSES, 6, 10].iter() {
+            assert_eq!(draw_hangman(0, max), empty);
+            assert_eq!(draw_hangman(max, max), full);
+            // The figure should never be complete before the player is out of guesses
+            assert_ne!(draw_hangman(max - 1, max), full);
+        }
+        // With more guesses than parts, some wrong guesses don't add a part
+        assert_eq!(draw_hangman(2, 12), draw_hangman(3, 12));
     }
 }
@@ -13,9 +13,9 @@ fn read_file_lines(filename: &String) -> Result<Vec<String>, io::Error> {
     Ok(lines)
 }
 
-fn read_words(lines: &Vec<String>) -> Vec<String> {
+fn read_words(lines: &[String]) -> Vec<String> {
     lines
-        .into_iter()
+        .iter()
         .flat_map(|l| l.split_whitespace())
         .fold(Vec::new(), |mut acc, w| {
             acc.push(String::from(w));
@@ -23,9 +23,25 @@ fn read_words(lines: &Vec<String>) -> Vec<String> {
         })
 }
 
-fn read_chars(lines: &Vec<String>) -> Vec<String> {
+/// Splits every line on the delimiter (like `awk -F`) and returns the fields. Consecutive
+/// delimiters produce empty fields, which are only kept if keep_empty is set. Empty lines have no
+/// fields.
+fn read_fields(lines: &[String], delimiter: &str, keep_empty: bool) -> Vec<String> {
     lines
-        .into_iter()
+        .iter()
+        .map(|l| l.trim_end_matches('\n'))
+        .filter(|l| !l.is_empty())
+        .flat_map(|l| l.split(delimiter))
+        .filter(|f| keep_empty || !f.is_empty())
+        .fold(Vec::new(), |mut acc, f| {
+            acc.push(String::from(f));
+            acc
+        })
+}
+
+fn read_chars(lines: &[String]) -> Vec<String> {
+    lines
+        .iter()
         .flat_map(|l| l.chars())
         .fold(Vec::new(), |mut acc, c| {
             acc.push(String::from(c));
@@ -33,15 +49,51 @@ fn read_chars(lines: &Vec<String>) -> Vec<String> {
         })
 }
 
+fn print_usage() {
+    println!("Usage: rwc [--fields [--delimiter <delim> | --tab] [--skip-empty]] <file>");
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        println!("Too few arguments.");
-        process::exit(1);
+    let mut fields_mode = false;
+    let mut delimiter = String::from(",");
+    let mut keep_empty = true;
+    let mut filename = None;
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--fields" => fields_mode = true,
+            "--tab" => delimiter = String::from("\t"),
+            "--skip-empty" => keep_empty = false,
+            "--delimiter" | "-d" => {
+                i += 1;
+                match args.get(i) {
+                    Some(d) if !d.is_empty() => delimiter = d.clone(),
+                    _ => {
+                        print_usage();
+                        process::exit(1);
+                    }
+                }
+            }
+            _ => filename = Some(&args[i]),
+        }
+        i += 1;
     }
-    let filename = &args[1];
+    let filename = match filename {
+        Some(filename) => filename,
+        None => {
+            println!("Too few arguments.");
+            print_usage();
+            process::exit(1);
+        }
+    };
 
     let lines = read_file_lines(filename).unwrap();
+    if fields_mode {
+        let fields = read_fields(&lines, &delimiter, keep_empty);
+        println!("{} {} {}", lines.len(), fields.len(), filename);
+        return;
+    }
     let words = read_words(&lines);
     let chars = read_chars(&lines); // reason for having it on basis of lines and not words is that ' ' and '\n'
     println!(
@@ -74,6 +126,18 @@ mod test {
         assert_eq!(words[0], "ab");
     }
 
+    #[test]
+    fn test_read_fields() {
+        let lines = read_file_lines(&String::from("simple.csv")).unwrap();
+        let fields = read_fields(&lines, ",", true);
+        assert_eq!(fields.len(), 15);
+        assert_eq!(fields[0], "name");
+        assert_eq!(fields[7], "");
+        let fields = read_fields(&lines, ",", false);
+        assert_eq!(fields.len(), 10);
+        assert_eq!(fields[7], "london");
+    }
+
     #[test]
     fn test_read_chars() {
         let lines = read_file_lines(&String::from("simple.txt")).unwrap();
@@ -62,6 +62,38 @@ impl<T> LinkedList<T> {
         self.size -= 1;
         Some(node.value)
     }
+
+    /// Returns true if following next pointers from the head ever revisits a node, using Floyd's
+    /// tortoise-and-hare algorithm. The Box-based list can't form a cycle through its public API,
+    /// but Drop, Clone, PartialEq and Display all assume it can't, so traversals check this in
+    /// debug builds rather than looping forever if that assumption is ever broken.
+    fn has_cycle(&self) -> bool {
+        let mut slow: &Option<Box<Node<T>>> = &self.head;
+        let mut fast: &Option<Box<Node<T>>> = &self.head;
+        loop {
+            fast = match fast {
+                Some(node) => match &node.next {
+                    Some(next) => &next.next,
+                    None => return false,
+                },
+                None => return false,
+            };
+            if let Some(node) = slow {
+                slow = &node.next;
+            }
+            if let (Some(slow_node), Some(fast_node)) = (slow, fast) {
+                if std::ptr::eq(&**slow_node, &**fast_node) {
+                    return true;
+                }
+            }
+        }
+    }
+}
+
+impl<T> Default for LinkedList<T> {
+    fn default() -> LinkedList<T> {
+        LinkedList::new()
+    }
 }
 
 impl<T> fmt::Display for LinkedList<T>
@@ -69,16 +101,12 @@ where
     T: fmt::Display,
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        debug_assert!(!self.has_cycle());
         let mut current: &Option<Box<Node<T>>> = &self.head;
         let mut result = String::new();
-        loop {
-            match current {
-                Some(node) => {
-                    result = format!("{} {}", result, node.value);
-                    current = &node.next;
-                }
-                None => break,
-            }
+        while let Some(node) = current {
+            result = format!("{} {}", result, node.value);
+            current = &node.next;
         }
         write!(f, "{}", result)
     }
@@ -86,6 +114,7 @@ where
 
 impl<T> Drop for LinkedList<T> {
     fn drop(&mut self) {
+        debug_assert!(!self.has_cycle());
         let mut current = self.head.take();
         while let Some(mut node) = current {
             current = node.next.take();
@@ -98,6 +127,7 @@ where
     T: Clone,
 {
     fn clone(&self) -> LinkedList<T> {
+        debug_assert!(!self.has_cycle());
         LinkedList {
             head: self.head.clone(),
             size: self.size,
@@ -110,6 +140,7 @@ where
     T: PartialEq,
 {
     fn eq(&self, other: &Self) -> bool {
+        debug_assert!(!self.has_cycle() && !other.has_cycle());
         if self.size != other.size {
             false
         } else {
@@ -178,3 +209,22 @@ impl<T> Iterator for LinkedList<T> {
 //         self.iter()
 //     }
 // }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_has_cycle() {
+        let mut list: LinkedList<u32> = LinkedList::new();
+        assert!(!list.has_cycle());
+        for i in 1..12 {
+            list.push_front(i);
+            assert!(!list.has_cycle());
+        }
+        assert!(!list.clone().has_cycle());
+        while list.pop_front().is_some() {
+            assert!(!list.has_cycle());
+        }
+    }
+}
@@ -20,7 +20,8 @@ fn main() {
     println!("top element: {}", list.pop_front().unwrap());
     println!("{}", list);
     println!("size: {}", list.get_size());
-    println!("{}", list.to_string()); // ToString impl for anything impl Display
+    let list_string: String = list.to_string(); // ToString impl for anything impl Display
+    println!("{}", list_string);
     println!("Cloned list: {}", clone_list); // impl Clone
     println!("Match list: {}", clone_list == match_list); // impl PartialEq
     println!("Unmatch list: {}", clone_list == unmatch_list); // impl PartialEq
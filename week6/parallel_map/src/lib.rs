@@ -1,4 +1,5 @@
-use crossbeam_channel;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::{thread, time};
 
 struct ParVal<T> {
@@ -13,51 +14,129 @@ s1  -   r1 -> f() -> s2    -   r2
 
 */
 
-fn parallel_map<T, U, F>(input_vec: Vec<T>, num_threads: usize, f: F) -> Vec<U>
+pub fn parallel_map<T, U, F>(input_vec: Vec<T>, num_threads: usize, f: F) -> Vec<U>
 where
     F: FnOnce(T) -> U + Send + Copy + 'static,
-    T: Send + Copy + 'static,
-    U: Send + 'static + Default + Clone,
+    T: Send + 'static,
+    U: Send + 'static,
 {
-    let mut output_vec: Vec<U> = vec![Default::default(); input_vec.len()];
+    // Nothing ever sets the token, so every item gets computed
+    let (output_vec, _) =
+        parallel_map_cancellable(input_vec, num_threads, Arc::new(AtomicBool::new(false)), f);
+    output_vec
+        .into_iter()
+        .map(|val| val.expect("a worker exited without computing its value"))
+        .collect()
+}
+
+/// Like parallel_map, but can be stopped early from another thread by setting cancel_token. Workers
+/// check the token before starting each item, so cancellation takes effect as soon as the items
+/// currently being processed finish. Returns the output vector, with None for every item that was
+/// never computed, along with whether the map was cancelled (that is, whether any item was left
+/// uncomputed; setting the token after the last item has started changes nothing).
+pub fn parallel_map_cancellable<T, U, F>(
+    input_vec: Vec<T>,
+    num_threads: usize,
+    cancel_token: Arc<AtomicBool>,
+    f: F,
+) -> (Vec<Option<U>>, bool)
+where
+    F: FnOnce(T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    let (output_vec, _) = run_workers(input_vec, num_threads, cancel_token, f);
+    let cancelled = output_vec.iter().any(Option::is_none);
+    (output_vec, cancelled)
+}
+
+/// Statistics about how the work of a parallel_map_stats call was spread across its workers
+#[derive(Debug, Clone)]
+pub struct ParStats {
+    /// Number of items each worker processed
+    pub items_per_worker: Vec<usize>,
+    /// Total time each worker spent inside f
+    pub busy_time_per_worker: Vec<time::Duration>,
+    /// Time the whole map took, from start to finish
+    pub wall_time: time::Duration,
+}
+
+/// Like parallel_map, but also reports per-worker statistics, which is useful for diagnosing load
+/// imbalance when tuning num_threads
+pub fn parallel_map_stats<T, U, F>(
+    input_vec: Vec<T>,
+    num_threads: usize,
+    f: F,
+) -> (Vec<U>, ParStats)
+where
+    F: FnOnce(T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    let (output_vec, stats) =
+        run_workers(input_vec, num_threads, Arc::new(AtomicBool::new(false)), f);
+    let output_vec = output_vec
+        .into_iter()
+        .map(|val| val.expect("a worker exited without computing its value"))
+        .collect();
+    (output_vec, stats)
+}
+
+/// The worker pool behind all the parallel_map variants. Each worker takes items off a shared
+/// channel until it's empty or cancel_token is set (checked before each item), sending back the
+/// results and, once it's done, its own counts over a separate stats channel. Returns the output
+/// vector, with None for every item that was never computed, and the workers' stats.
+fn run_workers<T, U, F>(
+    input_vec: Vec<T>,
+    num_threads: usize,
+    cancel_token: Arc<AtomicBool>,
+    f: F,
+) -> (Vec<Option<U>>, ParStats)
+where
+    F: FnOnce(T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    let start = time::Instant::now();
+    let mut output_vec: Vec<Option<U>> = (0..input_vec.len()).map(|_| None).collect();
     let (s1, r1) = crossbeam_channel::unbounded();
 
-    for (i, num) in input_vec.iter().enumerate() {
-        s1.send(ParVal { num: *num, i })
+    for (i, num) in input_vec.into_iter().enumerate() {
+        s1.send(ParVal { num, i })
             .expect("couldn't send init value");
     }
 
     drop(s1);
     let (s2, r2) = crossbeam_channel::unbounded();
+    let (stats_sender, stats_receiver) = crossbeam_channel::unbounded();
 
     let mut threads = Vec::new();
-    for _ in 0..num_threads {
+    for worker in 0..num_threads {
         let rlone = r1.clone();
         let slone = s2.clone();
+        let stats_sender = stats_sender.clone();
+        let cancel_token = cancel_token.clone();
         let thread = thread::spawn(move || {
-            let start = time::Instant::now();
-            let timeout = crossbeam_channel::after(time::Duration::from_millis(500));
-            loop {
-                crossbeam_channel::select! {
-                    recv(rlone) -> msg => {
-                        if let Ok(p) = msg {
-                            let result = f(p.num);
-                            slone
-                                .send(ParVal {
-                                    num: result,
-                                    i: p.i,
-                                })
-                                .expect("couldn't send final value");
-                        } else {
-                            break;
-                        }
-                    },
-                    recv(timeout) -> _ => {
-                        println!("timeout after {:?}", start.elapsed());
-                        break;
-                    },
+            let mut items = 0;
+            let mut busy_time = time::Duration::from_secs(0);
+            for p in rlone.iter() {
+                if cancel_token.load(Ordering::SeqCst) {
+                    break;
                 }
+                let item_start = time::Instant::now();
+                let result = f(p.num);
+                busy_time += item_start.elapsed();
+                items += 1;
+                slone
+                    .send(ParVal {
+                        num: result,
+                        i: p.i,
+                    })
+                    .expect("couldn't send final value");
             }
+            stats_sender
+                .send((worker, items, busy_time))
+                .expect("couldn't send worker stats");
         });
         threads.push(thread);
     }
@@ -70,13 +149,21 @@ where
 
     drop(r1);
     drop(s2);
-    for _ in 0..input_vec.len() {
-        let p = r2.recv().expect("couldn't recv final value");
-        output_vec[p.i] = p.num;
+    drop(stats_sender);
+    for p in r2.iter() {
+        output_vec[p.i] = Some(p.num);
     }
 
-    drop(r2);
-    output_vec
+    let mut stats = ParStats {
+        items_per_worker: vec![0; num_threads],
+        busy_time_per_worker: vec![time::Duration::from_secs(0); num_threads],
+        wall_time: start.elapsed(),
+    };
+    for (worker, items, busy_time) in stats_receiver.iter() {
+        stats.items_per_worker[worker] = items;
+        stats.busy_time_per_worker[worker] = busy_time;
+    }
+    (output_vec, stats)
 }
 
 #[test]
@@ -90,3 +177,95 @@ fn squares() {
     });
     assert_eq!(expected, result);
 }
+
+#[test]
+fn cancel_mid_run() {
+    use std::sync::atomic::AtomicUsize;
+
+    // Items past the first 10 hold their worker until the token has been set, so no more than
+    // one item per worker can start after that
+    static STARTED: AtomicUsize = AtomicUsize::new(0);
+    static TOKEN_SET: AtomicBool = AtomicBool::new(false);
+    let v: Vec<u64> = (0..100).collect();
+    let cancel_token = Arc::new(AtomicBool::new(false));
+    let canceller_token = cancel_token.clone();
+    let canceller = thread::spawn(move || {
+        while STARTED.load(Ordering::SeqCst) < 10 {
+            thread::sleep(time::Duration::from_millis(1));
+        }
+        canceller_token.store(true, Ordering::SeqCst);
+        TOKEN_SET.store(true, Ordering::SeqCst);
+    });
+
+    let (result, cancelled) = parallel_map_cancellable(v, 2, cancel_token, |num| {
+        if STARTED.fetch_add(1, Ordering::SeqCst) >= 10 {
+            while !TOKEN_SET.load(Ordering::SeqCst) {
+                thread::sleep(time::Duration::from_millis(1));
+            }
+        }
+        num * num
+    });
+    canceller.join().unwrap();
+
+    assert!(cancelled);
+    assert_eq!(result.len(), 100);
+    let processed = result.iter().filter(|val| val.is_some()).count();
+    assert_eq!(processed, STARTED.load(Ordering::SeqCst));
+    assert!(
+        (10..=12).contains(&processed),
+        "processed {} items",
+        processed
+    );
+    for (i, val) in result.iter().enumerate() {
+        if let Some(val) = val {
+            assert_eq!(*val, (i * i) as u64);
+        }
+    }
+}
+
+#[test]
+fn cancel_after_finish() {
+    // The token is set while the last item is being computed, by which point there's nothing left
+    // for it to stop
+    let v: Vec<u64> = (0..5).collect();
+    let cancel_token = Arc::new(AtomicBool::new(false));
+    // f has to be Copy, so it gets the token by 'static reference
+    let worker_token: &'static AtomicBool = Box::leak(Box::new(cancel_token.clone()));
+    let (result, cancelled) = parallel_map_cancellable(v, 1, cancel_token, move |num| {
+        if num == 4 {
+            worker_token.store(true, Ordering::SeqCst);
+        }
+        num * num
+    });
+    assert!(!cancelled);
+    assert_eq!(result, vec![Some(0), Some(1), Some(4), Some(9), Some(16)]);
+}
+
+#[test]
+fn skewed_stats() {
+    use std::sync::atomic::AtomicUsize;
+
+    // The first item isn't finished until all the others are, so whichever worker picks it up
+    // should process just that one while the other worker does the rest
+    static FINISHED: AtomicUsize = AtomicUsize::new(0);
+    let v: Vec<u64> = (0..21).collect();
+    let (result, stats) = parallel_map_stats(v, 2, |num| {
+        if num == 0 {
+            while FINISHED.load(Ordering::SeqCst) < 20 {
+                thread::sleep(time::Duration::from_millis(1));
+            }
+        } else {
+            FINISHED.fetch_add(1, Ordering::SeqCst);
+        }
+        num * num
+    });
+    let expected: Vec<u64> = (0..21).map(|num| num * num).collect();
+    assert_eq!(expected, result);
+
+    let mut items_per_worker = stats.items_per_worker.clone();
+    items_per_worker.sort_unstable();
+    assert_eq!(items_per_worker, vec![1, 20], "stats: {:?}", stats);
+    for busy_time in &stats.busy_time_per_worker {
+        assert!(*busy_time <= stats.wall_time);
+    }
+}